@@ -1,4 +1,9 @@
-use ag::pipeline::{ErrorReporter, Pipeline, QueryContainer};
+use ag::pipeline::{
+    expand_env_vars, is_gzip, seed_random, seek_to_last_lines, seek_to_line_at, ColorChoice,
+    ConcatReader, Decompressed, ErrorReporter, Follow, InputFormat, MacroConfig, OutputMode,
+    Overflow, Pipeline, QueryContainer, RenderConfig, SnapshotConfig, SplitConfig, Theme,
+    TrailerTarget, DEFAULT_UPDATE_INTERVAL, ELLIPSIS, MIN_UPDATE_INTERVAL,
+};
 use annotate_snippets::snippet::Snippet;
 use atty::Stream;
 use human_panic::setup_panic;
@@ -6,12 +11,18 @@ use quicli::prelude::*;
 use self_update;
 use self_update::cargo_crate_version;
 use std::env;
+use std::ffi::OsString;
+use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
 
-use structopt::clap::ArgGroup;
+use structopt::clap::{AppSettings, ArgGroup, ErrorKind};
 
 // Needed to require either "--self-update" or a query
 fn main_arg_group() -> ArgGroup<'static> {
@@ -21,13 +32,25 @@ fn main_arg_group() -> ArgGroup<'static> {
 #[derive(Debug, StructOpt)]
 #[structopt(
     after_help = "For more details + docs, see https://github.com/rcoh/angle-grinder",
-    raw(group = "main_arg_group()")
+    raw(group = "main_arg_group()"),
+    // Defaults from the config file and AGRIND_OPTS come first, so a later flag wins
+    raw(global_setting = "AppSettings::AllArgsOverrideSelf")
 )]
 struct Cli {
     /// The query
     #[structopt(group = "main")]
     query: Option<String>,
 
+    /// Files to read, one after another, instead of stdin. Use `--` before the query if it
+    /// could be mistaken for a flag, like `agrind -- '-x' app.log`
+    #[structopt(parse(from_os_str))]
+    files: Vec<PathBuf>,
+
+    /// Read gzipped input as it is, rather than decompressing it. Input starting with the gzip
+    /// magic bytes is otherwise decompressed automatically
+    #[structopt(long = "no-decompress")]
+    no_decompress: bool,
+
     /// Update agrind to the latest published version Github (https://github.com/rcoh/angle-grinder)
     #[structopt(long = "self-update", group = "main")]
     update: bool,
@@ -36,10 +59,261 @@ struct Cli {
     #[structopt(long = "file", short = "f")]
     file: Option<String>,
 
+    /// Only read the last N lines of the file given with --file, like `tail -n`. The file is read
+    /// backward from the end, so the rest of a large file is skipped without being read
+    #[structopt(long = "input-tail")]
+    input_tail: Option<u64>,
+
+    /// Start reading the file given with --file at this byte offset. If the offset is in the
+    /// middle of a line, reading starts at the next line
+    #[structopt(long = "seek", conflicts_with = "input_tail")]
+    seek: Option<u64>,
+
+    /// When the input ends, print the byte offset reading stopped at to stderr as
+    /// `offset=<bytes>`, so a later run can continue from there with --seek
+    #[structopt(long = "report-offset")]
+    report_offset: bool,
+
+    /// Keep reading the file given with --file as lines are appended to it, like `tail -f`,
+    /// until Ctrl-C. Aggregates are redrawn as they change. A truncated or rotated file is read
+    /// again from the start
+    #[structopt(long = "follow", conflicts_with = "report_offset")]
+    follow: bool,
+
+    /// Set how input lines are parsed before the query runs. One of: raw, auto. `auto` parses
+    /// each line as JSON or logfmt if it looks like one of them
+    #[structopt(long = "input-format")]
+    input_format: Option<InputFormat>,
+
     /// Provide a Rust std::fmt string to format output
     #[structopt(long = "format", short = "m")]
     format: Option<String>,
 
+    /// Write this after each record instead of a newline. Accepts the escapes \0, \n, \t, \r
+    /// and \\, so `--output-record-separator '\0'` gives null terminated records for `xargs -0`.
+    /// Aggregate tables still end lines with newlines
+    #[structopt(
+        long = "output-record-separator",
+        parse(try_from_str = "parse_separator")
+    )]
+    output_record_separator: Option<String>,
+
+    /// After all the output, write a trailer line like
+    /// `agrind-trailer records=3 bytes=42 crc32=414fa339` with the number of records (or
+    /// aggregate rows), bytes and the CRC-32 of those bytes, so truncated output can be detected
+    #[structopt(long = "trailer", conflicts_with = "split_by")]
+    trailer: bool,
+
+    /// Write the --trailer to stderr instead of the end of the output
+    #[structopt(long = "trailer-stderr", requires = "trailer")]
+    trailer_stderr: bool,
+
+    /// Set the output mode. One of: legacy, csv, xlsx, vertical, sql, json, markdown
+    #[structopt(long = "output", short = "o")]
+    output: Option<OutputMode>,
+
+    /// Write output to a file instead of stdout. Unless --output is given, the output mode is
+    /// chosen from the extension: `.csv` for csv, `.xlsx` for a spreadsheet, `.sql` for sql,
+    /// `.json` for json, `.md` for markdown, otherwise legacy
+    #[structopt(long = "output-file", parse(from_os_str))]
+    output_file: Option<PathBuf>,
+
+    /// Like --output-file, but add this run's output to the end of the file instead of replacing
+    /// it. A CSV header is only written if the file is empty. The file is locked until the run
+    /// ends, so runs appending to the same file at once wait for each other
+    #[structopt(long = "append", parse(from_os_str), conflicts_with = "output_file")]
+    append: Option<PathBuf>,
+
+    /// Write each record to `<value>.log` in --split-dir, where `<value>` is the value of this
+    /// field, instead of to the output
+    #[structopt(long = "split-by")]
+    split_by: Option<String>,
+
+    /// The directory for the files written by --split-by. Defaults to the current directory
+    #[structopt(long = "split-dir", parse(from_os_str))]
+    split_dir: Option<PathBuf>,
+
+    /// Suppress output lines that are identical to the line before them, like `uniq`
+    #[structopt(long = "dedup-output")]
+    dedup_output: bool,
+
+    /// Like --dedup-output, but prefix each line with the number of times it was repeated
+    #[structopt(long = "dedup-count")]
+    dedup_count: bool,
+
+    /// Render the fields of each record in alphabetical order, instead of adding fields to the end
+    /// as they first appear
+    #[structopt(long = "sort-record-fields")]
+    sort_record_fields: bool,
+
+    /// Render these comma separated fields first in each record, in the order given, like
+    /// `level,host`. The rest follow in their usual order
+    #[structopt(long = "pin-fields")]
+    pin_fields: Option<String>,
+
+    /// How often to redraw live aggregates, in milliseconds. Defaults to 50. Intervals under 20
+    /// are raised to 20
+    #[structopt(long = "interval")]
+    interval: Option<u64>,
+
+    /// Redraw live aggregates after this many records as well as on a timer, so bursts of input
+    /// show up right away
+    #[structopt(long = "records-per-aggregate-update")]
+    records_per_aggregate_update: Option<u64>,
+
+    /// Leave fields that are None or empty out of records entirely, so sparse records take up
+    /// less space. Records with different fields won't line up
+    #[structopt(long = "compact")]
+    compact: bool,
+
+    /// Size each column to fit this percentile of its values, like 95, instead of the longest
+    /// one. Values that don't fit are cut off with an ellipsis
+    #[structopt(long = "width-percentile")]
+    width_percentile: Option<f64>,
+
+    /// What to do with values too long for their column. One of: truncate, wrap. `wrap`
+    /// continues them on the following lines instead of cutting them off
+    #[structopt(long = "overflow")]
+    overflow: Option<Overflow>,
+
+    /// Write values that are None, like a JSON null, as this text instead of `None`, like `null`
+    /// or `-`. Fields missing from a row of an aggregate are always left blank
+    #[structopt(long = "none-placeholder")]
+    none_placeholder: Option<String>,
+
+    /// Repeat the header of aggregate tables after every N rows, which helps when a long table
+    /// is written to a file or pager
+    #[structopt(long = "header-interval")]
+    header_interval: Option<usize>,
+
+    /// Only write ASCII characters, for terminals that can't show Unicode. Values that are cut
+    /// off end in `...` instead of `…`, and sparklines are drawn with punctuation instead of
+    /// block characters
+    #[structopt(long = "ascii")]
+    ascii: bool,
+
+    /// Right-align the columns of aggregate tables that only hold numbers
+    #[structopt(long = "right-align-numbers")]
+    right_align_numbers: bool,
+
+    /// How many decimal places tables round numbers to. Defaults to 2. Columns always shown with
+    /// decimal places, like averages, use it too, so `--precision 0` shows them as whole numbers.
+    /// Machine-readable output, like csv or json, always keeps every digit
+    #[structopt(long = "precision")]
+    precision: Option<usize>,
+
+    /// Separate the thousands of numbers in tables, like `1,000,000`. Only the whole part of a
+    /// decimal is grouped. Machine-readable output, like csv or json, is never grouped
+    #[structopt(long = "group-digits")]
+    group_digits: bool,
+
+    /// The character --group-digits puts between groups of digits, like `.` or a space. Defaults
+    /// to `,`. With `.`, the decimal point is written as `,`
+    #[structopt(long = "digit-separator", requires = "group_digits")]
+    digit_separator: Option<char>,
+
+    /// The table named in the statements written by `--output sql`. Defaults to `results`
+    #[structopt(long = "table")]
+    table: Option<String>,
+
+    /// Start `--output sql` with a `CREATE TABLE` statement, with column types inferred from
+    /// the values
+    #[structopt(long = "sql-create")]
+    sql_create: bool,
+
+    /// Colors for records and aggregate tables. One of: auto, dark, light, none. `auto` picks
+    /// light or dark from the COLORFGBG environment variable. Set NO_COLOR to disable colors
+    /// entirely
+    #[structopt(long = "theme")]
+    theme: Option<Theme>,
+
+    /// When to color the output and errors. One of: always, never, auto. `auto`, the default,
+    /// colors when writing to a terminal and NO_COLOR isn't set. `always` colors even when
+    /// piped and even if NO_COLOR is set
+    #[structopt(long = "color")]
+    color: Option<ColorChoice>,
+
+    /// Never color the output, like `--color never`
+    #[structopt(long = "no-color")]
+    no_color: bool,
+
+    /// Periodically write the current state of the aggregate to this file, replacing it each
+    /// time. The format is picked from the extension like with --output-file
+    #[structopt(long = "snapshot-file", parse(from_os_str))]
+    snapshot_file: Option<PathBuf>,
+
+    /// How often to write --snapshot-file, like `30s` or `5m`. Defaults to 30 seconds
+    #[structopt(
+        long = "snapshot-interval",
+        parse(try_from_str = "humantime::parse_duration")
+    )]
+    snapshot_interval: Option<Duration>,
+
+    /// When the output isn't a terminal, also write the aggregate as it stands this often, like
+    /// `30s` or `5m`, each table after a `--- partial result N ---` line. Otherwise aggregates
+    /// are only written once the input ends
+    #[structopt(
+        long = "partial-interval",
+        parse(try_from_str = "humantime::parse_duration")
+    )]
+    partial_interval: Option<Duration>,
+
+    /// Enrich the records of the query with the rows of a second query over another file, like
+    /// `users.log | json | fields id, name`. The part before the first `|` is the file. The
+    /// second query is run to the end first and its rows are kept in memory
+    #[structopt(long = "join", requires = "join_on")]
+    join: Option<String>,
+
+    /// The field that matches records of the query with rows of the --join query
+    #[structopt(long = "join-on", requires = "join")]
+    join_on: Option<String>,
+
+    /// Write at most N records for each value of a field, like `host=5`, so one busy value
+    /// can't crowd out the rest. Further records with that value are dropped until the counts
+    /// reset
+    #[structopt(long = "per-key-limit", parse(try_from_str = "parse_per_key_limit"))]
+    per_key_limit: Option<(String, u64)>,
+
+    /// How often the --per-key-limit counts start over, like `30s` or `5m`. Defaults to 1 minute
+    #[structopt(
+        long = "per-key-reset",
+        requires = "per_key_limit",
+        parse(try_from_str = "humantime::parse_duration")
+    )]
+    per_key_reset: Option<Duration>,
+
+    /// Warn when `count_distinct` holds more than N distinct values for one group. Every distinct
+    /// value is kept in memory, so high-cardinality fields can use a lot of it. Defaults to
+    /// 1000000
+    #[structopt(long = "distinct-warning")]
+    distinct_warning: Option<usize>,
+
+    /// Seed the random numbers returned by `rand()`, so a query that samples with it picks the
+    /// same rows every time it runs over the same input
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// Print how the query will run to stderr before running it: each operator with its defaults
+    /// filled in, the columns it creates and whether it streams records or aggregates them
+    #[structopt(long = "show-plan")]
+    show_plan: bool,
+
+    /// When the input ends, print to stderr how many records went into and came out of the
+    /// search and each operator before the first aggregate, like `where status >= 500: 120 →
+    /// 3 (dropped 117)`
+    #[structopt(long = "stats")]
+    stats: bool,
+
+    /// Replace `$NAME` in the query with an empty string when the environment variable isn't
+    /// set, instead of failing
+    #[structopt(long = "allow-unset-env")]
+    allow_unset_env: bool,
+
+    /// Use the defaults from the `[profiles.<name>]` table of the config file, on top of the
+    /// top level defaults. It can also be set in AGRIND_OPTS or the config file
+    #[structopt(long = "profile")]
+    profile: Option<String>,
+
     #[structopt(flatten)]
     verbosity: Verbosity,
 }
@@ -48,6 +322,268 @@ struct Cli {
 pub enum InvalidArgs {
     #[fail(display = "Query was missing. Usage: `agrind 'query'`")]
     MissingQuery,
+
+    #[fail(display = "No profile named `{}` in {}", name, path)]
+    UnknownProfile { name: String, path: String },
+
+    #[fail(display = "AGRIND_OPTS ends inside a quote or after a backslash")]
+    UnterminatedOpts,
+
+    #[fail(
+        display = "{} needs a file to seek within, so it can't be used with stdin. Use it with --file",
+        flag
+    )]
+    NeedsFile { flag: String },
+
+    #[fail(
+        display = "{} can't seek within a gzipped file. Decompress it first, or pass --no-decompress to read it as it is",
+        flag
+    )]
+    SeekCompressed { flag: String },
+
+    #[fail(
+        display = "{} can only seek within one file, but {} files were given",
+        flag, count
+    )]
+    NeedsSingleFile { flag: String, count: usize },
+
+    #[fail(display = "--split-dir is only used with --split-by")]
+    SplitDirWithoutField,
+
+    #[fail(display = "--split-by can't be combined with --dedup-output or --dedup-count")]
+    SplitWithDedup,
+
+    #[fail(
+        display = "--width-percentile must be more than 0 and at most 100, found {}",
+        percentile
+    )]
+    WidthPercentile { percentile: f64 },
+
+    #[fail(display = "--snapshot-interval is only used with --snapshot-file")]
+    SnapshotIntervalWithoutFile,
+
+    #[fail(
+        display = "--partial-interval writes tables, so it can't be used with csv, json, sql or xlsx output"
+    )]
+    PartialMachineReadable,
+
+    #[fail(display = "{} is only used with --output sql", flag)]
+    NeedsSqlOutput { flag: String },
+
+    #[fail(display = "--append can't add to a spreadsheet. Use csv output instead")]
+    AppendXlsx,
+
+    #[fail(display = "--per-key-limit only limits records, so it can't be used with an aggregate")]
+    PerKeyLimitWithAggregate,
+}
+
+/// The exit code of a run stopped with Ctrl-C, like a shell reports for a process killed by
+/// SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// The exit code of a run that stopped because the input couldn't be read, once the error has
+/// been reported
+const READ_ERROR_EXIT_CODE: i32 = 1;
+
+/// Replace the escapes in a separator given on the command line, since a null byte can't be
+/// passed as an argument
+fn parse_separator(raw: &str) -> Result<String, String> {
+    let mut separator = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            separator.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('0') => separator.push('\0'),
+            Some('n') => separator.push('\n'),
+            Some('t') => separator.push('\t'),
+            Some('r') => separator.push('\r'),
+            Some('\\') => separator.push('\\'),
+            Some(other) => return Err(format!("Unknown escape `\\{}` in the separator", other)),
+            None => return Err("The separator ends with an unused backslash".to_string()),
+        }
+    }
+    Ok(separator)
+}
+
+/// Split a `--per-key-limit` like `host=5` into the field and the number of records
+fn parse_per_key_limit(raw: &str) -> Result<(String, u64), String> {
+    let invalid = || {
+        format!(
+            "Expected a field and a number of records like `host=5`, found `{}`",
+            raw
+        )
+    };
+    let (field, limit) = raw.rsplit_once('=').ok_or_else(invalid)?;
+    let limit = limit.trim().parse().map_err(|_| invalid())?;
+    if field.trim().is_empty() {
+        return Err(invalid());
+    }
+    Ok((field.trim().to_string(), limit))
+}
+
+/// Decompress the input as it's read if it's gzipped, unless decompression was turned off
+fn decompressed<R: BufRead>(reader: R, decompress: bool) -> io::Result<Decompressed<R>> {
+    if decompress {
+        Decompressed::new(reader)
+    } else {
+        Ok(Decompressed::Plain(reader))
+    }
+}
+
+/// The config file with default flags: `$XDG_CONFIG_HOME/agrind/config.toml`, falling back to
+/// `~/.config/agrind/config.toml`
+fn config_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("agrind").join("config.toml"))
+}
+
+/// Convert an entry of the config file into a flag, like `output = "csv"` into `--output=csv`.
+/// Entries that don't name a flag are skipped with a warning.
+fn config_flag(key: &str, value: &toml::Value) -> Option<String> {
+    let flag = match value {
+        toml::Value::Boolean(true) => format!("--{}", key),
+        toml::Value::Boolean(false) => return None,
+        toml::Value::String(s) => format!("--{}={}", key, s),
+        toml::Value::Integer(n) => format!("--{}={}", key, n),
+        toml::Value::Float(n) => format!("--{}={}", key, n),
+        _ => {
+            eprintln!(
+                "warning: ignoring `{}` in the config file: expected a string, number or boolean",
+                key
+            );
+            return None;
+        }
+    };
+    // Let clap decide whether the flag exists.  `--self-update` stands in for the query.
+    match Cli::clap().get_matches_from_safe(["agrind", "--self-update", &flag]) {
+        Err(ref e) if e.kind == ErrorKind::UnknownArgument => {
+            eprintln!(
+                "warning: ignoring unknown option `{}` in the config file",
+                key
+            );
+            None
+        }
+        _ => Some(flag),
+    }
+}
+
+/// Default flags from the config file and `AGRIND_OPTS`.  They go before the command line
+/// arguments, so the command line takes precedence over the environment, which takes precedence
+/// over the profile, which takes precedence over the rest of the config file.
+struct DefaultArgs {
+    path: Option<PathBuf>,
+    config: Vec<String>,
+    profiles: Option<toml::Value>,
+    opts: Vec<String>,
+}
+
+impl DefaultArgs {
+    fn load() -> Result<Self, Error> {
+        let path = config_path();
+        let config = match path.as_ref().map(fs::read_to_string) {
+            Some(Ok(contents)) => contents.parse::<toml::Value>()?,
+            Some(Err(ref e)) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(format_err!(
+                    "Failed to read {}: {}",
+                    path.unwrap().display(),
+                    e
+                ))
+            }
+            _ => toml::Value::Table(toml::value::Table::new()),
+        };
+        let mut args = Vec::new();
+        if let Some(table) = config.as_table() {
+            for (key, value) in table.iter().filter(|(key, _)| *key != "profiles") {
+                args.extend(config_flag(key, value));
+            }
+        }
+        let opts = match env::var("AGRIND_OPTS") {
+            Ok(opts) => split_opts(&opts)?,
+            Err(_) => Vec::new(),
+        };
+        Ok(DefaultArgs {
+            path,
+            config: args,
+            profiles: config.get("profiles").cloned(),
+            opts,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.config.is_empty() && self.opts.is_empty()
+    }
+
+    /// The flags of the `[profiles.<name>]` table
+    fn profile(&self, name: &str) -> Result<Vec<String>, Error> {
+        let table = self
+            .profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(name))
+            .and_then(toml::Value::as_table)
+            .ok_or_else(|| InvalidArgs::UnknownProfile {
+                name: name.to_string(),
+                path: self
+                    .path
+                    .as_ref()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_else(|| "the config file".to_string()),
+            })?;
+        Ok(table
+            .iter()
+            .filter_map(|(key, value)| config_flag(key, value))
+            .collect())
+    }
+
+    /// Parse the command line with the defaults, and those of `profile`, in front of it
+    fn parse(&self, profile: &[String]) -> Cli {
+        let mut cli_args = env::args_os();
+        let bin = cli_args.next();
+        Cli::from_iter(
+            bin.into_iter()
+                .chain(
+                    self.config
+                        .iter()
+                        .chain(profile)
+                        .chain(&self.opts)
+                        .map(OsString::from),
+                )
+                .chain(cli_args),
+        )
+    }
+}
+
+/// Split `AGRIND_OPTS` into arguments like a shell would: on whitespace, except inside single or
+/// double quotes, and a backslash takes the next character literally outside single quotes
+fn split_opts(opts: &str) -> Result<Vec<String>, InvalidArgs> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    let mut chars = opts.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                let escaped = chars.next().ok_or(InvalidArgs::UnterminatedOpts)?;
+                arg.get_or_insert_with(String::new).push(escaped);
+            }
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (_, c) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(InvalidArgs::UnterminatedOpts);
+    }
+    args.extend(arg);
+    Ok(args)
 }
 
 /// An ErrorReporter that writes errors related to the query string to the terminal
@@ -55,6 +591,16 @@ struct TermErrorReporter {
     formatter: annotate_snippets::formatter::DisplayListFormatter,
 }
 
+impl TermErrorReporter {
+    fn boxed(color: ColorChoice) -> Box<dyn ErrorReporter> {
+        Box::new(TermErrorReporter {
+            formatter: annotate_snippets::formatter::DisplayListFormatter::new(
+                color.enabled(atty::is(Stream::Stderr)),
+            ),
+        })
+    }
+}
+
 impl ErrorReporter for TermErrorReporter {
     fn handle_error(&self, snippet: Snippet) {
         let dl = annotate_snippets::display_list::DisplayList::from(snippet);
@@ -65,31 +611,319 @@ impl ErrorReporter for TermErrorReporter {
 
 fn main() -> CliResult {
     setup_panic!();
-    let args = Cli::from_args();
+    let defaults = DefaultArgs::load()?;
+    let args = if defaults.is_empty() {
+        Cli::from_args()
+    } else {
+        defaults.parse(&[])
+    };
+    // The profile can be picked by the config file or AGRIND_OPTS as well as the command line
+    let args = match args.profile {
+        Some(ref name) => defaults.parse(&defaults.profile(name)?),
+        None => args,
+    };
     if args.update {
         return update();
     }
-    let query = QueryContainer::new(
-        args.query.ok_or(InvalidArgs::MissingQuery)?,
-        Box::new(TermErrorReporter {
-            formatter: annotate_snippets::formatter::DisplayListFormatter::new(
-                env::var("NO_COLOR").is_err() && atty::is(Stream::Stderr),
-            ),
-        }),
-    );
+    let query = args.query.ok_or(InvalidArgs::MissingQuery)?;
+    let query = match env::var_os("HOME") {
+        Some(home) => MacroConfig::load(&Path::new(&home).join(".agrind.toml"))?.expand(&query)?,
+        None => query,
+    };
+    let allow_unset_env = args.allow_unset_env;
+    let query = expand_env_vars(&query, |name| match env::var(name) {
+        Ok(value) => Some(value),
+        Err(_) if allow_unset_env => Some(String::new()),
+        Err(_) => None,
+    })?;
+    let color = if args.no_color {
+        ColorChoice::Never
+    } else {
+        args.color.unwrap_or(ColorChoice::Auto)
+    };
+    let query = QueryContainer::new(query, TermErrorReporter::boxed(color));
     args.verbosity.setup_env_logger("agrind")?;
-    let pipeline = Pipeline::new(&query, args.format)?;
-    match args.file {
-        Some(file_name) => {
-            let f = File::open(file_name)?;
-            pipeline.process(BufReader::new(f))
+    let append = args.append.is_some();
+    let output_file = args.output_file.or(args.append);
+    let output_mode = args
+        .output
+        .or_else(|| output_file.as_ref().and_then(|f| OutputMode::from_path(f)))
+        .unwrap_or(OutputMode::Legacy);
+    if append && output_mode == OutputMode::Xlsx {
+        return Err(InvalidArgs::AppendXlsx.into());
+    }
+    if args.partial_interval.is_some() && output_mode.is_machine_readable() {
+        return Err(InvalidArgs::PartialMachineReadable.into());
+    }
+    if output_mode != OutputMode::Sql {
+        let flag = if args.table.is_some() {
+            Some("--table")
+        } else if args.sql_create {
+            Some("--sql-create")
+        } else {
+            None
+        };
+        if let Some(flag) = flag {
+            return Err(InvalidArgs::NeedsSqlOutput {
+                flag: flag.to_string(),
+            }
+            .into());
+        }
+    }
+    let split = match (args.split_by, args.split_dir) {
+        (Some(_), _) if args.dedup_output || args.dedup_count => {
+            return Err(InvalidArgs::SplitWithDedup.into())
+        }
+        (Some(field), dir) => Some(SplitConfig {
+            field,
+            dir: dir.unwrap_or_else(|| PathBuf::from(".")),
+        }),
+        (None, Some(_)) => return Err(InvalidArgs::SplitDirWithoutField.into()),
+        (None, None) => None,
+    };
+    if let Some(percentile) = args.width_percentile {
+        if !(percentile > 0.0 && percentile <= 100.0) {
+            return Err(InvalidArgs::WidthPercentile { percentile }.into());
+        }
+    }
+    let update_interval = match args.interval.map(Duration::from_millis) {
+        Some(interval) if interval < MIN_UPDATE_INTERVAL => {
+            eprintln!(
+                "warning: --interval can't be under {0}ms, so redrawing every {0}ms instead",
+                MIN_UPDATE_INTERVAL.as_millis()
+            );
+            MIN_UPDATE_INTERVAL
+        }
+        Some(interval) => interval,
+        None => DEFAULT_UPDATE_INTERVAL,
+    };
+    let snapshot = match (args.snapshot_file, args.snapshot_interval) {
+        (Some(path), interval) => Some(SnapshotConfig {
+            path,
+            interval: interval.unwrap_or_else(|| Duration::from_secs(30)),
+        }),
+        (None, Some(_)) => return Err(InvalidArgs::SnapshotIntervalWithoutFile.into()),
+        (None, None) => None,
+    };
+    let render_config = RenderConfig {
+        min_buffer: 4,
+        max_buffer: 8,
+        floating_points: args.precision.unwrap_or(2),
+        format: args.format,
+        output_mode,
+        output_file,
+        append,
+        dedup_output: args.dedup_output,
+        dedup_count: args.dedup_count,
+        theme: args.theme.unwrap_or(Theme::Auto),
+        color,
+        sort_record_fields: args.sort_record_fields,
+        pinned_fields: args
+            .pin_fields
+            .map(|fields| {
+                fields
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|field| !field.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        update_interval,
+        partial_interval: args.partial_interval,
+        records_per_update: args.records_per_aggregate_update,
+        split,
+        width_percentile: args.width_percentile,
+        snapshot,
+        compact: args.compact,
+        ascii: args.ascii,
+        right_align_numbers: args.right_align_numbers,
+        group_digits: args.group_digits,
+        digit_separator: args.digit_separator.unwrap_or(','),
+        ellipsis: if args.ascii { "..." } else { ELLIPSIS }.to_string(),
+        overflow: args.overflow.unwrap_or(Overflow::Truncate),
+        header_interval: args.header_interval.unwrap_or(0),
+        none_placeholder: args.none_placeholder.unwrap_or_else(|| "None".to_string()),
+        sql_table: args.table.unwrap_or_else(|| "results".to_string()),
+        sql_create: args.sql_create,
+        record_separator: args
+            .output_record_separator
+            .unwrap_or_else(|| "\n".to_string()),
+        trailer: match (args.trailer, args.trailer_stderr) {
+            (true, true) => Some(TrailerTarget::Stderr),
+            (true, false) => Some(TrailerTarget::Output),
+            _ => None,
+        },
+    };
+    if let Some(seed) = args.seed {
+        seed_random(seed);
+    }
+    let mut pipeline = Pipeline::new(
+        &query,
+        args.input_format.unwrap_or(InputFormat::Raw),
+        render_config,
+    )?;
+    if args.show_plan {
+        if let Some(plan) = query.describe_plan() {
+            eprintln!("{}", plan);
         }
-        None => {
+    }
+    if let (Some(join), Some(key)) = (args.join, args.join_on) {
+        let (path, side_query) = match join.find('|') {
+            Some(pipe) => (join[..pipe].trim(), format!("* {}", &join[pipe..])),
+            None => (join.trim(), "*".to_string()),
+        };
+        let side_query = QueryContainer::new(side_query, TermErrorReporter::boxed(color));
+        let side = Pipeline::new(&side_query, InputFormat::Raw, RenderConfig::default())?;
+        let side_file = File::open(path)
+            .map_err(|e| format_err!("Failed to open the --join file {}: {}", path, e))?;
+        pipeline
+            .join(side, BufReader::new(side_file), &key)
+            .map_err(|e| format_err!("Failed to read the --join file {}: {}", path, e))?;
+    }
+    if let Some(limit) = args.distinct_warning {
+        pipeline.warn_distinct_over(limit);
+    }
+    if let Some((field, limit)) = args.per_key_limit {
+        if pipeline.has_aggregate() {
+            return Err(InvalidArgs::PerKeyLimitWithAggregate.into());
+        }
+        let reset = args
+            .per_key_reset
+            .unwrap_or_else(|| Duration::from_secs(60));
+        pipeline.limit_per_key(&field, limit, reset);
+    }
+    let interrupt = pipeline.interrupt_handle();
+    {
+        let interrupt = interrupt.clone();
+        ctrlc::set_handler(move || {
+            if !interrupt.interrupt() {
+                // A second Ctrl-C doesn't wait for the output
+                process::exit(INTERRUPTED_EXIT_CODE);
+            }
+            // The input may never end, like stdin from a terminal, so don't wait for the read
+            // to return once the partial result is out.  The main thread gets a moment to exit
+            // on its own first, so --report-offset is still printed when it isn't blocked.
+            let interrupt = interrupt.clone();
+            thread::spawn(move || {
+                interrupt.wait_until_rendered();
+                thread::sleep(Duration::from_millis(200));
+                process::exit(INTERRUPTED_EXIT_CODE);
+            });
+        })?;
+    }
+    let mut files = args.files;
+    if let Some(file) = args.file {
+        files.insert(0, PathBuf::from(file));
+    }
+    let seek_flag = if args.input_tail.is_some() {
+        Some("--input-tail")
+    } else if args.seek.is_some() {
+        Some("--seek")
+    } else if args.report_offset {
+        Some("--report-offset")
+    } else if args.follow {
+        Some("--follow")
+    } else {
+        None
+    };
+    if files.len() != 1 {
+        match (seek_flag, files.len()) {
+            (Some(flag), 0) => {
+                return Err(InvalidArgs::NeedsFile {
+                    flag: flag.to_string(),
+                }
+                .into())
+            }
+            (Some(flag), count) => {
+                return Err(InvalidArgs::NeedsSingleFile {
+                    flag: flag.to_string(),
+                    count,
+                }
+                .into())
+            }
+            (None, _) => (),
+        }
+    }
+    // Files after the first are only opened once they're reached, so check they can be read
+    // before starting instead of failing partway through the output
+    for path in &files {
+        File::open(path).map_err(|e| format_err!("Failed to open {}: {}", path.display(), e))?;
+    }
+    let decompress = !args.no_decompress;
+    let stats = match files.len() {
+        1 if seek_flag.is_none() => {
+            let reader = BufReader::new(File::open(&files[0])?);
+            pipeline.process(decompressed(reader, decompress)?)
+        }
+        1 => {
+            let file_name = files[0].display().to_string();
+            let mut f = File::open(&files[0])?;
+            if decompress {
+                let mut magic = [0; 2];
+                let read = f.read(&mut magic)?;
+                if is_gzip(&magic[..read]) {
+                    return Err(InvalidArgs::SeekCompressed {
+                        flag: seek_flag.unwrap_or_default().to_string(),
+                    }
+                    .into());
+                }
+                f.seek(SeekFrom::Start(0))?;
+            }
+            let seek_error = |flag: &str, e: io::Error| {
+                format_err!("{} can't seek within {}: {}", flag, file_name, e)
+            };
+            if let Some(lines) = args.input_tail {
+                seek_to_last_lines(&mut f, lines).map_err(|e| seek_error("--input-tail", e))?;
+            }
+            if let Some(offset) = args.seek {
+                seek_to_line_at(&mut f, offset).map_err(|e| seek_error("--seek", e))?;
+            }
+            if args.follow {
+                let interrupt = interrupt.clone();
+                let follow = Follow::new(files[0].clone(), f, move || interrupt.is_interrupted())?;
+                pipeline.process(BufReader::new(follow))
+            } else {
+                let mut reader = BufReader::new(f);
+                let stats = pipeline.process(&mut reader);
+                if args.report_offset {
+                    let offset = reader
+                        .stream_position()
+                        .map_err(|e| seek_error("--report-offset", e))?;
+                    eprintln!("offset={}", offset);
+                }
+                stats
+            }
+        }
+        0 => {
+            if output_mode == OutputMode::Sql && !pipeline.has_aggregate() {
+                eprintln!(
+                    "warning: writing an INSERT for every record of stdin, which may never end"
+                );
+            }
             let stdin = io::stdin();
             let locked = stdin.lock();
-            pipeline.process(locked)
+            pipeline.process(decompressed(locked, decompress)?)
+        }
+        _ => {
+            let inputs = files
+                .iter()
+                .map(|path| decompressed(BufReader::new(File::open(path)?), decompress));
+            pipeline.process(BufReader::new(ConcatReader::new(inputs)))
         }
     };
+    let stats = match stats {
+        Ok(stats) => stats,
+        Err(_) => process::exit(READ_ERROR_EXIT_CODE),
+    };
+    if args.stats {
+        for stage in stats {
+            eprintln!("{}", stage);
+        }
+    }
+    if interrupt.is_interrupted() {
+        process::exit(INTERRUPTED_EXIT_CODE);
+    }
     Ok(())
 }
 
@@ -11,6 +11,37 @@ use std::fmt::Display;
 
 pub type VMap = HashMap<String, Value>;
 
+/// Insert the configured separator between each group of three digits of a rendered number, like
+/// `1,000,000`.  Only the whole part is grouped, so the decimals of a float are left as they are,
+/// except that grouping with `.` writes the decimal point as `,`, like `1.234,5`.  Output that's
+/// read by other programs is never grouped.
+fn group_digits(rendered: String, render_config: &render::RenderConfig) -> String {
+    if !render_config.group_digits || render_config.output_mode.is_machine_readable() {
+        return rendered;
+    }
+    let digits_start = if rendered.starts_with('-') { 1 } else { 0 };
+    let digits_end = rendered[digits_start..]
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(rendered.len(), |end| digits_start + end);
+    let digits = &rendered[digits_start..digits_end];
+    let mut grouped = String::with_capacity(rendered.len() + digits.len() / 3);
+    grouped.push_str(&rendered[..digits_start]);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && i % 3 == digits.len() % 3 {
+            grouped.push(render_config.digit_separator);
+        }
+        grouped.push(digit);
+    }
+    let rest = &rendered[digits_end..];
+    if render_config.digit_separator == '.' && rest.starts_with('.') {
+        grouped.push(',');
+        grouped.push_str(&rest[1..]);
+    } else {
+        grouped.push_str(rest);
+    }
+    grouped
+}
+
 pub enum Row {
     Aggregate(Aggregate),
     Record(Record),
@@ -20,6 +51,21 @@ pub enum Row {
 pub struct Aggregate {
     pub columns: Vec<String>,
     pub data: Vec<VMap>,
+    /// The suggested display format for columns produced by aggregate functions
+    pub formats: HashMap<String, ValueFormat>,
+}
+
+/// How the values of an aggregate column should be displayed.  Without a format a value
+/// renders as whatever type it happens to hold, so an average of whole numbers would print as
+/// an integer in one row and a float in the next.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ValueFormat {
+    /// Round floats to the nearest integer
+    Integer,
+    /// Render integers with the configured number of decimal places
+    Float,
+    /// Draw an array of numbers as a sparkline
+    Sparkline,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -100,12 +146,56 @@ impl Value {
         }
     }
 
+    /// Render the value in the suggested format.  Only numbers, and arrays of them for
+    /// sparklines, are affected by the format.
+    pub fn render_as(
+        &self,
+        render_config: &render::RenderConfig,
+        format: Option<ValueFormat>,
+    ) -> String {
+        match (format, self) {
+            // Machine-readable output keeps whole numbers whole, like it keeps every digit of
+            // a float
+            (Some(ValueFormat::Float), Value::Int(_))
+                if render_config.output_mode.is_machine_readable() =>
+            {
+                self.render(render_config)
+            }
+            (Some(ValueFormat::Float), Value::Int(i)) => group_digits(
+                format!("{:.*}", render_config.floating_points, *i as f64),
+                render_config,
+            ),
+            (Some(ValueFormat::Integer), Value::Float(f)) => {
+                group_digits(format!("{:.0}", f), render_config)
+            }
+            (Some(ValueFormat::Sparkline), Value::Array(points)) => {
+                let points: Vec<f64> = points
+                    .iter()
+                    .filter_map(|point| match point {
+                        Value::Int(i) => Some(*i as f64),
+                        Value::Float(f) => Some(f.into_inner()),
+                        _ => None,
+                    })
+                    .collect();
+                render::sparkline(&points, render_config.ascii)
+            }
+            _ => self.render(render_config),
+        }
+    }
+
     pub fn render(&self, render_config: &render::RenderConfig) -> String {
         match *self {
             Value::Str(ref s) => s.to_string(),
-            Value::Int(ref s) => format!("{}", s),
-            Value::None => "None".to_string(),
-            Value::Float(ref s) => format!("{:.*}", render_config.floating_points, s),
+            Value::Int(ref s) => group_digits(format!("{}", s), render_config),
+            Value::None => render_config.none_placeholder.clone(),
+            // The shortest text that reads back as exactly the same float
+            Value::Float(ref s) if render_config.output_mode.is_machine_readable() => {
+                group_digits(format!("{}", s), render_config)
+            }
+            Value::Float(ref s) => group_digits(
+                format!("{:.*}", render_config.floating_points, s),
+                render_config,
+            ),
             Value::Bool(ref s) => format!("{}", s),
             Value::Obj(ref o) => {
                 // todo: this is pretty janky...
@@ -137,10 +227,12 @@ impl Value {
         }
     }
 
+    /// A whole number is an `Int`, unless it's too large for one: casting it would saturate
     pub fn from_float(f: f64) -> Value {
-        let rounded = f as i64;
-        if (f - f.floor()).abs() < std::f64::EPSILON {
-            Value::Int(rounded)
+        // i64::MIN is -2^63, a float exactly, while i64::MAX rounds up to 2^63 as one
+        let fits = f >= i64::MIN as f64 && f < -(i64::MIN as f64);
+        if fits && (f - f.floor()).abs() < std::f64::EPSILON {
+            Value::Int(f as i64)
         } else {
             Value::Float(OrderedFloat(f))
         }
@@ -192,6 +284,7 @@ impl Aggregate {
         Aggregate {
             data: raw_data,
             columns,
+            formats: HashMap::new(),
         }
     }
 }
@@ -261,7 +354,7 @@ impl Record {
                 std::mem::replace(root_record, value);
             }
             // These should not happen, if so this is a programming error
-            // since the data cannot be indexed by BoolUnary / Comparison / Value Exprs.
+            // since the data cannot be indexed by BoolUnary / Comparison / Arithmetic / Logical / InCidr / Glob / RegexMatch / Fuzzy / Value Exprs.
             Expr::BoolUnary(_) => Err(EvalError::ExpectedXYZ {
                 expected: "valid expr".to_string(),
                 found: "bool unary expr".to_string(),
@@ -270,6 +363,34 @@ impl Record {
                 expected: "valid expr".to_string(),
                 found: "comparison expr".to_string(),
             })?,
+            Expr::Arithmetic(_) => Err(EvalError::ExpectedXYZ {
+                expected: "valid expr".to_string(),
+                found: "arithmetic expr".to_string(),
+            })?,
+            Expr::Logical(_) => Err(EvalError::ExpectedXYZ {
+                expected: "valid expr".to_string(),
+                found: "logical expr".to_string(),
+            })?,
+            Expr::InCidr(_) => Err(EvalError::ExpectedXYZ {
+                expected: "valid expr".to_string(),
+                found: "in_cidr expr".to_string(),
+            })?,
+            Expr::Glob(_) => Err(EvalError::ExpectedXYZ {
+                expected: "valid expr".to_string(),
+                found: "glob expr".to_string(),
+            })?,
+            Expr::RegexMatch(_) => Err(EvalError::ExpectedXYZ {
+                expected: "valid expr".to_string(),
+                found: "regex match expr".to_string(),
+            })?,
+            Expr::Fuzzy(_) => Err(EvalError::ExpectedXYZ {
+                expected: "valid expr".to_string(),
+                found: "fuzzy expr".to_string(),
+            })?,
+            Expr::FunctionCall(_) => Err(EvalError::ExpectedXYZ {
+                expected: "valid expr".to_string(),
+                found: "function call".to_string(),
+            })?,
             Expr::Value(_) => Err(EvalError::ExpectedXYZ {
                 expected: "valid expr".to_string(),
                 found: "value expr".to_string(),
@@ -306,7 +427,7 @@ impl Record {
 mod tests {
     use super::*;
     use maplit::hashmap;
-    use render::RenderConfig;
+    use render::{OutputMode, RenderConfig};
 
     #[test]
     fn record_put_get() {
@@ -343,6 +464,90 @@ mod tests {
         assert_eq!(rec.render(&RenderConfig::default()), "[false, 123.50, []]");
     }
 
+    #[test]
+    fn render_with_format() {
+        let config = RenderConfig::default();
+        assert_eq!(
+            Value::Int(3).render_as(&config, Some(ValueFormat::Float)),
+            "3.00"
+        );
+        assert_eq!(
+            Value::from_float(2.6).render_as(&config, Some(ValueFormat::Integer)),
+            "3"
+        );
+        assert_eq!(
+            Value::from_float(2.5).render_as(&config, Some(ValueFormat::Float)),
+            "2.50"
+        );
+        assert_eq!(
+            Value::Str("3".to_string()).render_as(&config, Some(ValueFormat::Float)),
+            "3"
+        );
+        assert_eq!(Value::Int(3).render_as(&config, None), "3");
+    }
+
+    #[test]
+    fn render_machine_readable_floats() {
+        let config = RenderConfig {
+            output_mode: OutputMode::Csv,
+            ..RenderConfig::default()
+        };
+        let f = 0.1 + 0.2;
+        let rendered = Value::from_float(f).render(&config);
+        assert_eq!(rendered, "0.30000000000000004");
+        assert_eq!(rendered.parse::<f64>().unwrap(), f);
+        assert_eq!(
+            Value::Int(3).render_as(&config, Some(ValueFormat::Float)),
+            "3"
+        );
+        assert_eq!(
+            Value::from_float(2.5).render_as(&config, Some(ValueFormat::Float)),
+            "2.5"
+        );
+    }
+
+    #[test]
+    fn render_grouped_digits() {
+        let config = RenderConfig {
+            group_digits: true,
+            ..RenderConfig::default()
+        };
+        assert_eq!(Value::Int(1_000_000).render(&config), "1,000,000");
+        assert_eq!(Value::Int(-12_345).render(&config), "-12,345");
+        assert_eq!(Value::Int(999).render(&config), "999");
+        assert_eq!(
+            Value::from_float(1234567.125).render(&config),
+            "1,234,567.12"
+        );
+        assert_eq!(Value::from_float(f64::NAN).render(&config), "NaN");
+        assert_eq!(
+            Value::Int(12_345).render_as(&config, Some(ValueFormat::Float)),
+            "12,345.00"
+        );
+        assert_eq!(Value::Str("12345".to_string()).render(&config), "12345");
+        let dots = RenderConfig {
+            digit_separator: '.',
+            ..config
+        };
+        // The decimal point becomes a comma so it can't be mistaken for a separator
+        assert_eq!(Value::from_float(-4321.5).render(&dots), "-4.321,50");
+        assert_eq!(
+            Value::from_float(-1234567.89).render(&dots),
+            "-1.234.567,89"
+        );
+        assert_eq!(Value::Int(1234).render(&dots), "1.234");
+        for output_mode in &[OutputMode::Csv, OutputMode::Json, OutputMode::Sql] {
+            let machine = RenderConfig {
+                output_mode: *output_mode,
+                group_digits: true,
+                digit_separator: '.',
+                ..RenderConfig::default()
+            };
+            assert_eq!(Value::from_float(-4321.5).render(&machine), "-4321.5");
+            assert_eq!(Value::Int(1_000_000).render(&machine), "1000000");
+        }
+    }
+
     #[test]
     #[should_panic]
     fn panic_on_invalid_row() {
@@ -369,6 +574,25 @@ mod tests {
         assert_eq!(Value::from_string("1 "), Value::Int(1));
     }
 
+    #[test]
+    fn from_float_out_of_range() {
+        assert_eq!(
+            Value::from_float(-9.0e18),
+            Value::Int(-9_000_000_000_000_000_000)
+        );
+        assert_eq!(Value::from_float(i64::MIN as f64), Value::Int(i64::MIN));
+        let too_large = i64::MAX as f64;
+        assert_eq!(
+            Value::from_float(too_large),
+            Value::Float(OrderedFloat(too_large))
+        );
+        assert_eq!(Value::from_float(-1e19), Value::Float(OrderedFloat(-1e19)));
+        assert_eq!(
+            Value::from_string("9223372036854775808"),
+            Value::Float(OrderedFloat(too_large))
+        );
+    }
+
     #[test]
     fn value_ordering() {
         assert_eq!(
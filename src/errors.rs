@@ -6,6 +6,7 @@ use nom::types::CompleteStr;
 use nom::ErrorKind;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
+use std::cmp::Reverse;
 use std::convert::From;
 use strsim::normalized_levenshtein;
 
@@ -38,6 +39,9 @@ pub enum SyntaxErrors {
 
     #[fail(display = "Invalid boolean expression")]
     InvalidBooleanExpression,
+
+    #[fail(display = "invalid escape sequence")]
+    InvalidEscapeSequence,
 }
 
 /// Trait that can be used to report errors by the parser and other layers.
@@ -51,6 +55,12 @@ impl QueryContainer {
         QueryContainer { query, reporter }
     }
 
+    /// Describe how the contained query will run, one step per line, or `None` if it doesn't
+    /// parse.
+    pub fn describe_plan(&self) -> Option<String> {
+        self.parse().ok().map(|query| query.describe().join("\n"))
+    }
+
     /// Parse the contained query string.
     pub fn parse(&self) -> Result<Query, QueryPosition> {
         let parse_result = query(Span::new(CompleteStr(&self.query)));
@@ -131,7 +141,8 @@ fn did_you_mean(input: &str, choices: &[&str]) -> Option<String> {
         .iter()
         .map(|choice| (choice, normalized_levenshtein(choice, input)));
     let mut candidates: Vec<_> = similarities.filter(|(_op, score)| *score > 0.6).collect();
-    candidates.sort_by_key(|(_op, score)| (score * 100 as f64) as u16);
+    // Suggest the closest match first, so `cont` is `count` rather than the further `concat`
+    candidates.sort_by_key(|(_op, score)| Reverse((score * 100 as f64) as u16));
     candidates
         .iter()
         .map(|(choice, _score)| choice.to_string())
@@ -183,6 +194,11 @@ impl SyntaxErrors {
                 }
                 base
             }
+            SyntaxErrors::InvalidEscapeSequence => vec![
+                r#"The escape sequences allowed in double quoted strings are \t, \n, \r, \\ and \""#
+                    .to_string(),
+                "Use single quotes for a string without escape sequences".to_string(),
+            ],
         }
     }
 }
@@ -299,6 +315,23 @@ impl<'a> SnippetBuilder<'a> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn did_you_mean_closest_first() {
+        // Both are close enough to suggest, whichever order they're listed in
+        assert_eq!(
+            super::did_you_mean("cont", &["concat", "count"]),
+            Some("count".to_string())
+        );
+        assert_eq!(
+            super::did_you_mean("cont", &["count", "concat"]),
+            Some("count".to_string())
+        );
+        assert_eq!(
+            super::did_you_mean("concat_distnct", &["concat", "concat_distinct"]),
+            Some("concat_distinct".to_string())
+        );
+    }
+
     #[test]
     fn did_you_mean() {
         assert_eq!(
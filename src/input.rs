@@ -0,0 +1,416 @@
+//! Helpers for positioning the input before the pipeline reads it, for reading several inputs
+//! as one, and for decompressing gzipped input.
+use flate2::bufread::MultiGzDecoder;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+const CHUNK_SIZE: u64 = 8192;
+
+/// How long `Follow` waits before checking a file for more lines
+const FOLLOW_POLL: Duration = Duration::from_millis(100);
+
+/// Seek `reader` to the start of its last `lines` lines, like `tail -n`.  The input is read
+/// backward from the end one chunk at a time, so only the tail of a large file is ever read.
+/// A final line without a trailing newline still counts as a line.  If the input has fewer
+/// lines, the reader is left at the start.
+pub fn seek_to_last_lines<R: Read + Seek>(reader: &mut R, lines: u64) -> io::Result<()> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    if lines == 0 {
+        return Ok(());
+    }
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    let mut end = len;
+    let mut remaining = lines;
+    // The newline that ends the last line doesn't separate it from anything
+    let mut skip_last = true;
+    while end > 0 {
+        let start = end.saturating_sub(CHUNK_SIZE);
+        let chunk = &mut buf[..(end - start) as usize];
+        reader.seek(SeekFrom::Start(start))?;
+        reader.read_exact(chunk)?;
+        for (i, &byte) in chunk.iter().enumerate().rev() {
+            if skip_last {
+                skip_last = false;
+                if byte == b'\n' {
+                    continue;
+                }
+            }
+            if byte == b'\n' {
+                remaining -= 1;
+                if remaining == 0 {
+                    reader.seek(SeekFrom::Start(start + i as u64 + 1))?;
+                    return Ok(());
+                }
+            }
+        }
+        end = start;
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(())
+}
+
+/// Seek `reader` to the first line that starts at or after byte `offset`.  If `offset` is in
+/// the middle of a line, the rest of that line is skipped, so an offset saved at the end of
+/// one run resumes at the next line.  Returns the offset reading will start from.
+pub fn seek_to_line_at<R: Read + Seek>(reader: &mut R, offset: u64) -> io::Result<u64> {
+    if offset == 0 {
+        return reader.seek(SeekFrom::Start(0));
+    }
+    // Reading from the byte before the offset means a line that starts exactly at the offset
+    // isn't skipped
+    let start = reader.seek(SeekFrom::Start(offset - 1))?;
+    let mut rest_of_line = Vec::new();
+    let skipped = BufReader::new(&mut *reader).read_until(b'\n', &mut rest_of_line)?;
+    if skipped == 0 {
+        // The offset is past the end of the input
+        return reader.seek(SeekFrom::Start(offset));
+    }
+    reader.seek(SeekFrom::Start(start + skipped as u64))
+}
+
+/// Reads a sequence of inputs one after another as a single stream.  Each input is only opened
+/// once the one before it has been read to the end, so a long list of files doesn't hold them all
+/// open.  A newline is added after an input that doesn't end with one, so its last line isn't
+/// joined to the first line of the next.
+pub struct ConcatReader<R, I> {
+    inputs: I,
+    current: Option<R>,
+    /// The last byte read from the current input, if any has been
+    last_byte: Option<u8>,
+    pending_newline: bool,
+}
+
+impl<R: Read, I: Iterator<Item = io::Result<R>>> ConcatReader<R, I> {
+    pub fn new(inputs: I) -> Self {
+        ConcatReader {
+            inputs,
+            current: None,
+            last_byte: None,
+            pending_newline: false,
+        }
+    }
+}
+
+impl<R: Read, I: Iterator<Item = io::Result<R>>> Read for ConcatReader<R, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.pending_newline {
+                self.pending_newline = false;
+                buf[0] = b'\n';
+                return Ok(1);
+            }
+            let input = match self.current {
+                Some(ref mut input) => input,
+                None => match self.inputs.next() {
+                    Some(input) => {
+                        self.current = Some(input?);
+                        self.last_byte = None;
+                        continue;
+                    }
+                    None => return Ok(0),
+                },
+            };
+            let read = input.read(buf)?;
+            if read > 0 {
+                self.last_byte = Some(buf[read - 1]);
+                return Ok(read);
+            }
+            self.current = None;
+            self.pending_newline = matches!(self.last_byte, Some(byte) if byte != b'\n');
+        }
+    }
+}
+
+/// Reads a file that is still being written, like `tail -f`.  At the end of the file the read
+/// waits for more to be appended instead of returning.  If the file is truncated, or the path
+/// now names a different file because the log was rotated, it's reopened and read from the
+/// start.  The read only ends, as if the file had ended, once `stop` returns true.
+///
+/// Truncation is noticed by the file being shorter than what's been read, so if a file is
+/// truncated in place, as by logrotate's `copytruncate`, and grows back to that length before
+/// the next poll, the truncation is missed and reading carries on from the old position.
+pub struct Follow<S> {
+    path: PathBuf,
+    file: File,
+    /// Where the next read from `file` starts
+    pos: u64,
+    stop: S,
+}
+
+impl<S: Fn() -> bool> Follow<S> {
+    /// Follow `file`, which was opened from `path`, starting from its current position
+    pub fn new(path: PathBuf, mut file: File, stop: S) -> io::Result<Self> {
+        let pos = file.stream_position()?;
+        Ok(Follow {
+            path,
+            file,
+            pos,
+            stop,
+        })
+    }
+
+    /// Whether the file at `path` isn't the one being read anymore, or is shorter than what's
+    /// been read from it.  While a rotated log hasn't been replaced yet, the old one is kept.
+    fn replaced(&self) -> io::Result<bool> {
+        let at_path = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(false),
+        };
+        Ok(at_path.len() < self.pos || !same_file(&self.file.metadata()?, &at_path))
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+/// Without inode numbers, only truncation is noticed
+#[cfg(not(unix))]
+fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    true
+}
+
+impl<S: Fn() -> bool> Read for Follow<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let read = self.file.read(buf)?;
+            if read > 0 {
+                self.pos += read as u64;
+                return Ok(read);
+            }
+            if (self.stop)() {
+                return Ok(0);
+            }
+            if self.replaced()? {
+                self.file = File::open(&self.path)?;
+                self.pos = 0;
+                continue;
+            }
+            thread::sleep(FOLLOW_POLL);
+        }
+    }
+}
+
+/// Whether `bytes` start with the magic number of a gzip stream
+pub fn is_gzip(bytes: &[u8]) -> bool {
+    bytes.starts_with(&[0x1f, 0x8b])
+}
+
+/// Input that's decompressed as it's read when it turns out to be gzipped.  Which one it is is
+/// decided by peeking at the first bytes in the reader's buffer, without consuming them, so
+/// plain input is read straight from the underlying reader.
+pub enum Decompressed<R> {
+    Plain(R),
+    /// Concatenated gzip streams, like those written by `gzip -c a b`, are read as one
+    Gzip(Box<BufReader<MultiGzDecoder<R>>>),
+}
+
+impl<R: BufRead> Decompressed<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        if is_gzip(reader.fill_buf()?) {
+            Ok(Decompressed::Gzip(Box::new(BufReader::new(
+                MultiGzDecoder::new(reader),
+            ))))
+        } else {
+            Ok(Decompressed::Plain(reader))
+        }
+    }
+}
+
+impl<R: BufRead> Read for Decompressed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Decompressed::Plain(reader) => reader.read(buf),
+            Decompressed::Gzip(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl<R: BufRead> BufRead for Decompressed<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Decompressed::Plain(reader) => reader.fill_buf(),
+            Decompressed::Gzip(reader) => reader.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            Decompressed::Plain(reader) => reader.consume(amt),
+            Decompressed::Gzip(reader) => reader.consume(amt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::cell::Cell;
+    use std::env;
+    use std::io::{Cursor, Write};
+
+    fn tail(input: &str, lines: u64) -> String {
+        let mut cursor = Cursor::new(input.as_bytes().to_vec());
+        seek_to_last_lines(&mut cursor, lines).unwrap();
+        let mut rest = String::new();
+        cursor.read_to_string(&mut rest).unwrap();
+        rest
+    }
+
+    #[test]
+    fn last_lines() {
+        assert_eq!(tail("a\nb\nc\n", 2), "b\nc\n");
+        assert_eq!(tail("a\nb\nc", 2), "b\nc");
+        assert_eq!(tail("a\nb\nc\n", 3), "a\nb\nc\n");
+        assert_eq!(tail("a\nb\nc\n", 10), "a\nb\nc\n");
+        assert_eq!(tail("a\nb\nc\n", 0), "");
+        assert_eq!(tail("", 5), "");
+        assert_eq!(tail("a\n\n\n", 2), "\n\n");
+    }
+
+    fn resume(input: &str, offset: u64) -> (u64, String) {
+        let mut cursor = Cursor::new(input.as_bytes().to_vec());
+        let start = seek_to_line_at(&mut cursor, offset).unwrap();
+        let mut rest = String::new();
+        cursor.read_to_string(&mut rest).unwrap();
+        (start, rest)
+    }
+
+    #[test]
+    fn line_at_offset() {
+        assert_eq!(resume("ab\ncd\nef\n", 0), (0, "ab\ncd\nef\n".to_string()));
+        assert_eq!(resume("ab\ncd\nef\n", 3), (3, "cd\nef\n".to_string()));
+        assert_eq!(resume("ab\ncd\nef\n", 4), (6, "ef\n".to_string()));
+        assert_eq!(resume("ab\ncd\nef\n", 2), (3, "cd\nef\n".to_string()));
+        assert_eq!(resume("ab\ncd\nef\n", 9), (9, "".to_string()));
+        assert_eq!(resume("ab\ncd\nef\n", 100), (100, "".to_string()));
+    }
+
+    #[test]
+    fn concat_inputs() {
+        let concat = |inputs: &[&'static str]| {
+            let inputs = inputs.iter().map(|input| Ok(Cursor::new(input.as_bytes())));
+            let mut out = String::new();
+            ConcatReader::new(inputs).read_to_string(&mut out).unwrap();
+            out
+        };
+        assert_eq!(concat(&["a\nb\n", "c\n"]), "a\nb\nc\n");
+        assert_eq!(concat(&["a\nb", "c", "", "d\n"]), "a\nb\nc\nd\n");
+        assert_eq!(concat(&[]), "");
+
+        let failing = vec![
+            Ok(Cursor::new(&b"a\n"[..])),
+            Err(io::Error::new(io::ErrorKind::NotFound, "missing.log")),
+        ];
+        let mut reader = BufReader::new(ConcatReader::new(failing.into_iter()));
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "a\n");
+        assert!(reader.read_line(&mut line).is_err());
+    }
+
+    fn gzip(input: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(input.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decompressed(input: Vec<u8>) -> (bool, String) {
+        let mut reader = Decompressed::new(Cursor::new(input)).unwrap();
+        let compressed = matches!(reader, Decompressed::Gzip(_));
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        (compressed, out)
+    }
+
+    #[test]
+    fn decompress_gzip() {
+        assert_eq!(decompressed(gzip("a\nb\n")), (true, "a\nb\n".to_string()));
+        let mut concatenated = gzip("a\n");
+        concatenated.extend(gzip("b\n"));
+        assert_eq!(decompressed(concatenated), (true, "a\nb\n".to_string()));
+        assert_eq!(
+            decompressed(b"plain\n".to_vec()),
+            (false, "plain\n".to_string())
+        );
+        assert_eq!(decompressed(vec![0x1f]), (false, "\u{1f}".to_string()));
+        assert_eq!(decompressed(Vec::new()), (false, "".to_string()));
+    }
+
+    #[test]
+    fn last_lines_across_chunks() {
+        let input: String = (0..5000).map(|i| format!("line {}\n", i)).collect();
+        assert_eq!(tail(&input, 3), "line 4997\nline 4998\nline 4999\n");
+        assert_eq!(tail(&input, 5000), input);
+        let expected: String = (1000..5000).map(|i| format!("line {}\n", i)).collect();
+        assert_eq!(tail(&input, 4000), expected);
+    }
+
+    /// A directory of the test's own, removed when it's dropped, even if the test panics
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("agrind-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TestDir(path)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn follow_appends_and_truncation() {
+        let dir = TestDir::new("follow");
+        let path = dir.0.join("app.log");
+        fs::write(&path, "a\n").unwrap();
+        let stopped = Cell::new(false);
+        let follow = Follow::new(path.clone(), File::open(&path).unwrap(), || stopped.get());
+        let mut reader = BufReader::new(follow.unwrap());
+        let mut next_line = || {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line
+        };
+        assert_eq!(next_line(), "a\n");
+
+        let mut appending = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        appending.write_all(b"bb\ncc\n").unwrap();
+        assert_eq!(next_line(), "bb\n");
+        assert_eq!(next_line(), "cc\n");
+
+        // Truncated and written again, so it's shorter than what's been read
+        fs::write(&path, "d\n").unwrap();
+        assert_eq!(next_line(), "d\n");
+
+        if cfg!(unix) {
+            // Rotated: the old log is moved away and a longer one takes its place
+            fs::rename(&path, dir.0.join("app.log.1")).unwrap();
+            fs::write(&path, "eeeeeeee\n").unwrap();
+            assert_eq!(next_line(), "eeeeeeee\n");
+        }
+
+        stopped.set(true);
+        assert_eq!(next_line(), "");
+    }
+}
@@ -1,6 +1,7 @@
 use crate::alias::{self, AliasConfig};
 use crate::data;
 use crate::errors::SyntaxErrors;
+use crate::typecheck::DEFAULT_LIMIT;
 use lazy_static::lazy_static;
 use nom;
 use nom::types::CompleteStr;
@@ -65,11 +66,46 @@ pub const VALID_AGGREGATES: &'static [&str] = &[
     "max",
     "sum",
     "count_distinct",
+    "concat",
+    "concat_distinct",
     "sort",
+    "topk_approx",
+    "rate",
+    "ratio",
+    "argmin",
+    "argmax",
+    "sparkline",
+    "median",
+    "mode",
+    "first",
+    "last",
+    "percentile",
 ];
 
+/// The separators `kv` uses when none are given, matching logfmt
+pub const DEFAULT_PAIR_SEPARATOR: &str = " ";
+pub const DEFAULT_KV_SEPARATOR: &str = "=";
+
 pub const VALID_INLINE: &'static [&str] = &[
-    "parse", "limit", "json", "logfmt", "total", "fields", "where", "split",
+    "parse",
+    "extract",
+    "limit",
+    "json",
+    "logfmt",
+    "kv",
+    "total",
+    "moving_avg",
+    "qbucket",
+    "squeeze",
+    "lowercase",
+    "uppercase",
+    "substring",
+    "timeslice",
+    "fields",
+    "rename",
+    "eval",
+    "where",
+    "split",
 ];
 
 lazy_static! {
@@ -90,7 +126,7 @@ pub type Span<'a> = LocatedSpan<CompleteStr<'a>>;
 
 /// Container for the position of some syntax in the input string.  This is similar to the Span,
 /// but it only contains the offset.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct QueryPosition(pub usize);
 
 impl<'a> From<Span<'a>> for QueryPosition {
@@ -100,7 +136,7 @@ impl<'a> From<Span<'a>> for QueryPosition {
 }
 
 /// Container for values from the query that records the location in the query string.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Positioned<T> {
     pub start_pos: QueryPosition,
     pub end_pos: QueryPosition,
@@ -123,9 +159,25 @@ pub enum ComparisonOp {
     Lte,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ArithmeticOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum BinaryOp {
     Comparison(ComparisonOp),
+    Arithmetic(ArithmeticOp),
+    Logical(LogicalOp),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -154,6 +206,28 @@ pub enum Expr {
         left: Box<Expr>,
         right: Box<Expr>,
     },
+    InCidr {
+        operand: Box<Expr>,
+        cidrs: Vec<Positioned<String>>,
+    },
+    Glob {
+        operand: Box<Expr>,
+        pattern: Positioned<String>,
+    },
+    RegexMatch {
+        operand: Box<Expr>,
+        pattern: Positioned<String>,
+    },
+    Fuzzy {
+        operand: Box<Expr>,
+        target: String,
+        op: ComparisonOp,
+        threshold: Positioned<i64>,
+    },
+    FunctionCall {
+        name: Positioned<String>,
+        args: Vec<Expr>,
+    },
     Value(data::Value),
 }
 
@@ -193,9 +267,11 @@ impl Keyword {
         regex_str.insert_str(0, "(?i)");
         if self.1 == KeywordType::WILDCARD {
             regex_str = regex_str.replace("\\*", "(.*?)");
-            // If it ends with a star, we need to ensure we read until the end.
+            // A trailing star reads until the end, newlines included, so it keeps everything
+            // after a structured prefix, like the rest of a multi-line message.
             if self.0.ends_with('*') {
-                regex_str.push('$');
+                regex_str.truncate(regex_str.len() - "(.*?)".len());
+                regex_str.push_str("((?s:.*))$");
             }
         }
 
@@ -226,6 +302,25 @@ pub enum Operator {
     Inline(Positioned<InlineOperator>),
     MultiAggregate(MultiAggregateOperator),
     Sort(SortOperator),
+    TopKApprox(TopKApproxOperator),
+    Rate(RateOperator),
+    /// `total` on its own, after an aggregate: a last row with the sum of each numeric column
+    GrandTotal,
+}
+
+/// How a captured field is converted to a value
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CaptureType {
+    /// `:num` - a number, or the text with a warning when it isn't one
+    Number,
+    /// `:str` - always the text, even when it looks like a number
+    String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Case {
+    Lower,
+    Upper,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -236,19 +331,51 @@ pub enum InlineOperator {
     Logfmt {
         input_column: Option<Expr>,
     },
+    /// Key-value pairs with configurable separators.  `None` uses the logfmt-style default.
+    KeyValue {
+        pair_separator: Option<Positioned<String>>,
+        kv_separator: Option<Positioned<String>>,
+        input_column: Option<Expr>,
+    },
     Parse {
         pattern: Keyword,
         fields: Vec<String>,
-        input_column: Option<Expr>,
+        /// Fields declared with a type, like `amount:num`.  Others are inferred from their text.
+        field_types: Vec<(String, CaptureType)>,
+        /// Fields to parse, tried in order until one matches.  Empty means the raw line.
+        input_columns: Vec<Expr>,
+        /// Values for fields that weren't captured
+        defaults: Vec<(Positioned<String>, data::Value)>,
         no_drop: bool,
     },
+    /// Named capture groups of a regex become fields
+    Extract {
+        pattern: Positioned<String>,
+        input_column: Option<Expr>,
+        drop_nomatch: bool,
+    },
     Fields {
         mode: FieldMode,
         fields: Vec<String>,
     },
+    /// Compute a field from an arithmetic expression
+    Eval {
+        output_column: String,
+        expr: Expr,
+    },
+    /// Pairs of the old and new names
+    Rename {
+        renames: Vec<(String, String)>,
+    },
     Where {
         expr: Option<Positioned<Expr>>,
     },
+    /// Keep (or with `negate`, drop) records that match any regex listed in a file
+    WhereMatchesFile {
+        input_column: Option<Expr>,
+        negate: bool,
+        path: Positioned<String>,
+    },
     Limit {
         /// The count for the limit is pretty loosely typed at this point, the next phase will
         /// check the value to see if it's sane or provide a default if no number was given.
@@ -258,11 +385,47 @@ pub enum InlineOperator {
         separator: String,
         input_column: Option<Expr>,
         output_column: Option<Expr>,
+        /// Write a record for each part instead of an array of the parts
+        explode: bool,
     },
     Total {
         input_column: Expr,
         output_column: String,
     },
+    MovingAverage {
+        input_column: Expr,
+        window: Positioned<f64>,
+        /// Non-numeric values take up a slot in the window instead of being skipped
+        gaps: bool,
+        output_column: String,
+    },
+    QuantileBucket {
+        input_column: Expr,
+        buckets: Positioned<f64>,
+        output_column: String,
+    },
+    Squeeze {
+        input_column: Expr,
+        output_column: Expr,
+    },
+    ChangeCase {
+        case: Case,
+        input_column: Expr,
+        output_column: Expr,
+    },
+    /// Round timestamps down to the start of an interval, given in seconds
+    Timeslice {
+        interval: Positioned<f64>,
+        input_column: Expr,
+        output_column: Expr,
+    },
+    /// Character offsets, counted from the end when negative.  No end means the end of the string.
+    Substring {
+        input_column: Expr,
+        start: i64,
+        end: Option<i64>,
+        output_column: Expr,
+    },
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -271,7 +434,7 @@ pub enum FieldMode {
     Except,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum SortMode {
     Ascending,
     Descending,
@@ -297,9 +460,59 @@ pub enum AggregateFunction {
         percentile_str: String,
         column: Expr,
     },
+    /// `percentile(column, 50, 90, 99)`: one column for each percentile, from 0 to 100.  With
+    /// `approx=true`, the percentiles are estimated in bounded memory.
+    Percentiles {
+        column: Expr,
+        percentiles: Vec<Positioned<f64>>,
+        approx: bool,
+    },
     CountDistinct {
         column: Option<Positioned<Vec<Expr>>>,
     },
+    Concat {
+        distinct: bool,
+        args: Positioned<Vec<Expr>>,
+    },
+    Ratio {
+        args: Positioned<Vec<Expr>>,
+    },
+    /// The value of the second argument in the row where the first is largest (or smallest)
+    ArgMinMax {
+        max: bool,
+        args: Positioned<Vec<Expr>>,
+    },
+    Sparkline {
+        column: Expr,
+    },
+    Median {
+        column: Expr,
+    },
+    Mode {
+        column: Expr,
+    },
+    First {
+        column: Expr,
+    },
+    Last {
+        column: Expr,
+    },
+}
+
+/// `topk_approx(column, k)`: the approximate k most frequent values of a column
+#[derive(Debug, PartialEq)]
+pub struct TopKApproxOperator {
+    pub key_col: Expr,
+    pub key_col_header: String,
+    pub k: Positioned<f64>,
+}
+
+/// `rate(column, interval) [fill]`: records per second in each interval of a timestamp
+#[derive(Debug, PartialEq)]
+pub struct RateOperator {
+    pub column: Expr,
+    pub interval: Positioned<f64>,
+    pub fill: bool,
 }
 
 #[derive(Debug, PartialEq)]
@@ -309,9 +522,49 @@ pub struct MultiAggregateOperator {
     pub aggregate_functions: Vec<(String, Positioned<AggregateFunction>)>,
 }
 
+impl MultiAggregateOperator {
+    /// The aggregate columns in the output.  Each function has the column it's named after,
+    /// except `percentile` with a list, which has one column for each percentile.
+    pub fn output_columns(&self) -> Vec<String> {
+        self.aggregate_functions
+            .iter()
+            .flat_map(|(name, function)| function_columns(name, function))
+            .collect()
+    }
+
+    /// The first aggregate with a column named the same as an earlier column, grouping columns
+    /// included, since one of the two would replace the other in the output.  Names given with
+    /// `as` and generated ones are treated the same.
+    pub fn duplicate_name(&self) -> Option<(String, &Positioned<AggregateFunction>)> {
+        let mut seen: Vec<String> = self.key_col_headers.clone();
+        for (name, function) in &self.aggregate_functions {
+            for column in function_columns(name, function) {
+                if seen.contains(&column) {
+                    return Some((column, function));
+                }
+                seen.push(column);
+            }
+        }
+        None
+    }
+}
+
+/// The output columns of the aggregate function called `name`
+fn function_columns(name: &str, function: &Positioned<AggregateFunction>) -> Vec<String> {
+    match &function.value {
+        AggregateFunction::Percentiles { percentiles, .. } => percentiles
+            .iter()
+            .map(|pct| format!("{}_p{}", name, pct.value))
+            .collect(),
+        _ => vec![name.to_string()],
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct SortOperator {
-    pub sort_cols: Vec<String>,
+    /// The columns to sort by, each in its own direction
+    pub sort_cols: Vec<(String, SortMode)>,
+    /// The direction given after the last column, which columns without their own direction use
     pub direction: SortMode,
 }
 
@@ -349,10 +602,41 @@ fn not_escape_sq(c: char) -> bool {
 fn not_escape_dq(c: char) -> bool {
     c != '\\' && c != '\"'
 }
+/// The number of seconds in a duration unit: `ms`, `s`, `m`, `h` or `d`
+fn duration_unit_secs(unit: &str) -> f64 {
+    match unit {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => 86400.0,
+    }
+}
+
+// 30s, 5m, 1h: a duration, as a number of seconds
+named!(duration<Span, data::Value>, do_parse!(
+    amount: digit1 >>
+    unit: alt!(tag!("ms") | tag!("s") | tag!("m") | tag!("h") | tag!("d")) >>
+    not!(take_while1!(is_ident)) >>
+    (data::Value::from_float(
+        amount.fragment.0.parse::<f64>().unwrap() * duration_unit_secs(unit.fragment.0)
+    ))
+));
+
+// true, false: unlike a column name, the whole word has to match
+named!(bool_literal<Span, data::Value>, do_parse!(
+    literal: alt!(tag!("true") | tag!("false")) >>
+    not!(take_while1!(is_ident)) >>
+    (data::Value::Bool(literal.fragment.0 == "true"))
+));
+
 named!(value<Span, data::Value>, ws!(
     alt!(
-        map!(quoted_string, |s|data::Value::Str(s.to_string()))
-        | map!(digit1, |s|data::Value::from_string(s.fragment.0))
+        map!(quoted_string, data::Value::Str)
+        | duration
+        | map!(
+            recognize!(tuple!(digit1, opt!(preceded!(char!('.'), digit1)))),
+            |s|data::Value::from_string(s.fragment.0))
     )
 ));
 
@@ -384,16 +668,39 @@ named!(ident<Span, String>, do_parse!(
     (start.fragment.0.to_owned() + rest.fragment.0)
 ));
 
-named!(e_ident<Span, Expr>,
-    ws!(alt_complete!(
-      column_ref
+named!(function_name<Span, Positioned<String>>, with_pos!(ident));
+
+named!(function_args<Span, Vec<Expr>>, delimited!(
+    ws!(tag!("(")),
+    ws!(separated_list!(tag!(","), expr)),
+    return_error!(SyntaxErrors::MissingParen.into(), ws!(tag!(")")))
+));
+
+// len(path)
+named!(function_call<Span, Expr>, ws!(do_parse!(
+    name: function_name >>
+    args: function_args >>
+    (Expr::FunctionCall { name, args })
+)));
+
+// Whitespace is skipped around the `alt_complete!` rather than with `ws!`: `ws!` turns an
+// alternative's `Failure` into a plain error and moves on, which loses errors like an
+// invalid escape sequence in `where x == "\q"`.
+named!(e_ident<Span, Expr>, delimited!(
+    opt!(multispace),
+    alt_complete!(
+      function_call
+    | map!(bool_literal, Expr::Value)
+    | column_ref
     | map!(value, Expr::Value)
       //expr
     | ws!(add_return_error!(SyntaxErrors::StartOfError.into(), delimited!(
           tag!("("),
           expr,
           return_error!(SyntaxErrors::MissingParen.into(), tag!(")")))))
-)));
+    ),
+    opt!(multispace)
+));
 
 named!(raw_keyword<Span, Span>,
    take_while1!(is_keyword)
@@ -418,21 +725,204 @@ named!(unary_op<Span, UnaryOp>, ws!(alt_complete!(
     map!(tag!("!"), |_|UnaryOp::Not)
 )));
 
-named!(expr<Span, Expr>, ws!(alt_complete!(
-    do_parse!(
+named!(cidr_literal<Span, Positioned<String>>,
+    with_pos!(quoted_string)
+);
+
+// in_cidr "10.0.0.0/8" or in_cidr ("10.0.0.0/8", "192.168.0.0/16")
+named!(cidr_list<Span, Vec<Positioned<String>>>, ws!(alt_complete!(
+    map!(cidr_literal, |cidr|vec![cidr])
+    | delimited!(
+        tag!("("),
+        separated_nonempty_list!(tag!(","), cidr_literal),
+        return_error!(SyntaxErrors::MissingParen.into(), tag!(")")))
+)));
+
+named!(glob_pattern<Span, Positioned<String>>,
+    with_pos!(quoted_string)
+);
+
+// path glob "/api/*/users" or path not glob "/static/*"
+named!(glob<Span, Expr>, ws!(do_parse!(
+    operand: e_ident >>
+    negated: opt!(tag!("not")) >>
+    tag!("glob") >>
+    pattern: glob_pattern >>
+    ({
+        let glob = Expr::Glob { operand: Box::new(operand), pattern };
+        match negated {
+            Some(_) => Expr::Unary { op: UnaryOp::Not, operand: Box::new(glob) },
+            None => glob,
+        }
+    })
+)));
+
+named!(regex_match_op<Span, bool>, ws!(alt_complete!(
+    map!(tag!("~="), |_|false)
+    | map!(tag!("!~"), |_|true)
+)));
+
+// path ~= "^/api/" or path !~ "\.css$"
+named!(regex_match<Span, Expr>, ws!(do_parse!(
+    operand: e_ident >>
+    negated: regex_match_op >>
+    pattern: with_pos!(quoted_string) >>
+    ({
+        let matches = Expr::RegexMatch { operand: Box::new(operand), pattern };
+        if negated {
+            Expr::Unary { op: UnaryOp::Not, operand: Box::new(matches) }
+        } else {
+            matches
+        }
+    })
+)));
+
+// Only upper bounds make sense for a distance
+named!(fuzzy_op<Span, ComparisonOp>, ws!(alt_complete!(
+    map!(tag!("<="), |_|ComparisonOp::Lte)
+    | map!(tag!("<"), |_|ComparisonOp::Lt)
+)));
+
+named!(fuzzy_threshold<Span, Positioned<i64>>, with_pos!(i64_parser));
+
+// message fuzzy "connection refused" < 3
+named!(fuzzy<Span, Expr>, ws!(do_parse!(
+    operand: e_ident >>
+    tag!("fuzzy") >>
+    target: quoted_string >>
+    op: fuzzy_op >>
+    threshold: fuzzy_threshold >>
+    ( Expr::Fuzzy { operand: Box::new(operand), target, op, threshold } )
+)));
+
+named!(expr<Span, Expr>, delimited!(
+    opt!(multispace),
+    alt_complete!(
+    ws!(do_parse!(
         l: e_ident >>
         comp: comp_op >>
         r: e_ident >>
         ( Expr::Binary { op: BinaryOp::Comparison(comp), left: Box::new(l), right: Box::new(r)} )
-    )
-    | do_parse!(
+    ))
+    | ws!(do_parse!(
+        operand: e_ident >>
+        tag!("in_cidr") >>
+        cidrs: cidr_list >>
+        ( Expr::InCidr { operand: Box::new(operand), cidrs } )
+    ))
+    | fuzzy
+    | glob
+    | regex_match
+    | ws!(do_parse!(
         op: unary_op >>
         operand: e_ident >>
         ( Expr::Unary { op, operand: Box::new(operand) } )
-    )
+    ))
+    | e_ident
+    ),
+    opt!(multispace)
+));
+
+named!(add_op<Span, ArithmeticOp>, ws!(alt_complete!(
+    map!(tag!("+"), |_|ArithmeticOp::Add)
+    | map!(tag!("-"), |_|ArithmeticOp::Subtract)
+)));
+
+named!(mul_op<Span, ArithmeticOp>, ws!(alt_complete!(
+    map!(tag!("*"), |_|ArithmeticOp::Multiply)
+    | map!(tag!("/"), |_|ArithmeticOp::Divide)
+)));
+
+/// Combine operands left to right, so `a - b - c` is `(a - b) - c`
+fn fold_arithmetic(first: Expr, rest: Vec<(ArithmeticOp, Expr)>) -> Expr {
+    rest.into_iter()
+        .fold(first, |left, (op, right)| Expr::Binary {
+            op: BinaryOp::Arithmetic(op),
+            left: Box::new(left),
+            right: Box::new(right),
+        })
+}
+
+named!(arithmetic_factor<Span, Expr>, ws!(alt_complete!(
+    delimited!(tag!("("), arithmetic, tag!(")"))
     | e_ident
 )));
 
+named!(arithmetic_term<Span, Expr>, ws!(do_parse!(
+    first: arithmetic_factor >>
+    rest: many0!(ws!(pair!(mul_op, arithmetic_factor))) >>
+    (fold_arithmetic(first, rest))
+)));
+
+// bytes_out / (bytes_in + 1)
+named!(arithmetic<Span, Expr>, ws!(do_parse!(
+    first: arithmetic_term >>
+    rest: many0!(ws!(pair!(add_op, arithmetic_term))) >>
+    (fold_arithmetic(first, rest))
+)));
+
+// `and`, `or` and `not` are whole words, so they aren't confused with fields like `order`
+named!(and_op<Span, Span>, preceded!(opt!(multispace), terminated!(
+    alt_complete!(tag!("and") | tag!("AND")),
+    not!(take_while1!(is_ident))
+)));
+
+named!(or_op<Span, Span>, preceded!(opt!(multispace), terminated!(
+    alt_complete!(tag!("or") | tag!("OR")),
+    not!(take_while1!(is_ident))
+)));
+
+named!(not_op<Span, Span>, preceded!(opt!(multispace), alt_complete!(
+    terminated!(alt_complete!(tag!("not") | tag!("NOT")), not!(take_while1!(is_ident)))
+    | tag!("!")
+)));
+
+/// Combine conditions left to right with `op`, so `a and b and c` is `(a and b) and c`
+fn fold_logical(first: Expr, op: LogicalOp, rest: Vec<Expr>) -> Expr {
+    rest.into_iter().fold(first, |left, right| Expr::Binary {
+        op: BinaryOp::Logical(op.clone()),
+        left: Box::new(left),
+        right: Box::new(right),
+    })
+}
+
+named!(not_condition<Span, Expr>, delimited!(
+    opt!(multispace),
+    alt_complete!(
+    ws!(do_parse!(
+        not_op >>
+        operand: not_condition >>
+        ( Expr::Unary { op: UnaryOp::Not, operand: Box::new(operand) } )
+    ))
+    // `(a) == b` is a comparison rather than a condition in parentheses
+    | ws!(terminated!(delimited!(tag!("("), condition, tag!(")")), not!(comp_op)))
+    | expr
+    ),
+    opt!(multispace)
+));
+
+named!(and_condition<Span, Expr>, ws!(do_parse!(
+    first: not_condition >>
+    rest: many0!(preceded!(and_op, not_condition)) >>
+    (fold_logical(first, LogicalOp::And, rest))
+)));
+
+// status >= 400 and latency > 0.5 or not (host == "web1"): `and` comes before `or`
+named!(condition<Span, Expr>, ws!(do_parse!(
+    first: and_condition >>
+    rest: many0!(preceded!(or_op, and_condition)) >>
+    (fold_logical(first, LogicalOp::Or, rest))
+)));
+
+// eval ratio = bytes_out / bytes_in
+named!(eval<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
+    tag!("eval") >>
+    output_column: ident >>
+    tag!("=") >>
+    expr: arithmetic >>
+    (InlineOperator::Eval { output_column, expr })
+))));
+
 named!(json<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
     tag!("json") >>
     from_column_opt: opt!(ws!(preceded!(tag!("from"), expr))) >>
@@ -445,9 +935,51 @@ named!(logfmt<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
     (InlineOperator::Logfmt { input_column: from_column_opt })
 ))));
 
+named!(kv_separator<Span, Positioned<String>>,
+    with_pos!(quoted_string)
+);
+
+// kv pair_sep=";" kv_sep=":" from payload
+named!(key_value<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
+    tag!("kv") >>
+    pair_separator: opt!(ws!(preceded!(tag!("pair_sep"), preceded!(tag!("="), kv_separator)))) >>
+    kv_separator: opt!(ws!(preceded!(tag!("kv_sep"), preceded!(tag!("="), kv_separator)))) >>
+    from_column_opt: opt!(ws!(preceded!(tag!("from"), expr))) >>
+    (InlineOperator::KeyValue { pair_separator, kv_separator, input_column: from_column_opt })
+))));
+
+/// `raw` refers to the whole input line rather than a field
+fn column_or_raw(expr: Expr) -> Option<Expr> {
+    match expr {
+        Expr::Column {
+            head: DataAccessAtom::Key(ref key),
+            ref rest,
+        } if key == "raw" && rest.is_empty() => None,
+        other => Some(other),
+    }
+}
+
+named!(pattern_file<Span, Positioned<String>>,
+    with_pos!(quoted_string)
+);
+
+// where raw not matches_file "ignore_patterns.txt"
+named!(where_matches_file<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
+    tag!("where") >>
+    column: column_ref >>
+    negate: opt!(tag!("not")) >>
+    tag!("matches_file") >>
+    path: pattern_file >>
+    (InlineOperator::WhereMatchesFile {
+        input_column: column_or_raw(column),
+        negate: negate.is_some(),
+        path
+    })
+))));
+
 named!(whre<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
     tag!("where") >>
-    ex: opt!(with_pos!(expr)) >>
+    ex: opt!(with_pos!(condition)) >>
     (InlineOperator::Where { expr: ex })
 ))));
 
@@ -469,12 +1001,77 @@ named!(split<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
     separator_opt: opt!(ws!(preceded!(tag!("on"), quoted_string))) >>
     // TODO: make variant of expr that only accepts Expr::Column instead of all types
     rename_opt: opt!(ws!(preceded!(tag!("as"), expr))) >>
+    explode: opt!(terminated!(tag!("explode"), not!(take_while1!(is_ident)))) >>
     (InlineOperator::Split {
-        separator: separator_opt.map(|s| s.to_string()).unwrap_or_else(|| ",".to_string()),
+        separator: separator_opt.unwrap_or_else(|| ",".to_string()),
         input_column: from_column_opt.clone(),
         // If from column specified, but output column not specified
         // output should be the from column.
         output_column: rename_opt.or(from_column_opt),
+        explode: explode.is_some(),
+    })
+))));
+
+// squeeze message [as normalized]
+named!(squeeze<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
+    tag!("squeeze") >>
+    input_column: expr >>
+    rename_opt: opt!(ws!(preceded!(tag!("as"), expr))) >>
+    (InlineOperator::Squeeze {
+        output_column: rename_opt.unwrap_or_else(|| input_column.clone()),
+        input_column,
+    })
+))));
+
+named!(case<Span, Case>, alt_complete!(
+    map!(tag!("lowercase"), |_|Case::Lower)
+    | map!(tag!("uppercase"), |_|Case::Upper)
+));
+
+// lowercase host [as host_lc]
+named!(change_case<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
+    case: case >>
+    input_column: expr >>
+    rename_opt: opt!(ws!(preceded!(tag!("as"), expr))) >>
+    (InlineOperator::ChangeCase {
+        case,
+        output_column: rename_opt.unwrap_or_else(|| input_column.clone()),
+        input_column,
+    })
+))));
+
+named!(timeslice_interval<Span, Positioned<f64>>, with_pos!(map!(duration, |secs| match secs {
+    data::Value::Int(secs) => secs as f64,
+    data::Value::Float(secs) => secs.into_inner(),
+    _ => 0.0,
+})));
+
+// timeslice 5m timestamp [as bucket]
+named!(timeslice<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
+    tag!("timeslice") >>
+    interval: timeslice_interval >>
+    input_column: expr >>
+    rename_opt: opt!(ws!(preceded!(tag!("as"), expr))) >>
+    (InlineOperator::Timeslice {
+        interval,
+        output_column: rename_opt.unwrap_or_else(|| input_column.clone()),
+        input_column,
+    })
+))));
+
+// substring id from 4 [to -1] [as short_id]
+named!(substring<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
+    tag!("substring") >>
+    input_column: expr >>
+    tag!("from") >>
+    start: i64_parser >>
+    end: opt!(ws!(preceded!(tag!("to"), i64_parser))) >>
+    rename_opt: opt!(ws!(preceded!(tag!("as"), expr))) >>
+    (InlineOperator::Substring {
+        output_column: rename_opt.unwrap_or_else(|| input_column.clone()),
+        input_column,
+        start,
+        end,
     })
 ))));
 
@@ -488,21 +1085,104 @@ named!(total<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
             rename_opt.map(|s|s.to_string()).unwrap_or_else(||"_total".to_string()),
 })))));
 
-named!(double_quoted_string <Span, &str>, add_return_error!(
+named!(moving_avg_window<Span, Positioned<f64>>, with_pos!(double));
+
+named!(moving_avg_close<Span, Span>,
+    return_error!(SyntaxErrors::MissingParen.into(), tag!(")"))
+);
+
+// moving_avg(latency, 10) gaps as smooth
+named!(moving_avg<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
+    tag!("moving_avg") >>
+    tag!("(") >>
+    input_column: expr >>
+    tag!(",") >>
+    window: moving_avg_window >>
+    moving_avg_close >>
+    gaps: opt!(tag!("gaps")) >>
+    rename_opt: opt!(ws!(preceded!(tag!("as"), ident))) >>
+    (InlineOperator::MovingAverage {
+        input_column,
+        window,
+        gaps: gaps.is_some(),
+        output_column:
+            rename_opt.map(|s|s.to_string()).unwrap_or_else(||"_moving_avg".to_string()),
+    })
+))));
+
+named!(qbucket_count<Span, Positioned<f64>>, with_pos!(double));
+
+// qbucket(latency, 4) as quartile
+named!(qbucket<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
+    tag!("qbucket") >>
+    tag!("(") >>
+    input_column: expr >>
+    tag!(",") >>
+    buckets: qbucket_count >>
+    moving_avg_close >>
+    rename_opt: opt!(ws!(preceded!(tag!("as"), ident))) >>
+    (InlineOperator::QuantileBucket {
+        input_column,
+        buckets,
+        output_column:
+            rename_opt.map(|s|s.to_string()).unwrap_or_else(||"_qbucket".to_string()),
+    })
+))));
+
+/// Replace the escape sequences in the body of a double quoted string.  On an invalid escape,
+/// returns the offset just past it.
+fn unescape(raw: &str) -> Result<String, usize> {
+    let mut unescaped = String::with_capacity(raw.len());
+    let mut chars = raw.char_indices();
+    while let Some((_, c)) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some((_, 't')) => unescaped.push('\t'),
+            Some((_, 'n')) => unescaped.push('\n'),
+            Some((_, 'r')) => unescaped.push('\r'),
+            Some((_, '\\')) => unescaped.push('\\'),
+            Some((_, '"')) => unescaped.push('"'),
+            Some((i, other)) => return Err(i + other.len_utf8()),
+            None => return Err(raw.len()),
+        }
+    }
+    Ok(unescaped)
+}
+
+fn double_quoted_body(input: Span) -> IResult<Span, String> {
+    let (rest, raw) = escaped!(input, take_while1!(not_escape_dq), '\\', anychar)?;
+    match unescape(raw.fragment.0) {
+        Ok(unescaped) => Ok((rest, unescaped)),
+        Err(offset) => {
+            // Point the error at the end of the bad escape so the whole string up to it is
+            // highlighted
+            let (after_escape, _) = take!(raw, offset)?;
+            Err(nom::Err::Failure(nom::Context::List(vec![(
+                after_escape,
+                SyntaxErrors::InvalidEscapeSequence.into(),
+            )])))
+        }
+    }
+}
+
+named!(double_quoted_string <Span, String>, add_return_error!(
     SyntaxErrors::StartOfError.into(), delimited!(
         tag!("\""),
-        map!(escaped!(take_while1!(not_escape_dq), '\\', anychar), |ref s|s.fragment.0),
+        double_quoted_body,
         return_error!(SyntaxErrors::UnterminatedDoubleQuotedString.into(), tag!("\""))
 )));
 
-named!(single_quoted_string <Span, &str>, add_return_error!(
+named!(single_quoted_string <Span, String>, add_return_error!(
     SyntaxErrors::StartOfError.into(), delimited!(
         tag!("'"),
-        map!(escaped!(take_while1!(not_escape_sq), '\\', anychar), |ref s|s.fragment.0),
+        map!(escaped!(take_while1!(not_escape_sq), '\\', anychar), |ref s|s.fragment.0.to_string()),
         return_error!(SyntaxErrors::UnterminatedSingleQuotedString.into(), tag!("'"))
 )));
 
-named!(quoted_string<Span, &str>, alt_complete!(double_quoted_string | single_quoted_string));
+named!(quoted_string<Span, String>, alt_complete!(double_quoted_string | single_quoted_string));
 
 named!(var_list<Span, Vec<String> >, ws!(separated_nonempty_list!(
     tag!(","), ws!(ident)
@@ -543,22 +1223,87 @@ named!(did_you_mean_aggregate<Span, Span>,
     call!(did_you_mean, &VALID_AGGREGATES, SyntaxErrors::NotAnAggregateOperator)
 );
 
-// parse "blah * ... *" [from other_field] as x, y
+fn is_default_value_char(c: char) -> bool {
+    !c.is_whitespace() && c != ','
+}
+
+// A quoted string is always a string, anything else is inferred like a parsed value
+named!(default_value<Span, data::Value>, alt_complete!(
+    map!(quoted_string, data::Value::Str)
+    | map!(take_while1!(is_default_value_char), |s|data::Value::from_string(s.fragment.0))
+));
+
+named!(default_field<Span, Positioned<String>>, with_pos!(ident));
+
+named!(parse_default<Span, (Positioned<String>, data::Value)>, ws!(do_parse!(
+    field: default_field >>
+    tag!("=") >>
+    value: default_value >>
+    ((field, value))
+)));
+
+// defaults x=0, y="none"
+named!(parse_defaults<Span, Vec<(Positioned<String>, data::Value)>>, ws!(preceded!(
+    tag!("defaults"),
+    separated_nonempty_list!(tag!(","), ws!(parse_default))
+)));
+
+named!(capture_type<Span, CaptureType>, alt_complete!(
+    map!(tag!("num"), |_|CaptureType::Number)
+    | map!(tag!("str"), |_|CaptureType::String)
+));
+
+// amount:num
+named!(parse_var<Span, (String, Option<CaptureType>)>, ws!(do_parse!(
+    field: ident >>
+    capture: opt!(preceded!(tag!(":"), capture_type)) >>
+    ((field, capture))
+)));
+
+named!(parse_var_list<Span, Vec<(String, Option<CaptureType>)>>, ws!(separated_nonempty_list!(
+    tag!(","), ws!(parse_var)
+)));
+
+// parse "blah * ... *" [from other_field] as x, y:num [defaults x=0] [nodrop]
 named!(parse<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
     tag!("parse") >>
     pattern: quoted_string >>
-    from_column_opt: opt!(ws!(preceded!(tag!("from"), expr))) >>
+    from_columns_opt: opt!(ws!(preceded!(
+        tag!("from"),
+        separated_nonempty_list!(ws!(tag!(",")), expr)))) >>
     tag!("as") >>
-    vars: var_list >>
+    vars: parse_var_list >>
+    defaults_opt: opt!(parse_defaults) >>
     no_drop_opt: opt!(ws!(tag!("nodrop"))) >>
     ( InlineOperator::Parse{
-        pattern: Keyword::new_wildcard(pattern.to_string()),
-        fields: vars,
-        input_column: from_column_opt,
+        pattern: Keyword::new_wildcard(pattern),
+        field_types: vars.iter()
+            .filter_map(|(field, capture)| capture.map(|capture| (field.clone(), capture)))
+            .collect(),
+        fields: vars.into_iter().map(|(field, _)| field).collect(),
+        input_columns: from_columns_opt.unwrap_or_default(),
+        defaults: defaults_opt.unwrap_or_default(),
         no_drop: no_drop_opt.is_some()
         } )
 ))));
 
+named!(extract_pattern<Span, Positioned<String>>,
+    with_pos!(quoted_string)
+);
+
+// extract '(?P<user>\w+) logged in' [from other_field] [drop_nomatch]
+named!(extract<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
+    tag!("extract") >>
+    pattern: extract_pattern >>
+    from_column_opt: opt!(ws!(preceded!(tag!("from"), expr))) >>
+    drop_nomatch: opt!(ws!(tag!("drop_nomatch"))) >>
+    (InlineOperator::Extract {
+        pattern,
+        input_column: from_column_opt,
+        drop_nomatch: drop_nomatch.is_some(),
+    })
+))));
+
 named!(fields_mode<Span, FieldMode>, alt_complete!(
     map!(
         alt_complete!(tag!("+") | tag!("only") | tag!("include")),
@@ -582,6 +1327,20 @@ named!(fields<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
     )
 ))));
 
+named!(rename_pair<Span, (String, String)>, ws!(do_parse!(
+    old: ident >>
+    tag!("as") >>
+    new: ident >>
+    ((old, new))
+)));
+
+// rename a as x, b as y
+named!(rename<Span, Positioned<InlineOperator>>, with_pos!(ws!(do_parse!(
+    tag!("rename") >>
+    renames: separated_nonempty_list!(tag!(","), ws!(rename_pair)) >>
+    (InlineOperator::Rename { renames })
+))));
+
 named!(arg_list<Span, Positioned<Vec<Expr>>>, add_return_error!(
     SyntaxErrors::StartOfError.into(), with_pos!(delimited!(
         tag!("("),
@@ -617,15 +1376,70 @@ named!(count_distinct<Span, Positioned<AggregateFunction>>, with_pos!(ws!(do_par
     (AggregateFunction::CountDistinct{ column })
 ))));
 
-named!(sum<Span, Positioned<AggregateFunction>>, with_pos!(ws!(do_parse!(
-    tag!("sum") >>
-    column: delimited!(tag!("("), expr,tag!(")")) >>
-    (AggregateFunction::Sum{column})
+// concat(field [, "separator" [, max_items [, max_length]]])
+named!(concat<Span, Positioned<AggregateFunction>>, with_pos!(ws!(do_parse!(
+    distinct: alt_complete!(
+        map!(tag!("concat_distinct"), |_|true)
+        | map!(tag!("concat"), |_|false)) >>
+    args: arg_list >>
+    (AggregateFunction::Concat{ distinct, args })
 ))));
 
-fn is_digit_char(digit: char) -> bool {
-    is_digit(digit as u8)
-}
+// ratio(numerator_condition, denominator_condition)
+named!(ratio<Span, Positioned<AggregateFunction>>, with_pos!(ws!(do_parse!(
+    tag!("ratio") >>
+    args: arg_list >>
+    (AggregateFunction::Ratio{ args })
+))));
+
+// argmax(value_field, return_field)
+named!(arg_min_max<Span, Positioned<AggregateFunction>>, with_pos!(ws!(do_parse!(
+    max: alt_complete!(
+        map!(tag!("argmax"), |_|true)
+        | map!(tag!("argmin"), |_|false)) >>
+    args: arg_list >>
+    (AggregateFunction::ArgMinMax{ max, args })
+))));
+
+named!(first<Span, Positioned<AggregateFunction>>, with_pos!(ws!(do_parse!(
+    tag!("first") >>
+    column: delimited!(tag!("("), expr,tag!(")")) >>
+    (AggregateFunction::First{column})
+))));
+
+named!(last<Span, Positioned<AggregateFunction>>, with_pos!(ws!(do_parse!(
+    tag!("last") >>
+    column: delimited!(tag!("("), expr,tag!(")")) >>
+    (AggregateFunction::Last{column})
+))));
+
+named!(mode<Span, Positioned<AggregateFunction>>, with_pos!(ws!(do_parse!(
+    tag!("mode") >>
+    column: delimited!(tag!("("), expr,tag!(")")) >>
+    (AggregateFunction::Mode{column})
+))));
+
+named!(median<Span, Positioned<AggregateFunction>>, with_pos!(ws!(do_parse!(
+    tag!("median") >>
+    column: delimited!(tag!("("), expr,tag!(")")) >>
+    (AggregateFunction::Median{column})
+))));
+
+named!(sparkline<Span, Positioned<AggregateFunction>>, with_pos!(ws!(do_parse!(
+    tag!("sparkline") >>
+    column: delimited!(tag!("("), expr,tag!(")")) >>
+    (AggregateFunction::Sparkline{column})
+))));
+
+named!(sum<Span, Positioned<AggregateFunction>>, with_pos!(ws!(do_parse!(
+    tag!("sum") >>
+    column: delimited!(tag!("("), expr,tag!(")")) >>
+    (AggregateFunction::Sum{column})
+))));
+
+fn is_digit_char(digit: char) -> bool {
+    is_digit(digit as u8)
+}
 
 named!(pct_fn<Span, Span>, preceded!(
     alt_complete!(tag!("pct") | tag!("percentile") | tag!("p")),
@@ -644,6 +1458,26 @@ named!(p_nn<Span, Positioned<AggregateFunction>>, ws!(
     ))
 ));
 
+named!(approx_flag<Span, bool>, ws!(do_parse!(
+    tag!(",") >>
+    tag!("approx") >>
+    tag!("=") >>
+    approx: alt_complete!(value!(true, tag!("true")) | value!(false, tag!("false"))) >>
+    (approx)
+)));
+
+// percentile(latency, 50, 90, 99 [, approx=true])
+named!(percentiles<Span, Positioned<AggregateFunction>>, with_pos!(ws!(do_parse!(
+    tag!("percentile") >>
+    tag!("(") >>
+    column: expr >>
+    tag!(",") >>
+    percentiles: separated_nonempty_list!(tag!(","), with_pos!(double)) >>
+    approx: opt!(approx_flag) >>
+    tag!(")") >>
+    (AggregateFunction::Percentiles{column, percentiles, approx: approx.unwrap_or(false)})
+))));
+
 fn string_from_located_span(
     span: LocatedSpan<impl nom::AsBytes>,
 ) -> Result<String, std::string::FromUtf8Error> {
@@ -659,7 +1493,7 @@ named!(alias<Span, Operator>, map!(
 ));
 
 named!(inline_operator<Span, Operator>,
-    map!(alt_complete!(parse | json | logfmt | fields | whre | limit | total | split), Operator::Inline)
+    map!(alt_complete!(parse | extract | json | logfmt | key_value | fields | rename | eval | where_matches_file | whre | limit | total | moving_avg | qbucket | squeeze | change_case | substring | timeslice | split), Operator::Inline)
 );
 
 named!(aggregate_function<Span, Positioned<AggregateFunction>>, do_parse!(
@@ -667,16 +1501,25 @@ named!(aggregate_function<Span, Positioned<AggregateFunction>>, do_parse!(
     res: alt_complete!(
         count_distinct |
         count |
+        concat |
+        ratio |
+        arg_min_max |
         min |
         average |
         max |
+        sparkline |
+        median |
+        mode |
+        first |
+        last |
         sum |
+        percentiles |
         p_nn) >> (res)
 ));
 
 named!(operator<Span, Operator>, do_parse!(
     peek!(did_you_mean_operator) >>
-    res: alt_complete!(inline_operator | sort | alias | multi_aggregate_operator) >> (res)
+    res: alt_complete!(inline_operator | sort | topk_approx | rate | grand_total | alias | multi_aggregate_operator) >> (res)
 ));
 
 // count by x,y
@@ -690,12 +1533,79 @@ fn default_output(func: &Positioned<AggregateFunction>) -> String {
         AggregateFunction::Average { .. } => "_average".to_string(),
         AggregateFunction::Max { .. } => "_max".to_string(),
         AggregateFunction::CountDistinct { .. } => "_countDistinct".to_string(),
+        AggregateFunction::Concat {
+            distinct: false, ..
+        } => "_concat".to_string(),
+        AggregateFunction::Concat { distinct: true, .. } => "_concatDistinct".to_string(),
+        AggregateFunction::Ratio { .. } => "ratio".to_string(),
+        AggregateFunction::ArgMinMax { max: true, .. } => "_argmax".to_string(),
+        AggregateFunction::ArgMinMax { max: false, .. } => "_argmin".to_string(),
+        AggregateFunction::Sparkline { .. } => "_sparkline".to_string(),
+        AggregateFunction::Median { .. } => "_median".to_string(),
+        AggregateFunction::Mode { .. } => "_mode".to_string(),
+        AggregateFunction::First { .. } => "_first".to_string(),
+        AggregateFunction::Last { .. } => "_last".to_string(),
         AggregateFunction::Percentile {
             ref percentile_str, ..
         } => "p".to_string() + percentile_str,
+        AggregateFunction::Percentiles { ref column, .. } => describe_expr(column),
+    }
+}
+
+/// The columns an aggregate function reads, for telling apart functions with the same name
+fn aggregated_columns(func: &AggregateFunction) -> Option<String> {
+    let joined = |exprs: &[Expr]| {
+        exprs
+            .iter()
+            .map(describe_expr)
+            .collect::<Vec<_>>()
+            .join("_")
+    };
+    match func {
+        AggregateFunction::Sum { column }
+        | AggregateFunction::Min { column }
+        | AggregateFunction::Average { column }
+        | AggregateFunction::Max { column }
+        | AggregateFunction::Percentile { column, .. }
+        | AggregateFunction::Sparkline { column }
+        | AggregateFunction::Median { column }
+        | AggregateFunction::Mode { column }
+        | AggregateFunction::First { column }
+        | AggregateFunction::Last { column } => Some(describe_expr(column)),
+        AggregateFunction::CountDistinct { column } => {
+            column.as_ref().map(|column| joined(&column.value))
+        }
+        AggregateFunction::Concat { args, .. }
+        | AggregateFunction::Ratio { args }
+        | AggregateFunction::ArgMinMax { args, .. } => Some(joined(&args.value)),
+        AggregateFunction::Count | AggregateFunction::Percentiles { .. } => None,
     }
 }
 
+/// Functions that would share a default name, like `sum(a), sum(b)`, get the columns they read
+/// added to it instead, like `_sum_a` and `_sum_b`, so neither column replaces the other.
+/// Functions that read the same columns, like `sum(a), sum(a)`, still share a name, which
+/// `MultiAggregateOperator::duplicate_name` reports.
+fn distinct_default_names(
+    functions: Vec<(String, Positioned<AggregateFunction>)>,
+) -> Vec<(String, Positioned<AggregateFunction>)> {
+    let defaults: Vec<Option<String>> = functions
+        .iter()
+        .map(|(name, func)| Some(default_output(func)).filter(|default| default == name))
+        .collect();
+    functions
+        .into_iter()
+        .zip(&defaults)
+        .map(|((name, func), default)| {
+            let shared = default.is_some() && defaults.iter().filter(|d| *d == default).count() > 1;
+            match aggregated_columns(&func.value) {
+                Some(columns) if shared => (format!("{}_{}", name, columns), func),
+                _ => (name, func),
+            }
+        })
+        .collect()
+}
+
 named!(complete_agg_function<Span, (String, Positioned<AggregateFunction>)>, ws!(do_parse!(
         agg_function: aggregate_function >>
         rename_opt: opt!(ws!(preceded!(tag!("as"), ident))) >>
@@ -716,29 +1626,89 @@ named!(multi_aggregate_operator<Span, Operator>, ws!(do_parse!(
         key_cols: key_cols_opt.clone()
             .unwrap_or_default()
             .iter().cloned().map(|col|col.1).collect(),
-        aggregate_functions: agg_functions,
+        aggregate_functions: distinct_default_names(agg_functions),
      })))
 ));
 
+named!(topk_count<Span, Positioned<f64>>, with_pos!(double));
+
+named!(topk_close<Span, Span>, return_error!(SyntaxErrors::MissingParen.into(), tag!(")")));
+
+// topk_approx(url, 20)
+named!(topk_approx<Span, Operator>, ws!(do_parse!(
+    tag!("topk_approx") >>
+    tag!("(") >>
+    key_col: sourced_expr >>
+    tag!(",") >>
+    k: topk_count >>
+    topk_close >>
+    (Operator::TopKApprox(TopKApproxOperator {
+        key_col_header: key_col.0,
+        key_col: key_col.1,
+        k,
+    }))
+)));
+
+// rate(timestamp, 1m) [fill]
+named!(rate<Span, Operator>, ws!(do_parse!(
+    tag!("rate") >>
+    tag!("(") >>
+    column: expr >>
+    tag!(",") >>
+    interval: timeslice_interval >>
+    topk_close >>
+    fill: opt!(tag!("fill")) >>
+    (Operator::Rate(RateOperator {
+        column,
+        interval,
+        fill: fill.is_some(),
+    }))
+)));
+
+// count by host | total
+named!(grand_total<Span, Operator>, map!(
+    delimited!(
+        opt!(multispace),
+        terminated!(tag!("total"), not!(take_while1!(is_ident))),
+        opt!(multispace)
+    ),
+    |_| Operator::GrandTotal
+));
+
 named!(sort_mode<Span, SortMode>, alt_complete!(
     map!(
-        alt_complete!(tag!("asc") | tag!("ascending")),
+        alt_complete!(tag!("ascending") | tag!("asc")),
         |_|SortMode::Ascending
     ) |
     map!(
-        alt_complete!(tag!("desc") | tag!("dsc") | tag!("descending")),
+        alt_complete!(tag!("descending") | tag!("desc") | tag!("dsc")),
         |_|SortMode::Descending
     )
 ));
 
+named!(sort_col<Span, (String, Option<SortMode>)>, ws!(pair!(
+    ident,
+    opt!(terminated!(sort_mode, not!(take_while1!(is_ident))))
+)));
+
+// sort by a desc, b: a direction after the last column also applies to the columns without one
 named!(sort<Span, Operator>, ws!(do_parse!(
     tag!("sort") >>
-    key_cols_opt: opt!(preceded!(opt!(tag!("by")), var_list)) >>
+    key_cols_opt: opt!(preceded!(opt!(tag!("by")), separated_nonempty_list!(tag!(","), sort_col))) >>
     dir: opt!(sort_mode) >>
-    (Operator::Sort(SortOperator{
-        sort_cols: key_cols_opt.unwrap_or_default(),
-        direction: dir.unwrap_or(SortMode::Ascending) ,
-     })))
+    ({
+        let key_cols = key_cols_opt.unwrap_or_default();
+        let direction = dir
+            .or_else(|| key_cols.last().and_then(|(_, dir)| *dir))
+            .unwrap_or(SortMode::Ascending);
+        Operator::Sort(SortOperator {
+            sort_cols: key_cols
+                .into_iter()
+                .map(|(col, dir)| (col, dir.unwrap_or(direction)))
+                .collect(),
+            direction,
+        })
+    }))
 ));
 
 named!(filter_explicit_and<Span, Search>, do_parse!(
@@ -777,7 +1747,7 @@ named!(filter_implicit_and<Span, Search>, map!(
 ));
 
 named!(filter_atom<Span, Search>, alt_complete!(
-    map!(quoted_string, |s| Search::Keyword(Keyword::new_exact(s.to_string()))) |
+    map!(quoted_string, |s| Search::Keyword(Keyword::new_exact(s))) |
     map!(keyword, |s| Search::Keyword(Keyword::new_wildcard(s.trim_matches('*').to_string())))
 ));
 
@@ -847,6 +1817,573 @@ named!(pub query<Span, Query, SyntaxErrors>, fix_error!(SyntaxErrors, exact!(ws!
 
 named!(pub operator_list<Span, Vec<Operator>>, ws!(separated_nonempty_list!(tag!("|"), operator)));
 
+fn describe_atom(atom: &DataAccessAtom, first: bool) -> String {
+    match atom {
+        DataAccessAtom::Key(key) if first => key.clone(),
+        DataAccessAtom::Key(key) => format!(".{}", key),
+        DataAccessAtom::Index(index) => format!("[{}]", index),
+    }
+}
+
+fn describe_comparison(op: &ComparisonOp) -> &'static str {
+    match op {
+        ComparisonOp::Eq => "==",
+        ComparisonOp::Neq => "!=",
+        ComparisonOp::Gt => ">",
+        ComparisonOp::Lt => "<",
+        ComparisonOp::Gte => ">=",
+        ComparisonOp::Lte => "<=",
+    }
+}
+
+fn describe_arithmetic(op: &ArithmeticOp) -> &'static str {
+    match op {
+        ArithmeticOp::Add => "+",
+        ArithmeticOp::Subtract => "-",
+        ArithmeticOp::Multiply => "*",
+        ArithmeticOp::Divide => "/",
+    }
+}
+
+/// Describe an operand of arithmetic, in parentheses if it's arithmetic itself
+fn describe_operand(expr: &Expr) -> String {
+    match expr {
+        Expr::Binary {
+            op: BinaryOp::Arithmetic(_),
+            ..
+        } => format!("({})", describe_expr(expr)),
+        other => describe_expr(other),
+    }
+}
+
+/// Describe a condition combined with `op`, in parentheses if it's combined with the other one
+fn describe_condition(op: &LogicalOp, expr: &Expr) -> String {
+    match expr {
+        Expr::Binary {
+            op: BinaryOp::Logical(inner),
+            ..
+        } if inner != op => format!("({})", describe_expr(expr)),
+        other => describe_expr(other),
+    }
+}
+
+fn describe_exprs(exprs: &[Expr]) -> String {
+    exprs
+        .iter()
+        .map(describe_expr)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Write an expression back out the way it would appear in a query
+fn describe_expr(expr: &Expr) -> String {
+    match expr {
+        Expr::Column { head, rest } => {
+            let mut column = describe_atom(head, true);
+            for atom in rest {
+                column.push_str(&describe_atom(atom, false));
+            }
+            column
+        }
+        Expr::Unary {
+            op: UnaryOp::Not,
+            operand,
+        } => match **operand {
+            Expr::Glob {
+                ref operand,
+                ref pattern,
+            } => format!("{} not glob {:?}", describe_expr(operand), pattern.value),
+            Expr::RegexMatch {
+                ref operand,
+                ref pattern,
+            } => format!("{} !~ {:?}", describe_expr(operand), pattern.value),
+            ref operand @ Expr::Binary {
+                op: BinaryOp::Logical(_),
+                ..
+            } => format!("!({})", describe_expr(operand)),
+            ref operand => format!("!{}", describe_expr(operand)),
+        },
+        Expr::Binary {
+            op: BinaryOp::Comparison(op),
+            left,
+            right,
+        } => format!(
+            "{} {} {}",
+            describe_expr(left),
+            describe_comparison(op),
+            describe_expr(right)
+        ),
+        Expr::Binary {
+            op: BinaryOp::Arithmetic(op),
+            left,
+            right,
+        } => format!(
+            "{} {} {}",
+            describe_operand(left),
+            describe_arithmetic(op),
+            describe_operand(right)
+        ),
+        Expr::Binary {
+            op: BinaryOp::Logical(op),
+            left,
+            right,
+        } => {
+            let (left, right) = (describe_condition(op, left), describe_condition(op, right));
+            match op {
+                LogicalOp::And => format!("{} and {}", left, right),
+                LogicalOp::Or => format!("{} or {}", left, right),
+            }
+        }
+        Expr::InCidr { operand, cidrs } => {
+            let cidrs: Vec<String> = cidrs
+                .iter()
+                .map(|cidr| format!("{:?}", cidr.value))
+                .collect();
+            format!("{} in_cidr ({})", describe_expr(operand), cidrs.join(", "))
+        }
+        Expr::Glob { operand, pattern } => {
+            format!("{} glob {:?}", describe_expr(operand), pattern.value)
+        }
+        Expr::RegexMatch { operand, pattern } => {
+            format!("{} ~= {:?}", describe_expr(operand), pattern.value)
+        }
+        Expr::Fuzzy {
+            operand,
+            target,
+            op,
+            threshold,
+        } => format!(
+            "{} fuzzy {:?} {} {}",
+            describe_expr(operand),
+            target,
+            describe_comparison(op),
+            threshold.value
+        ),
+        Expr::FunctionCall { name, args } => format!("{}({})", name.value, describe_exprs(args)),
+        Expr::Value(data::Value::Str(s)) => format!("{:?}", s),
+        Expr::Value(value) => value.to_string(),
+    }
+}
+
+fn describe_input(input_column: &Option<Expr>) -> String {
+    match input_column {
+        Some(column) => describe_expr(column),
+        None => "the raw line".to_string(),
+    }
+}
+
+fn describe_search(search: &Search) -> String {
+    let describe_all = |searches: &[Search], separator: &str| match searches {
+        [] => "*".to_string(),
+        [search] => describe_search(search),
+        _ => {
+            let described: Vec<String> = searches.iter().map(describe_search).collect();
+            format!("({})", described.join(separator))
+        }
+    };
+    match search {
+        Search::And(searches) => describe_all(searches, " AND "),
+        Search::Or(searches) => describe_all(searches, " OR "),
+        Search::Not(search) => format!("NOT {}", describe_search(search)),
+        Search::Keyword(Keyword(keyword, _)) if keyword.is_empty() => "*".to_string(),
+        Search::Keyword(Keyword(keyword, KeywordType::EXACT)) => format!("{:?}", keyword),
+        Search::Keyword(Keyword(keyword, KeywordType::WILDCARD)) => keyword.clone(),
+    }
+}
+
+fn describe_limit(count: &Option<Positioned<f64>>) -> String {
+    match count {
+        Some(count) if count.value < 0.0 => format!("limit {} (keeps the last rows)", count.value),
+        Some(count) => format!("limit {}", count.value),
+        None => format!("limit {} (the default)", DEFAULT_LIMIT),
+    }
+}
+
+pub(crate) fn describe_inline(op: &InlineOperator) -> String {
+    match op {
+        InlineOperator::Json { input_column } => {
+            format!("json from {}", describe_input(input_column))
+        }
+        InlineOperator::Logfmt { input_column } => {
+            format!("logfmt from {}", describe_input(input_column))
+        }
+        InlineOperator::KeyValue {
+            pair_separator,
+            kv_separator,
+            input_column,
+        } => format!(
+            "kv pair_sep={:?} kv_sep={:?} from {}",
+            pair_separator
+                .as_ref()
+                .map_or(DEFAULT_PAIR_SEPARATOR, |sep| &sep.value),
+            kv_separator
+                .as_ref()
+                .map_or(DEFAULT_KV_SEPARATOR, |sep| &sep.value),
+            describe_input(input_column)
+        ),
+        InlineOperator::Parse {
+            pattern: Keyword(pattern, _),
+            fields,
+            field_types,
+            input_columns,
+            defaults,
+            no_drop,
+        } => {
+            let input = if input_columns.is_empty() {
+                "the raw line".to_string()
+            } else {
+                let columns: Vec<String> = input_columns.iter().map(describe_expr).collect();
+                columns.join(", then ")
+            };
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|field| match field_types.iter().find(|(f, _)| f == field) {
+                    Some((_, CaptureType::Number)) => format!("{}:num", field),
+                    Some((_, CaptureType::String)) => format!("{}:str", field),
+                    None => field.clone(),
+                })
+                .collect();
+            let mut description = format!(
+                "parse {:?} from {} as {}",
+                pattern,
+                input,
+                fields.join(", ")
+            );
+            if !defaults.is_empty() {
+                let defaults: Vec<String> = defaults
+                    .iter()
+                    .map(|(field, value)| format!("{}={}", field.value, value))
+                    .collect();
+                description.push_str(&format!(" defaults {}", defaults.join(", ")));
+            }
+            if *no_drop {
+                description.push_str(", keeping lines that don't match");
+            } else {
+                description.push_str(", dropping lines that don't match");
+            }
+            description
+        }
+        InlineOperator::Fields { mode, fields } => {
+            let mode = match mode {
+                FieldMode::Only => "only",
+                FieldMode::Except => "except",
+            };
+            format!("fields {} {}", mode, fields.join(", "))
+        }
+        InlineOperator::Eval {
+            output_column,
+            expr,
+        } => format!("eval {} = {}", output_column, describe_expr(expr)),
+        InlineOperator::Rename { renames } => {
+            let renames: Vec<String> = renames
+                .iter()
+                .map(|(old, new)| format!("{} as {}", old, new))
+                .collect();
+            format!("rename {}", renames.join(", "))
+        }
+        InlineOperator::Where { expr } => match expr {
+            Some(expr) => format!("where {}", describe_expr(&expr.value)),
+            None => "where".to_string(),
+        },
+        InlineOperator::Extract {
+            pattern,
+            input_column,
+            drop_nomatch,
+        } => format!(
+            "extract the named groups of {:?} from {}, {} lines that don't match",
+            pattern.value,
+            describe_input(input_column),
+            if *drop_nomatch { "dropping" } else { "keeping" }
+        ),
+        InlineOperator::WhereMatchesFile {
+            input_column,
+            negate,
+            path,
+        } => format!(
+            "where {} {}matches_file {:?}",
+            describe_input(input_column),
+            if *negate { "not " } else { "" },
+            path.value
+        ),
+        InlineOperator::Limit { count } => describe_limit(count),
+        InlineOperator::Split {
+            separator,
+            input_column,
+            output_column,
+            explode,
+        } => format!(
+            "split {} on {:?} into {}{}",
+            describe_input(input_column),
+            separator,
+            output_column
+                .as_ref()
+                .map(describe_expr)
+                .unwrap_or_else(|| "_split".to_string()),
+            if *explode { ", a record per part" } else { "" }
+        ),
+        InlineOperator::Squeeze {
+            input_column,
+            output_column,
+        } => format!(
+            "squeeze the whitespace of {} into {}",
+            describe_expr(input_column),
+            describe_expr(output_column)
+        ),
+        InlineOperator::ChangeCase {
+            case,
+            input_column,
+            output_column,
+        } => format!(
+            "{} {} into {}",
+            match case {
+                Case::Lower => "lowercase",
+                Case::Upper => "uppercase",
+            },
+            describe_expr(input_column),
+            describe_expr(output_column)
+        ),
+        InlineOperator::Timeslice {
+            interval,
+            input_column,
+            output_column,
+        } => format!(
+            "timeslice {} into {}-second buckets in {}",
+            describe_expr(input_column),
+            interval.value,
+            describe_expr(output_column)
+        ),
+        InlineOperator::Substring {
+            input_column,
+            start,
+            end,
+            output_column,
+        } => {
+            let end = match end {
+                Some(end) => format!(" to {}", end),
+                None => " to the end".to_string(),
+            };
+            format!(
+                "substring {} from {}{} into {}",
+                describe_expr(input_column),
+                start,
+                end,
+                describe_expr(output_column)
+            )
+        }
+        InlineOperator::Total {
+            input_column,
+            output_column,
+        } => format!(
+            "total({}) as {}",
+            describe_expr(input_column),
+            output_column
+        ),
+        InlineOperator::MovingAverage {
+            input_column,
+            window,
+            gaps,
+            output_column,
+        } => format!(
+            "moving_avg({}, {}){} as {}",
+            describe_expr(input_column),
+            window.value,
+            if *gaps { " gaps" } else { "" },
+            output_column
+        ),
+        InlineOperator::QuantileBucket {
+            input_column,
+            buckets,
+            output_column,
+        } => format!(
+            "qbucket({}, {}) as {} (buffers every record until the input ends)",
+            describe_expr(input_column),
+            buckets.value,
+            output_column
+        ),
+    }
+}
+
+fn describe_aggregate(function: &AggregateFunction) -> String {
+    match function {
+        AggregateFunction::Count => "count".to_string(),
+        AggregateFunction::Sum { column } => format!("sum({})", describe_expr(column)),
+        AggregateFunction::Min { column } => format!("min({})", describe_expr(column)),
+        AggregateFunction::Average { column } => format!("average({})", describe_expr(column)),
+        AggregateFunction::Max { column } => format!("max({})", describe_expr(column)),
+        AggregateFunction::Percentile {
+            percentile_str,
+            column,
+            ..
+        } => format!("p{}({})", percentile_str, describe_expr(column)),
+        AggregateFunction::Percentiles {
+            column,
+            percentiles,
+            approx,
+        } => format!(
+            "percentile({}, {}{})",
+            describe_expr(column),
+            percentiles
+                .iter()
+                .map(|pct| pct.value.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            if *approx { ", approx=true" } else { "" }
+        ),
+        AggregateFunction::CountDistinct { column: None } => "count_distinct".to_string(),
+        AggregateFunction::CountDistinct {
+            column: Some(columns),
+        } => format!("count_distinct({})", describe_exprs(&columns.value)),
+        AggregateFunction::Concat { distinct, args } => format!(
+            "{}({})",
+            if *distinct {
+                "concat_distinct"
+            } else {
+                "concat"
+            },
+            describe_exprs(&args.value)
+        ),
+        AggregateFunction::Ratio { args } => format!("ratio({})", describe_exprs(&args.value)),
+        AggregateFunction::ArgMinMax { max, args } => format!(
+            "{}({})",
+            if *max { "argmax" } else { "argmin" },
+            describe_exprs(&args.value)
+        ),
+        AggregateFunction::Sparkline { column } => {
+            format!("sparkline({})", describe_expr(column))
+        }
+        AggregateFunction::Median { column } => format!("median({})", describe_expr(column)),
+        AggregateFunction::Mode { column } => format!("mode({})", describe_expr(column)),
+        AggregateFunction::First { column } => format!("first({})", describe_expr(column)),
+        AggregateFunction::Last { column } => format!("last({})", describe_expr(column)),
+    }
+}
+
+impl Query {
+    /// Describe how the query will run, one line per step: the search, each operator with its
+    /// defaults filled in and the columns it creates, whether it streams records or works on an
+    /// aggregate, and what the output will look like.  Sorts that are added after aggregates
+    /// and aliases that are expanded into other operators are included.
+    pub fn describe(&self) -> Vec<String> {
+        let mut steps: Vec<(bool, String)> = Vec::new();
+        let mut in_agg = false;
+        describe_operators(&self.operators, &mut in_agg, &mut steps);
+        let mut lines = vec![format!("search: {}", describe_search(&self.search))];
+        for (i, (aggregate, step)) in steps.iter().enumerate() {
+            let kind = if *aggregate { "aggregate" } else { "stream" };
+            lines.push(format!("{}. [{}] {}", i + 1, kind, step));
+        }
+        lines.push(if in_agg {
+            "output: an aggregate table".to_string()
+        } else {
+            "output: records, one per line".to_string()
+        });
+        lines
+    }
+}
+
+fn describe_operators(operators: &[Operator], in_agg: &mut bool, steps: &mut Vec<(bool, String)>) {
+    for (i, op) in operators.iter().enumerate() {
+        match op {
+            Operator::RenderedAlias(alias) => {
+                match operator_list(Span::new(CompleteStr(&alias.value))) {
+                    Ok((_, expanded)) => describe_operators(&expanded, in_agg, steps),
+                    Err(_) => steps.push((*in_agg, format!("alias {}", alias.value))),
+                }
+            }
+            Operator::Inline(op) => steps.push((*in_agg, describe_inline(&op.value))),
+            Operator::MultiAggregate(agg) => {
+                *in_agg = true;
+                let functions: Vec<String> = agg
+                    .aggregate_functions
+                    .iter()
+                    .map(|(name, function)| {
+                        format!("{} as {}", describe_aggregate(&function.value), name)
+                    })
+                    .collect();
+                let mut step = functions.join(", ");
+                if !agg.key_col_headers.is_empty() {
+                    step.push_str(&format!(" by {}", agg.key_col_headers.join(", ")));
+                }
+                steps.push((true, step));
+                // Like the pipeline, sort the groups when nothing but a limit or a total comes next
+                let needs_sort = matches!(
+                    operators.get(i + 1),
+                    None | Some(Operator::GrandTotal)
+                        | Some(Operator::Inline(Positioned {
+                            value: InlineOperator::Limit { .. },
+                            ..
+                        }))
+                );
+                if needs_sort {
+                    let columns = agg.output_columns();
+                    steps.push((
+                        true,
+                        format!(
+                            "sort by {} descending (added automatically)",
+                            columns.join(", ")
+                        ),
+                    ));
+                }
+            }
+            Operator::Sort(sort) => {
+                *in_agg = true;
+                let describe_mode = |mode: &SortMode| match mode {
+                    SortMode::Ascending => "ascending",
+                    SortMode::Descending => "descending",
+                };
+                let step = if sort.sort_cols.is_empty() {
+                    format!("sort {}", describe_mode(&sort.direction))
+                } else if sort
+                    .sort_cols
+                    .iter()
+                    .all(|(_, mode)| *mode == sort.direction)
+                {
+                    let columns: Vec<&str> =
+                        sort.sort_cols.iter().map(|(col, _)| col.as_str()).collect();
+                    format!(
+                        "sort by {} {}",
+                        columns.join(", "),
+                        describe_mode(&sort.direction)
+                    )
+                } else {
+                    let columns: Vec<String> = sort
+                        .sort_cols
+                        .iter()
+                        .map(|(col, mode)| format!("{} {}", col, describe_mode(mode)))
+                        .collect();
+                    format!("sort by {}", columns.join(", "))
+                };
+                steps.push((true, step));
+            }
+            Operator::TopKApprox(topk) => {
+                *in_agg = true;
+                steps.push((
+                    true,
+                    format!(
+                        "topk_approx({}, {}) as {}, _count",
+                        describe_expr(&topk.key_col),
+                        topk.k.value,
+                        topk.key_col_header
+                    ),
+                ));
+            }
+            Operator::Rate(rate) => {
+                *in_agg = true;
+                steps.push((
+                    true,
+                    format!(
+                        "rate({}, {}s){} as _timeslice, _rate",
+                        describe_expr(&rate.column),
+                        rate.interval.value,
+                        if rate.fill { " fill" } else { "" }
+                    ),
+                ));
+            }
+            Operator::GrandTotal => steps.push((true, "total of each numeric column".to_string())),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -925,6 +2462,32 @@ mod tests {
         expect_fail!(quoted_string, "\"hello'");
     }
 
+    #[test]
+    fn parse_quoted_string_escapes() {
+        expect!(quoted_string, r#""a\tb""#, "a\tb");
+        expect!(quoted_string, r#""a\nb""#, "a\nb");
+        expect!(quoted_string, r#""a\rb""#, "a\rb");
+        expect!(quoted_string, r#""a\\b""#, "a\\b");
+        expect!(quoted_string, r#""say \"hi\"""#, "say \"hi\"");
+        expect!(quoted_string, r#""\t\\n""#, "\t\\n");
+        // Single quoted strings are taken literally
+        expect!(quoted_string, r#"'a\tb'"#, "a\\tb");
+        expect_fail!(quoted_string, r#""a\qb""#);
+        assert_eq!(unescape(r"a\qb"), Err(3));
+        assert_eq!(unescape(r"\t\é"), Err(5));
+        // The error survives being nested in a condition rather than being backtracked over
+        for input in &[
+            r#"x == "\q""#,
+            r#"path ~= "\.css$""#,
+            r#"a and not x == "\q""#,
+        ] {
+            match condition(Span::new(CompleteStr(input))) {
+                Err(nom::Err::Failure(_)) => (),
+                other => panic!("{}: expected a failure, got {:?}", input, other),
+            }
+        }
+    }
+
     #[test]
     fn parse_expr() {
         expect!(
@@ -951,6 +2514,285 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_condition() {
+        let comparison = |column: &str, op, value: i64| Expr::Binary {
+            op: BinaryOp::Comparison(op),
+            left: Box::new(Expr::column(column)),
+            right: Box::new(Expr::Value(data::Value::Int(value))),
+        };
+        // `and` binds tighter than `or`
+        expect!(
+            condition,
+            "a == 1 or b > 2 and not order",
+            Expr::Binary {
+                op: BinaryOp::Logical(LogicalOp::Or),
+                left: Box::new(comparison("a", ComparisonOp::Eq, 1)),
+                right: Box::new(Expr::Binary {
+                    op: BinaryOp::Logical(LogicalOp::And),
+                    left: Box::new(comparison("b", ComparisonOp::Gt, 2)),
+                    right: Box::new(Expr::Unary {
+                        op: UnaryOp::Not,
+                        operand: Box::new(Expr::column("order")),
+                    }),
+                }),
+            }
+        );
+        expect!(
+            condition,
+            "!(a == 1 OR b > 2) AND (c) < 3",
+            Expr::Binary {
+                op: BinaryOp::Logical(LogicalOp::And),
+                left: Box::new(Expr::Unary {
+                    op: UnaryOp::Not,
+                    operand: Box::new(Expr::Binary {
+                        op: BinaryOp::Logical(LogicalOp::Or),
+                        left: Box::new(comparison("a", ComparisonOp::Eq, 1)),
+                        right: Box::new(comparison("b", ComparisonOp::Gt, 2)),
+                    }),
+                }),
+                right: Box::new(comparison("c", ComparisonOp::Lt, 3)),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_where_condition_in_parentheses() {
+        let (_, op) =
+            operator(Span::new(CompleteStr("where (a == 1 or b == 2) and not c"))).unwrap();
+        assert!(matches!(
+            op,
+            Operator::Inline(Positioned {
+                value: InlineOperator::Where {
+                    expr: Some(Positioned {
+                        value: Expr::Binary {
+                            op: BinaryOp::Logical(LogicalOp::And),
+                            ..
+                        },
+                        ..
+                    })
+                },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn parse_expr_bool() {
+        expect!(
+            expr,
+            "ok == true",
+            Expr::Binary {
+                op: BinaryOp::Comparison(ComparisonOp::Eq),
+                left: Box::new(Expr::column("ok")),
+                right: Box::new(Expr::Value(data::Value::Bool(true))),
+            }
+        );
+        expect!(
+            expr,
+            "false != truthy",
+            Expr::Binary {
+                op: BinaryOp::Comparison(ComparisonOp::Neq),
+                left: Box::new(Expr::Value(data::Value::Bool(false))),
+                right: Box::new(Expr::column("truthy")),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_expr_fuzzy() {
+        expect!(
+            expr,
+            r#"message fuzzy "connection refused" < 3"#,
+            Expr::Fuzzy {
+                operand: Box::new(Expr::column("message")),
+                target: "connection refused".to_string(),
+                op: ComparisonOp::Lt,
+                threshold: Positioned {
+                    start_pos: QueryPosition(37),
+                    end_pos: QueryPosition(38),
+                    value: 3,
+                },
+            }
+        );
+        expect!(
+            expr,
+            r#"message fuzzy 'timeout' <= 1"#,
+            Expr::Fuzzy {
+                operand: Box::new(Expr::column("message")),
+                target: "timeout".to_string(),
+                op: ComparisonOp::Lte,
+                threshold: Positioned {
+                    start_pos: QueryPosition(27),
+                    end_pos: QueryPosition(28),
+                    value: 1,
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn parse_expr_glob() {
+        expect!(
+            expr,
+            r#"path glob "/api/*/users""#,
+            Expr::Glob {
+                operand: Box::new(Expr::column("path")),
+                pattern: Positioned {
+                    start_pos: QueryPosition(10),
+                    end_pos: QueryPosition(24),
+                    value: "/api/*/users".to_string(),
+                },
+            }
+        );
+        expect!(
+            expr,
+            r#"path not glob "*.css""#,
+            Expr::Unary {
+                op: UnaryOp::Not,
+                operand: Box::new(Expr::Glob {
+                    operand: Box::new(Expr::column("path")),
+                    pattern: Positioned {
+                        start_pos: QueryPosition(14),
+                        end_pos: QueryPosition(21),
+                        value: "*.css".to_string(),
+                    },
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_expr_regex_match() {
+        expect!(
+            expr,
+            r#"path ~= "^/api/""#,
+            Expr::RegexMatch {
+                operand: Box::new(Expr::column("path")),
+                pattern: Positioned {
+                    start_pos: QueryPosition(8),
+                    end_pos: QueryPosition(16),
+                    value: "^/api/".to_string(),
+                },
+            }
+        );
+        expect!(
+            expr,
+            r#"path !~ "css$""#,
+            Expr::Unary {
+                op: UnaryOp::Not,
+                operand: Box::new(Expr::RegexMatch {
+                    operand: Box::new(Expr::column("path")),
+                    pattern: Positioned {
+                        start_pos: QueryPosition(8),
+                        end_pos: QueryPosition(14),
+                        value: "css$".to_string(),
+                    },
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_expr_in_cidr() {
+        expect!(
+            expr,
+            r#"src_ip in_cidr "10.0.0.0/8""#,
+            Expr::InCidr {
+                operand: Box::new(Expr::column("src_ip")),
+                cidrs: vec![Positioned {
+                    start_pos: QueryPosition(15),
+                    end_pos: QueryPosition(27),
+                    value: "10.0.0.0/8".to_string(),
+                }],
+            }
+        );
+        expect!(
+            expr,
+            r#"src_ip in_cidr ("10.0.0.0/8", "::1")"#,
+            Expr::InCidr {
+                operand: Box::new(Expr::column("src_ip")),
+                cidrs: vec![
+                    Positioned {
+                        start_pos: QueryPosition(16),
+                        end_pos: QueryPosition(28),
+                        value: "10.0.0.0/8".to_string(),
+                    },
+                    Positioned {
+                        start_pos: QueryPosition(30),
+                        end_pos: QueryPosition(35),
+                        value: "::1".to_string(),
+                    }
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_expr_function_call() {
+        expect!(
+            expr,
+            "len(path) > 2048",
+            Expr::Binary {
+                op: BinaryOp::Comparison(ComparisonOp::Gt),
+                left: Box::new(Expr::FunctionCall {
+                    name: Positioned {
+                        start_pos: QueryPosition(0),
+                        end_pos: QueryPosition(3),
+                        value: "len".to_string(),
+                    },
+                    args: vec![Expr::column("path")],
+                }),
+                right: Box::new(Expr::Value(data::Value::Int(2048))),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_duration() {
+        expect!(value, "30s", data::Value::Int(30));
+        expect!(value, "5m", data::Value::Int(300));
+        expect!(value, "1h", data::Value::Int(3600));
+        expect!(value, "2d", data::Value::Int(172_800));
+        expect!(value, "250ms", data::Value::from_float(0.25));
+        expect!(
+            expr,
+            "age(ts) < 5m",
+            Expr::Binary {
+                op: BinaryOp::Comparison(ComparisonOp::Lt),
+                left: Box::new(Expr::FunctionCall {
+                    name: Positioned {
+                        start_pos: QueryPosition(0),
+                        end_pos: QueryPosition(3),
+                        value: "age".to_string(),
+                    },
+                    args: vec![Expr::column("ts")],
+                }),
+                right: Box::new(Expr::Value(data::Value::Int(300))),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_expr_rand() {
+        expect!(
+            expr,
+            "rand() < 0.05",
+            Expr::Binary {
+                op: BinaryOp::Comparison(ComparisonOp::Lt),
+                left: Box::new(Expr::FunctionCall {
+                    name: Positioned {
+                        start_pos: QueryPosition(0),
+                        end_pos: QueryPosition(4),
+                        value: "rand".to_string(),
+                    },
+                    args: vec![],
+                }),
+                right: Box::new(Expr::Value(data::Value::from_float(0.05))),
+            }
+        );
+    }
+
     #[test]
     fn parse_expr_ident() {
         expect!(expr, "foo", Expr::column("foo"));
@@ -1001,7 +2843,9 @@ mod tests {
                 value: InlineOperator::Parse {
                     pattern: Keyword::new_wildcard("[key=*]".to_string()),
                     fields: vec!["v".to_string()],
-                    input_column: None,
+                    field_types: vec![],
+                    input_columns: vec![],
+                    defaults: vec![],
                     no_drop: false
                 }
             }
@@ -1015,7 +2859,9 @@ mod tests {
                 value: InlineOperator::Parse {
                     pattern: Keyword::new_wildcard("[key=*]".to_string()),
                     fields: vec!["v".to_string()],
-                    input_column: None,
+                    field_types: vec![],
+                    input_columns: vec![],
+                    defaults: vec![],
                     no_drop: true
                 }
             }
@@ -1029,7 +2875,9 @@ mod tests {
                 value: InlineOperator::Parse {
                     pattern: Keyword::new_wildcard("[key=*][val=*]".to_string()),
                     fields: vec!["k".to_string(), "v".to_string()],
-                    input_column: None,
+                    field_types: vec![],
+                    input_columns: vec![],
+                    defaults: vec![],
                     no_drop: true
                 }
             }
@@ -1037,7 +2885,172 @@ mod tests {
     }
 
     #[test]
-    fn parse_operator() {
+    fn parse_arithmetic() {
+        let column = |name: &str| Box::new(Expr::column(name));
+        let binary = |op, left, right| Expr::Binary {
+            op: BinaryOp::Arithmetic(op),
+            left,
+            right,
+        };
+        expect!(
+            arithmetic,
+            "a - b - c * 2",
+            binary(
+                ArithmeticOp::Subtract,
+                Box::new(binary(ArithmeticOp::Subtract, column("a"), column("b"))),
+                Box::new(binary(
+                    ArithmeticOp::Multiply,
+                    column("c"),
+                    Box::new(Expr::Value(data::Value::Int(2)))
+                ))
+            )
+        );
+        expect!(
+            operator,
+            "eval ratio = out / (in + 1)",
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(27),
+                value: InlineOperator::Eval {
+                    output_column: "ratio".to_string(),
+                    expr: binary(
+                        ArithmeticOp::Divide,
+                        column("out"),
+                        Box::new(binary(
+                            ArithmeticOp::Add,
+                            column("in"),
+                            Box::new(Expr::Value(data::Value::Int(1)))
+                        ))
+                    ),
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn parse_rename() {
+        expect!(
+            operator,
+            "rename a as x, b as y",
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(21),
+                value: InlineOperator::Rename {
+                    renames: vec![
+                        ("a".to_string(), "x".to_string()),
+                        ("b".to_string(), "y".to_string()),
+                    ],
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn parse_extract() {
+        expect!(
+            operator,
+            r#"extract '(?P<user>\w+) in' from msg drop_nomatch"#,
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(48),
+                value: InlineOperator::Extract {
+                    pattern: Positioned {
+                        start_pos: QueryPosition(8),
+                        end_pos: QueryPosition(26),
+                        value: r#"(?P<user>\w+) in"#.to_string(),
+                    },
+                    input_column: Some(Expr::column("msg")),
+                    drop_nomatch: true,
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn parse_typed_fields() {
+        expect!(
+            parse,
+            r#"parse "* * *" as user:str, amount:num, note"#,
+            Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(43),
+                value: InlineOperator::Parse {
+                    pattern: Keyword::new_wildcard("* * *".to_string()),
+                    fields: vec!["user".to_string(), "amount".to_string(), "note".to_string()],
+                    field_types: vec![
+                        ("user".to_string(), CaptureType::String),
+                        ("amount".to_string(), CaptureType::Number),
+                    ],
+                    input_columns: vec![],
+                    defaults: vec![],
+                    no_drop: false
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn parse_defaults() {
+        expect!(
+            parse,
+            r#"parse "* *" as status, user defaults status=0, user="no one" nodrop"#,
+            Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(67),
+                value: InlineOperator::Parse {
+                    pattern: Keyword::new_wildcard("* *".to_string()),
+                    fields: vec!["status".to_string(), "user".to_string()],
+                    field_types: vec![],
+                    input_columns: vec![],
+                    defaults: vec![
+                        (
+                            Positioned {
+                                start_pos: QueryPosition(37),
+                                end_pos: QueryPosition(43),
+                                value: "status".to_string(),
+                            },
+                            data::Value::Int(0)
+                        ),
+                        (
+                            Positioned {
+                                start_pos: QueryPosition(47),
+                                end_pos: QueryPosition(51),
+                                value: "user".to_string(),
+                            },
+                            data::Value::Str("no one".to_string())
+                        ),
+                    ],
+                    no_drop: true
+                }
+            }
+        );
+        expect!(
+            parse,
+            r#"parse "*" as latency defaults latency=1.5"#,
+            Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(41),
+                value: InlineOperator::Parse {
+                    pattern: Keyword::new_wildcard("*".to_string()),
+                    fields: vec!["latency".to_string()],
+                    field_types: vec![],
+                    input_columns: vec![],
+                    defaults: vec![(
+                        Positioned {
+                            start_pos: QueryPosition(30),
+                            end_pos: QueryPosition(37),
+                            value: "latency".to_string(),
+                        },
+                        data::Value::from_float(1.5)
+                    )],
+                    no_drop: false
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn parse_operator() {
         expect!(
             operator,
             "  json",
@@ -1065,13 +3078,69 @@ mod tests {
                 value: InlineOperator::Parse {
                     pattern: Keyword::new_wildcard("[key=*]".to_string()),
                     fields: vec!["v".to_string()],
-                    input_column: Some(Expr::column("field")),
+                    field_types: vec![],
+                    input_columns: vec![Expr::column("field")],
+                    defaults: vec![],
+                    no_drop: false
+                },
+            })
+        );
+        expect!(
+            operator,
+            r#" parse "[key=*]" from a, b as v "#,
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(1),
+                end_pos: QueryPosition(32),
+                value: InlineOperator::Parse {
+                    pattern: Keyword::new_wildcard("[key=*]".to_string()),
+                    fields: vec!["v".to_string()],
+                    field_types: vec![],
+                    input_columns: vec![Expr::column("a"), Expr::column("b")],
+                    defaults: vec![],
                     no_drop: false
                 },
             })
         );
     }
 
+    #[test]
+    fn parse_where_matches_file() {
+        expect!(
+            operator,
+            r#" where raw not matches_file "ignore.txt""#,
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(1),
+                end_pos: QueryPosition(40),
+                value: InlineOperator::WhereMatchesFile {
+                    input_column: None,
+                    negate: true,
+                    path: Positioned {
+                        start_pos: QueryPosition(28),
+                        end_pos: QueryPosition(40),
+                        value: "ignore.txt".to_string(),
+                    },
+                }
+            })
+        );
+        expect!(
+            operator,
+            r#" where message matches_file "errors.txt""#,
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(1),
+                end_pos: QueryPosition(40),
+                value: InlineOperator::WhereMatchesFile {
+                    input_column: Some(Expr::column("message")),
+                    negate: false,
+                    path: Positioned {
+                        start_pos: QueryPosition(28),
+                        end_pos: QueryPosition(40),
+                        value: "errors.txt".to_string(),
+                    },
+                }
+            })
+        );
+    }
+
     #[test]
     fn parse_limit() {
         expect!(
@@ -1150,6 +3219,212 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_moving_avg() {
+        expect!(
+            operator,
+            "moving_avg(latency, 10)",
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(23),
+                value: InlineOperator::MovingAverage {
+                    input_column: Expr::column("latency"),
+                    window: Positioned {
+                        value: 10.0,
+                        start_pos: QueryPosition(20),
+                        end_pos: QueryPosition(22),
+                    },
+                    gaps: false,
+                    output_column: "_moving_avg".to_string(),
+                }
+            })
+        );
+        expect!(
+            operator,
+            "moving_avg(latency, 10) gaps as smooth",
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(38),
+                value: InlineOperator::MovingAverage {
+                    input_column: Expr::column("latency"),
+                    window: Positioned {
+                        value: 10.0,
+                        start_pos: QueryPosition(20),
+                        end_pos: QueryPosition(22),
+                    },
+                    gaps: true,
+                    output_column: "smooth".to_string(),
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn parse_qbucket() {
+        expect!(
+            operator,
+            "qbucket(latency, 4) as quartile",
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(31),
+                value: InlineOperator::QuantileBucket {
+                    input_column: Expr::column("latency"),
+                    buckets: Positioned {
+                        value: 4.0,
+                        start_pos: QueryPosition(17),
+                        end_pos: QueryPosition(18),
+                    },
+                    output_column: "quartile".to_string(),
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn parse_squeeze() {
+        expect!(
+            operator,
+            "squeeze message",
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(15),
+                value: InlineOperator::Squeeze {
+                    input_column: Expr::column("message"),
+                    output_column: Expr::column("message"),
+                }
+            })
+        );
+        expect!(
+            operator,
+            "squeeze message as normalized",
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(29),
+                value: InlineOperator::Squeeze {
+                    input_column: Expr::column("message"),
+                    output_column: Expr::column("normalized"),
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn parse_change_case() {
+        expect!(
+            operator,
+            "lowercase(host)",
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(15),
+                value: InlineOperator::ChangeCase {
+                    case: Case::Lower,
+                    input_column: Expr::column("host"),
+                    output_column: Expr::column("host"),
+                }
+            })
+        );
+        expect!(
+            operator,
+            "uppercase level as level_uc",
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(27),
+                value: InlineOperator::ChangeCase {
+                    case: Case::Upper,
+                    input_column: Expr::column("level"),
+                    output_column: Expr::column("level_uc"),
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn parse_timeslice() {
+        expect!(
+            operator,
+            "timeslice 5m ts as bucket",
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(25),
+                value: InlineOperator::Timeslice {
+                    interval: Positioned {
+                        start_pos: QueryPosition(10),
+                        end_pos: QueryPosition(12),
+                        value: 300.0,
+                    },
+                    input_column: Expr::column("ts"),
+                    output_column: Expr::column("bucket"),
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn parse_substring() {
+        expect!(
+            operator,
+            "substring id from 4",
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(19),
+                value: InlineOperator::Substring {
+                    input_column: Expr::column("id"),
+                    start: 4,
+                    end: None,
+                    output_column: Expr::column("id"),
+                }
+            })
+        );
+        expect!(
+            operator,
+            "substring id from -6 to -2 as short",
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(35),
+                value: InlineOperator::Substring {
+                    input_column: Expr::column("id"),
+                    start: -6,
+                    end: Some(-2),
+                    output_column: Expr::column("short"),
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn parse_topk_approx() {
+        expect!(
+            operator,
+            "topk_approx(url, 20)",
+            Operator::TopKApprox(TopKApproxOperator {
+                key_col: Expr::column("url"),
+                key_col_header: "url".to_string(),
+                k: Positioned {
+                    value: 20.0,
+                    start_pos: QueryPosition(17),
+                    end_pos: QueryPosition(19),
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn parse_rate() {
+        expect!(
+            operator,
+            "rate(ts, 1m) fill",
+            Operator::Rate(RateOperator {
+                column: Expr::column("ts"),
+                interval: Positioned {
+                    value: 60.0,
+                    start_pos: QueryPosition(9),
+                    end_pos: QueryPosition(11),
+                },
+                fill: true,
+            })
+        );
+    }
+
     #[test]
     fn parse_percentile() {
         expect!(
@@ -1170,6 +3445,200 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_same_aggregate_of_several_columns() {
+        let parsed = multi_aggregate_operator(Span::new(CompleteStr(
+            "sum(a), sum(b) as b_total, sum(c), average(a), count, count_distinct(a, b) by k",
+        )));
+        let columns = match parsed {
+            Ok((_, Operator::MultiAggregate(agg))) => agg.output_columns(),
+            other => panic!("expected an aggregate, got {:?}", other),
+        };
+        assert_eq!(
+            columns,
+            vec![
+                "_sum_a",
+                "b_total",
+                "_sum_c",
+                "_average",
+                "_count",
+                "_countDistinct"
+            ]
+        );
+    }
+
+    #[test]
+    fn aggregate_duplicate_names() {
+        let duplicate = |query: &str| match multi_aggregate_operator(Span::new(CompleteStr(query)))
+        {
+            Ok((_, Operator::MultiAggregate(agg))) => agg.duplicate_name().map(|(name, _)| name),
+            other => panic!("expected an aggregate, got {:?}", other),
+        };
+        assert_eq!(duplicate("count as total, sum(a) by host"), None);
+        assert_eq!(
+            duplicate("count as total, sum(a) as total"),
+            Some("total".to_string())
+        );
+        assert_eq!(duplicate("count as host by host"), Some("host".to_string()));
+        assert_eq!(
+            duplicate("percentile(a, 50, 90), p50(a) as a_p50"),
+            Some("a_p50".to_string())
+        );
+        // Generated names are checked too, including ones that still match after the columns
+        // were added to them
+        assert_eq!(duplicate("count, count"), Some("_count".to_string()));
+        assert_eq!(
+            duplicate("count as _count, count"),
+            Some("_count".to_string())
+        );
+        assert_eq!(duplicate("sum(a), sum(a)"), Some("_sum_a".to_string()));
+        assert_eq!(duplicate("sum(a), sum(b)"), None);
+    }
+
+    #[test]
+    fn parse_percentiles() {
+        expect!(
+            complete_agg_function,
+            "percentile(x, 50, 99.9)",
+            (
+                "x".to_string(),
+                Positioned {
+                    value: AggregateFunction::Percentiles {
+                        column: Expr::column("x"),
+                        percentiles: vec![
+                            Positioned {
+                                value: 50.0,
+                                start_pos: QueryPosition(14),
+                                end_pos: QueryPosition(16),
+                            },
+                            Positioned {
+                                value: 99.9,
+                                start_pos: QueryPosition(18),
+                                end_pos: QueryPosition(22),
+                            },
+                        ],
+                        approx: false,
+                    },
+                    start_pos: QueryPosition(0),
+                    end_pos: QueryPosition(23),
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn parse_approx_percentiles() {
+        expect!(
+            complete_agg_function,
+            "percentile(x, 99, approx=true)",
+            (
+                "x".to_string(),
+                Positioned {
+                    value: AggregateFunction::Percentiles {
+                        column: Expr::column("x"),
+                        percentiles: vec![Positioned {
+                            value: 99.0,
+                            start_pos: QueryPosition(14),
+                            end_pos: QueryPosition(16),
+                        }],
+                        approx: true,
+                    },
+                    start_pos: QueryPosition(0),
+                    end_pos: QueryPosition(30),
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn parse_ratio() {
+        expect!(
+            complete_agg_function,
+            "ratio(status >= 500, ok) as error_rate",
+            (
+                "error_rate".to_string(),
+                Positioned {
+                    value: AggregateFunction::Ratio {
+                        args: Positioned {
+                            value: vec![
+                                Expr::Binary {
+                                    op: BinaryOp::Comparison(ComparisonOp::Gte),
+                                    left: Box::new(Expr::column("status")),
+                                    right: Box::new(Expr::Value(data::Value::Int(500))),
+                                },
+                                Expr::column("ok"),
+                            ],
+                            start_pos: QueryPosition(5),
+                            end_pos: QueryPosition(24),
+                        },
+                    },
+                    start_pos: QueryPosition(0),
+                    end_pos: QueryPosition(25),
+                }
+            )
+        );
+        expect!(
+            complete_agg_function,
+            "ratio(a, b)",
+            (
+                "ratio".to_string(),
+                Positioned {
+                    value: AggregateFunction::Ratio {
+                        args: Positioned {
+                            value: vec![Expr::column("a"), Expr::column("b")],
+                            start_pos: QueryPosition(5),
+                            end_pos: QueryPosition(11),
+                        },
+                    },
+                    start_pos: QueryPosition(0),
+                    end_pos: QueryPosition(11),
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn parse_arg_min_max() {
+        expect!(
+            complete_agg_function,
+            "argmax(latency, ts)",
+            (
+                "_argmax".to_string(),
+                Positioned {
+                    value: AggregateFunction::ArgMinMax {
+                        max: true,
+                        args: Positioned {
+                            value: vec![Expr::column("latency"), Expr::column("ts")],
+                            start_pos: QueryPosition(6),
+                            end_pos: QueryPosition(19),
+                        },
+                    },
+                    start_pos: QueryPosition(0),
+                    end_pos: QueryPosition(19),
+                }
+            )
+        );
+        expect!(
+            complete_agg_function,
+            "argmin(latency, ts) as fastest",
+            (
+                "fastest".to_string(),
+                Positioned {
+                    value: AggregateFunction::ArgMinMax {
+                        max: false,
+                        args: Positioned {
+                            value: vec![Expr::column("latency"), Expr::column("ts")],
+                            start_pos: QueryPosition(6),
+                            end_pos: QueryPosition(19),
+                        },
+                    },
+                    start_pos: QueryPosition(0),
+                    end_pos: QueryPosition(20),
+                }
+            )
+        );
+    }
+
     #[test]
     fn query_no_operators() {
         expect!(
@@ -1282,6 +3751,46 @@ mod tests {
         );
     }
 
+    #[test]
+    fn describe_query() {
+        let describe = |query_str: &str| {
+            let (_, parsed) = query(Span::new(CompleteStr(query_str))).unwrap();
+            parsed.describe()
+        };
+        assert_eq!(
+            describe(r#""error" | json from msg | where status >= 500 | count by host | limit"#),
+            vec![
+                r#"search: "error""#,
+                "1. [stream] json from msg",
+                "2. [stream] where status >= 500",
+                "3. [aggregate] count as _count by host",
+                "4. [aggregate] sort by _count descending (added automatically)",
+                "5. [aggregate] limit 10 (the default)",
+                "output: an aggregate table",
+            ]
+        );
+        assert_eq!(
+            describe(r#"* | parse "a=*" as a nodrop | split(a) | fields except b"#),
+            vec![
+                "search: *",
+                r#"1. [stream] parse "a=*" from the raw line as a, keeping lines that don't match"#,
+                r#"2. [stream] split a on "," into a"#,
+                "3. [stream] fields except b",
+                "output: records, one per line",
+            ]
+        );
+        assert_eq!(
+            describe(r#"* | split(tags) as tag explode | count by tag"#),
+            vec![
+                "search: *",
+                r#"1. [stream] split tags on "," into tag, a record per part"#,
+                "2. [aggregate] count as _count by tag",
+                "3. [aggregate] sort by _count descending (added automatically)",
+                "output: an aggregate table",
+            ]
+        );
+    }
+
     #[test]
     fn query_operators() {
         let query_str =
@@ -1305,7 +3814,9 @@ mod tests {
                         value: InlineOperator::Parse {
                             pattern: Keyword::new_wildcard("!123*".to_string()),
                             fields: vec!["foo".to_string()],
-                            input_column: None,
+                            field_types: vec![],
+                            input_columns: vec![],
+                            defaults: vec![],
                             no_drop: false
                         }
                     }),
@@ -1329,7 +3840,7 @@ mod tests {
                         ),],
                     }),
                     Operator::Sort(SortOperator {
-                        sort_cols: vec!["foo".to_string()],
+                        sort_cols: vec![("foo".to_string(), SortMode::Descending)],
                         direction: SortMode::Descending,
                     }),
                 ],
@@ -1337,6 +3848,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn kv_operator() {
+        expect!(
+            operator,
+            r#"kv pair_sep=";" kv_sep=':' from col"#,
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(35),
+                value: InlineOperator::KeyValue {
+                    pair_separator: Some(Positioned {
+                        start_pos: QueryPosition(12),
+                        end_pos: QueryPosition(15),
+                        value: ";".to_string(),
+                    }),
+                    kv_separator: Some(Positioned {
+                        start_pos: QueryPosition(23),
+                        end_pos: QueryPosition(26),
+                        value: ":".to_string(),
+                    }),
+                    input_column: Some(Expr::column("col")),
+                }
+            })
+        );
+        expect!(
+            operator,
+            "kv",
+            Operator::Inline(Positioned {
+                start_pos: QueryPosition(0),
+                end_pos: QueryPosition(2),
+                value: InlineOperator::KeyValue {
+                    pair_separator: None,
+                    kv_separator: None,
+                    input_column: None,
+                }
+            })
+        );
+    }
+
     #[test]
     fn logfmt_operator() {
         let query_str = r#"* | logfmt from col | sort by foo dsc "#;
@@ -1354,11 +3903,71 @@ mod tests {
                         }
                     }),
                     Operator::Sort(SortOperator {
-                        sort_cols: vec!["foo".to_string()],
+                        sort_cols: vec![("foo".to_string(), SortMode::Descending)],
                         direction: SortMode::Descending,
                     }),
                 ],
             }
         );
     }
+
+    #[test]
+    fn parse_sort_directions() {
+        expect!(
+            sort,
+            "sort by a desc, b",
+            Operator::Sort(SortOperator {
+                sort_cols: vec![
+                    ("a".to_string(), SortMode::Descending),
+                    ("b".to_string(), SortMode::Ascending),
+                ],
+                direction: SortMode::Ascending,
+            })
+        );
+        expect!(
+            sort,
+            "sort by a, b descending",
+            Operator::Sort(SortOperator {
+                sort_cols: vec![
+                    ("a".to_string(), SortMode::Descending),
+                    ("b".to_string(), SortMode::Descending),
+                ],
+                direction: SortMode::Descending,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_grand_total() {
+        expect!(
+            operator_list,
+            "count | total | limit 2",
+            vec![
+                Operator::MultiAggregate(MultiAggregateOperator {
+                    key_cols: vec![],
+                    key_col_headers: vec![],
+                    aggregate_functions: vec![(
+                        "_count".to_string(),
+                        Positioned {
+                            start_pos: QueryPosition(0),
+                            end_pos: QueryPosition(5),
+                            value: AggregateFunction::Count,
+                        }
+                    )],
+                }),
+                Operator::GrandTotal,
+                Operator::Inline(Positioned {
+                    start_pos: QueryPosition(16),
+                    end_pos: QueryPosition(23),
+                    value: InlineOperator::Limit {
+                        count: Some(Positioned {
+                            start_pos: QueryPosition(22),
+                            end_pos: QueryPosition(23),
+                            value: 2.0,
+                        }),
+                    },
+                }),
+            ]
+        );
+    }
 }
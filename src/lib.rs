@@ -18,24 +18,43 @@ mod alias;
 mod data;
 mod errors;
 mod filter;
+mod input;
 mod lang;
+mod macros;
 mod operator;
 mod render;
 mod typecheck;
 
 pub mod pipeline {
+    use crate::data;
     use crate::data::{Record, Row};
     pub use crate::errors::{ErrorReporter, QueryContainer};
     use crate::filter;
+    pub use crate::input::{
+        is_gzip, seek_to_last_lines, seek_to_line_at, ConcatReader, Decompressed, Follow,
+    };
     use crate::lang::*;
+    pub use crate::macros::{expand_env_vars, MacroConfig, MacroError};
     use crate::operator;
-    use crate::render::{RenderConfig, Renderer};
-    use crate::typecheck::{TypeCheck, TypeError};
-    use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+    pub use crate::operator::seed_random;
+    use crate::render::Renderer;
+    pub use crate::render::{
+        ColorChoice, OutputMode, Overflow, RenderConfig, SnapshotConfig, SplitConfig, Theme,
+        TrailerTarget, DEFAULT_UPDATE_INTERVAL, ELLIPSIS, MIN_UPDATE_INTERVAL,
+    };
+    pub use crate::typecheck::TypeError;
+    use crate::typecheck::{check_aggregate_names, TypeCheck, DEFAULT_LIMIT};
+    use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
     use failure::Error;
     use nom::types::CompleteStr;
     use std::collections::VecDeque;
+    use std::error;
+    use std::fmt;
+    use std::io;
     use std::io::BufRead;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Condvar, Mutex};
     use std::thread;
     use std::time::Duration;
 
@@ -47,34 +66,266 @@ pub mod pipeline {
         #[fail(display = "Non aggregate operators can't follow aggregate operators")]
         NonAggregateAfterAggregate,
 
+        #[fail(
+            display = "xlsx output is only supported for aggregates like `count by ...`, not streaming records"
+        )]
+        XlsxRequiresAggregate,
+
+        #[fail(
+            display = "Snapshots are only written for aggregates like `count by ...`, not streaming records"
+        )]
+        SnapshotRequiresAggregate,
+
+        #[fail(
+            display = "A `total` without a field adds a row to an aggregate like `count by ...`; use `total(field)` for a running total of records"
+        )]
+        TotalRequiresAggregate,
+
+        #[fail(display = "{}", _0)]
+        Type(TypeError),
+
+        #[fail(
+            display = "Failed to parse rendered alias: `{}` as operator_list",
+            alias
+        )]
+        RenderedAlias { alias: String },
+
         #[fail(display = "Unexpected failure: {}", message)]
         Unexpected { message: String },
     }
 
+    /// The ways setting up a pipeline can fail, so code embedding agrind can tell a query that
+    /// doesn't parse apart from a file that can't be written
+    #[derive(Debug)]
+    pub enum AgrindError {
+        /// The query couldn't be parsed.  The details have already been sent to the query's
+        /// `ErrorReporter`.
+        Parse,
+        /// The query parsed, but it's invalid or can't be run with this configuration
+        Query(CompileError),
+        /// Opening a file failed
+        Io(io::Error),
+        /// The output can't be rendered as configured
+        Render(String),
+    }
+
+    impl fmt::Display for AgrindError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                AgrindError::Parse => write!(f, "{}", CompileError::Parse),
+                AgrindError::Query(e) => write!(f, "{}", e),
+                AgrindError::Io(e) => write!(f, "{}", e),
+                AgrindError::Render(message) => write!(f, "{}", message),
+            }
+        }
+    }
+
+    impl error::Error for AgrindError {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            match self {
+                AgrindError::Io(e) => Some(e),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<CompileError> for AgrindError {
+        fn from(e: CompileError) -> Self {
+            match e {
+                CompileError::Parse => AgrindError::Parse,
+                other => AgrindError::Query(other),
+            }
+        }
+    }
+
+    impl From<TypeError> for AgrindError {
+        fn from(e: TypeError) -> Self {
+            AgrindError::Query(CompileError::Type(e))
+        }
+    }
+
+    impl From<io::Error> for AgrindError {
+        fn from(e: io::Error) -> Self {
+            AgrindError::Io(e)
+        }
+    }
+
+    /// How input lines are interpreted before the query's operators run
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum InputFormat {
+        /// Lines are passed to the query as-is
+        Raw,
+        /// Each line is parsed as JSON or logfmt when it looks like one of them
+        Auto,
+    }
+
+    #[derive(Debug, Fail)]
+    #[fail(
+        display = "Unknown input format `{}`. Expected one of: raw, auto",
+        format
+    )]
+    pub struct UnknownInputFormat {
+        format: String,
+    }
+
+    impl FromStr for InputFormat {
+        type Err = UnknownInputFormat;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "raw" => Ok(InputFormat::Raw),
+                "auto" => Ok(InputFormat::Auto),
+                other => Err(UnknownInputFormat {
+                    format: other.to_string(),
+                }),
+            }
+        }
+    }
+
     pub struct Pipeline {
         filter: filter::Filter,
-        pre_aggregates: Vec<Box<dyn operator::UnaryPreAggOperator>>,
+        pre_aggregates: Vec<PreAggStage>,
         aggregators: Vec<Box<dyn operator::AggregateOperator>>,
         renderer: Renderer,
+        interrupt: InterruptHandle,
+    }
+
+    /// How many records went into and came out of one stage of a pipeline before its first
+    /// aggregate
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct StageStats {
+        pub name: String,
+        pub records_in: u64,
+        pub records_out: u64,
+    }
+
+    impl StageStats {
+        fn new(name: String) -> Self {
+            StageStats {
+                name,
+                records_in: 0,
+                records_out: 0,
+            }
+        }
+    }
+
+    impl fmt::Display for StageStats {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "{}: {} → {}",
+                self.name, self.records_in, self.records_out
+            )?;
+            if self.records_out < self.records_in {
+                write!(f, " (dropped {})", self.records_in - self.records_out)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// A pre-aggregate operator and the count of records that have passed through it
+    struct PreAggStage {
+        operator: Box<dyn operator::UnaryPreAggOperator>,
+        stats: StageStats,
+    }
+
+    impl PreAggStage {
+        fn new(name: &str, operator: Box<dyn operator::UnaryPreAggOperator>) -> Self {
+            PreAggStage {
+                operator,
+                stats: StageStats::new(name.to_string()),
+            }
+        }
+    }
+
+    /// Stops a running pipeline early from another thread, like a Ctrl-C handler.  The pipeline
+    /// stops reading input and renders what it has processed so far as if the input had ended,
+    /// so an interrupted aggregate still prints its partial result.
+    #[derive(Clone, Default)]
+    pub struct InterruptHandle {
+        interrupted: Arc<AtomicBool>,
+        rendered: Arc<(Mutex<bool>, Condvar)>,
+    }
+
+    impl InterruptHandle {
+        /// Ask the pipeline to stop.  Returns false if it had already been interrupted.
+        pub fn interrupt(&self) -> bool {
+            !self.interrupted.swap(true, Ordering::SeqCst)
+        }
+
+        pub fn is_interrupted(&self) -> bool {
+            self.interrupted.load(Ordering::SeqCst)
+        }
+
+        /// Block until the pipeline has written its final output.  This returns even if the
+        /// thread reading the input is still stuck waiting for a line that will never come.
+        pub fn wait_until_rendered(&self) {
+            let (ref lock, ref cvar) = *self.rendered;
+            let mut rendered = lock.lock().unwrap();
+            while !*rendered {
+                rendered = cvar.wait(rendered).unwrap();
+            }
+        }
+
+        fn set_rendered(&self) {
+            let (ref lock, ref cvar) = *self.rendered;
+            *lock.lock().unwrap() = true;
+            cvar.notify_all();
+        }
     }
 
     impl Pipeline {
-        fn convert_sort(op: SortOperator) -> Box<dyn operator::AggregateOperator> {
-            let mode = match op.direction {
-                SortMode::Ascending => operator::SortDirection::Ascending,
-                SortMode::Descending => operator::SortDirection::Descending,
+        fn convert_sort(
+            op: SortOperator,
+            top_k: Option<usize>,
+        ) -> Box<dyn operator::AggregateOperator> {
+            let columns = op
+                .sort_cols
+                .into_iter()
+                .map(|(col, mode)| match mode {
+                    SortMode::Ascending => (col, operator::SortDirection::Ascending),
+                    SortMode::Descending => (col, operator::SortDirection::Descending),
+                })
+                .collect();
+            match top_k {
+                Some(k) => Box::new(operator::Sorter::top_k(columns, k)),
+                None => Box::new(operator::Sorter::new(columns)),
+            }
+        }
+
+        /// A sort followed by a positive `limit` is fused into a single top-K sort.  If the next
+        /// operator is such a limit, it's removed from the queue and its count is returned.
+        /// Other limits are left in place so they are type checked and run as usual.
+        fn take_top_k(op_deque: &mut VecDeque<Operator>) -> Option<usize> {
+            let limit = match op_deque.front() {
+                Some(Operator::Inline(Positioned {
+                    value: InlineOperator::Limit { count },
+                    ..
+                })) => count
+                    .as_ref()
+                    .map(|count| count.value)
+                    .unwrap_or(DEFAULT_LIMIT as f64),
+                _ => return None,
             };
-            Box::new(operator::Sorter::new(op.sort_cols, mode))
+            if limit >= 1.0 && limit.fract() == 0.0 {
+                op_deque.pop_front();
+                Some(limit as usize)
+            } else {
+                None
+            }
         }
 
         fn convert_multi_agg(
             op: MultiAggregateOperator,
             pipeline: &QueryContainer,
+            ellipsis: &str,
         ) -> Result<Box<dyn operator::AggregateOperator>, TypeError> {
+            check_aggregate_names(&op, pipeline)?;
             let mut agg_functions = Vec::with_capacity(op.aggregate_functions.len());
 
             for agg in op.aggregate_functions {
-                let operator_function = agg.1.type_check(pipeline)?;
+                let mut operator_function = agg.1.type_check(pipeline)?;
+                operator_function.set_ellipsis(ellipsis);
                 agg_functions.push((agg.0, operator_function));
             }
             let key_cols: Vec<operator::Expr> = op
@@ -92,10 +343,9 @@ pub mod pipeline {
         fn implicit_sort(multi_agg: &MultiAggregateOperator) -> SortOperator {
             SortOperator {
                 sort_cols: multi_agg
-                    .aggregate_functions
-                    .iter()
-                    .map(|&(ref k, _)| k)
-                    .cloned()
+                    .output_columns()
+                    .into_iter()
+                    .map(|col| (col, SortMode::Descending))
                     .collect(),
                 direction: SortMode::Descending,
             }
@@ -116,15 +366,29 @@ pub mod pipeline {
             }
         }
 
-        pub fn new(pipeline: &QueryContainer, format: Option<String>) -> Result<Self, Error> {
+        pub fn new(
+            pipeline: &QueryContainer,
+            input_format: InputFormat,
+            mut render_config: RenderConfig,
+        ) -> Result<Self, AgrindError> {
             let parsed = pipeline.parse().map_err(|_pos| CompileError::Parse);
             let query = parsed?;
             let filters = Pipeline::convert_filter(query.search);
             let mut in_agg = false;
-            let mut pre_agg: Vec<Box<dyn operator::UnaryPreAggOperator>> = Vec::new();
+            let mut pre_agg: Vec<PreAggStage> = Vec::new();
+            if input_format == InputFormat::Auto {
+                pre_agg.push(PreAggStage::new(
+                    "auto parse",
+                    Box::new(operator::AutoParse),
+                ));
+            }
             let mut post_agg: Vec<Box<dyn operator::AggregateOperator>> = Vec::new();
             let mut op_deque = query.operators.into_iter().collect::<VecDeque<_>>();
             let mut has_errors = false;
+            // The fields kept by the last `fields` before any aggregate, in the order they're listed
+            let mut field_order: Option<Vec<String>> = None;
+            // The key columns of the last aggregate, which a grand total doesn't sum
+            let mut key_columns: Vec<String> = Vec::new();
             while let Some(op) = op_deque.pop_front() {
                 match op {
                     Operator::RenderedAlias(rendered_alias) => {
@@ -133,10 +397,12 @@ pub mod pipeline {
                         // let inner_query = QueryContainer::new(rendered_alias.value, ???);
                         let (_span, operators) =
                             match operator_list(Span::new(CompleteStr(&rendered_alias.value))) {
-                                Err(_err) => bail!(
-                                    "Failed to parse rendered alias: `{}` as operator_list",
-                                    rendered_alias.value,
-                                ),
+                                Err(_err) => {
+                                    return Err(CompileError::RenderedAlias {
+                                        alias: rendered_alias.value,
+                                    }
+                                    .into())
+                                }
                                 Ok(v) => v,
                             };
 
@@ -146,18 +412,31 @@ pub mod pipeline {
                         }
                     }
                     Operator::Inline(inline_op) => {
+                        let name = describe_inline(&inline_op.value);
+                        if let InlineOperator::Fields {
+                            mode: FieldMode::Only,
+                            ref fields,
+                        } = inline_op.value
+                        {
+                            if !in_agg {
+                                field_order = Some(fields.clone());
+                            }
+                        }
                         let op_builder = inline_op.type_check(pipeline)?;
 
                         if !in_agg {
-                            pre_agg.push(op_builder.build());
+                            pre_agg.push(PreAggStage::new(&name, op_builder.build()));
                         } else {
                             post_agg.push(Box::new(operator::PreAggAdapter::new(op_builder)));
                         }
                     }
                     Operator::MultiAggregate(agg_op) => {
                         in_agg = true;
+                        key_columns = agg_op.key_col_headers.clone();
                         let sorter = Pipeline::implicit_sort(&agg_op);
-                        if let Ok(op) = Pipeline::convert_multi_agg(agg_op, pipeline) {
+                        if let Ok(op) =
+                            Pipeline::convert_multi_agg(agg_op, pipeline, &render_config.ellipsis)
+                        {
                             post_agg.push(op);
 
                             let needs_sort = match op_deque.front() {
@@ -165,54 +444,113 @@ pub mod pipeline {
                                     value: InlineOperator::Limit { .. },
                                     ..
                                 })) => true,
-                                None => true,
+                                Some(Operator::GrandTotal) | None => true,
                                 _ => false,
                             };
                             if needs_sort {
-                                post_agg.push(Pipeline::convert_sort(sorter));
+                                let top_k = Pipeline::take_top_k(&mut op_deque);
+                                post_agg.push(Pipeline::convert_sort(sorter, top_k));
                             }
                         } else {
                             has_errors = true;
                         }
                     }
-                    Operator::Sort(sort_op) => post_agg.push(Pipeline::convert_sort(sort_op)),
+                    Operator::Sort(sort_op) => {
+                        let top_k = Pipeline::take_top_k(&mut op_deque);
+                        post_agg.push(Pipeline::convert_sort(sort_op, top_k))
+                    }
+                    Operator::TopKApprox(topk_op) => {
+                        in_agg = true;
+                        key_columns = vec![topk_op.key_col_header.clone()];
+                        post_agg.push(Box::new(topk_op.type_check(pipeline)?));
+                    }
+                    Operator::Rate(rate_op) => {
+                        in_agg = true;
+                        key_columns = vec!["_timeslice".to_string()];
+                        post_agg.push(Box::new(rate_op.type_check(pipeline)?));
+                    }
+                    Operator::GrandTotal => {
+                        if post_agg.is_empty() {
+                            return Err(CompileError::TotalRequiresAggregate.into());
+                        }
+                        post_agg.push(Box::new(operator::GrandTotal::new(key_columns.clone())));
+                    }
                 }
             }
             if has_errors {
                 return Err(CompileError::Parse.into());
             }
+            if render_config.output_mode == OutputMode::Xlsx && post_agg.is_empty() {
+                return Err(CompileError::XlsxRequiresAggregate.into());
+            }
+            if render_config.snapshot.is_some() && post_agg.is_empty() {
+                return Err(CompileError::SnapshotRequiresAggregate.into());
+            }
+            // Records are laid out in the order `fields` listed them, unless fields were pinned
+            if let Some(field_order) = field_order {
+                if post_agg.is_empty() && render_config.pinned_fields.is_empty() {
+                    render_config.pinned_fields = field_order;
+                }
+            }
             Result::Ok(Pipeline {
                 filter: filters,
                 pre_aggregates: pre_agg,
                 aggregators: post_agg,
-                renderer: Renderer::new(
-                    RenderConfig {
-                        floating_points: 2,
-                        min_buffer: 4,
-                        max_buffer: 8,
-                        format: format,
-                    },
-                    Duration::from_millis(50),
-                ),
+                renderer: {
+                    let update_interval = render_config.update_interval;
+                    Renderer::new(render_config, update_interval)?
+                },
+                interrupt: InterruptHandle::default(),
             })
         }
 
-        fn render_noagg(mut renderer: Renderer, rx: &Receiver<Row>) {
-            loop {
-                let next = rx.recv_timeout(Duration::from_millis(50));
-                match next {
-                    Ok(row) => {
-                        let result = renderer.render(&row, false);
+        /// A handle for stopping this pipeline while it runs
+        pub fn interrupt_handle(&self) -> InterruptHandle {
+            self.interrupt.clone()
+        }
 
-                        if let Err(e) = result {
-                            eprintln!("error: {}", e);
-                            break;
-                        }
+        /// Whether the query ends in an aggregate, rather than writing out every record
+        pub fn has_aggregate(&self) -> bool {
+            !self.aggregators.is_empty()
+        }
+
+        /// Print an error from the renderer.  When the reader of our output goes away, like when
+        /// piping to `head`, there's nothing left to do so the renderer stops quietly and the
+        /// pipeline finishes without reporting an error.
+        fn report_render_error(e: &Error) {
+            match e.downcast_ref::<io::Error>() {
+                Some(io_error) if io_error.kind() == io::ErrorKind::BrokenPipe => (),
+                _ => eprintln!("error: {}", e),
+            }
+        }
+
+        /// The next row to render, or `None` once the input has ended.  After an interrupt, the
+        /// rows already sent are still returned, but nothing more is waited for.
+        fn next_row(rx: &Receiver<Row>, interrupt: &InterruptHandle) -> Option<Option<Row>> {
+            if interrupt.is_interrupted() {
+                return rx.try_recv().ok().map(Some);
+            }
+            match rx.recv_timeout(Duration::from_millis(50)) {
+                Ok(row) => Some(Some(row)),
+                Err(RecvTimeoutError::Timeout) => Some(None),
+                Err(RecvTimeoutError::Disconnected) => None,
+            }
+        }
+
+        fn render_noagg(mut renderer: Renderer, rx: &Receiver<Row>, interrupt: &InterruptHandle) {
+            while let Some(next) = Pipeline::next_row(rx, interrupt) {
+                if let Some(row) = next {
+                    let result = renderer.render(&row, false);
+
+                    if let Err(e) = result {
+                        Pipeline::report_render_error(&e);
+                        return;
                     }
-                    Err(RecvTimeoutError::Timeout) => {}
-                    Err(RecvTimeoutError::Disconnected) => break,
                 }
             }
+            if let Err(e) = renderer.finish() {
+                Pipeline::report_render_error(&e);
+            }
         }
 
         fn render_aggregate(
@@ -220,52 +558,93 @@ pub mod pipeline {
             mut rest: Vec<Box<dyn operator::AggregateOperator>>,
             mut renderer: Renderer,
             rx: &Receiver<Row>,
+            interrupt: &InterruptHandle,
         ) {
-            loop {
-                let next = rx.recv_timeout(Duration::from_millis(50));
-                match next {
-                    Ok(row) => (*head).process(row),
-                    Err(RecvTimeoutError::Timeout) => {}
-                    Err(RecvTimeoutError::Disconnected) => break,
+            while let Some(next) = Pipeline::next_row(rx, interrupt) {
+                if let Some(row) = next {
+                    (*head).process(row);
+                    renderer.count_record();
                 }
 
-                if renderer.should_print() {
+                if renderer.should_print() || renderer.should_snapshot() {
                     let result =
                         renderer.render(&Pipeline::run_agg_pipeline(&head, &mut rest), false);
 
                     if let Err(e) = result {
-                        eprintln!("error: {}", e);
+                        Pipeline::report_render_error(&e);
                         return;
                     }
                 }
             }
-            let result = renderer.render(&Pipeline::run_agg_pipeline(&head, &mut rest), true);
+            let result = renderer
+                .render(&Pipeline::run_agg_pipeline(&head, &mut rest), true)
+                .and_then(|_| renderer.finish());
 
             if let Err(e) = result {
-                eprintln!("error: {}", e);
-                return;
+                Pipeline::report_render_error(&e);
             }
         }
 
-        pub fn process<T: BufRead>(self, mut buf: T) {
+        /// Run the pipeline over `buf`, rendering its output.  Returns how many records passed
+        /// through the search and each operator before the first aggregate.  If reading `buf`
+        /// fails, like for a truncated gzip file, the error is reported on stderr after what
+        /// was read so far is rendered, and returned.
+        pub fn process<T: BufRead>(self, buf: T) -> io::Result<Vec<StageStats>> {
             let (tx, rx) = bounded(1000);
             let mut aggregators = self.aggregators;
-            let mut preaggs = self.pre_aggregates;
+            let preaggs = self.pre_aggregates;
             let renderer = self.renderer;
+            let interrupt = self.interrupt.clone();
             let t = if !aggregators.is_empty() {
                 let head = aggregators.remove(0);
-                thread::spawn(move || Pipeline::render_aggregate(head, aggregators, renderer, &rx))
+                thread::spawn(move || {
+                    Pipeline::render_aggregate(head, aggregators, renderer, &rx, &interrupt);
+                    interrupt.set_rendered();
+                })
             } else {
-                thread::spawn(move || Pipeline::render_noagg(renderer, &rx))
+                thread::spawn(move || {
+                    Pipeline::render_noagg(renderer, &rx, &interrupt);
+                    interrupt.set_rendered();
+                })
             };
 
+            let stats = Pipeline::send_input(&self.filter, &self.interrupt, preaggs, buf, &tx);
+
+            // Drop tx when causes the thread to exit.
+            drop(tx);
+            match t.join() {
+                Ok(_) => (),
+                Err(e) => println!("Error: {:?}", e),
+            }
+            if let Err(ref e) = stats {
+                eprintln!("error: failed to read the input: {}", e);
+            }
+            stats
+        }
+
+        /// Run each line of the input that matches the filter through the pre-agg operators,
+        /// sending what comes out of them to `tx`
+        fn send_input<T: BufRead>(
+            filter: &filter::Filter,
+            interrupt: &InterruptHandle,
+            mut preaggs: Vec<PreAggStage>,
+            mut buf: T,
+            tx: &Sender<Row>,
+        ) -> io::Result<Vec<StageStats>> {
+            let mut search = StageStats::new("search".to_string());
             // This is pretty slow in practice. We could move line splitting until after
             // we find a match. Another option is moving the transformation to String until
             // after we match (staying as Vec<u8> until then)
             let mut line = String::with_capacity(1024);
-            while buf.read_line(&mut line).unwrap() > 0 {
-                if self.filter.matches(&line) {
-                    if !Pipeline::proc_preagg(Record::new(&line), &mut preaggs, &tx) {
+            while !interrupt.is_interrupted() && buf.read_line(&mut line)? > 0 {
+                search.records_in += 1;
+                if filter.matches(&line) {
+                    search.records_out += 1;
+                    if !Pipeline::proc_preagg(Record::new(&line), &mut preaggs, tx) {
+                        break;
+                    }
+                    // Once a limit has passed all of its records, nothing more can get through
+                    if preaggs.iter().any(|stage| stage.operator.is_done()) {
                         break;
                     }
                 }
@@ -273,43 +652,111 @@ pub mod pipeline {
             }
 
             // Drain any remaining records from the operators.
+            let mut stats = vec![search];
             while !preaggs.is_empty() {
-                let preagg = preaggs.remove(0);
+                let PreAggStage {
+                    operator,
+                    stats: mut stage_stats,
+                } = preaggs.remove(0);
 
-                for rec in preagg.drain() {
-                    if !Pipeline::proc_preagg(rec, &mut preaggs, &tx) {
+                for rec in operator.drain() {
+                    stage_stats.records_out += 1;
+                    if !Pipeline::proc_preagg(rec, &mut preaggs, tx) {
                         break;
                     }
                 }
+                stats.push(stage_stats);
             }
+            Ok(stats)
+        }
 
-            // Drop tx when causes the thread to exit.
+        /// Run the whole pipeline over `buf` and return the rows it produces: the records, or
+        /// the rows of the final aggregate.  Nothing is rendered.
+        fn collect_rows<T: BufRead>(self, buf: T) -> io::Result<Vec<data::VMap>> {
+            let (tx, rx) = unbounded();
+            Pipeline::send_input(&self.filter, &self.interrupt, self.pre_aggregates, buf, &tx)?;
             drop(tx);
-            match t.join() {
-                Ok(_) => (),
-                Err(e) => println!("Error: {:?}", e),
+            let mut aggregators = self.aggregators;
+            if aggregators.is_empty() {
+                return Ok(rx
+                    .iter()
+                    .filter_map(|row| match row {
+                        Row::Record(rec) => Some(rec.data),
+                        Row::Aggregate(_) => None,
+                    })
+                    .collect());
+            }
+            let mut head = aggregators.remove(0);
+            for row in rx.iter() {
+                head.process(row);
+            }
+            Ok(match Pipeline::run_agg_pipeline(&head, &mut aggregators) {
+                Row::Aggregate(agg) => agg.data,
+                Row::Record(rec) => vec![rec.data],
+            })
+        }
+
+        /// Enrich each record of this pipeline with the fields of the row of `side` that has
+        /// the same value for `key`.  `side` is run over `side_input` first and all of its rows
+        /// are kept in memory.  The join happens after the other operators before the first
+        /// aggregate, so the aggregates can use the joined fields.  Fails if `side_input` can't
+        /// be read.
+        pub fn join<T: BufRead>(
+            &mut self,
+            side: Pipeline,
+            side_input: T,
+            key: &str,
+        ) -> io::Result<()> {
+            let rows = side.collect_rows(side_input)?;
+            self.pre_aggregates.push(PreAggStage::new(
+                &format!("join on {}", key),
+                Box::new(operator::Join::new(key.to_string(), rows)),
+            ));
+            Ok(())
+        }
+
+        /// Warn when `count_distinct` holds more than `limit` values for any one group, since every
+        /// distinct value is kept in memory until the aggregate is emitted
+        pub fn warn_distinct_over(&mut self, limit: usize) {
+            for aggregator in &mut self.aggregators {
+                aggregator.warn_distinct_over(limit);
             }
         }
 
+        /// Pass at most `limit` records for each value of `field`, starting the counts over
+        /// every `reset`.  Like a join, this runs after the other operators before the first
+        /// aggregate.
+        pub fn limit_per_key(&mut self, field: &str, limit: u64, reset: Duration) {
+            self.pre_aggregates.push(PreAggStage::new(
+                &format!("per-key limit {}={}", field, limit),
+                Box::new(operator::PerKeyLimit::new(field.to_string(), limit, reset)),
+            ));
+        }
+
         /// Process a record using the pre-agg operators.  The output of the last operator will be
         /// sent to `tx`.
-        fn proc_preagg(
-            mut rec: Record,
-            pre_aggs: &mut [Box<dyn operator::UnaryPreAggOperator>],
-            tx: &Sender<Row>,
-        ) -> bool {
-            for pre_agg in pre_aggs {
-                match (*pre_agg).process_mut(rec) {
-                    Ok(Some(next_rec)) => rec = next_rec,
-                    Ok(None) => return true,
-                    Err(err) => {
-                        eprintln!("error: {}", err);
-                        return true;
+        fn proc_preagg(rec: Record, pre_aggs: &mut [PreAggStage], tx: &Sender<Row>) -> bool {
+            let (pre_agg, rest) = match pre_aggs.split_first_mut() {
+                Some(stages) => stages,
+                None => return tx.send(Row::Record(rec)).is_ok(),
+            };
+            pre_agg.stats.records_in += 1;
+            match pre_agg.operator.process_all(rec) {
+                Ok(next_recs) => {
+                    // An operator like `split ... explode` can pass on several records
+                    for next_rec in next_recs {
+                        pre_agg.stats.records_out += 1;
+                        if !Pipeline::proc_preagg(next_rec, rest, tx) {
+                            return false;
+                        }
                     }
+                    true
+                }
+                Err(err) => {
+                    eprintln!("error: {}", err);
+                    true
                 }
             }
-
-            tx.send(Row::Record(rec)).is_ok()
         }
 
         pub fn run_agg_pipeline(
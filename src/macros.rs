@@ -0,0 +1,308 @@
+//! User defined query macros.  Macros are loaded from the `[macros]` table of `~/.agrind.toml`:
+//!
+//! ```toml
+//! [macros]
+//! errors = "json | where status >= 500 | count by host"
+//! ```
+//!
+//! `@errors` anywhere in a query is replaced by the macro's text before the query is parsed.
+//! Macros can reference other macros, but not themselves.
+//!
+//! After macros are expanded, `$NAME` is replaced by the value of the environment variable, so
+//! saved queries can be reused with different hosts or thresholds.
+use failure::Error;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum MacroError {
+    #[fail(display = "Unknown macro `@{}`", name)]
+    Unknown { name: String },
+
+    #[fail(display = "Macro `@{}` is recursive: {}", name, cycle)]
+    Recursive { name: String, cycle: String },
+
+    #[fail(
+        display = "Environment variable `${}` isn't set. Set it, or pass --allow-unset-env to use an empty string",
+        name
+    )]
+    UnsetVariable { name: String },
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MacroConfig {
+    #[serde(default)]
+    macros: HashMap<String, String>,
+}
+
+fn is_macro_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+impl MacroConfig {
+    /// Load macros from the given config file.  A missing file means there are no macros.
+    pub fn load(path: &Path) -> Result<MacroConfig, Error> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(toml::from_str(&contents)?),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(MacroConfig::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace every `@name` in the query with the text of the macro.  `@` only starts a macro
+    /// at the beginning of a word and outside of quoted strings, so searches like
+    /// `"user@example.com"` are left alone.
+    pub fn expand(&self, query: &str) -> Result<String, MacroError> {
+        self.expand_inner(query, &mut Vec::new())
+    }
+
+    fn expand_inner<'a>(
+        &'a self,
+        query: &str,
+        stack: &mut Vec<&'a str>,
+    ) -> Result<String, MacroError> {
+        let mut expanded = String::with_capacity(query.len());
+        let mut quote = None;
+        let mut escaped = false;
+        // Anything that can't be part of a name marks the start of the query as a word boundary
+        let mut prev = ' ';
+        let mut chars = query.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match quote {
+                Some(q) => {
+                    if escaped {
+                        escaped = false;
+                    } else if c == '\\' {
+                        escaped = true;
+                    } else if c == q {
+                        quote = None;
+                    }
+                    expanded.push(c);
+                }
+                None if c == '"' || c == '\'' => {
+                    quote = Some(c);
+                    expanded.push(c);
+                }
+                None if c == '@' && !is_macro_char(prev) => {
+                    let start = i + 1;
+                    let mut end = start;
+                    while let Some(&(j, next)) = chars.peek() {
+                        if !is_macro_char(next) {
+                            break;
+                        }
+                        end = j + next.len_utf8();
+                        chars.next();
+                    }
+                    let name = &query[start..end];
+                    if name.is_empty() {
+                        expanded.push(c);
+                    } else {
+                        expanded.push_str(&self.expand_macro(name, stack)?);
+                    }
+                }
+                None => expanded.push(c),
+            }
+            prev = c;
+        }
+        Ok(expanded)
+    }
+
+    fn expand_macro<'a>(
+        &'a self,
+        name: &str,
+        stack: &mut Vec<&'a str>,
+    ) -> Result<String, MacroError> {
+        let (name, body) = match self.macros.get_key_value(name) {
+            Some((name, body)) => (name.as_str(), body.trim()),
+            None => {
+                return Err(MacroError::Unknown {
+                    name: name.to_string(),
+                })
+            }
+        };
+        if stack.contains(&name) {
+            let cycle: Vec<String> = stack
+                .iter()
+                .chain(Some(&name))
+                .map(|name| format!("@{}", name))
+                .collect();
+            return Err(MacroError::Recursive {
+                name: name.to_string(),
+                cycle: cycle.join(" -> "),
+            });
+        }
+        stack.push(name);
+        let expanded = self.expand_inner(body, stack)?;
+        stack.pop();
+        Ok(expanded)
+    }
+}
+
+/// The query text for the value of an environment variable.  Whole numbers and decimals are
+/// left bare so they compare as numbers, and anything else becomes a double quoted string.
+fn env_literal(value: &str) -> String {
+    let is_digits = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+    let is_number = match value.split_once('.') {
+        Some((whole, fraction)) => is_digits(whole) && is_digits(fraction),
+        None => is_digits(value),
+    };
+    if is_number {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Replace every `$NAME` in the query with the value `lookup` gives for it.  Like macros,
+/// variables are only replaced outside of quoted strings, and `\$` is a literal `$`.  The query
+/// is expanded once, when it's built, rather than for every record.
+pub fn expand_env_vars<F: Fn(&str) -> Option<String>>(
+    query: &str,
+    lookup: F,
+) -> Result<String, MacroError> {
+    let mut expanded = String::with_capacity(query.len());
+    let mut quote = None;
+    let mut escaped = false;
+    let mut chars = query.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+                expanded.push(c);
+            }
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                expanded.push(c);
+            }
+            None if c == '\\' && chars.peek().map(|&(_, next)| next) == Some('$') => {
+                expanded.push('$');
+                chars.next();
+            }
+            None if c == '$' => {
+                let start = i + 1;
+                let mut end = start;
+                while let Some(&(j, next)) = chars.peek() {
+                    if !is_macro_char(next) {
+                        break;
+                    }
+                    end = j + next.len_utf8();
+                    chars.next();
+                }
+                let name = &query[start..end];
+                if name.is_empty() {
+                    expanded.push(c);
+                } else {
+                    let value = lookup(name).ok_or_else(|| MacroError::UnsetVariable {
+                        name: name.to_string(),
+                    })?;
+                    expanded.push_str(&env_literal(&value));
+                }
+            }
+            None => expanded.push(c),
+        }
+    }
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maplit::hashmap;
+
+    fn config() -> MacroConfig {
+        MacroConfig {
+            macros: hashmap! {
+                "errors".to_string() => "json | where status >= 500".to_string(),
+                "error_hosts".to_string() => "@errors | count by host".to_string(),
+                "a".to_string() => "@b".to_string(),
+                "b".to_string() => "json | @a".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn expand_macros() {
+        let config = config();
+        assert_eq!(
+            config.expand("* | @errors | count"),
+            Ok("* | json | where status >= 500 | count".to_string())
+        );
+        assert_eq!(
+            config.expand("* | @error_hosts"),
+            Ok("* | json | where status >= 500 | count by host".to_string())
+        );
+        assert_eq!(
+            config.expand(r#"user@example.com or "@errors" | json"#),
+            Ok(r#"user@example.com or "@errors" | json"#.to_string())
+        );
+    }
+
+    #[test]
+    fn expand_macro_errors() {
+        let config = config();
+        assert_eq!(
+            config.expand("* | @missing"),
+            Err(MacroError::Unknown {
+                name: "missing".to_string()
+            })
+        );
+        assert_eq!(
+            config.expand("* | @a"),
+            Err(MacroError::Recursive {
+                name: "a".to_string(),
+                cycle: "@a -> @b -> @a".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn expand_env() {
+        let lookup = |name: &str| match name {
+            "HOSTNAME" => Some("web-1".to_string()),
+            "MIN_STATUS" => Some("500".to_string()),
+            "QUOTED" => Some(r#"say "hi""#.to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_env_vars(
+                "* | json | where host == $HOSTNAME | where status >= $MIN_STATUS",
+                lookup
+            ),
+            Ok(r#"* | json | where host == "web-1" | where status >= 500"#.to_string())
+        );
+        assert_eq!(
+            expand_env_vars("* | where msg == $QUOTED", lookup),
+            Ok(r#"* | where msg == "say \"hi\"""#.to_string())
+        );
+        assert_eq!(
+            expand_env_vars(r#""$HOSTNAME" \$HOSTNAME $ | json"#, lookup),
+            Ok(r#""$HOSTNAME" $HOSTNAME $ | json"#.to_string())
+        );
+        assert_eq!(
+            expand_env_vars("* | where host == $MISSING", lookup),
+            Err(MacroError::UnsetVariable {
+                name: "MISSING".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_config() {
+        let config: MacroConfig =
+            toml::from_str("[macros]\nerrors = \"json | where status >= 500\"\n").unwrap();
+        assert_eq!(
+            config.expand("* | @errors"),
+            Ok("* | json | where status >= 500".to_string())
+        );
+        let empty: MacroConfig = toml::from_str("").unwrap();
+        assert!(empty.macros.is_empty());
+    }
+}
@@ -10,18 +10,37 @@ use self::serde_json::Value as JsonValue;
 use crate::data;
 use crate::data::{Aggregate, Record, Row};
 use crate::operator::itertools::Itertools;
-use crate::render::RenderConfig;
+use crate::render::{RenderConfig, ELLIPSIS};
+use lazy_static::lazy_static;
+use ordered_float::OrderedFloat;
+use rand::rngs::SmallRng;
+use rand::{FromEntropy, Rng, SeedableRng};
+use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::iter;
 use std::iter::FromIterator;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 type Data = HashMap<String, data::Value>;
 
+mod cidr;
+mod fuzzy;
+mod glob;
 mod split;
+mod url;
+
+pub use self::cidr::IpNetwork;
+pub use self::glob::glob_to_regex;
 
 #[derive(Debug, Fail, PartialEq)]
 pub enum EvalError {
@@ -48,40 +67,62 @@ pub enum EvalError {
 
     #[fail(display = "Expected boolean, found {}", found)]
     ExpectedBoolean { found: String },
+
+    #[fail(
+        display = "Expected number, found the boolean {}. Booleans aren't counted as 1 or 0, so filter on them with `where` and `count` the records instead",
+        value
+    )]
+    BooleanNotNumber { value: bool },
 }
 
 pub trait Evaluatable<T>: Send + Sync + Clone {
     fn eval(&self, record: &Data) -> Result<T, EvalError>;
 }
 
-pub trait EvaluatableBorrowed<T> {
-    fn eval_borrowed<'a>(&self, record: &'a Data) -> Result<&'a T, EvalError>;
+/// Evaluate to a value that borrows from the record where possible.  Values computed by an
+/// expression, like the result of a function call, are returned owned.
+pub trait EvaluatableBorrowed<T: ToOwned + ?Sized> {
+    fn eval_borrowed<'a>(&self, record: &'a Data) -> Result<Cow<'a, T>, EvalError>;
 }
 
 /// Trait for operators that are functional in nature and do not maintain state.
 pub trait UnaryPreAggFunction: Send + Sync {
     fn process(&self, rec: Record) -> Result<Option<Record>, EvalError>;
+    /// Like `process`, for functions that can turn one record into several
+    fn process_all(&self, rec: Record) -> Result<Vec<Record>, EvalError> {
+        Ok(self.process(rec)?.into_iter().collect())
+    }
 }
 
 /// Get a column from the given record.
-fn get_input<'a>(rec: &'a Record, col: &Option<Expr>) -> Result<&'a str, EvalError> {
+fn get_input<'a>(rec: &'a Record, col: &Option<Expr>) -> Result<Cow<'a, str>, EvalError> {
     match col {
         Some(expr) => {
-            let res: &String = expr.eval_borrowed(&rec.data)?;
+            let res: Cow<'a, str> = expr.eval_borrowed(&rec.data)?;
             Ok(res)
         }
-        None => Ok(&rec.raw),
+        None => Ok(Cow::Borrowed(&rec.raw)),
     }
 }
 
 /// Trait for operators that maintain state while processing records.
 pub trait UnaryPreAggOperator: Send + Sync {
     fn process_mut(&mut self, rec: Record) -> Result<Option<Record>, EvalError>;
+    /// Every record that comes out of the operator for `rec`.  Most operators pass on at most
+    /// one record, but some fan a record out into several.
+    fn process_all(&mut self, rec: Record) -> Result<Vec<Record>, EvalError> {
+        Ok(self.process_mut(rec)?.into_iter().collect())
+    }
     /// Return any remaining records that may have been gathered by the operator.  This method
     /// will be called when there are no more new input records.
     fn drain(self: Box<Self>) -> Box<dyn Iterator<Item = Record>> {
         Box::new(iter::empty())
     }
+    /// True once the operator won't pass any more records, so the rest of the input doesn't
+    /// need to be read.
+    fn is_done(&self) -> bool {
+        false
+    }
 }
 
 /// Trait used to instantiate an operator from its definition.  If an operator does not maintain
@@ -115,6 +156,7 @@ impl PreAggAdapter {
             state: Aggregate {
                 columns: Vec::new(),
                 data: Vec::new(),
+                formats: HashMap::new(),
             },
         }
     }
@@ -129,6 +171,10 @@ where
     fn process_mut(&mut self, rec: Record) -> Result<Option<Record>, EvalError> {
         self.process(rec)
     }
+
+    fn process_all(&mut self, rec: Record) -> Result<Vec<Record>, EvalError> {
+        UnaryPreAggFunction::process_all(self, rec)
+    }
 }
 
 impl AggregateOperator for PreAggAdapter {
@@ -149,7 +195,7 @@ impl AggregateOperator for PreAggAdapter {
                             data: vmap,
                             raw: "".to_string(),
                         })
-                        .flat_map(|rec| op.process_mut(rec).unwrap_or(None))
+                        .flat_map(|rec| op.process_all(rec).unwrap_or_default())
                         .map(|rec| rec.data);
                     records.collect()
                 };
@@ -178,6 +224,7 @@ impl AggregateOperator for PreAggAdapter {
                 self.state = Aggregate {
                     data: processed_records,
                     columns,
+                    formats: agg.formats,
                 };
             }
         }
@@ -187,12 +234,47 @@ impl AggregateOperator for PreAggAdapter {
 pub trait AggregateOperator: Send + Sync {
     fn emit(&self) -> data::Aggregate;
     fn process(&mut self, row: Row);
+
+    /// Warn once any aggregate function in this operator holds more than `limit` distinct values
+    /// for a single group
+    fn warn_distinct_over(&mut self, _limit: usize) {}
 }
 
 pub trait AggregateFunction: Send + Sync {
     fn process(&mut self, rec: &Data) -> Result<(), EvalError>;
     fn emit(&self) -> data::Value;
     fn empty_box(&self) -> Box<dyn AggregateFunction>;
+
+    /// Mark output that was cut off with `ellipsis`, the one the output is rendered with
+    fn set_ellipsis(&mut self, _ellipsis: &str) {}
+
+    /// What's appended to the function's name for each column it emits.  Most functions emit a
+    /// single column with no suffix.
+    fn column_suffixes(&self) -> Vec<String> {
+        vec![String::new()]
+    }
+
+    /// One value for each of the `column_suffixes`
+    fn emit_columns(&self) -> Vec<data::Value> {
+        vec![self.emit()]
+    }
+
+    /// The suggested display format for the emitted values, if the function has one
+    fn value_format(&self) -> Option<data::ValueFormat> {
+        None
+    }
+
+    /// How many distinct values the function is holding, for functions whose memory grows with
+    /// the number of distinct values they see
+    fn distinct_values(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether values that aren't numbers are reported with a warning when they're skipped,
+    /// rather than silently ignored
+    fn warns_on_non_numbers(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -207,6 +289,13 @@ pub enum Expr {
     NestedColumn { head: String, rest: Vec<ValueRef> },
     BoolUnary(UnaryExpr<BoolUnaryExpr>),
     Comparison(BinaryExpr<BoolExpr>),
+    Arithmetic(BinaryExpr<ArithmeticExpr>),
+    Logical(BinaryExpr<LogicalExpr>),
+    InCidr(InCidrExpr),
+    Glob(GlobExpr),
+    RegexMatch(RegexMatchExpr),
+    Fuzzy(FuzzyExpr),
+    FunctionCall(FunctionCall),
     Value(&'static data::Value),
 }
 
@@ -228,6 +317,85 @@ pub struct BinaryExpr<T> {
     pub right: Box<Expr>,
 }
 
+/// Tests whether the operand is an IP address inside any of the given networks.
+#[derive(Debug, Clone)]
+pub struct InCidrExpr {
+    pub operand: Box<Expr>,
+    pub networks: Vec<IpNetwork>,
+}
+
+/// Tests whether some part of the operand is within `max_distance` edits of the target.
+#[derive(Debug, Clone)]
+pub struct FuzzyExpr {
+    pub operand: Box<Expr>,
+    pub target: Vec<char>,
+    pub max_distance: usize,
+}
+
+/// Tests whether the operand matches a glob, which was compiled to a regex when the query was
+/// built.
+#[derive(Debug, Clone)]
+pub struct GlobExpr {
+    pub operand: Box<Expr>,
+    pub pattern: regex::Regex,
+}
+
+/// Tests whether the operand matches a regex anywhere, using its string rendering for values that
+/// aren't strings
+#[derive(Debug, Clone)]
+pub struct RegexMatchExpr {
+    pub operand: Box<Expr>,
+    pub regex: regex::Regex,
+}
+
+/// The built-in functions that can be called from an expression
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    /// The number of characters in the rendered value, or the number of elements in an array
+    Len,
+    /// Whether the value is a float that is NaN
+    IsNan,
+    /// Whether the value is a float that is positive or negative infinity
+    IsInf,
+    /// The number of seconds between a timestamp and now
+    Age,
+    /// A uniformly distributed float in [0, 1)
+    Rand,
+    /// The value an RFC 6901 JSON Pointer like `/a/b/0` refers to within an object or array
+    JsonGet,
+    /// An array of the parts of a value between a separator, like the `split` operator.  When
+    /// it's used as a `by` column, each part is counted as its own group.
+    Split,
+    /// The name of the kind of value: `int`, `float`, `string`, `bool`, `null`, `list` or
+    /// `object`
+    TypeOf,
+    /// The host of a URL, lowercased
+    UrlHost,
+    /// The path of a URL, without the query or fragment
+    UrlPath,
+    /// The scheme of a URL, like `https`
+    UrlScheme,
+    /// The port given in a URL, as an integer
+    UrlPort,
+}
+
+lazy_static! {
+    /// The generator behind `rand()`.  It's shared by every call so that a seeded run produces
+    /// the same values for the same input.
+    static ref RANDOM: Mutex<SmallRng> = Mutex::new(SmallRng::from_entropy());
+}
+
+/// Seed the generator behind `rand()` so that sampling with it is repeatable
+pub fn seed_random(seed: u64) {
+    *RANDOM.lock().unwrap() = SmallRng::seed_from_u64(seed);
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionCall {
+    pub function: Function,
+    pub args: Vec<Expr>,
+}
+
 #[derive(Clone, Debug)]
 pub enum BoolExpr {
     Eq,
@@ -238,6 +406,78 @@ pub enum BoolExpr {
     Lte,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum ArithmeticExpr {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LogicalExpr {
+    And,
+    Or,
+}
+
+/// Whether a condition combined by `and` or `or` holds.  Missing fields and None are false rather
+/// than errors, so `a == 1 or b` doesn't need every record to have `b`.
+fn condition_holds(expr: &Expr, record: &Data) -> Result<bool, EvalError> {
+    match expr.eval_borrowed(record) {
+        Ok(value) => match *value {
+            data::Value::Bool(holds) => Ok(holds),
+            data::Value::None => Ok(false),
+            ref other => Err(EvalError::ExpectedBoolean {
+                found: other.to_string(),
+            }),
+        },
+        Err(EvalError::NoValueForKey { .. }) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The value as a number, or an error for anything else
+fn as_number(value: &data::Value) -> Result<f64, EvalError> {
+    match value {
+        data::Value::Int(i) => Ok(*i as f64),
+        data::Value::Float(f) => Ok(f.into_inner()),
+        data::Value::Bool(b) => Err(EvalError::BooleanNotNumber { value: *b }),
+        other => Err(EvalError::ExpectedNumber {
+            found: format!("{}", other),
+        }),
+    }
+}
+
+impl Evaluatable<data::Value> for BinaryExpr<ArithmeticExpr> {
+    /// Integers stay integers while the result is exact and fits, otherwise the result is a
+    /// float.  Dividing by zero is None.
+    fn eval(&self, record: &HashMap<String, data::Value>) -> Result<data::Value, EvalError> {
+        let l: Cow<data::Value> = self.left.eval_borrowed(record)?;
+        let r: Cow<data::Value> = self.right.eval_borrowed(record)?;
+        if let (data::Value::Int(l), data::Value::Int(r)) = (l.as_ref(), r.as_ref()) {
+            let exact = match self.operator {
+                ArithmeticExpr::Add => l.checked_add(*r),
+                ArithmeticExpr::Subtract => l.checked_sub(*r),
+                ArithmeticExpr::Multiply => l.checked_mul(*r),
+                ArithmeticExpr::Divide if *r == 0 => return Ok(data::Value::None),
+                ArithmeticExpr::Divide => l.checked_rem(*r).filter(|rem| *rem == 0).map(|_| l / r),
+            };
+            if let Some(exact) = exact {
+                return Ok(data::Value::Int(exact));
+            }
+        }
+        let (l, r) = (as_number(&l)?, as_number(&r)?);
+        let result = match self.operator {
+            ArithmeticExpr::Add => l + r,
+            ArithmeticExpr::Subtract => l - r,
+            ArithmeticExpr::Multiply => l * r,
+            ArithmeticExpr::Divide if r == 0.0 => return Ok(data::Value::None),
+            ArithmeticExpr::Divide => l / r,
+        };
+        Ok(data::Value::from_float(result))
+    }
+}
+
 impl<T: Copy + Send + Sync> Evaluatable<T> for T {
     fn eval(&self, _record: &HashMap<String, data::Value>) -> Result<T, EvalError> {
         Ok(*self)
@@ -246,25 +486,103 @@ impl<T: Copy + Send + Sync> Evaluatable<T> for T {
 
 impl Evaluatable<bool> for BinaryExpr<BoolExpr> {
     fn eval(&self, record: &HashMap<String, data::Value>) -> Result<bool, EvalError> {
-        let l: &data::Value = self.left.eval_borrowed(record)?;
-        let r: &data::Value = self.right.eval_borrowed(record)?;
+        let l: Cow<data::Value> = self.left.eval_borrowed(record)?;
+        let r: Cow<data::Value> = self.right.eval_borrowed(record)?;
+        // There's no sensible ordering between None and a value, so those comparisons are false
+        let is_none = *l == data::Value::None || *r == data::Value::None;
         let result = match self.operator {
             BoolExpr::Eq => l == r,
             BoolExpr::Neq => l != r,
-            BoolExpr::Gt => l > r,
-            BoolExpr::Lt => l < r,
-            BoolExpr::Gte => l >= r,
-            BoolExpr::Lte => l <= r,
+            BoolExpr::Gt => !is_none && l > r,
+            BoolExpr::Lt => !is_none && l < r,
+            BoolExpr::Gte => !is_none && l >= r,
+            BoolExpr::Lte => !is_none && l <= r,
         };
         Ok(result)
     }
 }
 
+impl Evaluatable<bool> for BinaryExpr<LogicalExpr> {
+    /// The right side is only evaluated if the left side doesn't decide the result
+    fn eval(&self, record: &HashMap<String, data::Value>) -> Result<bool, EvalError> {
+        let left = condition_holds(&self.left, record)?;
+        match self.operator {
+            LogicalExpr::And if !left => Ok(false),
+            LogicalExpr::Or if left => Ok(true),
+            _ => condition_holds(&self.right, record),
+        }
+    }
+}
+
+impl Evaluatable<bool> for InCidrExpr {
+    fn eval(&self, record: &HashMap<String, data::Value>) -> Result<bool, EvalError> {
+        // Missing fields and non-IP values are never inside a network, so they aren't errors.
+        let value: Result<Cow<data::Value>, EvalError> = self.operand.eval_borrowed(record);
+        let ip = match value.as_ref().map(|v| v.as_ref()) {
+            Ok(data::Value::Str(s)) => s.trim().parse::<IpAddr>().ok(),
+            _ => None,
+        };
+        Ok(ip
+            .map(|ip| self.networks.iter().any(|net| net.contains(&ip)))
+            .unwrap_or(false))
+    }
+}
+
+impl Evaluatable<bool> for FuzzyExpr {
+    fn eval(&self, record: &HashMap<String, data::Value>) -> Result<bool, EvalError> {
+        // Like in_cidr, missing fields never match rather than being errors.
+        let value = match self.operand.eval_borrowed(record) {
+            Ok(value) => value,
+            Err(EvalError::NoValueForKey { .. }) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let text = match *value {
+            data::Value::None => return Ok(false),
+            data::Value::Str(ref s) => Cow::Borrowed(s.as_str()),
+            ref other => Cow::Owned(other.render(&RenderConfig::default())),
+        };
+        Ok(fuzzy::substring_distance(&self.target, &text) <= self.max_distance)
+    }
+}
+
+impl Evaluatable<bool> for GlobExpr {
+    fn eval(&self, record: &HashMap<String, data::Value>) -> Result<bool, EvalError> {
+        let value = match self.operand.eval_borrowed(record) {
+            Ok(value) => value,
+            Err(EvalError::NoValueForKey { .. }) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        match *value {
+            data::Value::None => Ok(false),
+            data::Value::Str(ref s) => Ok(self.pattern.is_match(s)),
+            ref other => Ok(self
+                .pattern
+                .is_match(&other.render(&RenderConfig::default()))),
+        }
+    }
+}
+
+impl Evaluatable<bool> for RegexMatchExpr {
+    fn eval(&self, record: &HashMap<String, data::Value>) -> Result<bool, EvalError> {
+        // Like glob, missing fields never match
+        let value = match self.operand.eval_borrowed(record) {
+            Ok(value) => value,
+            Err(EvalError::NoValueForKey { .. }) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        match *value {
+            data::Value::None => Ok(false),
+            data::Value::Str(ref s) => Ok(self.regex.is_match(s)),
+            ref other => Ok(self.regex.is_match(&other.render(&RenderConfig::default()))),
+        }
+    }
+}
+
 impl Evaluatable<bool> for UnaryExpr<BoolUnaryExpr> {
     fn eval(&self, record: &HashMap<String, data::Value>) -> Result<bool, EvalError> {
-        let bool_res: &data::Value = self.operand.eval_borrowed(record)?;
+        let bool_res: Cow<data::Value> = self.operand.eval_borrowed(record)?;
 
-        match bool_res {
+        match *bool_res {
             data::Value::Bool(true) => Ok(false),
             data::Value::Bool(false) => Ok(true),
             _ => Err(EvalError::ExpectedBoolean {
@@ -276,20 +594,211 @@ impl Evaluatable<bool> for UnaryExpr<BoolUnaryExpr> {
 
 impl Evaluatable<bool> for Expr {
     fn eval(&self, record: &HashMap<String, data::Value>) -> Result<bool, EvalError> {
-        match self.eval_borrowed(record)? {
-            data::Value::Bool(bool_value) => Ok(*bool_value),
-            other => Err(EvalError::ExpectedBoolean {
+        match *self.eval_borrowed(record)? {
+            data::Value::Bool(bool_value) => Ok(bool_value),
+            ref other => Err(EvalError::ExpectedBoolean {
                 found: other.to_string(),
             }),
         }
     }
 }
 
+impl FunctionCall {
+    fn eval(&self, record: &Data) -> Result<data::Value, EvalError> {
+        match self.function {
+            Function::Len => {
+                // A missing field has no length, rather than being an error
+                let value = match self.args[0].eval_borrowed(record) {
+                    Ok(value) => value,
+                    Err(EvalError::NoValueForKey { .. }) => return Ok(data::Value::None),
+                    Err(e) => return Err(e),
+                };
+                let len = match *value {
+                    data::Value::None => return Ok(data::Value::None),
+                    data::Value::Array(ref vec) => vec.len(),
+                    ref other => other.render(&RenderConfig::default()).chars().count(),
+                };
+                Ok(data::Value::Int(len as i64))
+            }
+            Function::IsNan | Function::IsInf => {
+                // Only floats can be NaN or infinite, anything else (including a missing field)
+                // is false
+                let value = match self.args[0].eval_borrowed(record) {
+                    Ok(value) => value,
+                    Err(EvalError::NoValueForKey { .. }) => return Ok(data::Value::Bool(false)),
+                    Err(e) => return Err(e),
+                };
+                let matches = match *value {
+                    data::Value::Float(OrderedFloat(f)) if self.function == Function::IsNan => {
+                        f.is_nan()
+                    }
+                    data::Value::Float(OrderedFloat(f)) => f.is_infinite(),
+                    _ => false,
+                };
+                Ok(data::Value::Bool(matches))
+            }
+            Function::Age => {
+                // Anything that isn't a timestamp has no age, so comparisons with it are false
+                let value = match self.args[0].eval_borrowed(record) {
+                    Ok(value) => value,
+                    Err(EvalError::NoValueForKey { .. }) => return Ok(data::Value::None),
+                    Err(e) => return Err(e),
+                };
+                Ok(timestamp_secs(&value)
+                    .map(|secs| data::Value::from_float(unix_now() - secs))
+                    .unwrap_or(data::Value::None))
+            }
+            Function::Rand => {
+                let sample: f64 = RANDOM.lock().unwrap().gen();
+                Ok(data::Value::Float(OrderedFloat(sample)))
+            }
+            Function::JsonGet => {
+                // Anything the pointer can't reach is None, rather than an error
+                let value = match self.args[0].eval_borrowed(record) {
+                    Ok(value) => value,
+                    Err(EvalError::NoValueForKey { .. }) => return Ok(data::Value::None),
+                    Err(e) => return Err(e),
+                };
+                let pointer = self.args[1].eval_borrowed(record)?;
+                let found = match *pointer {
+                    data::Value::Str(ref pointer) => json_pointer(&value, pointer),
+                    _ => None,
+                };
+                Ok(found.cloned().unwrap_or(data::Value::None))
+            }
+            Function::Split => {
+                let value = match self.args[0].eval_borrowed(record) {
+                    Ok(value) => value,
+                    Err(EvalError::NoValueForKey { .. }) => return Ok(data::Value::None),
+                    Err(e) => return Err(e),
+                };
+                let separator = self.args[1].eval_borrowed(record)?;
+                let separator = match *separator {
+                    data::Value::Str(ref separator) => separator,
+                    _ => return Ok(data::Value::None),
+                };
+                let inp = match *value {
+                    data::Value::None => return Ok(data::Value::None),
+                    data::Value::Str(ref s) => Cow::Borrowed(s.as_str()),
+                    ref other => Cow::Owned(other.render(&RenderConfig::default())),
+                };
+                let parts =
+                    split::split_with_delimiters(&inp, separator, &split::DEFAULT_DELIMITERS)
+                        .into_iter()
+                        .map(data::Value::from_string)
+                        .collect();
+                Ok(data::Value::Array(parts))
+            }
+            Function::TypeOf => {
+                // A missing field is reported the same way as one that's set to None
+                let value = match self.args[0].eval_borrowed(record) {
+                    Ok(value) => value,
+                    Err(EvalError::NoValueForKey { .. }) => Cow::Borrowed(data::NONE),
+                    Err(e) => return Err(e),
+                };
+                let name = match *value {
+                    data::Value::Int(_) => "int",
+                    data::Value::Float(_) => "float",
+                    data::Value::Str(_) => "string",
+                    data::Value::Bool(_) => "bool",
+                    data::Value::None => "null",
+                    data::Value::Array(_) => "list",
+                    data::Value::Obj(_) => "object",
+                };
+                Ok(data::Value::Str(name.to_string()))
+            }
+            Function::UrlHost | Function::UrlPath | Function::UrlScheme | Function::UrlPort => {
+                // A value that isn't a URL, or a URL without the part, gives None
+                let value = match self.args[0].eval_borrowed(record) {
+                    Ok(value) => value,
+                    Err(EvalError::NoValueForKey { .. }) => return Ok(data::Value::None),
+                    Err(e) => return Err(e),
+                };
+                let parts = match *value {
+                    data::Value::Str(ref s) => url::parse_url(s),
+                    _ => None,
+                };
+                let parts = match parts {
+                    Some(parts) => parts,
+                    None => return Ok(data::Value::None),
+                };
+                let part = match self.function {
+                    Function::UrlHost => parts.host.map(data::Value::Str),
+                    Function::UrlPath => Some(data::Value::Str(parts.path.to_string())),
+                    Function::UrlScheme => parts.scheme.map(data::Value::Str),
+                    _ => parts.port.map(|port| data::Value::Int(i64::from(port))),
+                };
+                Ok(part.unwrap_or(data::Value::None))
+            }
+        }
+    }
+}
+
+/// Follow an RFC 6901 JSON Pointer into a value.  The empty pointer is the value itself and
+/// each `/`-separated token is a key of an object or an index of an array, with `~1` standing
+/// for `/` and `~0` for `~` in keys.
+fn json_pointer<'a>(value: &'a data::Value, pointer: &str) -> Option<&'a data::Value> {
+    if pointer.is_empty() {
+        return Some(value);
+    }
+    if !pointer.starts_with('/') {
+        return None;
+    }
+    pointer[1..].split('/').try_fold(value, |value, token| {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        match value {
+            data::Value::Obj(ref map) => map.get(&token),
+            data::Value::Array(ref items) => {
+                // Indexes are plain decimal numbers without leading zeros
+                let is_index = token.chars().all(|c| c.is_ascii_digit())
+                    && !token.is_empty()
+                    && (token == "0" || !token.starts_with('0'));
+                if is_index {
+                    token.parse::<usize>().ok().and_then(|i| items.get(i))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Seconds since the Unix epoch for an RFC 3339 timestamp like `2019-04-01T12:30:00Z`, or a
+/// number of seconds since the epoch
+fn timestamp_secs(value: &data::Value) -> Option<f64> {
+    match value {
+        data::Value::Int(i) => Some(*i as f64),
+        data::Value::Float(f) => Some(f.into_inner()),
+        data::Value::Str(s) => humantime::parse_rfc3339_weak(s.trim())
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_secs_f64()),
+        _ => None,
+    }
+}
+
+/// Timestamps from `10000-01-01T00:00:00Z` on can't be written in RFC 3339
+const END_OF_RFC3339_SECS: f64 = 253_402_300_800.0;
+
+/// Seconds since the epoch for a timestamp that can be written back out in RFC 3339.  A number
+/// that's too large, like a timestamp in milliseconds, isn't taken as a date in the far future.
+fn writable_timestamp_secs(value: &data::Value) -> Option<f64> {
+    timestamp_secs(value).filter(|secs| *secs >= 0.0 && *secs < END_OF_RFC3339_SECS)
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
 impl EvaluatableBorrowed<data::Value> for Expr {
     fn eval_borrowed<'a>(
         &self,
         record: &'a HashMap<String, data::Value>,
-    ) -> Result<&'a data::Value, EvalError> {
+    ) -> Result<Cow<'a, data::Value>, EvalError> {
         match *self {
             Expr::NestedColumn { ref head, ref rest } => {
                 let mut root_record: &data::Value = record
@@ -327,7 +836,7 @@ impl EvaluatableBorrowed<data::Value> for Expr {
                         }
                     }
                 }
-                Ok(root_record)
+                Ok(Cow::Borrowed(root_record))
             }
             Expr::BoolUnary(
                 ref unary_op @ UnaryExpr {
@@ -336,38 +845,57 @@ impl EvaluatableBorrowed<data::Value> for Expr {
                 },
             ) => {
                 let bool_res = unary_op.eval(record)?;
-                Ok(data::Value::from_bool(bool_res))
+                Ok(Cow::Borrowed(data::Value::from_bool(bool_res)))
             }
             Expr::Comparison(ref binary_expr) => {
                 let bool_res = binary_expr.eval(record)?;
-                Ok(data::Value::from_bool(bool_res))
+                Ok(Cow::Borrowed(data::Value::from_bool(bool_res)))
+            }
+            Expr::Arithmetic(ref binary_expr) => Ok(Cow::Owned(binary_expr.eval(record)?)),
+            Expr::Logical(ref binary_expr) => {
+                let bool_res = binary_expr.eval(record)?;
+                Ok(Cow::Borrowed(data::Value::from_bool(bool_res)))
+            }
+            Expr::InCidr(ref in_cidr) => {
+                let bool_res = in_cidr.eval(record)?;
+                Ok(Cow::Borrowed(data::Value::from_bool(bool_res)))
+            }
+            Expr::Fuzzy(ref fuzzy) => {
+                let bool_res = fuzzy.eval(record)?;
+                Ok(Cow::Borrowed(data::Value::from_bool(bool_res)))
+            }
+            Expr::Glob(ref glob) => {
+                let bool_res = glob.eval(record)?;
+                Ok(Cow::Borrowed(data::Value::from_bool(bool_res)))
+            }
+            Expr::RegexMatch(ref regex_match) => {
+                let bool_res = regex_match.eval(record)?;
+                Ok(Cow::Borrowed(data::Value::from_bool(bool_res)))
             }
-            Expr::Value(ref v) => Ok(v),
+            Expr::FunctionCall(ref call) => Ok(Cow::Owned(call.eval(record)?)),
+            Expr::Value(v) => Ok(Cow::Borrowed(v)),
         }
     }
 }
 
 impl Evaluatable<f64> for Expr {
     fn eval(&self, record: &HashMap<String, data::Value>) -> Result<f64, EvalError> {
-        let value: &data::Value = self.eval_borrowed(record)?;
-        match value {
-            data::Value::Int(i) => Ok(*i as f64),
-            data::Value::Float(f) => Ok(f.into_inner()),
-            other => Err(EvalError::ExpectedNumber {
-                found: format!("{}", other),
-            }),
-        }
+        let value: Cow<data::Value> = self.eval_borrowed(record)?;
+        as_number(&value)
     }
 }
 
-impl EvaluatableBorrowed<String> for Expr {
-    fn eval_borrowed<'a>(&self, record: &'a Data) -> Result<&'a String, EvalError> {
-        let as_value: &data::Value = self.eval_borrowed(record)?;
+impl EvaluatableBorrowed<str> for Expr {
+    fn eval_borrowed<'a>(&self, record: &'a Data) -> Result<Cow<'a, str>, EvalError> {
+        let as_value: Cow<'a, data::Value> = self.eval_borrowed(record)?;
         match as_value {
-            data::Value::None => Err(EvalError::UnexpectedNone {
-                tpe: "String".to_string(),
-            }),
-            data::Value::Str(ref s) => Ok(s),
+            Cow::Borrowed(data::Value::None) | Cow::Owned(data::Value::None) => {
+                Err(EvalError::UnexpectedNone {
+                    tpe: "String".to_string(),
+                })
+            }
+            Cow::Borrowed(data::Value::Str(ref s)) => Ok(Cow::Borrowed(s)),
+            Cow::Owned(data::Value::Str(s)) => Ok(Cow::Owned(s)),
             _ => Err(EvalError::ExpectedString {
                 found: "other".to_string(),
             }),
@@ -398,6 +926,10 @@ impl AggregateFunction for Count {
     fn empty_box(&self) -> Box<dyn AggregateFunction> {
         Box::new(Count::new())
     }
+
+    fn value_format(&self) -> Option<data::ValueFormat> {
+        Some(data::ValueFormat::Integer)
+    }
 }
 
 pub struct Sum {
@@ -430,507 +962,2120 @@ impl AggregateFunction for Sum {
     }
 }
 
-pub struct CountDistinct {
-    state: HashSet<data::Value>,
+/// The most points a sparkline keeps before pairs of them are averaged together
+const MAX_SPARKLINE_POINTS: usize = 32;
+
+/// The trend of a column in the order its values arrived, rendered as a sparkline.  To keep
+/// the memory and the width of the cell fixed, once there are too many points each pair of
+/// neighbours is replaced by their mean, so every point stands for twice as many values.
+pub struct Sparkline {
     column: Expr,
+    points: Vec<f64>,
+    /// How many values each point is the mean of
+    span: usize,
+    /// The sum and count of values that haven't yet filled a point
+    pending: (f64, usize),
 }
 
-impl CountDistinct {
+impl Sparkline {
     pub fn empty<T: Into<Expr>>(column: T) -> Self {
-        CountDistinct {
-            state: HashSet::new(),
+        Sparkline {
             column: column.into(),
+            points: Vec::new(),
+            span: 1,
+            pending: (0.0, 0),
         }
     }
 }
 
-impl AggregateFunction for CountDistinct {
+impl AggregateFunction for Sparkline {
     fn process(&mut self, rec: &Data) -> Result<(), EvalError> {
-        let value: &data::Value = self.column.eval_borrowed(rec)?;
-        self.state.insert(value.clone());
+        let value: f64 = self.column.eval(rec)?;
+        self.pending = (self.pending.0 + value, self.pending.1 + 1);
+        if self.pending.1 == self.span {
+            self.points.push(self.pending.0 / self.span as f64);
+            self.pending = (0.0, 0);
+        }
+        if self.points.len() == MAX_SPARKLINE_POINTS {
+            self.points = self
+                .points
+                .chunks(2)
+                .map(|pair| pair.iter().sum::<f64>() / pair.len() as f64)
+                .collect();
+            self.span *= 2;
+        }
         Ok(())
     }
 
     fn emit(&self) -> data::Value {
-        data::Value::Int(self.state.len() as i64)
+        let mut points: Vec<data::Value> = self
+            .points
+            .iter()
+            .cloned()
+            .map(data::Value::from_float)
+            .collect();
+        if self.pending.1 > 0 {
+            points.push(data::Value::from_float(
+                self.pending.0 / self.pending.1 as f64,
+            ));
+        }
+        data::Value::Array(points)
     }
 
     fn empty_box(&self) -> Box<dyn AggregateFunction> {
-        Box::new(CountDistinct::empty(self.column.clone()))
+        Box::new(Sparkline::empty(self.column.clone()))
+    }
+
+    fn value_format(&self) -> Option<data::ValueFormat> {
+        Some(data::ValueFormat::Sparkline)
     }
 }
 
-pub struct Min {
-    min: f64,
+/// How many distinct values one group's `count_distinct` can hold before a warning is printed
+pub const DEFAULT_DISTINCT_WARNING: usize = 1_000_000;
+
+pub struct CountDistinct {
+    state: HashSet<data::Value>,
     column: Expr,
 }
 
-impl Min {
-    pub fn empty<T: Into<Expr>>(column: T) -> Min {
-        Min {
-            min: std::f64::INFINITY,
+impl CountDistinct {
+    pub fn empty<T: Into<Expr>>(column: T) -> Self {
+        CountDistinct {
+            state: HashSet::new(),
             column: column.into(),
         }
     }
 }
 
-impl AggregateFunction for Min {
-    fn process(&mut self, data: &Data) -> Result<(), EvalError> {
-        let value: f64 = self.column.eval(data)?;
-        if value < self.min {
-            self.min = value;
-        }
+impl AggregateFunction for CountDistinct {
+    fn process(&mut self, rec: &Data) -> Result<(), EvalError> {
+        let value: Cow<data::Value> = self.column.eval_borrowed(rec)?;
+        self.state.insert(value.into_owned());
         Ok(())
     }
 
     fn emit(&self) -> data::Value {
-        if self.min.is_finite() {
-            data::Value::from_float(self.min)
-        } else {
-            data::Value::None
-        }
+        data::Value::Int(self.state.len() as i64)
     }
 
     fn empty_box(&self) -> Box<dyn AggregateFunction> {
-        Box::new(Min::empty(self.column.clone()))
+        Box::new(CountDistinct::empty(self.column.clone()))
+    }
+
+    fn value_format(&self) -> Option<data::ValueFormat> {
+        Some(data::ValueFormat::Integer)
+    }
+
+    fn distinct_values(&self) -> Option<usize> {
+        Some(self.state.len())
     }
 }
 
-pub struct Average {
-    total: f64,
-    count: i64,
+/// The most frequent value of a column.  When several values are equally frequent, the one that
+/// was seen first wins, so the result doesn't depend on hashing order.
+pub struct Mode {
+    /// Each value's count along with the order it was first seen
+    counts: HashMap<data::Value, (u64, usize)>,
     column: Expr,
 }
 
-impl Average {
-    pub fn empty<T: Into<Expr>>(column: T) -> Average {
-        Average {
-            total: 0.0,
-            count: 0,
+impl Mode {
+    pub fn empty<T: Into<Expr>>(column: T) -> Self {
+        Mode {
+            counts: HashMap::new(),
             column: column.into(),
         }
     }
 }
 
-impl AggregateFunction for Average {
-    fn process(&mut self, data: &Data) -> Result<(), EvalError> {
-        let value: f64 = self.column.eval(data)?;
-        self.total += value;
-        self.count += 1;
+impl AggregateFunction for Mode {
+    fn process(&mut self, rec: &Data) -> Result<(), EvalError> {
+        let value: Cow<data::Value> = self.column.eval_borrowed(rec)?;
+        let seen = self.counts.len();
+        self.counts.entry(value.into_owned()).or_insert((0, seen)).0 += 1;
         Ok(())
     }
 
     fn emit(&self) -> data::Value {
-        data::Value::from_float(self.total / self.count as f64)
+        self.counts
+            .iter()
+            .max_by(|(_, (l_count, l_seen)), (_, (r_count, r_seen))| {
+                l_count.cmp(r_count).then(r_seen.cmp(l_seen))
+            })
+            .map(|(value, _)| value.clone())
+            .unwrap_or(data::Value::None)
     }
 
     fn empty_box(&self) -> Box<dyn AggregateFunction> {
-        Box::new(Average::empty(self.column.clone()))
+        Box::new(Mode::empty(self.column.clone()))
     }
 }
 
-pub struct Max {
-    max: f64,
+/// The first value of a column, in input order.  Later records are ignored once a value is seen.
+pub struct First {
+    value: Option<data::Value>,
     column: Expr,
 }
 
-impl Max {
-    pub fn empty<T: Into<Expr>>(column: T) -> Max {
-        Max {
-            max: std::f64::NEG_INFINITY,
+impl First {
+    pub fn empty<T: Into<Expr>>(column: T) -> Self {
+        First {
+            value: None,
             column: column.into(),
         }
     }
 }
 
-impl AggregateFunction for Max {
-    fn process(&mut self, data: &Data) -> Result<(), EvalError> {
-        let value: f64 = self.column.eval(data)?;
-        if value > self.max {
-            self.max = value;
+impl AggregateFunction for First {
+    fn process(&mut self, rec: &Data) -> Result<(), EvalError> {
+        if self.value.is_none() {
+            let value: Cow<data::Value> = self.column.eval_borrowed(rec)?;
+            self.value = Some(value.into_owned());
         }
         Ok(())
     }
 
     fn emit(&self) -> data::Value {
-        if self.max.is_finite() {
-            data::Value::from_float(self.max)
-        } else {
-            data::Value::None
-        }
+        self.value.clone().unwrap_or(data::Value::None)
     }
 
     fn empty_box(&self) -> Box<dyn AggregateFunction> {
-        Box::new(Max::empty(self.column.clone()))
+        Box::new(First::empty(self.column.clone()))
     }
 }
 
-pub struct Percentile {
-    ckms: CKMS<f64>,
+/// The last value of a column, in input order.  Every record with the column replaces the value.
+pub struct Last {
+    value: Option<data::Value>,
     column: Expr,
-    percentile: f64,
 }
 
-impl Percentile {
-    pub fn empty<T: Into<Expr>>(column: T, percentile: f64) -> Self {
-        if percentile >= 1.0 {
-            panic!("Percentiles must be < 1");
-        }
-
-        Percentile {
-            ckms: CKMS::<f64>::new(0.001),
+impl Last {
+    pub fn empty<T: Into<Expr>>(column: T) -> Self {
+        Last {
+            value: None,
             column: column.into(),
-            percentile,
         }
     }
 }
 
-impl AggregateFunction for Percentile {
-    fn process(&mut self, data: &Data) -> Result<(), EvalError> {
-        let value: f64 = self.column.eval(data)?;
-        self.ckms.insert(value);
+impl AggregateFunction for Last {
+    fn process(&mut self, rec: &Data) -> Result<(), EvalError> {
+        let value: Cow<data::Value> = self.column.eval_borrowed(rec)?;
+        self.value = Some(value.into_owned());
         Ok(())
     }
 
     fn emit(&self) -> data::Value {
-        let pct_opt = self.ckms.query(self.percentile);
-        pct_opt
-            .map(|(_usize, pct_float)| data::Value::from_float(pct_float))
-            .unwrap_or(data::Value::None)
+        self.value.clone().unwrap_or(data::Value::None)
     }
 
     fn empty_box(&self) -> Box<dyn AggregateFunction> {
-        Box::new(Percentile::empty(self.column.clone(), self.percentile))
+        Box::new(Last::empty(self.column.clone()))
     }
 }
 
-#[derive(PartialEq, Eq)]
-pub enum SortDirection {
-    Ascending,
-    Descending,
+/// The number of records matching `numerator` divided by the number matching `denominator`.
+/// The conditions are counted independently, so `ratio(status >= 500, status > 0)` is the
+/// fraction of records with a status that were errors.  With no denominator matches the ratio
+/// is None.
+pub struct Ratio {
+    numerator: Expr,
+    denominator: Expr,
+    numerator_count: i64,
+    denominator_count: i64,
 }
 
-pub struct Sorter {
+impl Ratio {
+    pub fn empty(numerator: Expr, denominator: Expr) -> Self {
+        Ratio {
+            numerator,
+            denominator,
+            numerator_count: 0,
+            denominator_count: 0,
+        }
+    }
+}
+
+impl AggregateFunction for Ratio {
+    fn process(&mut self, rec: &Data) -> Result<(), EvalError> {
+        let numerator: bool = self.numerator.eval(rec)?;
+        let denominator: bool = self.denominator.eval(rec)?;
+        if numerator {
+            self.numerator_count += 1;
+        }
+        if denominator {
+            self.denominator_count += 1;
+        }
+        Ok(())
+    }
+
+    fn emit(&self) -> data::Value {
+        if self.denominator_count == 0 {
+            data::Value::None
+        } else {
+            data::Value::Float(OrderedFloat(
+                self.numerator_count as f64 / self.denominator_count as f64,
+            ))
+        }
+    }
+
+    fn empty_box(&self) -> Box<dyn AggregateFunction> {
+        Box::new(Ratio::empty(
+            self.numerator.clone(),
+            self.denominator.clone(),
+        ))
+    }
+
+    fn value_format(&self) -> Option<data::ValueFormat> {
+        Some(data::ValueFormat::Float)
+    }
+}
+
+/// The value of `ret` in the row with the largest (or smallest) `value`, in the same order as
+/// `Max` and `Min`.  On a tie, the first row seen wins.
+pub struct ArgMinMax {
+    value: Expr,
+    ret: Expr,
+    max: bool,
+    best: Option<(data::Value, data::Value)>,
+}
+
+impl ArgMinMax {
+    pub fn empty(value: Expr, ret: Expr, max: bool) -> Self {
+        ArgMinMax {
+            value,
+            ret,
+            max,
+            best: None,
+        }
+    }
+}
+
+impl AggregateFunction for ArgMinMax {
+    fn process(&mut self, rec: &Data) -> Result<(), EvalError> {
+        let value: Cow<data::Value> = self.value.eval_borrowed(rec)?;
+        if !has_extreme(&value) {
+            return Ok(());
+        }
+        let wanted = if self.max {
+            std::cmp::Ordering::Greater
+        } else {
+            std::cmp::Ordering::Less
+        };
+        let better = match self.best {
+            None => true,
+            Some((ref best, _)) => further_extreme(&value, best, wanted),
+        };
+        if better {
+            // A row without the field to return still counts as the extreme
+            let ret: Result<Cow<data::Value>, EvalError> = self.ret.eval_borrowed(rec);
+            let ret = match ret {
+                Ok(ret) => ret.into_owned(),
+                Err(EvalError::NoValueForKey { .. }) => data::Value::None,
+                Err(e) => return Err(e),
+            };
+            self.best = Some((value.into_owned(), ret));
+        }
+        Ok(())
+    }
+
+    fn emit(&self) -> data::Value {
+        self.best
+            .as_ref()
+            .map(|(_, ret)| ret.clone())
+            .unwrap_or(data::Value::None)
+    }
+
+    fn empty_box(&self) -> Box<dyn AggregateFunction> {
+        Box::new(ArgMinMax::empty(
+            self.value.clone(),
+            self.ret.clone(),
+            self.max,
+        ))
+    }
+}
+
+/// Joins the rendered values of a column into a single string.  At most `max_items` values are
+/// kept and the joined string is cut off after `max_length` characters; an ellipsis marks any
+/// output that was dropped.
+pub struct Concat {
+    column: Expr,
+    separator: String,
+    distinct: bool,
+    max_items: usize,
+    max_length: usize,
+    ellipsis: String,
+    items: Vec<String>,
+    truncated: bool,
+}
+
+impl Concat {
+    pub fn empty<T: Into<Expr>>(
+        column: T,
+        separator: String,
+        distinct: bool,
+        max_items: usize,
+        max_length: usize,
+    ) -> Self {
+        Concat {
+            column: column.into(),
+            separator,
+            distinct,
+            max_items,
+            max_length,
+            ellipsis: ELLIPSIS.to_string(),
+            items: Vec::new(),
+            truncated: false,
+        }
+    }
+}
+
+impl AggregateFunction for Concat {
+    fn process(&mut self, rec: &Data) -> Result<(), EvalError> {
+        let value: Cow<data::Value> = self.column.eval_borrowed(rec)?;
+        if *value == data::Value::None {
+            return Ok(());
+        }
+        let item = value.render(&RenderConfig::default());
+        if self.distinct && self.items.contains(&item) {
+            return Ok(());
+        }
+        if self.items.len() < self.max_items {
+            self.items.push(item);
+        } else {
+            self.truncated = true;
+        }
+        Ok(())
+    }
+
+    fn emit(&self) -> data::Value {
+        let mut joined = self.items.join(&self.separator);
+        if self.truncated {
+            joined = joined + &self.separator + &self.ellipsis;
+        }
+        if joined.chars().count() > self.max_length {
+            joined = joined.chars().take(self.max_length).collect::<String>() + &self.ellipsis;
+        }
+        data::Value::Str(joined)
+    }
+
+    fn empty_box(&self) -> Box<dyn AggregateFunction> {
+        let mut concat = Concat::empty(
+            self.column.clone(),
+            self.separator.clone(),
+            self.distinct,
+            self.max_items,
+            self.max_length,
+        );
+        concat.ellipsis = self.ellipsis.clone();
+        Box::new(concat)
+    }
+
+    fn set_ellipsis(&mut self, ellipsis: &str) {
+        self.ellipsis = ellipsis.to_string();
+    }
+}
+
+/// Set once a group given to min, max, argmin or argmax has mixed numbers and strings, so the
+/// warning about it is only printed once
+static WARNED_MIXED_EXTREMES: AtomicBool = AtomicBool::new(false);
+
+/// Whether `value` can be a min or max: a number other than NaN, or a string.  Other values,
+/// like `None`, are skipped.
+fn has_extreme(value: &data::Value) -> bool {
+    match value {
+        data::Value::Float(f) => !f.is_nan(),
+        data::Value::Int(_) | data::Value::Str(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether `value` is further than `current` in the direction of `wanted`, for min, max, argmin
+/// and argmax.  Numbers are compared as numbers and strings alphabetically, but a number always
+/// wins over a string, so strings only count in a group without any numbers.
+fn further_extreme(value: &data::Value, current: &data::Value, wanted: std::cmp::Ordering) -> bool {
+    let is_number = |v: &data::Value| matches!(v, data::Value::Int(_) | data::Value::Float(_));
+    match (is_number(value), is_number(current)) {
+        (true, false) | (false, true) => {
+            let string = if is_number(value) { current } else { value };
+            if !WARNED_MIXED_EXTREMES.swap(true, AtomicOrdering::Relaxed) {
+                eprintln!(
+                    "warning: `{}` isn't a number like the rest of its group, so min and max left it out",
+                    string
+                );
+            }
+            is_number(value)
+        }
+        _ => value.cmp(current) == wanted,
+    }
+}
+
+/// Replace `extreme` with `value` if `value` is further in the direction of `wanted`
+fn keep_extreme(
+    extreme: &mut Option<data::Value>,
+    value: Cow<data::Value>,
+    wanted: std::cmp::Ordering,
+) {
+    if !has_extreme(&value) {
+        return;
+    }
+    let further = match extreme {
+        Some(current) => further_extreme(&value, current, wanted),
+        None => true,
+    };
+    if further {
+        *extreme = Some(value.into_owned());
+    }
+}
+
+/// Numbers are written the same way as a sum or an average, strings as they are
+fn emit_extreme(extreme: &Option<data::Value>) -> data::Value {
+    match extreme {
+        Some(data::Value::Str(s)) => data::Value::Str(s.clone()),
+        Some(number) => as_number(number)
+            .map(data::Value::from_float)
+            .unwrap_or(data::Value::None),
+        None => data::Value::None,
+    }
+}
+
+pub struct Min {
+    min: Option<data::Value>,
+    column: Expr,
+}
+
+impl Min {
+    pub fn empty<T: Into<Expr>>(column: T) -> Min {
+        Min {
+            min: None,
+            column: column.into(),
+        }
+    }
+}
+
+impl AggregateFunction for Min {
+    fn process(&mut self, data: &Data) -> Result<(), EvalError> {
+        let value: Cow<data::Value> = self.column.eval_borrowed(data)?;
+        keep_extreme(&mut self.min, value, std::cmp::Ordering::Less);
+        Ok(())
+    }
+
+    fn emit(&self) -> data::Value {
+        emit_extreme(&self.min)
+    }
+
+    fn empty_box(&self) -> Box<dyn AggregateFunction> {
+        Box::new(Min::empty(self.column.clone()))
+    }
+}
+
+pub struct Average {
+    total: f64,
+    count: i64,
+    column: Expr,
+}
+
+impl Average {
+    pub fn empty<T: Into<Expr>>(column: T) -> Average {
+        Average {
+            total: 0.0,
+            count: 0,
+            column: column.into(),
+        }
+    }
+}
+
+impl AggregateFunction for Average {
+    fn process(&mut self, data: &Data) -> Result<(), EvalError> {
+        let value: f64 = self.column.eval(data)?;
+        self.total += value;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn emit(&self) -> data::Value {
+        data::Value::from_float(self.total / self.count as f64)
+    }
+
+    fn empty_box(&self) -> Box<dyn AggregateFunction> {
+        Box::new(Average::empty(self.column.clone()))
+    }
+
+    fn value_format(&self) -> Option<data::ValueFormat> {
+        Some(data::ValueFormat::Float)
+    }
+}
+
+pub struct Max {
+    max: Option<data::Value>,
+    column: Expr,
+}
+
+impl Max {
+    pub fn empty<T: Into<Expr>>(column: T) -> Max {
+        Max {
+            max: None,
+            column: column.into(),
+        }
+    }
+}
+
+impl AggregateFunction for Max {
+    fn process(&mut self, data: &Data) -> Result<(), EvalError> {
+        let value: Cow<data::Value> = self.column.eval_borrowed(data)?;
+        keep_extreme(&mut self.max, value, std::cmp::Ordering::Greater);
+        Ok(())
+    }
+
+    fn emit(&self) -> data::Value {
+        emit_extreme(&self.max)
+    }
+
+    fn empty_box(&self) -> Box<dyn AggregateFunction> {
+        Box::new(Max::empty(self.column.clone()))
+    }
+}
+
+/// How far the rank of an approximate percentile can be from the true rank, as a fraction of the
+/// number of values
+pub const APPROX_PERCENTILE_ERROR: f64 = 0.001;
+
+pub struct Percentile {
+    ckms: CKMS<f64>,
+    column: Expr,
+    percentile: f64,
+}
+
+impl Percentile {
+    pub fn empty<T: Into<Expr>>(column: T, percentile: f64) -> Self {
+        if percentile >= 1.0 {
+            panic!("Percentiles must be < 1");
+        }
+
+        Percentile {
+            ckms: CKMS::<f64>::new(APPROX_PERCENTILE_ERROR),
+            column: column.into(),
+            percentile,
+        }
+    }
+}
+
+impl AggregateFunction for Percentile {
+    fn process(&mut self, data: &Data) -> Result<(), EvalError> {
+        let value: f64 = self.column.eval(data)?;
+        self.ckms.insert(value);
+        Ok(())
+    }
+
+    fn emit(&self) -> data::Value {
+        let pct_opt = self.ckms.query(self.percentile);
+        pct_opt
+            .map(|(_usize, pct_float)| data::Value::from_float(pct_float))
+            .unwrap_or(data::Value::None)
+    }
+
+    fn empty_box(&self) -> Box<dyn AggregateFunction> {
+        Box::new(Percentile::empty(self.column.clone(), self.percentile))
+    }
+}
+
+/// The values a `Percentiles` has seen: all of them, or a sketch that holds enough of them to find
+/// any percentile to within `APPROX_PERCENTILE_ERROR` in bounded memory
+enum PercentileValues {
+    Exact(Vec<f64>),
+    Approx(CKMS<f64>),
+}
+
+/// Percentiles of a column, between 0 and 100, each in its own column.  By default they're exact:
+/// like `Median`, every value is kept until the aggregate is emitted, and then sorted once for all
+/// of the percentiles.  Percentiles that fall between two values are interpolated.  Approximate
+/// percentiles use a CKMS sketch instead, like `Percentile`.
+pub struct Percentiles {
+    values: PercentileValues,
+    column: Expr,
+    percentiles: Vec<f64>,
+}
+
+impl Percentiles {
+    pub fn empty<T: Into<Expr>>(column: T, percentiles: Vec<f64>, approx: bool) -> Self {
+        let values = if approx {
+            PercentileValues::Approx(CKMS::<f64>::new(APPROX_PERCENTILE_ERROR))
+        } else {
+            PercentileValues::Exact(Vec::new())
+        };
+        Percentiles {
+            values,
+            column: column.into(),
+            percentiles,
+        }
+    }
+
+    fn percentile_of(sorted: &[f64], percentile: f64) -> f64 {
+        let rank = percentile / 100.0 * (sorted.len() - 1) as f64;
+        let (below, above) = (rank.floor() as usize, rank.ceil() as usize);
+        sorted[below] + (sorted[above] - sorted[below]) * (rank - below as f64)
+    }
+}
+
+impl AggregateFunction for Percentiles {
+    fn process(&mut self, data: &Data) -> Result<(), EvalError> {
+        let value: f64 = self.column.eval(data)?;
+        if !value.is_nan() {
+            match &mut self.values {
+                PercentileValues::Exact(values) => values.push(value),
+                PercentileValues::Approx(ckms) => ckms.insert(value),
+            }
+        }
+        Ok(())
+    }
+
+    fn emit(&self) -> data::Value {
+        self.emit_columns()
+            .into_iter()
+            .next()
+            .unwrap_or(data::Value::None)
+    }
+
+    fn empty_box(&self) -> Box<dyn AggregateFunction> {
+        Box::new(Percentiles::empty(
+            self.column.clone(),
+            self.percentiles.clone(),
+            matches!(self.values, PercentileValues::Approx(_)),
+        ))
+    }
+
+    fn column_suffixes(&self) -> Vec<String> {
+        self.percentiles
+            .iter()
+            .map(|pct| format!("_p{}", pct))
+            .collect()
+    }
+
+    fn emit_columns(&self) -> Vec<data::Value> {
+        match &self.values {
+            PercentileValues::Exact(values) if values.is_empty() => {
+                vec![data::Value::None; self.percentiles.len()]
+            }
+            PercentileValues::Exact(values) => {
+                let mut sorted = values.clone();
+                sorted.sort_by(|l, r| l.partial_cmp(r).unwrap_or(Ordering::Equal));
+                self.percentiles
+                    .iter()
+                    .map(|pct| data::Value::from_float(Percentiles::percentile_of(&sorted, *pct)))
+                    .collect()
+            }
+            PercentileValues::Approx(ckms) => self
+                .percentiles
+                .iter()
+                .map(|pct| {
+                    ckms.query(pct / 100.0)
+                        .map(|(_rank, value)| data::Value::from_float(value))
+                        .unwrap_or(data::Value::None)
+                })
+                .collect(),
+        }
+    }
+
+    fn value_format(&self) -> Option<data::ValueFormat> {
+        Some(data::ValueFormat::Float)
+    }
+
+    fn warns_on_non_numbers(&self) -> bool {
+        true
+    }
+}
+
+/// The exact median of a column.  Every value is kept until the aggregate is emitted, and with
+/// an even number of values the result is the mean of the two in the middle.
+pub struct Median {
+    values: Vec<f64>,
+    column: Expr,
+}
+
+impl Median {
+    pub fn empty<T: Into<Expr>>(column: T) -> Self {
+        Median {
+            values: Vec::new(),
+            column: column.into(),
+        }
+    }
+}
+
+impl AggregateFunction for Median {
+    fn process(&mut self, data: &Data) -> Result<(), EvalError> {
+        let value: f64 = self.column.eval(data)?;
+        if !value.is_nan() {
+            self.values.push(value);
+        }
+        Ok(())
+    }
+
+    fn emit(&self) -> data::Value {
+        if self.values.is_empty() {
+            return data::Value::None;
+        }
+        let mut sorted = self.values.clone();
+        sorted.sort_by(|l, r| l.partial_cmp(r).unwrap_or(Ordering::Equal));
+        let middle = sorted.len() / 2;
+        let median = if sorted.len() % 2 == 1 {
+            sorted[middle]
+        } else {
+            (sorted[middle - 1] + sorted[middle]) / 2.0
+        };
+        data::Value::from_float(median)
+    }
+
+    fn empty_box(&self) -> Box<dyn AggregateFunction> {
+        Box::new(Median::empty(self.column.clone()))
+    }
+
+    fn value_format(&self) -> Option<data::ValueFormat> {
+        Some(data::ValueFormat::Float)
+    }
+
+    fn warns_on_non_numbers(&self) -> bool {
+        true
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum SortDirection {
+    Ascending,
+    Descending,
+}
+
+pub struct Sorter {
     columns: Vec<String>,
     initial_columns: Vec<String>,
     state: Vec<Data>,
+    formats: HashMap<String, data::ValueFormat>,
     ordering: Box<dyn Fn(&Data, &Data) -> Ordering + Send + Sync>,
-    direction: SortDirection,
+    /// When the sort is followed by a `limit`, only the first `top_k` rows are emitted
+    top_k: Option<usize>,
+}
+
+/// An entry in the bounded heap used to select the top K rows.  Entries compare using the
+/// sorter's ordering, so the head of the heap is the row that would be emitted last.
+struct HeapEntry<'a> {
+    data: &'a Data,
+    cmp: &'a dyn Fn(&Data, &Data) -> Ordering,
+}
+
+impl<'a> PartialEq for HeapEntry<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<'a> Eq for HeapEntry<'a> {}
+
+impl<'a> PartialOrd for HeapEntry<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> Ord for HeapEntry<'a> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.cmp)(self.data, other.data)
+    }
+}
+
+impl Sorter {
+    /// Sorts rows by each of the columns in turn, each in its own direction
+    pub fn new(columns: Vec<(String, SortDirection)>) -> Self {
+        let initial_columns = columns.iter().map(|(col, _)| col.clone()).collect();
+        Sorter {
+            state: Vec::new(),
+            columns: Vec::new(),
+            formats: HashMap::new(),
+            initial_columns,
+            ordering: Box::new(Sorter::ordering(columns)),
+            top_k: None,
+        }
+    }
+
+    /// A sorter that only emits the first `k` rows.  Rather than sorting every row, the rows
+    /// are selected with a heap holding at most `k` entries, so selection needs O(K) memory
+    /// beyond the input.
+    pub fn top_k(columns: Vec<(String, SortDirection)>, k: usize) -> Self {
+        Sorter {
+            top_k: Some(k),
+            ..Sorter::new(columns)
+        }
+    }
+
+    fn ordering(
+        columns: Vec<(String, SortDirection)>,
+    ) -> impl Fn(&Data, &Data) -> Ordering + Send + Sync {
+        move |l: &Data, r: &Data| {
+            for (col, direction) in &columns {
+                let cmp = l.get(col).cmp(&r.get(col));
+                let cmp = match direction {
+                    SortDirection::Ascending => cmp,
+                    SortDirection::Descending => cmp.reverse(),
+                };
+                if cmp != Ordering::Equal {
+                    return cmp;
+                }
+            }
+            Ordering::Equal
+        }
+    }
+
+    fn new_columns(&self, data: &HashMap<String, data::Value>) -> Vec<String> {
+        let mut new_keys: Vec<String> = data
+            .keys()
+            .filter(|key| !self.columns.contains(key))
+            .cloned()
+            .collect();
+        new_keys.sort();
+        new_keys
+    }
+
+    fn select_top_k(&self, k: usize, cmp: &dyn Fn(&Data, &Data) -> Ordering) -> Vec<Data> {
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        for data in &self.state {
+            let entry = HeapEntry { data, cmp };
+            if heap.len() < k {
+                heap.push(entry);
+                continue;
+            }
+            match heap.peek() {
+                Some(last) if entry < *last => {
+                    heap.pop();
+                    heap.push(entry);
+                }
+                _ => (),
+            }
+        }
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|entry| entry.data.clone())
+            .collect()
+    }
+}
+
+impl AggregateOperator for Sorter {
+    fn emit(&self) -> data::Aggregate {
+        let order = &self.ordering;
+        // potential hotspot with large numbers of columns
+        let additional_columns: Vec<String> = self
+            .columns
+            .iter()
+            .filter(|c| !self.initial_columns.contains(c))
+            .cloned()
+            .collect();
+        // To produce a deterministic sort, we should also sort by the non-key columns
+
+        let second_ordering = Record::ordering(additional_columns);
+        let cmp = |l: &Data, r: &Data| (order)(l, r).then(second_ordering(l, r));
+
+        let sorted_data = match self.top_k {
+            Some(k) => self.select_top_k(k, &cmp),
+            None => {
+                let mut sorted_data = self.state.to_vec();
+                sorted_data.sort_by(cmp);
+                sorted_data
+            }
+        };
+        Aggregate {
+            data: sorted_data,
+            columns: self.columns.clone(),
+            formats: self.formats.clone(),
+        }
+    }
+
+    fn process(&mut self, row: Row) {
+        let order = &self.ordering;
+        match row {
+            Row::Aggregate(agg) => {
+                self.columns = agg.columns;
+                self.state = agg.data;
+                self.formats = agg.formats;
+            }
+            Row::Record(rec) => {
+                let new_cols = self.new_columns(&rec.data);
+                self.state.push(rec.data);
+                self.state.sort_by(|l, r| (order)(l, r));
+                self.columns.extend(new_cols);
+            }
+        }
+    }
+}
+
+pub struct MultiGrouper {
+    key_cols: Vec<Expr>,
+    /// Whether each key column is a `split(...)` call whose parts are grouped separately
+    fan_out: Vec<bool>,
+    key_col_headers: Vec<String>,
+    agg_col: Vec<(String, Box<dyn AggregateFunction>)>,
+    // key-column values -> (agg_columns -> builders)
+    state: HashMap<Vec<data::Value>, HashMap<String, Box<dyn AggregateFunction>>>,
+    /// Aggregate columns that have already reported a value they couldn't use, so the error is
+    /// only printed once per column rather than for every record
+    reported: HashSet<String>,
+    /// Warn when a single group's `count_distinct` holds more values than this
+    distinct_warning: usize,
+}
+
+impl MultiGrouper {
+    pub fn new(
+        key_cols: &[Expr],
+        key_col_headers: Vec<String>,
+        aggregators: Vec<(String, Box<dyn AggregateFunction>)>,
+    ) -> Self {
+        let fan_out = key_cols
+            .iter()
+            .map(|expr| {
+                matches!(
+                    expr,
+                    Expr::FunctionCall(FunctionCall {
+                        function: Function::Split,
+                        ..
+                    })
+                )
+            })
+            .collect();
+        MultiGrouper {
+            key_cols: key_cols.to_vec(),
+            fan_out,
+            key_col_headers,
+            agg_col: aggregators,
+            state: HashMap::new(),
+            reported: HashSet::new(),
+            distinct_warning: DEFAULT_DISTINCT_WARNING,
+        }
+    }
+    fn process_map(&mut self, data: &Data) {
+        let key_values = self
+            .key_cols
+            .iter()
+            .map(|expr| -> Result<Cow<data::Value>, EvalError> { expr.eval_borrowed(data) });
+        let key_columns: Vec<data::Value> = key_values
+            .map(|value_res| value_res.unwrap_or_else(|_| Cow::Borrowed(data::NONE)))
+            .map(Cow::into_owned)
+            .collect();
+        if !self.fan_out.contains(&true) {
+            self.process_group(key_columns, data);
+            return;
+        }
+        // Every combination of the parts of the fanned out columns is a group the record counts
+        // towards
+        let mut groups: Vec<Vec<data::Value>> = vec![Vec::new()];
+        for (value, &fan_out) in key_columns.into_iter().zip(self.fan_out.iter()) {
+            groups = match value {
+                data::Value::Array(parts) if fan_out => groups
+                    .into_iter()
+                    .flat_map(|group| {
+                        parts.iter().map(move |part| {
+                            let mut group = group.clone();
+                            group.push(part.clone());
+                            group
+                        })
+                    })
+                    .collect(),
+                value => groups
+                    .into_iter()
+                    .map(|mut group| {
+                        group.push(value.clone());
+                        group
+                    })
+                    .collect(),
+            };
+        }
+        for group in groups {
+            self.process_group(group, data);
+        }
+    }
+
+    fn process_group(&mut self, key_columns: Vec<data::Value>, data: &Data) {
+        let agg_col = &self.agg_col;
+        let row = self.state.entry(key_columns).or_insert_with(|| {
+            agg_col
+                .iter()
+                .map(|&(ref k, ref v)| (k.to_owned(), v.empty_box()))
+                .collect()
+        });
+        for (column, fun) in row.iter_mut() {
+            // TODO: #25 capture erorrs here instead of ignoring
+            let report = match fun.process(data) {
+                Err(e @ EvalError::BooleanNotNumber { .. }) => format!("error: {}: {}", column, e),
+                Err(EvalError::ExpectedNumber { found }) if fun.warns_on_non_numbers() => format!(
+                    "warning: {}: skipping values that aren't numbers, like `{}`",
+                    column, found
+                ),
+                _ => match fun.distinct_values() {
+                    Some(count)
+                        if count > self.distinct_warning && !self.reported.contains(column) =>
+                    {
+                        format!(
+                            "warning: {}: more than {} distinct values in one group, which are all \
+                             kept in memory",
+                            column, self.distinct_warning
+                        )
+                    }
+                    _ => continue,
+                },
+            };
+            if self.reported.insert(column.clone()) {
+                eprintln!("{}", report);
+            }
+        }
+    }
+}
+
+impl AggregateOperator for MultiGrouper {
+    fn warn_distinct_over(&mut self, limit: usize) {
+        self.distinct_warning = limit;
+    }
+
+    fn emit(&self) -> Aggregate {
+        let mut columns = self.key_col_headers.to_vec();
+        columns.extend(self.agg_col.iter().flat_map(|(k, fun)| {
+            fun.column_suffixes()
+                .into_iter()
+                .map(move |suffix| format!("{}{}", k, suffix))
+        }));
+        let data = self.state.iter().map(|(key_values, agg_map)| {
+            let key_values = key_values.iter().cloned();
+            let key_cols = self.key_col_headers.iter().map(|s| s.to_owned());
+            let mut res_map: data::VMap =
+                HashMap::from_iter(itertools::zip_eq(key_cols, key_values));
+            for (k, v) in agg_map {
+                for (suffix, value) in v.column_suffixes().into_iter().zip(v.emit_columns()) {
+                    res_map.insert(format!("{}{}", k, suffix), value);
+                }
+            }
+            res_map
+        });
+        let formats = self
+            .agg_col
+            .iter()
+            .filter_map(|(k, fun)| fun.value_format().map(|format| (k, fun, format)))
+            .flat_map(|(k, fun, format)| {
+                fun.column_suffixes()
+                    .into_iter()
+                    .map(move |suffix| (format!("{}{}", k, suffix), format))
+            })
+            .collect();
+        Aggregate {
+            columns,
+            data: data.collect(),
+            formats,
+        }
+    }
+
+    fn process(&mut self, row: Row) {
+        match row {
+            Row::Record(rec) => {
+                self.process_map(&rec.data);
+            }
+            Row::Aggregate(ag) => {
+                self.state.clear();
+                for row in ag.data {
+                    self.process_map(&row);
+                }
+            }
+        }
+    }
+}
+
+struct ApproxCount {
+    count: u64,
+    /// The most that `count` can be above the true count
+    max_overcount: u64,
+}
+
+/// An approximate count of the most frequent values of a column in bounded memory, using the
+/// Space-Saving algorithm.  At most `capacity` values are tracked.  When a new value arrives and
+/// every slot is taken, the value with the lowest count is evicted and the new value inherits
+/// its count, which becomes the new value's maximum overcount.  Counts are never too low, and
+/// values that appear more than `total / capacity` times are always tracked.
+pub struct TopKApprox {
+    column: Expr,
+    column_header: String,
+    k: usize,
+    capacity: usize,
+    counters: HashMap<data::Value, ApproxCount>,
+    // Counters ordered by count so the smallest can be found for eviction in O(log n)
+    by_count: BTreeSet<(u64, data::Value)>,
+}
+
+impl TopKApprox {
+    /// How many values are tracked for each value that is emitted
+    const SLOTS_PER_RESULT: usize = 10;
+
+    pub fn new(column: Expr, column_header: String, k: usize) -> Self {
+        TopKApprox {
+            column,
+            column_header,
+            k,
+            capacity: k.saturating_mul(TopKApprox::SLOTS_PER_RESULT),
+            counters: HashMap::new(),
+            by_count: BTreeSet::new(),
+        }
+    }
+
+    fn process_map(&mut self, data: &Data) {
+        let value: Cow<data::Value> = self
+            .column
+            .eval_borrowed(data)
+            .unwrap_or_else(|_| Cow::Borrowed(data::NONE));
+        if let Some(counter) = self.counters.get_mut(&value) {
+            let value = value.into_owned();
+            self.by_count.remove(&(counter.count, value.clone()));
+            counter.count += 1;
+            self.by_count.insert((counter.count, value));
+            return;
+        }
+
+        let counter = if self.counters.len() < self.capacity {
+            ApproxCount {
+                count: 1,
+                max_overcount: 0,
+            }
+        } else {
+            match self.by_count.iter().next().cloned() {
+                Some((min_count, evicted)) => {
+                    self.by_count.remove(&(min_count, evicted.clone()));
+                    self.counters.remove(&evicted);
+                    ApproxCount {
+                        count: min_count + 1,
+                        max_overcount: min_count,
+                    }
+                }
+                // Only possible with no capacity at all
+                None => return,
+            }
+        };
+        let value = value.into_owned();
+        self.by_count.insert((counter.count, value.clone()));
+        self.counters.insert(value, counter);
+    }
+}
+
+impl AggregateOperator for TopKApprox {
+    fn emit(&self) -> Aggregate {
+        let mut counters: Vec<(&data::Value, &ApproxCount)> = self.counters.iter().collect();
+        // Ties are broken by the value so the output is stable
+        counters.sort_by(|(lv, lc), (rv, rc)| rc.count.cmp(&lc.count).then_with(|| lv.cmp(rv)));
+        let data = counters
+            .into_iter()
+            .take(self.k)
+            .map(|(value, counter)| {
+                HashMap::from_iter(vec![
+                    (self.column_header.clone(), value.clone()),
+                    ("_count".to_string(), data::Value::Int(counter.count as i64)),
+                    (
+                        "_max_overcount".to_string(),
+                        data::Value::Int(counter.max_overcount as i64),
+                    ),
+                ])
+            })
+            .collect();
+        Aggregate {
+            columns: vec![
+                self.column_header.clone(),
+                "_count".to_string(),
+                "_max_overcount".to_string(),
+            ],
+            data,
+            formats: HashMap::from_iter(vec![
+                ("_count".to_string(), data::ValueFormat::Integer),
+                ("_max_overcount".to_string(), data::ValueFormat::Integer),
+            ]),
+        }
+    }
+
+    fn process(&mut self, row: Row) {
+        match row {
+            Row::Record(rec) => self.process_map(&rec.data),
+            Row::Aggregate(ag) => {
+                self.counters.clear();
+                self.by_count.clear();
+                for row in ag.data {
+                    self.process_map(&row);
+                }
+            }
+        }
+    }
+}
+
+/// Counts records in fixed intervals of a timestamp and reports each count as events per second.
+/// Records without a timestamp are skipped, with a warning the first time.  With `fill`, the
+/// intervals between the first and last one that have no records are reported with a rate of 0.
+pub struct Rate {
+    column: Expr,
+    interval: f64,
+    fill: bool,
+    /// Record counts keyed by the index of the interval since the epoch
+    counts: BTreeMap<u64, u64>,
+    warned: bool,
+}
+
+impl Rate {
+    /// Empty intervals aren't filled in when there would be more than this many rows
+    const MAX_FILLED_INTERVALS: u64 = 100_000;
+
+    pub fn new(column: Expr, interval: f64, fill: bool) -> Self {
+        Rate {
+            column,
+            interval,
+            fill,
+            counts: BTreeMap::new(),
+            warned: false,
+        }
+    }
+
+    fn process_map(&mut self, data: &Data) {
+        let value: Cow<data::Value> = self
+            .column
+            .eval_borrowed(data)
+            .unwrap_or_else(|_| Cow::Borrowed(data::NONE));
+        match writable_timestamp_secs(&value) {
+            Some(secs) => {
+                let index = (secs / self.interval).floor() as u64;
+                *self.counts.entry(index).or_insert(0) += 1;
+            }
+            None => {
+                if !self.warned {
+                    self.warned = true;
+                    eprintln!(
+                        "warning: `{}` isn't a timestamp, so rate skipped the record",
+                        value
+                    );
+                }
+            }
+        }
+    }
+
+    fn intervals(&self) -> Vec<(u64, u64)> {
+        let (first, last) = match (self.counts.keys().next(), self.counts.keys().next_back()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return vec![],
+        };
+        if self.fill && last - first < Rate::MAX_FILLED_INTERVALS {
+            (first..=last)
+                .map(|index| (index, self.counts.get(&index).cloned().unwrap_or(0)))
+                .collect()
+        } else {
+            self.counts.iter().map(|(k, v)| (*k, *v)).collect()
+        }
+    }
+}
+
+impl AggregateOperator for Rate {
+    fn emit(&self) -> Aggregate {
+        let data = self
+            .intervals()
+            .into_iter()
+            .map(|(index, count)| {
+                let start = index as f64 * self.interval;
+                HashMap::from_iter(vec![
+                    (
+                        "_timeslice".to_string(),
+                        data::Value::Str(format_interval_start(start, self.interval)),
+                    ),
+                    (
+                        "_rate".to_string(),
+                        data::Value::Float(OrderedFloat(count as f64 / self.interval)),
+                    ),
+                ])
+            })
+            .collect();
+        Aggregate {
+            columns: vec!["_timeslice".to_string(), "_rate".to_string()],
+            data,
+            formats: HashMap::from_iter(vec![("_rate".to_string(), data::ValueFormat::Float)]),
+        }
+    }
+
+    fn process(&mut self, row: Row) {
+        match row {
+            Row::Record(rec) => self.process_map(&rec.data),
+            Row::Aggregate(ag) => {
+                self.counts.clear();
+                for row in ag.data {
+                    self.process_map(&row);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CaptureType {
+    Number,
+    String,
+}
+
+#[derive(Clone)]
+pub struct ParseOptions {
+    pub drop_nonmatching: bool,
+}
+
+#[derive(Clone)]
+pub struct Parse {
+    regex: regex::Regex,
+    fields: Vec<String>,
+    /// Fields to parse, tried in order until one matches.  Empty means the raw line.
+    input_columns: Vec<Expr>,
+    options: ParseOptions,
+    defaults: HashMap<String, data::Value>,
+    types: HashMap<String, CaptureType>,
+    /// Typed fields that have already warned about a capture that didn't fit their type, shared
+    /// between copies of the operator
+    warned: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Parse {
+    pub fn new(
+        pattern: regex::Regex,
+        fields: Vec<String>,
+        input_columns: Vec<Expr>,
+        options: ParseOptions,
+    ) -> Self {
+        Parse {
+            regex: pattern,
+            fields,
+            input_columns,
+            options,
+            defaults: HashMap::new(),
+            types: HashMap::new(),
+            warned: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Convert the captures of these fields to the given type instead of inferring it from the
+    /// captured text
+    pub fn with_types(mut self, types: HashMap<String, CaptureType>) -> Self {
+        self.types = types;
+        self
+    }
+
+    fn capture_value(&self, field: &str, captured: &str) -> data::Value {
+        match self.types.get(field) {
+            None => data::Value::from_string(captured),
+            Some(CaptureType::String) => data::Value::Str(captured.to_string()),
+            Some(CaptureType::Number) => match data::Value::from_string(captured) {
+                number @ data::Value::Int(_) | number @ data::Value::Float(_) => number,
+                _ => {
+                    if self.warned.lock().unwrap().insert(field.to_string()) {
+                        eprintln!(
+                            "warning: {}: expected a number, found `{}`. Keeping it as a string",
+                            field, captured
+                        );
+                    }
+                    data::Value::Str(captured.to_string())
+                }
+            },
+        }
+    }
+
+    /// Use these values for fields whose capture was empty, or for every field when the input
+    /// doesn't match and non-matching records are kept
+    pub fn with_defaults(mut self, defaults: HashMap<String, data::Value>) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    fn default_for(&self, field: &str) -> data::Value {
+        self.defaults
+            .get(field)
+            .cloned()
+            .unwrap_or(data::Value::None)
+    }
+
+    fn matches(&self, rec: &Record) -> Result<Option<Vec<data::Value>>, EvalError> {
+        if self.input_columns.is_empty() {
+            return Ok(self.match_input(&rec.raw));
+        }
+        for column in &self.input_columns {
+            let inp: Result<Cow<str>, EvalError> = column.eval_borrowed(&rec.data);
+            match inp {
+                Ok(inp) => {
+                    if let Some(values) = self.match_input(&inp) {
+                        return Ok(Some(values));
+                    }
+                }
+                // When there are several fields to try, one that's missing is skipped like
+                // one that doesn't match
+                Err(e) if self.input_columns.len() == 1 => return Err(e),
+                Err(_) => (),
+            }
+        }
+        Ok(None)
+    }
+
+    fn match_input(&self, inp: &str) -> Option<Vec<data::Value>> {
+        let capture = self.regex.captures(inp.trim())?;
+        let mut values: Vec<data::Value> = Vec::new();
+        for (i, field) in self.fields.iter().enumerate() {
+            // the first capture is the entire string
+            let captured = &capture[i + 1];
+            if captured.is_empty() && self.defaults.contains_key(field) {
+                values.push(self.default_for(field));
+            } else {
+                values.push(self.capture_value(field, captured));
+            }
+        }
+        Some(values)
+    }
+}
+
+impl UnaryPreAggFunction for Parse {
+    fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
+        let matches = self.matches(&rec)?;
+        match (matches, self.options.drop_nonmatching) {
+            (None, true) => Ok(None),
+            (None, false) => {
+                let new_fields: Vec<_> = self
+                    .fields
+                    .iter()
+                    .filter(|f| !rec.data.contains_key(&f.to_string()))
+                    .collect();
+
+                let mut rec = rec;
+                for field in new_fields {
+                    let value = self.default_for(field);
+                    rec = rec.put(field, value);
+                }
+                Ok(Some(rec))
+            }
+            (Some(matches), _) => {
+                let mut rec = rec;
+                for (i, field) in self.fields.iter().enumerate() {
+                    rec = rec.put(field, matches[i].clone());
+                }
+                Ok(Some(rec))
+            }
+        }
+    }
 }
 
-impl Sorter {
-    pub fn new(columns: Vec<String>, direction: SortDirection) -> Self {
-        Sorter {
-            state: Vec::new(),
-            columns: Vec::new(),
-            direction,
-            initial_columns: columns.clone(),
-            ordering: Box::new(Record::ordering(columns)),
+/// Adds a string field for each named group of a regex that took part in the match
+#[derive(Clone)]
+pub struct Extract {
+    regex: regex::Regex,
+    input_column: Option<Expr>,
+    drop_nonmatching: bool,
+}
+
+impl Extract {
+    pub fn new(regex: regex::Regex, input_column: Option<Expr>, drop_nonmatching: bool) -> Self {
+        Extract {
+            regex,
+            input_column,
+            drop_nonmatching,
         }
     }
+}
 
-    fn new_columns(&self, data: &HashMap<String, data::Value>) -> Vec<String> {
-        let mut new_keys: Vec<String> = data
-            .keys()
-            .filter(|key| !self.columns.contains(key))
-            .cloned()
-            .collect();
-        new_keys.sort();
-        new_keys
+impl UnaryPreAggFunction for Extract {
+    fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
+        let fields: Vec<(String, String)> = {
+            let inp = get_input(&rec, &self.input_column)?;
+            let captures = match self.regex.captures(inp.trim_end()) {
+                Some(captures) => captures,
+                None if self.drop_nonmatching => return Ok(None),
+                None => return Ok(Some(rec)),
+            };
+            self.regex
+                .capture_names()
+                .flatten()
+                .filter_map(|name| {
+                    captures
+                        .name(name)
+                        .map(|value| (name.to_string(), value.as_str().to_string()))
+                })
+                .collect()
+        };
+        Ok(Some(fields.into_iter().fold(rec, |rec, (name, value)| {
+            rec.put(&name, data::Value::Str(value))
+        })))
     }
 }
 
-impl AggregateOperator for Sorter {
-    fn emit(&self) -> data::Aggregate {
-        let mut sorted_data = self.state.to_vec();
-        let order = &self.ordering;
-        // potential hotspot with large numbers of columns
-        let additional_columns: Vec<String> = self
-            .columns
-            .iter()
-            .filter(|c| !self.initial_columns.contains(c))
-            .cloned()
-            .collect();
-        // To produce a deterministic sort, we should also sort by the non-key columns
+#[derive(Clone)]
+pub struct Where<T: 'static + Evaluatable<bool>> {
+    expr: T,
+}
 
-        let second_ordering = Record::ordering(additional_columns);
+impl<T: 'static + Evaluatable<bool>> Where<T> {
+    pub fn new(expr: T) -> Self {
+        Where { expr }
+    }
+}
 
-        if self.direction == SortDirection::Ascending {
-            sorted_data.sort_by(|l, r| (order)(l, r).then(second_ordering(l, r)));
+impl<T: 'static + Evaluatable<bool>> UnaryPreAggFunction for Where<T> {
+    fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
+        if self.expr.eval(&rec.data)? {
+            Ok(Some(rec))
         } else {
-            sorted_data.sort_by(|l, r| (order)(r, l).then(second_ordering(l, r)));
+            Ok(None)
         }
-        Aggregate {
-            data: sorted_data,
-            columns: self.columns.clone(),
+    }
+}
+
+/// Filters records on whether the input matches any of a set of patterns, usually loaded from a
+/// file.  A missing input column never matches.
+#[derive(Clone)]
+pub struct MatchesAny {
+    patterns: regex::RegexSet,
+    input_column: Option<Expr>,
+    negate: bool,
+}
+
+impl MatchesAny {
+    pub fn new(patterns: regex::RegexSet, input_column: Option<Expr>, negate: bool) -> Self {
+        MatchesAny {
+            patterns,
+            input_column,
+            negate,
         }
     }
+}
 
-    fn process(&mut self, row: Row) {
-        let order = &self.ordering;
-        match row {
-            Row::Aggregate(agg) => {
-                self.columns = agg.columns;
-                self.state = agg.data;
-            }
-            Row::Record(rec) => {
-                let new_cols = self.new_columns(&rec.data);
-                self.state.push(rec.data);
-                self.state.sort_by(|l, r| (order)(l, r));
-                self.columns.extend(new_cols);
-            }
+impl UnaryPreAggFunction for MatchesAny {
+    fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
+        let matched = match get_input(&rec, &self.input_column) {
+            Ok(input) => self
+                .patterns
+                .is_match(input.trim_end_matches(&['\r', '\n'][..])),
+            Err(EvalError::NoValueForKey { .. }) => false,
+            Err(e) => return Err(e),
+        };
+        if matched != self.negate {
+            Ok(Some(rec))
+        } else {
+            Ok(None)
         }
     }
 }
 
-pub struct MultiGrouper {
-    key_cols: Vec<Expr>,
-    key_col_headers: Vec<String>,
-    agg_col: Vec<(String, Box<dyn AggregateFunction>)>,
-    // key-column values -> (agg_columns -> builders)
-    state: HashMap<Vec<data::Value>, HashMap<String, Box<dyn AggregateFunction>>>,
+pub struct TotalDef {
+    column: Expr,
+    output_column: String,
 }
 
-impl MultiGrouper {
-    pub fn new(
-        key_cols: &[Expr],
-        key_col_headers: Vec<String>,
-        aggregators: Vec<(String, Box<dyn AggregateFunction>)>,
-    ) -> Self {
-        MultiGrouper {
-            key_cols: key_cols.to_vec(),
-            key_col_headers,
-            agg_col: aggregators,
-            state: HashMap::new(),
+impl TotalDef {
+    pub fn new(column: Expr, output_column: String) -> Self {
+        TotalDef {
+            column,
+            output_column,
         }
     }
-    fn process_map(&mut self, data: &Data) {
-        let key_values = self.key_cols.iter().map(|expr| expr.eval_borrowed(data));
-        let key_columns: Vec<data::Value> = key_values
-            .map(|value_res| value_res.unwrap_or_else(|_| data::NONE))
-            .cloned()
-            .collect();
-        let agg_col = &self.agg_col;
-        let row = self.state.entry(key_columns).or_insert_with(|| {
-            agg_col
+}
+
+impl OperatorBuilder for TotalDef {
+    fn build(&self) -> Box<dyn UnaryPreAggOperator> {
+        Box::new(Total::new(self.column.clone(), self.output_column.clone()))
+    }
+}
+
+pub struct Total {
+    column: Expr,
+    total: f64,
+    output_column: String,
+}
+
+impl Total {
+    pub fn new<T: Into<Expr>>(column: T, output_column: String) -> Total {
+        Total {
+            column: column.into(),
+            total: 0.0,
+            output_column,
+        }
+    }
+}
+
+impl UnaryPreAggOperator for Total {
+    fn process_mut(&mut self, rec: Record) -> Result<Option<Record>, EvalError> {
+        // I guess this means there are cases when you need to both emit a warning _and_ a row, TODO
+        // for now, we'll just emit the row
+        let val: f64 = self.column.eval(&rec.data).unwrap_or(0.0);
+        self.total += val;
+        let rec = rec.put(&self.output_column, data::Value::from_float(self.total));
+        Ok(Some(rec))
+    }
+}
+
+/// Appends a row to an aggregate with the sum of each of its numeric columns.  Values that
+/// aren't numbers contribute nothing, and a column without any numbers is left empty.  The
+/// key columns are never summed; the first one labels the row instead.
+pub struct GrandTotal {
+    key_columns: Vec<String>,
+    aggregate: Option<data::Aggregate>,
+}
+
+impl GrandTotal {
+    pub fn new(key_columns: Vec<String>) -> Self {
+        GrandTotal {
+            key_columns,
+            aggregate: None,
+        }
+    }
+
+    fn total_row(&self, agg: &data::Aggregate) -> Data {
+        let mut row = HashMap::new();
+        if let Some(label) = self.key_columns.first() {
+            row.insert(label.clone(), data::Value::Str("total".to_string()));
+        }
+        for column in agg.columns.iter().filter(|c| !self.key_columns.contains(c)) {
+            let values: Vec<f64> = agg
+                .data
                 .iter()
-                .map(|&(ref k, ref v)| (k.to_owned(), v.empty_box()))
-                .collect()
-        });
-        for fun in row.values_mut() {
-            // TODO: #25 capture erorrs here instead of ignoring
-            let _ = fun.process(data);
+                .filter_map(|data| match data.get(column) {
+                    Some(data::Value::Int(i)) => Some(*i as f64),
+                    Some(data::Value::Float(f)) if !f.is_nan() => Some(f.into_inner()),
+                    _ => None,
+                })
+                .collect();
+            if !values.is_empty() {
+                row.insert(column.clone(), data::Value::from_float(values.iter().sum()));
+            }
         }
+        row
     }
 }
 
-impl AggregateOperator for MultiGrouper {
-    fn emit(&self) -> Aggregate {
-        let mut columns = self.key_col_headers.to_vec();
-        columns.extend(self.agg_col.iter().map(|&(ref k, ..)| k.to_string()));
-        let data = self.state.iter().map(|(key_values, agg_map)| {
-            let key_values = key_values.iter().cloned();
-            let key_cols = self.key_col_headers.iter().map(|s| s.to_owned());
-            let mut res_map: data::VMap =
-                HashMap::from_iter(itertools::zip_eq(key_cols, key_values));
-            for (k, v) in agg_map {
-                res_map.insert(k.to_string(), v.emit());
+impl AggregateOperator for GrandTotal {
+    fn emit(&self) -> data::Aggregate {
+        match &self.aggregate {
+            Some(agg) => {
+                let mut agg = agg.clone();
+                let total = self.total_row(&agg);
+                agg.data.push(total);
+                agg
             }
-            res_map
-        });
-        Aggregate {
-            columns,
-            data: data.collect(),
+            None => data::Aggregate {
+                columns: self.key_columns.clone(),
+                data: vec![],
+                formats: HashMap::new(),
+            },
         }
     }
 
     fn process(&mut self, row: Row) {
-        match row {
-            Row::Record(rec) => {
-                self.process_map(&rec.data);
-            }
-            Row::Aggregate(ag) => {
-                self.state.clear();
-                for row in ag.data {
-                    self.process_map(&row);
-                }
+        // Only ever follows an aggregate, so there are no records to total
+        if let Row::Aggregate(agg) = row {
+            self.aggregate = Some(agg);
+        }
+    }
+}
+
+pub struct MovingAverageDef {
+    column: Expr,
+    window: usize,
+    gaps: bool,
+    output_column: String,
+}
+
+impl MovingAverageDef {
+    pub fn new(column: Expr, window: usize, gaps: bool, output_column: String) -> Self {
+        MovingAverageDef {
+            column,
+            window,
+            gaps,
+            output_column,
+        }
+    }
+}
+
+impl OperatorBuilder for MovingAverageDef {
+    fn build(&self) -> Box<dyn UnaryPreAggOperator> {
+        Box::new(MovingAverage::new(
+            self.column.clone(),
+            self.window,
+            self.gaps,
+            self.output_column.clone(),
+        ))
+    }
+}
+
+/// The mean of the last `window` numeric values of a column.  Until the window fills up, the
+/// mean is over the values seen so far.
+pub struct MovingAverage {
+    column: Expr,
+    window: usize,
+    /// When set, non-numeric values are gaps that take up a slot in the window.  Otherwise
+    /// they're skipped and the window doesn't move.
+    gaps: bool,
+    values: VecDeque<Option<f64>>,
+    output_column: String,
+}
+
+impl MovingAverage {
+    pub fn new<T: Into<Expr>>(
+        column: T,
+        window: usize,
+        gaps: bool,
+        output_column: String,
+    ) -> MovingAverage {
+        MovingAverage {
+            column: column.into(),
+            window,
+            gaps,
+            values: VecDeque::with_capacity(window),
+            output_column,
+        }
+    }
+}
+
+impl UnaryPreAggOperator for MovingAverage {
+    fn process_mut(&mut self, rec: Record) -> Result<Option<Record>, EvalError> {
+        let val: Option<f64> = self.column.eval(&rec.data).ok();
+        if val.is_some() || self.gaps {
+            if self.values.len() == self.window {
+                self.values.pop_front();
             }
+            self.values.push_back(val);
+        }
+        let numbers: Vec<f64> = self.values.iter().flatten().cloned().collect();
+        let mean = if numbers.is_empty() {
+            data::Value::None
+        } else {
+            data::Value::from_float(numbers.iter().sum::<f64>() / numbers.len() as f64)
+        };
+        Ok(Some(rec.put(&self.output_column, mean)))
+    }
+}
+
+pub struct QuantileBucketDef {
+    column: Expr,
+    buckets: usize,
+    output_column: String,
+}
+
+impl QuantileBucketDef {
+    pub fn new(column: Expr, buckets: usize, output_column: String) -> Self {
+        QuantileBucketDef {
+            column,
+            buckets,
+            output_column,
+        }
+    }
+}
+
+impl OperatorBuilder for QuantileBucketDef {
+    fn build(&self) -> Box<dyn UnaryPreAggOperator> {
+        Box::new(QuantileBucket::new(
+            self.column.clone(),
+            self.buckets,
+            self.output_column.clone(),
+        ))
+    }
+}
+
+/// Which of `buckets` equal-sized quantile buckets each record's value falls into, counting from
+/// one.  The boundaries depend on every value, so records are held until the input ends and
+/// emitted by `drain`.  Records where the column isn't a number get `None`.
+pub struct QuantileBucket {
+    column: Expr,
+    buckets: usize,
+    records: Vec<(Record, Option<f64>)>,
+    output_column: String,
+}
+
+impl QuantileBucket {
+    pub fn new<T: Into<Expr>>(column: T, buckets: usize, output_column: String) -> Self {
+        QuantileBucket {
+            column: column.into(),
+            buckets,
+            records: Vec::new(),
+            output_column,
         }
     }
 }
 
+impl UnaryPreAggOperator for QuantileBucket {
+    fn process_mut(&mut self, rec: Record) -> Result<Option<Record>, EvalError> {
+        let val: Option<f64> = self.column.eval(&rec.data).ok();
+        self.records.push((rec, val));
+        Ok(None)
+    }
+
+    fn drain(self: Box<Self>) -> Box<dyn Iterator<Item = Record>> {
+        let mut sorted: Vec<f64> = self
+            .records
+            .iter()
+            .filter_map(|(_, val)| *val)
+            .filter(|val| !val.is_nan())
+            .collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let buckets = self.buckets;
+        let output_column = self.output_column;
+        Box::new(self.records.into_iter().map(move |(rec, val)| {
+            let bucket = match val {
+                Some(val) if !val.is_nan() => {
+                    // Equal values share the bucket of the first of them
+                    let rank = sorted.partition_point(|v| *v < val);
+                    data::Value::Int((rank * buckets / sorted.len() + 1) as i64)
+                }
+                _ => data::Value::None,
+            };
+            rec.put(&output_column, bucket)
+        }))
+    }
+}
+
 #[derive(Clone)]
-pub struct ParseOptions {
-    pub drop_nonmatching: bool,
-}
-
-#[derive(Clone)]
-pub struct Parse {
-    regex: regex::Regex,
-    fields: Vec<String>,
+pub struct Split {
+    separator: String,
     input_column: Option<Expr>,
-    options: ParseOptions,
+    output_column: Option<Expr>,
+    /// Write a record for each part instead of an array of the parts
+    explode: bool,
 }
 
-impl Parse {
+impl Split {
     pub fn new(
-        pattern: regex::Regex,
-        fields: Vec<String>,
+        separator: String,
         input_column: Option<Expr>,
-        options: ParseOptions,
+        output_column: Option<Expr>,
+        explode: bool,
     ) -> Self {
-        Parse {
-            regex: pattern,
-            fields,
+        Self {
+            separator,
             input_column,
-            options,
+            output_column,
+            explode,
         }
     }
 
-    fn matches(&self, rec: &Record) -> Result<Option<Vec<data::Value>>, EvalError> {
+    fn parts(&self, rec: &Record) -> Result<Vec<data::Value>, EvalError> {
         let inp = get_input(rec, &self.input_column)?;
-        let matches: Vec<regex::Captures> = self.regex.captures_iter(inp.trim()).collect();
-        if matches.is_empty() {
-            Ok(None)
-        } else {
-            let capture = &matches[0];
-            let mut values: Vec<data::Value> = Vec::new();
-            for i in 0..self.fields.len() {
-                // the first capture is the entire string
-                values.push(data::Value::from_string(&capture[i + 1]));
-            }
-            Ok(Some(values))
+        Ok(
+            split::split_with_delimiters(&inp, &self.separator, &split::DEFAULT_DELIMITERS)
+                .into_iter()
+                .map(data::Value::from_string)
+                .collect(),
+        )
+    }
+
+    fn put(&self, rec: Record, value: data::Value) -> Result<Record, EvalError> {
+        match &self.output_column {
+            Some(output_column) => rec.put_expr(output_column, value),
+            None => Ok(rec.put("_split", value)),
         }
     }
 }
 
-impl UnaryPreAggFunction for Parse {
+impl UnaryPreAggFunction for Split {
+    /// The record with the array of parts, even when exploding; `process_all` is what
+    /// writes a record per part
     fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
-        let matches = self.matches(&rec)?;
-        match (matches, self.options.drop_nonmatching) {
-            (None, true) => Ok(None),
-            (None, false) => {
-                let new_fields: Vec<_> = self
-                    .fields
-                    .iter()
-                    .filter(|f| !rec.data.contains_key(&f.to_string()))
-                    .collect();
+        let array = self.parts(&rec)?;
+        Ok(Some(self.put(rec, data::Value::Array(array))?))
+    }
 
-                let mut rec = rec;
-                for field in new_fields {
-                    rec = rec.put(field, data::Value::None);
-                }
-                Ok(Some(rec))
-            }
-            (Some(matches), _) => {
-                let mut rec = rec;
-                for (i, field) in self.fields.iter().enumerate() {
-                    rec = rec.put(field, matches[i].clone());
-                }
-                Ok(Some(rec))
-            }
+    fn process_all(&self, rec: Record) -> Result<Vec<Record>, EvalError> {
+        if !self.explode {
+            return Ok(self.process(rec)?.into_iter().collect());
         }
+        self.parts(&rec)?
+            .into_iter()
+            .map(|part| self.put(rec.clone(), part))
+            .collect()
     }
 }
 
+/// Collapses each run of whitespace in a string to a single space and trims both ends, so
+/// messages that only differ in spacing group together.  Values that aren't strings, including
+/// `None`, are left as they are.
 #[derive(Clone)]
-pub struct Where<T: 'static + Evaluatable<bool>> {
-    expr: T,
+pub struct Squeeze {
+    input_column: Expr,
+    output_column: Expr,
 }
 
-impl<T: 'static + Evaluatable<bool>> Where<T> {
-    pub fn new(expr: T) -> Self {
-        Where { expr }
+impl Squeeze {
+    pub fn new(input_column: Expr, output_column: Expr) -> Self {
+        Squeeze {
+            input_column,
+            output_column,
+        }
     }
 }
 
-impl<T: 'static + Evaluatable<bool>> UnaryPreAggFunction for Where<T> {
+impl UnaryPreAggFunction for Squeeze {
     fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
-        if self.expr.eval(&rec.data)? {
-            Ok(Some(rec))
-        } else {
-            Ok(None)
-        }
+        let value: Cow<data::Value> = match self.input_column.eval_borrowed(&rec.data) {
+            Ok(value) => value,
+            // Like None, a missing field has nothing to squeeze
+            Err(EvalError::NoValueForKey { .. }) => return Ok(Some(rec)),
+            Err(e) => return Err(e),
+        };
+        let squeezed = match value.into_owned() {
+            data::Value::Str(s) => {
+                data::Value::Str(s.split_whitespace().collect::<Vec<_>>().join(" "))
+            }
+            other => other,
+        };
+        Ok(Some(rec.put_expr(&self.output_column, squeezed)?))
     }
 }
 
-pub struct TotalDef {
-    column: Expr,
-    output_column: String,
+#[derive(Clone, Copy)]
+pub enum Case {
+    Lower,
+    Upper,
 }
 
-impl TotalDef {
-    pub fn new(column: Expr, output_column: String) -> Self {
-        TotalDef {
-            column,
+/// Lowercases or uppercases the string in a field, using Unicode's case mappings so text
+/// outside ASCII changes too.  Values that aren't strings are left as they are.
+#[derive(Clone)]
+pub struct ChangeCase {
+    case: Case,
+    input_column: Expr,
+    output_column: Expr,
+}
+
+impl ChangeCase {
+    pub fn new(case: Case, input_column: Expr, output_column: Expr) -> Self {
+        ChangeCase {
+            case,
+            input_column,
             output_column,
         }
     }
 }
 
-impl OperatorBuilder for TotalDef {
-    fn build(&self) -> Box<dyn UnaryPreAggOperator> {
-        Box::new(Total::new(self.column.clone(), self.output_column.clone()))
+impl UnaryPreAggFunction for ChangeCase {
+    fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
+        let value: Cow<data::Value> = match self.input_column.eval_borrowed(&rec.data) {
+            Ok(value) => value,
+            Err(EvalError::NoValueForKey { .. }) => return Ok(Some(rec)),
+            Err(e) => return Err(e),
+        };
+        let changed = match (value.into_owned(), self.case) {
+            (data::Value::Str(s), Case::Lower) => data::Value::Str(s.to_lowercase()),
+            (data::Value::Str(s), Case::Upper) => data::Value::Str(s.to_uppercase()),
+            (other, _) => other,
+        };
+        Ok(Some(rec.put_expr(&self.output_column, changed)?))
     }
 }
 
-pub struct Total {
-    column: Expr,
-    total: f64,
-    output_column: String,
+/// Rounds a timestamp down to the start of the interval it's in, written as an RFC 3339 string
+/// so it can be grouped and sorted.  Integers and floats are seconds since the epoch, and strings
+/// are RFC 3339 timestamps.  A value that isn't a timestamp is left as it is, with a warning the
+/// first time.
+#[derive(Clone)]
+pub struct Timeslice {
+    interval: f64,
+    input_column: Expr,
+    output_column: Expr,
+    /// Shared between copies of the operator so the warning is only printed once
+    warned: Arc<AtomicBool>,
 }
 
-impl Total {
-    pub fn new<T: Into<Expr>>(column: T, output_column: String) -> Total {
-        Total {
-            column: column.into(),
-            total: 0.0,
+impl Timeslice {
+    pub fn new(interval: f64, input_column: Expr, output_column: Expr) -> Self {
+        Timeslice {
+            interval,
+            input_column,
             output_column,
+            warned: Arc::new(AtomicBool::new(false)),
         }
     }
+
+    /// The start of the interval `value` is in, if it's a timestamp after the epoch
+    fn bucket(&self, value: &data::Value) -> Option<String> {
+        let secs = writable_timestamp_secs(value)?;
+        let start = (secs / self.interval).floor() * self.interval;
+        Some(format_interval_start(start, self.interval))
+    }
 }
 
-impl UnaryPreAggOperator for Total {
-    fn process_mut(&mut self, rec: Record) -> Result<Option<Record>, EvalError> {
-        // I guess this means there are cases when you need to both emit a warning _and_ a row, TODO
-        // for now, we'll just emit the row
-        let val: f64 = self.column.eval(&rec.data).unwrap_or(0.0);
-        self.total += val;
-        let rec = rec.put(&self.output_column, data::Value::from_float(self.total));
-        Ok(Some(rec))
+/// Writes the start of an interval as an RFC 3339 timestamp, with milliseconds if the interval
+/// isn't a whole number of seconds
+fn format_interval_start(start: f64, interval: f64) -> String {
+    let start = UNIX_EPOCH + Duration::from_secs_f64(start);
+    if interval.fract() == 0.0 {
+        humantime::format_rfc3339_seconds(start).to_string()
+    } else {
+        // Keep the milliseconds of the nine fractional digits before the `Z`
+        let nanos = humantime::format_rfc3339_nanos(start).to_string();
+        format!("{}Z", &nanos[..nanos.len() - 7])
     }
 }
 
+impl UnaryPreAggFunction for Timeslice {
+    fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
+        let value: Cow<data::Value> = match self.input_column.eval_borrowed(&rec.data) {
+            Ok(value) => value,
+            Err(EvalError::NoValueForKey { .. }) => return Ok(Some(rec)),
+            Err(e) => return Err(e),
+        };
+        match self.bucket(&value) {
+            Some(bucket) => Ok(Some(
+                rec.put_expr(&self.output_column, data::Value::Str(bucket))?,
+            )),
+            None => {
+                if !self.warned.swap(true, AtomicOrdering::Relaxed) {
+                    eprintln!(
+                        "warning: `{}` isn't a timestamp, so timeslice left it as it is",
+                        value
+                    );
+                }
+                Ok(Some(rec))
+            }
+        }
+    }
+}
+
+/// Keeps the characters of a string from `start` up to, but not including, `end`.  Negative
+/// offsets count back from the end of the string, and offsets past either end are clamped to it.
+/// Values that aren't strings are left as they are.
 #[derive(Clone)]
-pub struct Split {
-    separator: String,
-    input_column: Option<Expr>,
-    output_column: Option<Expr>,
+pub struct Substring {
+    input_column: Expr,
+    start: i64,
+    end: Option<i64>,
+    output_column: Expr,
 }
 
-impl Split {
-    pub fn new(separator: String, input_column: Option<Expr>, output_column: Option<Expr>) -> Self {
-        Self {
-            separator,
+impl Substring {
+    pub fn new(input_column: Expr, start: i64, end: Option<i64>, output_column: Expr) -> Self {
+        Substring {
             input_column,
+            start,
+            end,
             output_column,
         }
     }
+
+    fn slice(&self, s: &str) -> String {
+        let len = s.chars().count() as i64;
+        let clamp = |offset: i64| {
+            let offset = if offset < 0 { len + offset } else { offset };
+            offset.max(0).min(len) as usize
+        };
+        let start = clamp(self.start);
+        let end = self.end.map_or(len as usize, clamp);
+        s.chars()
+            .skip(start)
+            .take(end.saturating_sub(start))
+            .collect()
+    }
 }
 
-impl UnaryPreAggFunction for Split {
+impl UnaryPreAggFunction for Substring {
     fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
-        let inp = get_input(&rec, &self.input_column)?;
-        let array = split::split_with_delimiters(&inp, &self.separator, &split::DEFAULT_DELIMITERS)
-            .into_iter()
-            .map(data::Value::from_string)
-            .collect();
-        let rec = if let Some(output_column) = &self.output_column {
-            rec.put_expr(output_column, data::Value::Array(array))?
-        } else {
-            rec.put("_split", data::Value::Array(array))
+        let value: Cow<data::Value> = match self.input_column.eval_borrowed(&rec.data) {
+            Ok(value) => value,
+            Err(EvalError::NoValueForKey { .. }) => return Ok(Some(rec)),
+            Err(e) => return Err(e),
         };
-        Ok(Some(rec))
+        let sliced = match value.into_owned() {
+            data::Value::Str(s) => data::Value::Str(self.slice(&s)),
+            other => other,
+        };
+        Ok(Some(rec.put_expr(&self.output_column, sliced)?))
     }
 }
 
@@ -972,6 +3117,97 @@ impl UnaryPreAggFunction for Fields {
     }
 }
 
+/// Sets a field to the result of an expression.  When the expression can't be computed, like
+/// when a field it uses is missing or isn't a number, the field is set to None and a warning is
+/// printed the first time.
+#[derive(Clone)]
+pub struct Eval {
+    output_column: String,
+    expr: Expr,
+    /// Shared between copies of the operator so the warning is only printed once
+    warned: Arc<AtomicBool>,
+}
+
+impl Eval {
+    pub fn new(output_column: String, expr: Expr) -> Self {
+        Eval {
+            output_column,
+            expr,
+            warned: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl UnaryPreAggFunction for Eval {
+    fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
+        let value: Result<Cow<data::Value>, EvalError> = self.expr.eval_borrowed(&rec.data);
+        let value = match value {
+            Ok(value) => value.into_owned(),
+            Err(e) => {
+                if !self.warned.swap(true, AtomicOrdering::Relaxed) {
+                    eprintln!("warning: {}: {}. Setting it to None", self.output_column, e);
+                }
+                data::Value::None
+            }
+        };
+        Ok(Some(rec.put(&self.output_column, value)))
+    }
+}
+
+/// Moves the values of fields to new names.  Every old field is taken out before any new name is
+/// written, so `rename a as b, b as a` swaps them.  A new name replaces a field that's already
+/// there, and when two fields are renamed to the same name the last one wins.  Fields that don't
+/// exist are skipped.
+#[derive(Clone)]
+pub struct Rename {
+    renames: Vec<(String, String)>,
+}
+
+impl Rename {
+    pub fn new(renames: Vec<(String, String)>) -> Self {
+        Rename { renames }
+    }
+}
+
+impl UnaryPreAggFunction for Rename {
+    fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
+        let mut rec = rec;
+        let moved: Vec<(&String, data::Value)> = self
+            .renames
+            .iter()
+            .filter_map(|(old, new)| rec.data.remove(old).map(|value| (new, value)))
+            .collect();
+        for (new, value) in moved {
+            rec.data.insert(new.to_string(), value);
+        }
+        Ok(Some(rec))
+    }
+}
+
+fn json_to_value(v: &JsonValue) -> data::Value {
+    match v {
+        &JsonValue::Number(ref num) => {
+            if num.is_i64() {
+                data::Value::Int(num.as_i64().unwrap())
+            } else {
+                data::Value::from_float(num.as_f64().unwrap())
+            }
+        }
+        &JsonValue::String(ref s) => data::Value::Str(s.to_string()),
+        &JsonValue::Null => data::Value::None,
+        &JsonValue::Bool(b) => data::Value::Bool(b),
+        &JsonValue::Object(ref map) => data::Value::Obj(
+            map.iter()
+                .map(|(k, v)| (k.to_string(), json_to_value(v)))
+                .collect::<HashMap<String, data::Value>>()
+                .into(),
+        ),
+        &JsonValue::Array(ref vec) => {
+            data::Value::Array(vec.iter().map(json_to_value).collect::<Vec<data::Value>>())
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ParseJson {
     input_column: Option<Expr>,
@@ -985,29 +3221,6 @@ impl ParseJson {
 
 impl UnaryPreAggFunction for ParseJson {
     fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
-        fn json_to_value(v: &JsonValue) -> data::Value {
-            match v {
-                &JsonValue::Number(ref num) => {
-                    if num.is_i64() {
-                        data::Value::Int(num.as_i64().unwrap())
-                    } else {
-                        data::Value::from_float(num.as_f64().unwrap())
-                    }
-                }
-                &JsonValue::String(ref s) => data::Value::Str(s.to_string()),
-                &JsonValue::Null => data::Value::None,
-                &JsonValue::Bool(b) => data::Value::Bool(b),
-                &JsonValue::Object(ref map) => data::Value::Obj(
-                    map.iter()
-                        .map(|(k, v)| (k.to_string(), json_to_value(v)))
-                        .collect::<HashMap<String, data::Value>>()
-                        .into(),
-                ),
-                &JsonValue::Array(ref vec) => {
-                    data::Value::Array(vec.iter().map(json_to_value).collect::<Vec<data::Value>>())
-                }
-            }
-        };
         let json: JsonValue = {
             let inp = get_input(&rec, &self.input_column)?;
             serde_json::from_str(&inp).map_err(|_| EvalError::ExpectedJson {
@@ -1036,21 +3249,237 @@ impl ParseLogfmt {
     }
 }
 
-impl UnaryPreAggFunction for ParseLogfmt {
-    fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
-        let pairs = {
-            let inp = get_input(&rec, &self.input_column)?;
-            // Record includes the trailing newline, while logfmt considers that part of the
-            // message if present. Trim any trailing whitespace.
-            logfmt::parse(&inp.trim_end())
-        };
-        let res = {
-            pairs.into_iter().fold(rec, |record, pair| match pair.val {
-                None => record.put(&pair.key, data::Value::None),
-                Some(val) => record.put(&pair.key, data::Value::from_string(val)),
-            })
+impl UnaryPreAggFunction for ParseLogfmt {
+    fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
+        let pairs = {
+            let inp = get_input(&rec, &self.input_column)?;
+            // Record includes the trailing newline, while logfmt considers that part of the
+            // message if present. Trim any trailing whitespace.
+            logfmt::parse(&inp.trim_end())
+        };
+        let res = {
+            pairs.into_iter().fold(rec, |record, pair| match pair.val {
+                None => record.put(&pair.key, data::Value::None),
+                Some(val) => record.put(&pair.key, data::Value::from_string(val)),
+            })
+        };
+        Ok(Some(res))
+    }
+}
+
+/// Split `input` on each `separator` that isn't inside double quotes.  A backslash escapes the
+/// character after it within quotes.
+fn split_unquoted<'a>(input: &'a str, separator: &str) -> Vec<&'a str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if in_quotes {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_quotes = false,
+                _ => (),
+            }
+        } else if c == '"' {
+            in_quotes = true;
+        } else if input[i..].starts_with(separator) {
+            pieces.push(&input[start..i]);
+            start = i + separator.len();
+            // Skip the rest of the separator
+            while chars.as_str().len() > input.len() - start {
+                chars.next();
+            }
+        }
+    }
+    pieces.push(&input[start..]);
+    pieces
+}
+
+/// Remove the double quotes around a value along with the escapes inside them
+fn unquote(value: &str) -> Cow<'_, str> {
+    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+        return Cow::Borrowed(value);
+    }
+    let mut unquoted = String::with_capacity(value.len());
+    let mut chars = value[1..value.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            other => unquoted.push(other),
+        }
+    }
+    Cow::Owned(unquoted)
+}
+
+/// Parses `key<kv_sep>value` pairs separated by `pair_sep`, like logfmt with other delimiters.
+/// Separators inside double quoted values are part of the value.  A pair without `kv_sep` is
+/// a key with no value.
+#[derive(Clone)]
+pub struct ParseKeyValue {
+    pair_separator: String,
+    kv_separator: String,
+    input_column: Option<Expr>,
+}
+
+impl ParseKeyValue {
+    pub fn new(pair_separator: String, kv_separator: String, input_column: Option<Expr>) -> Self {
+        ParseKeyValue {
+            pair_separator,
+            kv_separator,
+            input_column,
+        }
+    }
+}
+
+impl UnaryPreAggFunction for ParseKeyValue {
+    fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
+        let pairs: Vec<(String, data::Value)> = {
+            let inp = get_input(&rec, &self.input_column)?;
+            split_unquoted(inp.trim_end(), &self.pair_separator)
+                .into_iter()
+                .map(str::trim)
+                .filter(|pair| !pair.is_empty())
+                .map(
+                    |pair| match split_unquoted(pair, &self.kv_separator).as_slice() {
+                        [key] => (key.to_string(), data::Value::None),
+                        [key, ..] => {
+                            let value = pair[key.len() + self.kv_separator.len()..].trim();
+                            (
+                                key.trim_end().to_string(),
+                                data::Value::from_string(unquote(value)),
+                            )
+                        }
+                        [] => unreachable!("split_unquoted always returns a piece"),
+                    },
+                )
+                .collect()
+        };
+        Ok(Some(
+            pairs
+                .into_iter()
+                .fold(rec, |record, (key, value)| record.put(&key, value)),
+        ))
+    }
+}
+
+/// Guesses the format of each record and parses it accordingly: lines starting with `{` are
+/// parsed as JSON and lines containing `key=value` pairs are parsed as logfmt.  Bare words in a
+/// logfmt line are ignored.  Lines that don't fit either format are passed through unchanged.
+pub struct AutoParse;
+
+impl UnaryPreAggFunction for AutoParse {
+    fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
+        if rec.raw.trim_start().starts_with('{') {
+            return Ok(Some(match serde_json::from_str(&rec.raw) {
+                Ok(JsonValue::Object(map)) => map
+                    .iter()
+                    .fold(rec, |record, (k, v)| record.put(k, json_to_value(v))),
+                _ => rec,
+            }));
+        }
+        if !rec.raw.contains('=') {
+            return Ok(Some(rec));
+        }
+        let pairs = logfmt::parse(rec.raw.trim_end());
+        Ok(Some(pairs.into_iter().fold(
+            rec,
+            |record, pair| match pair.val {
+                Some(val) => record.put(&pair.key, data::Value::from_string(val)),
+                None => record,
+            },
+        )))
+    }
+}
+
+/// Adds the fields of a row from another query to each record whose `key` field has the same
+/// value.  Values are compared as they're rendered, so `7` parsed from logfmt matches `7` parsed
+/// from JSON.  Records without a match, and fields the record already has, are left as they are.
+pub struct Join {
+    key: String,
+    rows: HashMap<String, data::VMap>,
+}
+
+impl Join {
+    /// When several rows have the same key, the first one is used
+    pub fn new(key: String, rows: Vec<data::VMap>) -> Self {
+        let mut by_key = HashMap::new();
+        for row in rows {
+            if let Some(value) = row.get(&key) {
+                by_key
+                    .entry(value.render(&RenderConfig::default()))
+                    .or_insert(row);
+            }
+        }
+        Join { key, rows: by_key }
+    }
+}
+
+impl UnaryPreAggFunction for Join {
+    fn process(&self, rec: Record) -> Result<Option<Record>, EvalError> {
+        let row = match rec.data.get(&self.key) {
+            Some(value) => self.rows.get(&value.render(&RenderConfig::default())),
+            None => None,
+        };
+        Ok(Some(match row {
+            Some(row) => row.iter().fold(rec, |rec, (field, value)| {
+                if rec.data.contains_key(field) {
+                    rec
+                } else {
+                    rec.put(field, value.clone())
+                }
+            }),
+            None => rec,
+        }))
+    }
+}
+
+/// The most distinct values `PerKeyLimit` counts at once.  Reaching it starts a new window
+/// early, so a field with unbounded values, like a request id, can't grow the state forever.
+pub const PER_KEY_LIMIT_MAX_KEYS: usize = 10_000;
+
+/// Passes at most `limit` records for each value of `field`, then drops the rest until `reset`
+/// has passed and the counts start over.  Records without the field share one count.
+pub struct PerKeyLimit {
+    field: String,
+    limit: u64,
+    reset: Duration,
+    counts: HashMap<String, u64>,
+    window_start: Instant,
+}
+
+impl PerKeyLimit {
+    pub fn new(field: String, limit: u64, reset: Duration) -> Self {
+        PerKeyLimit {
+            field,
+            limit,
+            reset,
+            counts: HashMap::new(),
+            window_start: Instant::now(),
+        }
+    }
+}
+
+impl UnaryPreAggOperator for PerKeyLimit {
+    fn process_mut(&mut self, rec: Record) -> Result<Option<Record>, EvalError> {
+        let key = match rec.data.get(&self.field) {
+            Some(value) => value.render(&RenderConfig::default()),
+            None => "None".to_string(),
         };
-        Ok(Some(res))
+        let full = self.counts.len() >= PER_KEY_LIMIT_MAX_KEYS && !self.counts.contains_key(&key);
+        if full || self.window_start.elapsed() >= self.reset {
+            self.counts.clear();
+            self.window_start = Instant::now();
+        }
+        let count = self.counts.entry(key).or_insert(0);
+        *count += 1;
+        if *count <= self.limit {
+            Ok(Some(rec))
+        } else {
+            Ok(None)
+        }
     }
 }
 
@@ -1136,6 +3565,13 @@ impl UnaryPreAggOperator for Limit {
             Limit::Tail { queue, .. } => Box::new(queue.into_iter()),
         }
     }
+
+    fn is_done(&self) -> bool {
+        match self {
+            Limit::Head { index, limit } => index >= limit,
+            Limit::Tail { .. } => false,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1171,8 +3607,136 @@ mod tests {
             head: "k1".to_string(),
             rest: vec![ValueRef::Field("k2".to_string())],
         };
-        let data: &data::Value = expr.eval_borrowed(&rec.data).unwrap();
-        assert_eq!(data, &data::Value::from_float(5.5));
+        let data: Cow<data::Value> = expr.eval_borrowed(&rec.data).unwrap();
+        assert_eq!(*data, data::Value::from_float(5.5));
+    }
+
+    #[test]
+    fn age_of_timestamp() {
+        assert_eq!(
+            timestamp_secs(&data::Value::Str("2019-04-01T12:30:00Z".to_string())),
+            Some(1_554_121_800.0)
+        );
+        assert_eq!(
+            timestamp_secs(&data::Value::Str("2019-04-01 12:30:00.5".to_string())),
+            Some(1_554_121_800.5)
+        );
+        assert_eq!(
+            timestamp_secs(&data::Value::Str("yesterday".to_string())),
+            None
+        );
+
+        let age = FunctionCall {
+            function: Function::Age,
+            args: vec![Expr::column("ts")],
+        };
+        let minute_ago = data::Value::Int(unix_now() as i64 - 60);
+        match age
+            .eval(&hashmap! {"ts".to_string() => minute_ago})
+            .unwrap()
+        {
+            data::Value::Int(secs) => assert!((59..=62).contains(&secs), "{}", secs),
+            data::Value::Float(OrderedFloat(secs)) => {
+                assert!((59.0..=62.0).contains(&secs), "{}", secs)
+            }
+            other => panic!("unexpected age {:?}", other),
+        }
+        assert_eq!(
+            age.eval(&hashmap! {"ts".to_string() => data::Value::Str("soon".to_string())}),
+            Ok(data::Value::None)
+        );
+        assert_eq!(age.eval(&HashMap::new()), Ok(data::Value::None));
+    }
+
+    #[test]
+    fn json_pointer_paths() {
+        let rec = ParseJson::new(None)
+            .process(Record::new(
+                r#"{"doc": {"a": {"b": [{"c": 5}, 7]}, "x/y": 1, "m~n": 2, "": 3}}"#,
+            ))
+            .unwrap()
+            .unwrap();
+        let get = |pointer: &str| {
+            FunctionCall {
+                function: Function::JsonGet,
+                args: vec![
+                    Expr::column("doc"),
+                    Expr::Value(Box::leak(Box::new(data::Value::Str(pointer.to_string())))),
+                ],
+            }
+            .eval(&rec.data)
+            .unwrap()
+        };
+        assert_eq!(get("/a/b/0/c"), data::Value::Int(5));
+        assert_eq!(get("/a/b/1"), data::Value::Int(7));
+        assert_eq!(get("/x~1y"), data::Value::Int(1));
+        assert_eq!(get("/m~0n"), data::Value::Int(2));
+        assert_eq!(get("/"), data::Value::Int(3));
+        assert_eq!(get(""), rec.data["doc"]);
+        assert_eq!(get("/a/b/2"), data::Value::None);
+        assert_eq!(get("/a/b/01"), data::Value::None);
+        assert_eq!(get("/a/b/-"), data::Value::None);
+        assert_eq!(get("/a/b/0/c/d"), data::Value::None);
+        assert_eq!(get("a/b"), data::Value::None);
+    }
+
+    #[test]
+    fn type_names() {
+        let rec = ParseJson::new(None)
+            .process(Record::new(
+                r#"{"i": 1, "f": 1.5, "s": "1", "b": true, "n": null, "l": [1], "o": {"a": 1}}"#,
+            ))
+            .unwrap()
+            .unwrap();
+        let type_of = |column: &str| {
+            FunctionCall {
+                function: Function::TypeOf,
+                args: vec![Expr::column(column)],
+            }
+            .eval(&rec.data)
+            .unwrap()
+        };
+        let names: Vec<data::Value> = ["i", "f", "s", "b", "n", "l", "o", "missing"]
+            .iter()
+            .map(|column| type_of(column))
+            .collect();
+        assert_eq!(
+            names,
+            ["int", "float", "string", "bool", "null", "list", "object", "null"]
+                .iter()
+                .map(|name| data::Value::Str(name.to_string()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn split_explode_writes_record_per_part() {
+        let rec = Record::new("INFO a, b,,c")
+            .put("level", data::Value::from_string("INFO"))
+            .put("tags", data::Value::from_string("a, b,,c"));
+        let tags = Some(Expr::column("tags"));
+        let split = Split::new(",".to_string(), tags.clone(), tags.clone(), true);
+        let recs = UnaryPreAggFunction::process_all(&split, rec.clone()).unwrap();
+        assert_eq!(
+            recs.iter()
+                .map(|rec| rec.data["tags"].clone())
+                .collect::<Vec<_>>(),
+            vec![
+                data::Value::from_string("a"),
+                data::Value::from_string("b"),
+                data::Value::from_string("c"),
+            ]
+        );
+        for out in &recs {
+            assert_eq!(out.data["level"], data::Value::from_string("INFO"));
+            assert_eq!(out.raw, rec.raw);
+        }
+
+        let split = Split::new(",".to_string(), tags.clone(), tags, false);
+        assert_eq!(
+            UnaryPreAggFunction::process_all(&split, rec).unwrap().len(),
+            1
+        );
     }
 
     #[test]
@@ -1186,7 +3750,7 @@ mod tests {
             head: "k1".to_string(),
             rest: vec![ValueRef::Field("k11".to_string())],
         };
-        let res: Result<&data::Value, EvalError> = expr.eval_borrowed(&rec.data);
+        let res: Result<Cow<data::Value>, EvalError> = expr.eval_borrowed(&rec.data);
         match res {
             Ok(_) => assert_eq!(false, true),
             Err(eval_error) => assert_eq!(
@@ -1255,6 +3819,256 @@ mod tests {
         );
     }
 
+    #[test]
+    fn key_value() {
+        let line = r#"k1:5; k2:"a;b:c" ;k3:; bare; k4: "say \"hi\"" "#.to_string() + "\n";
+        let parser = ParseKeyValue::new(";".to_string(), ":".to_string(), None);
+        let rec = parser.process(Record::new(&line)).unwrap().unwrap();
+        assert_eq!(
+            rec.data,
+            hashmap! {
+                "k1".to_string() => Value::Int(5),
+                "k2".to_string() => Value::Str("a;b:c".to_string()),
+                "k3".to_string() => Value::Str("".to_string()),
+                "bare".to_string() => Value::None,
+                "k4".to_string() => Value::Str("say \"hi\"".to_string()),
+            }
+        );
+
+        // Multi-character separators and the logfmt-style defaults both work
+        let parser = ParseKeyValue::new(" && ".to_string(), "=>".to_string(), None);
+        let rec = parser
+            .process(Record::new("a=>1 && b=>x y"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rec.data,
+            hashmap! {
+                "a".to_string() => Value::Int(1),
+                "b".to_string() => Value::Str("x y".to_string()),
+            }
+        );
+        let parser = ParseKeyValue::new(" ".to_string(), "=".to_string(), None);
+        let rec = parser
+            .process(Record::new("a=1   b=\"x y\"\n"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rec.data,
+            hashmap! {
+                "a".to_string() => Value::Int(1),
+                "b".to_string() => Value::Str("x y".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn auto_parse() {
+        let parse = |line: &str| AutoParse.process(Record::new(line)).unwrap().unwrap().data;
+        assert_eq!(
+            parse("{\"k1\": 5, \"k2\": \"str\"}\n"),
+            hashmap! {
+                "k1".to_string() => Value::Int(5),
+                "k2".to_string() => Value::Str("str".to_string()),
+            }
+        );
+        assert_eq!(
+            parse("INFO k1=5 k2=\"a str\"\n"),
+            hashmap! {
+                "k1".to_string() => Value::Int(5),
+                "k2".to_string() => Value::Str("a str".to_string()),
+            }
+        );
+        assert_eq!(parse("{not json\n"), hashmap! {});
+        assert_eq!(parse("just some text\n"), hashmap! {});
+    }
+
+    #[test]
+    fn squeeze_whitespace() {
+        let squeeze = Squeeze::new(Expr::column("msg"), Expr::column("msg"));
+        let squeezed = |value: Value| {
+            let rec = Record::new("").put("msg", value);
+            squeeze.process(rec).unwrap().unwrap().data["msg"].clone()
+        };
+        assert_eq!(
+            squeezed(Value::Str("  user\t logged   in \n".to_string())),
+            Value::Str("user logged in".to_string())
+        );
+        assert_eq!(squeezed(Value::None), Value::None);
+        assert_eq!(squeezed(Value::Int(5)), Value::Int(5));
+        let missing = squeeze.process(Record::new("")).unwrap().unwrap();
+        assert_eq!(missing.data, hashmap! {});
+    }
+
+    #[test]
+    fn change_case() {
+        let lower = ChangeCase::new(Case::Lower, Expr::column("v"), Expr::column("v"));
+        let upper = ChangeCase::new(Case::Upper, Expr::column("v"), Expr::column("out"));
+        let changed = |op: &ChangeCase, value: Value| {
+            let rec = Record::new("").put("v", value);
+            op.process(rec).unwrap().unwrap().data
+        };
+        assert_eq!(
+            changed(&lower, Value::Str("Ünïcode HOST".to_string()))["v"],
+            Value::Str("ünïcode host".to_string())
+        );
+        assert_eq!(
+            changed(&upper, Value::Str("straße".to_string())),
+            hashmap! {
+                "v".to_string() => Value::Str("straße".to_string()),
+                "out".to_string() => Value::Str("STRASSE".to_string()),
+            }
+        );
+        assert_eq!(changed(&lower, Value::Int(5))["v"], Value::Int(5));
+        assert_eq!(changed(&upper, Value::None)["out"], Value::None);
+        let missing = lower.process(Record::new("")).unwrap().unwrap();
+        assert_eq!(missing.data, hashmap! {});
+    }
+
+    #[test]
+    fn timeslice() {
+        let sliced = |interval, value: Value| {
+            let timeslice = Timeslice::new(interval, Expr::column("ts"), Expr::column("ts"));
+            let rec = Record::new("").put("ts", value);
+            timeslice.process(rec).unwrap().unwrap().data["ts"].clone()
+        };
+        assert_eq!(
+            sliced(300.0, Value::Int(1_500_000_123)),
+            Value::Str("2017-07-14T02:40:00Z".to_string())
+        );
+        assert_eq!(
+            sliced(60.0, Value::Str("2017-07-14T02:42:03.5Z".to_string())),
+            Value::Str("2017-07-14T02:42:00Z".to_string())
+        );
+        assert_eq!(
+            sliced(0.5, Value::from_float(1_500_000_000.7)),
+            Value::Str("2017-07-14T02:40:00.500Z".to_string())
+        );
+        assert_eq!(
+            sliced(86400.0, Value::Str("yesterday".to_string())),
+            Value::Str("yesterday".to_string())
+        );
+        // Milliseconds, or anything else past the year 9999, are left alone rather than panicking
+        for ts in &[1_551_434_700_000, 10_000_000_000_000, 253_402_300_800] {
+            assert_eq!(sliced(60.0, Value::Int(*ts)), Value::Int(*ts));
+        }
+        assert_eq!(
+            sliced(60.0, Value::from_float(1e20)),
+            Value::from_float(1e20)
+        );
+        assert_eq!(
+            sliced(1.0, Value::Int(253_402_300_799)),
+            Value::Str("9999-12-31T23:59:59Z".to_string())
+        );
+    }
+
+    #[test]
+    fn substring() {
+        let sliced = |start, end, value: Value| {
+            let substring = Substring::new(Expr::column("v"), start, end, Expr::column("v"));
+            let rec = Record::new("").put("v", value);
+            substring.process(rec).unwrap().unwrap().data["v"].clone()
+        };
+        let id = || Value::Str("req-ünï-42".to_string());
+        assert_eq!(sliced(4, None, id()), Value::Str("ünï-42".to_string()));
+        assert_eq!(sliced(4, Some(7), id()), Value::Str("ünï".to_string()));
+        assert_eq!(sliced(-2, None, id()), Value::Str("42".to_string()));
+        assert_eq!(sliced(0, Some(-3), id()), Value::Str("req-ünï".to_string()));
+        // Offsets past the ends are clamped, and an empty range is an empty string
+        assert_eq!(sliced(-50, Some(50), id()), id());
+        assert_eq!(sliced(8, Some(2), id()), Value::Str("".to_string()));
+        assert_eq!(sliced(1, None, Value::Int(12345)), Value::Int(12345));
+    }
+
+    #[test]
+    fn join_rows() {
+        let join = Join::new(
+            "id".to_string(),
+            vec![
+                hashmap! {
+                    "id".to_string() => Value::Int(7),
+                    "name".to_string() => Value::Str("first".to_string()),
+                    "level".to_string() => Value::Str("side".to_string()),
+                },
+                hashmap! {
+                    "id".to_string() => Value::Int(7),
+                    "name".to_string() => Value::Str("second".to_string()),
+                },
+                hashmap! {"name".to_string() => Value::Str("no id".to_string())},
+            ],
+        );
+        let joined = |rec: Record| join.process(rec).unwrap().unwrap().data;
+        assert_eq!(
+            joined(
+                Record::new("")
+                    .put("id", Value::Str("7".to_string()))
+                    .put("level", Value::Str("info".to_string()))
+            ),
+            hashmap! {
+                "id".to_string() => Value::Str("7".to_string()),
+                "name".to_string() => Value::Str("first".to_string()),
+                "level".to_string() => Value::Str("info".to_string()),
+            }
+        );
+        assert_eq!(
+            joined(Record::new("").put("id", Value::Int(8))),
+            hashmap! {"id".to_string() => Value::Int(8)}
+        );
+        assert_eq!(joined(Record::new("")), hashmap! {});
+    }
+
+    #[test]
+    fn limit_is_done_after_its_rows() {
+        let mut limit = LimitDef::new(2).build();
+        assert!(!limit.is_done());
+        assert!(limit.process_mut(Record::new("a")).unwrap().is_some());
+        assert!(!limit.is_done());
+        assert!(limit.process_mut(Record::new("b")).unwrap().is_some());
+        assert!(limit.is_done());
+
+        let mut tail = LimitDef::new(-1).build();
+        tail.process_mut(Record::new("a")).unwrap();
+        tail.process_mut(Record::new("b")).unwrap();
+        assert!(!tail.is_done());
+    }
+
+    #[test]
+    fn per_key_limit() {
+        let host = |host: &str| Record::new("").put("host", Value::Str(host.to_string()));
+        let mut limit = PerKeyLimit::new("host".to_string(), 2, Duration::from_secs(3600));
+        let passed: Vec<bool> = vec![
+            host("a"),
+            host("a"),
+            host("b"),
+            host("a"),
+            Record::new(""),
+            host("b"),
+            host("b"),
+        ]
+        .into_iter()
+        .map(|rec| limit.process_mut(rec).unwrap().is_some())
+        .collect();
+        assert_eq!(passed, vec![true, true, true, false, true, true, false]);
+
+        // Every record starts a new window, so nothing is dropped
+        let mut limit = PerKeyLimit::new("host".to_string(), 1, Duration::from_secs(0));
+        assert!(limit.process_mut(host("a")).unwrap().is_some());
+        assert!(limit.process_mut(host("a")).unwrap().is_some());
+    }
+
+    #[test]
+    fn per_key_limit_bounds_keys() {
+        let mut limit = PerKeyLimit::new("id".to_string(), 1, Duration::from_secs(3600));
+        for id in 0..PER_KEY_LIMIT_MAX_KEYS {
+            let rec = Record::new("").put("id", Value::Int(id as i64));
+            assert!(limit.process_mut(rec).unwrap().is_some());
+        }
+        assert_eq!(limit.counts.len(), PER_KEY_LIMIT_MAX_KEYS);
+        let rec = Record::new("").put("id", Value::Int(-1));
+        assert!(limit.process_mut(rec).unwrap().is_some());
+        assert_eq!(limit.counts.len(), 1);
+    }
+
     #[test]
     fn fields_only() {
         let rec = Record::new("");
@@ -1306,21 +4120,276 @@ mod tests {
                 "ignore".to_string(),
                 "length".to_string(),
             ],
-            None,
+            vec![],
+            ParseOptions {
+                drop_nonmatching: true,
+            },
+        );
+        let rec = parser.process(rec).unwrap().unwrap();
+        assert_eq!(
+            rec.data.get("sender").unwrap(),
+            &Value::Str("10.0.2.243.53938".to_string())
+        );
+        assert_eq!(rec.data.get("length").unwrap(), &Value::Int(99));
+        assert_eq!(
+            rec.data.get("recip").unwrap(),
+            &Value::Str("taotie.canonical.com.http".to_string())
+        )
+    }
+
+    #[test]
+    fn eval_logical() {
+        let logical = |operator, left: Expr, right: Expr| {
+            Expr::Logical(BinaryExpr::<LogicalExpr> {
+                operator,
+                left: Box::new(left),
+                right: Box::new(right),
+            })
+        };
+        let rec = Record::new("")
+            .put("yes", Value::Bool(true))
+            .put("no", Value::Bool(false))
+            .put("null", Value::None)
+            .put("n", Value::Int(1));
+        let eval = |expr: Expr| -> Result<bool, EvalError> { expr.eval(&rec.data) };
+        let column = Expr::column;
+
+        assert!(eval(logical(LogicalExpr::And, column("yes"), column("yes"))).unwrap());
+        assert!(!eval(logical(LogicalExpr::And, column("yes"), column("no"))).unwrap());
+        assert!(eval(logical(LogicalExpr::Or, column("no"), column("yes"))).unwrap());
+        assert!(!eval(logical(LogicalExpr::Or, column("no"), column("no"))).unwrap());
+        // Missing fields and None are false
+        assert!(!eval(logical(LogicalExpr::Or, column("missing"), column("null"))).unwrap());
+        assert!(eval(logical(LogicalExpr::Or, column("missing"), column("yes"))).unwrap());
+        // The right side isn't evaluated when the left side decides the result
+        assert!(!eval(logical(LogicalExpr::And, column("no"), column("n"))).unwrap());
+        assert!(eval(logical(LogicalExpr::And, column("yes"), column("n"))).is_err());
+    }
+
+    #[test]
+    fn eval_arithmetic() {
+        let column = |name: &str| Box::new(Expr::column(name));
+        let arithmetic = |operator, left, right| {
+            Expr::Arithmetic(BinaryExpr::<ArithmeticExpr> {
+                operator,
+                left,
+                right,
+            })
+        };
+        let rec = Record::new("")
+            .put("a", Value::Int(7))
+            .put("b", Value::Int(2))
+            .put("c", Value::from_float(0.5))
+            .put("zero", Value::Int(0))
+            .put("big", Value::Int(i64::MAX))
+            .put("s", Value::Str("x".to_string()));
+        let eval = |expr: Expr| Eval::new("out".to_string(), expr).process(rec.clone());
+        let result = |expr: Expr| eval(expr).unwrap().unwrap().data["out"].clone();
+
+        assert_eq!(
+            result(arithmetic(ArithmeticExpr::Add, column("a"), column("b"))),
+            Value::Int(9)
+        );
+        assert_eq!(
+            result(arithmetic(ArithmeticExpr::Divide, column("a"), column("b"))),
+            Value::from_float(3.5)
+        );
+        assert_eq!(
+            result(arithmetic(
+                ArithmeticExpr::Divide,
+                Box::new(arithmetic(
+                    ArithmeticExpr::Multiply,
+                    column("a"),
+                    column("b")
+                )),
+                column("b")
+            )),
+            Value::Int(7)
+        );
+        assert_eq!(
+            result(arithmetic(
+                ArithmeticExpr::Subtract,
+                column("a"),
+                column("c")
+            )),
+            Value::from_float(6.5)
+        );
+        // An integer result that overflows is a float rather than a saturated integer
+        assert_eq!(
+            result(arithmetic(
+                ArithmeticExpr::Multiply,
+                column("big"),
+                column("big")
+            )),
+            Value::Float(OrderedFloat(i64::MAX as f64 * i64::MAX as f64))
+        );
+        assert_eq!(
+            result(arithmetic(ArithmeticExpr::Add, column("big"), column("a"))),
+            Value::Float(OrderedFloat(i64::MAX as f64 + 7.0))
+        );
+        // Dividing by zero, missing fields and strings are all None
+        assert_eq!(
+            result(arithmetic(
+                ArithmeticExpr::Divide,
+                column("a"),
+                column("zero")
+            )),
+            Value::None
+        );
+        assert_eq!(
+            result(arithmetic(
+                ArithmeticExpr::Add,
+                column("a"),
+                column("missing")
+            )),
+            Value::None
+        );
+        assert_eq!(
+            result(arithmetic(
+                ArithmeticExpr::Multiply,
+                column("s"),
+                column("b")
+            )),
+            Value::None
+        );
+    }
+
+    #[test]
+    fn rename() {
+        let rename = |renames: &[(&str, &str)], rec: Record| {
+            let renames = renames
+                .iter()
+                .map(|(old, new)| (old.to_string(), new.to_string()))
+                .collect();
+            Rename::new(renames).process(rec).unwrap().unwrap().data
+        };
+        let rec = || {
+            Record::new("")
+                .put("a", Value::Int(1))
+                .put("b", Value::Int(2))
+                .put("c", Value::Int(3))
+        };
+        assert_eq!(
+            rename(&[("a", "x"), ("missing", "y")], rec()),
+            hashmap! {
+                "x".to_string() => Value::Int(1),
+                "b".to_string() => Value::Int(2),
+                "c".to_string() => Value::Int(3),
+            }
+        );
+        // Swaps work, and renaming onto an existing field replaces it
+        assert_eq!(
+            rename(&[("a", "b"), ("b", "a"), ("c", "a")], rec()),
+            hashmap! {
+                "a".to_string() => Value::Int(3),
+                "b".to_string() => Value::Int(1),
+            }
+        );
+    }
+
+    #[test]
+    fn extract() {
+        let regex = regex::Regex::new(r"(?P<user>\w+) logged in( from (?P<ip>\S+))?").unwrap();
+        let extract = Extract::new(regex.clone(), None, false);
+        let rec = extract
+            .process(Record::new("alice logged in from 10.0.0.1\n"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rec.data,
+            hashmap! {
+                "user".to_string() => Value::Str("alice".to_string()),
+                "ip".to_string() => Value::Str("10.0.0.1".to_string()),
+            }
+        );
+        // Groups that didn't take part in the match aren't added
+        let rec = extract
+            .process(Record::new("bob logged in"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            rec.data,
+            hashmap! {"user".to_string() => Value::Str("bob".to_string())}
+        );
+
+        let rec = extract.process(Record::new("logged out")).unwrap().unwrap();
+        assert!(rec.data.is_empty());
+        let extract = Extract::new(regex, None, true);
+        assert!(extract
+            .process(Record::new("logged out"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn parse_typed_fields() {
+        let parser = Parse::new(
+            lang::Keyword::new_wildcard("* * *".to_string()).to_regex(),
+            vec!["id".to_string(), "amount".to_string(), "n".to_string()],
+            vec![],
+            ParseOptions {
+                drop_nonmatching: true,
+            },
+        )
+        .with_types(hashmap! {
+            "id".to_string() => CaptureType::String,
+            "amount".to_string() => CaptureType::Number,
+        });
+        let parsed = |line: &str| parser.process(Record::new(line)).unwrap().unwrap().data;
+        assert_eq!(
+            parsed("007 12.5 3"),
+            hashmap! {
+                "id".to_string() => Value::Str("007".to_string()),
+                "amount".to_string() => Value::from_float(12.5),
+                "n".to_string() => Value::Int(3),
+            }
+        );
+        assert!(parser.warned.lock().unwrap().is_empty());
+
+        // A capture that isn't a number stays a string, and the field warns once
+        assert_eq!(
+            parsed("008 true 4")["amount"],
+            Value::Str("true".to_string())
+        );
+        assert_eq!(
+            *parser.warned.lock().unwrap(),
+            ["amount".to_string()].iter().cloned().collect()
+        );
+    }
+
+    #[test]
+    fn parse_greedy_rest() {
+        let parser = Parse::new(
+            lang::Keyword::new_wildcard("* [*] *".to_string()).to_regex(),
+            vec![
+                "level".to_string(),
+                "module".to_string(),
+                "rest".to_string(),
+            ],
+            vec![],
             ParseOptions {
                 drop_nonmatching: true,
             },
         );
-        let rec = parser.process(rec).unwrap().unwrap();
+        let parsed = |line: &str| {
+            let rec = parser.process(Record::new(line)).unwrap().unwrap();
+            (rec.data["level"].clone(), rec.data["rest"].clone())
+        };
+        // The earlier captures stop at the first delimiter, and the last one keeps the rest
         assert_eq!(
-            rec.data.get("sender").unwrap(),
-            &Value::Str("10.0.2.243.53938".to_string())
+            parsed("WARN [db] retrying [conn 2] in 5 s"),
+            (
+                Value::Str("WARN".to_string()),
+                Value::Str("retrying [conn 2] in 5 s".to_string())
+            )
         );
-        assert_eq!(rec.data.get("length").unwrap(), &Value::Int(99));
         assert_eq!(
-            rec.data.get("recip").unwrap(),
-            &Value::Str("taotie.canonical.com.http".to_string())
-        )
+            parsed("ERROR [api] failed:\n  at handler.rs:12"),
+            (
+                Value::Str("ERROR".to_string()),
+                Value::Str("failed:\n  at handler.rs:12".to_string())
+            )
+        );
     }
 
     #[test]
@@ -1329,7 +4398,7 @@ mod tests {
         let parser = Parse::new(
             lang::Keyword::new_wildcard("IP *".to_string()).to_regex(),
             vec!["ip".to_string()],
-            None,
+            vec![],
             ParseOptions {
                 drop_nonmatching: true,
             },
@@ -1344,7 +4413,7 @@ mod tests {
         let parser = Parse::new(
             lang::Keyword::new_wildcard("IP *".to_string()).to_regex(),
             vec!["ip".to_string()],
-            None,
+            vec![],
             ParseOptions {
                 drop_nonmatching: false,
             },
@@ -1353,13 +4422,43 @@ mod tests {
         assert_eq!(rec.data.get("ip").unwrap(), &Value::None);
     }
 
+    #[test]
+    fn parse_defaults() {
+        let parser = Parse::new(
+            lang::Keyword::new_wildcard("status=* user=*".to_string()).to_regex(),
+            vec!["status".to_string(), "user".to_string()],
+            vec![],
+            ParseOptions {
+                drop_nonmatching: false,
+            },
+        )
+        .with_defaults(HashMap::from_iter(vec![(
+            "user".to_string(),
+            Value::Str("anon".to_string()),
+        )]));
+        let rec = parser
+            .process(Record::new("status= user="))
+            .unwrap()
+            .unwrap();
+        assert_eq!(rec.data["status"], Value::Str("".to_string()));
+        assert_eq!(rec.data["user"], Value::Str("anon".to_string()));
+        let rec = parser
+            .process(Record::new("status=200 user=bob"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(rec.data["user"], Value::Str("bob".to_string()));
+        let rec = parser.process(Record::new("abcd")).unwrap().unwrap();
+        assert_eq!(rec.data["status"], Value::None);
+        assert_eq!(rec.data["user"], Value::Str("anon".to_string()));
+    }
+
     #[test]
     fn parse_nodrop_preserve_existing() {
         let rec = Record::new("abcd 1234").put("ip", Value::Str("127.0.0.1".to_string()));
         let parser = Parse::new(
             lang::Keyword::new_wildcard("IP *".to_string()).to_regex(),
             vec!["ip".to_string()],
-            None,
+            vec![],
             ParseOptions {
                 drop_nonmatching: false,
             },
@@ -1378,7 +4477,7 @@ mod tests {
         let parser = Parse::new(
             lang::Keyword::new_wildcard("[*=*]".to_string()).to_regex(),
             vec!["key".to_string(), "value".to_string()],
-            Some("from_col".into()),
+            vec!["from_col".into()],
             ParseOptions {
                 drop_nonmatching: true,
             },
@@ -1394,6 +4493,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_from_several_fields() {
+        let parser = Parse::new(
+            lang::Keyword::new_wildcard("[*=*]".to_string()).to_regex(),
+            vec!["key".to_string(), "value".to_string()],
+            vec!["first".into(), "second".into()],
+            ParseOptions {
+                drop_nonmatching: true,
+            },
+        );
+        let rec = Record::new("")
+            .put("first", data::Value::Str("no match".to_string()))
+            .put("second", data::Value::Str("[k2=v2]".to_string()));
+        let rec = parser.process(rec).unwrap().unwrap();
+        assert_eq!(
+            rec.data.get("key").unwrap(),
+            &data::Value::Str("k2".to_string())
+        );
+        // A missing field is skipped, and the first match wins
+        let rec = Record::new("")
+            .put("second", data::Value::Str("[k2=v2]".to_string()))
+            .put("third", data::Value::Str("[k3=v3]".to_string()));
+        let rec = parser.process(rec).unwrap().unwrap();
+        assert_eq!(
+            rec.data.get("value").unwrap(),
+            &data::Value::Str("v2".to_string())
+        );
+        let rec = Record::new("").put("first", data::Value::Str("none".to_string()));
+        assert_eq!(parser.process(rec).unwrap(), None);
+    }
+
     #[test]
     fn count_no_groups() {
         let ops: Vec<(String, Box<dyn AggregateFunction>)> =
@@ -1417,6 +4547,135 @@ mod tests {
         );
     }
 
+    #[test]
+    fn count_distinct_warns_over_limit() {
+        let ops: Vec<(String, Box<dyn AggregateFunction>)> = vec![(
+            "_countDistinct".to_string(),
+            Box::new(CountDistinct::empty("ip")),
+        )];
+        let mut grouper = MultiGrouper::new(&[Expr::column("code")], vec!["code".to_string()], ops);
+        grouper.warn_distinct_over(2);
+        for (code, ip) in &[(200, "a"), (200, "b"), (500, "a"), (500, "b"), (200, "a")] {
+            grouper.process(Row::Record(
+                Record::new("")
+                    .put("code", data::Value::Int(*code))
+                    .put("ip", data::Value::Str(ip.to_string())),
+            ));
+        }
+        assert!(grouper.reported.is_empty());
+        grouper.process(Row::Record(
+            Record::new("")
+                .put("code", data::Value::Int(500))
+                .put("ip", data::Value::Str("c".to_string())),
+        ));
+        assert_eq!(
+            grouper.reported,
+            ["_countDistinct".to_string()].iter().cloned().collect()
+        );
+        let mut counts = grouper.emit().data;
+        counts.sort_by_key(|row| row["code"].clone());
+        assert_eq!(
+            counts,
+            vec![
+                hashmap! {
+                    "code".to_string() => data::Value::Int(200),
+                    "_countDistinct".to_string() => data::Value::Int(2),
+                },
+                hashmap! {
+                    "code".to_string() => data::Value::Int(500),
+                    "_countDistinct".to_string() => data::Value::Int(3),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn numeric_aggregates_reject_booleans() {
+        let mut sum = Sum::empty("v1");
+        let rec = Record::new("").put("v1", data::Value::Bool(true));
+        assert_eq!(
+            sum.process(&rec.data),
+            Err(EvalError::BooleanNotNumber { value: true })
+        );
+        assert_eq!(sum.emit(), data::Value::Int(0));
+
+        let ops: Vec<(String, Box<dyn AggregateFunction>)> = vec![
+            ("_count".to_string(), Box::new(Count::new())),
+            ("_sum".to_string(), Box::new(Sum::empty("v1"))),
+        ];
+        let mut grouper = MultiGrouper::new(&[], vec![], ops);
+        for b in &[true, false, true] {
+            grouper.process(Row::Record(
+                Record::new("").put("v1", data::Value::Bool(*b)),
+            ));
+        }
+        // Only the column that needs numbers reports the error, and only once
+        assert_eq!(
+            grouper.reported,
+            ["_sum".to_string()].iter().cloned().collect()
+        );
+        assert_eq!(
+            grouper.emit().data,
+            vec![hashmap! {
+                "_count".to_string() => data::Value::Int(3),
+                "_sum".to_string() => data::Value::Int(0),
+            }]
+        );
+    }
+
+    #[test]
+    fn min_max_numbers_and_strings() {
+        let extremes = |values: Vec<data::Value>| {
+            let mut min = Min::empty("v");
+            let mut max = Max::empty("v");
+            for value in values {
+                let data = hashmap! {"v".to_string() => value};
+                min.process(&data).unwrap();
+                max.process(&data).unwrap();
+            }
+            (min.emit(), max.emit())
+        };
+        assert_eq!(
+            extremes(vec![
+                data::Value::Int(3),
+                data::Value::from_float(-1.5),
+                data::Value::None,
+                data::Value::Int(10),
+            ]),
+            (data::Value::from_float(-1.5), data::Value::Int(10))
+        );
+        assert_eq!(
+            extremes(vec![
+                data::Value::from_string("web-2"),
+                data::Value::from_string("db"),
+                data::Value::None,
+                data::Value::from_string("web-10"),
+            ]),
+            (
+                data::Value::from_string("db"),
+                data::Value::from_string("web-2")
+            )
+        );
+        // Once a group has a number, its strings are left out
+        assert_eq!(
+            extremes(vec![
+                data::Value::from_string("b"),
+                data::Value::Int(7),
+                data::Value::from_string("a"),
+                data::Value::Int(2),
+            ]),
+            (data::Value::Int(2), data::Value::Int(7))
+        );
+        assert_eq!(
+            extremes(vec![data::Value::Int(5), data::Value::from_string("-")]),
+            (data::Value::Int(5), data::Value::Int(5))
+        );
+        assert_eq!(
+            extremes(vec![data::Value::None, data::Value::Bool(true)]),
+            (data::Value::None, data::Value::None)
+        );
+    }
+
     #[test]
     fn multi_grouper() {
         let ops: Vec<(String, Box<dyn AggregateFunction>)> = vec![
@@ -1490,6 +4749,59 @@ mod tests {
         );
     }
 
+    #[test]
+    fn count_split_groups() {
+        let ops: Vec<(String, Box<dyn AggregateFunction>)> =
+            vec![("_count".to_string(), Box::new(Count::new()))];
+        let split = Expr::FunctionCall(FunctionCall {
+            function: Function::Split,
+            args: vec![
+                Expr::column("msg"),
+                Expr::Value(Box::leak(Box::new(data::Value::Str(" ".to_string())))),
+            ],
+        });
+        let mut count_agg = MultiGrouper::new(
+            &[Expr::column("level"), split],
+            vec!["level".to_string(), "word".to_string()],
+            ops,
+        );
+        for (level, msg) in &[
+            ("info", "the cat the end"),
+            ("warn", "the dog"),
+            ("info", ""),
+        ] {
+            let rec = Record::new("")
+                .put("level", data::Value::Str(level.to_string()))
+                .put("msg", data::Value::Str(msg.to_string()));
+            count_agg.process(Row::Record(rec));
+        }
+        let agg = count_agg.emit();
+        let mut counts: Vec<(String, String, data::Value)> = agg
+            .data
+            .iter()
+            .map(|row| {
+                (
+                    row["level"].to_string(),
+                    row["word"].to_string(),
+                    row["_count"].clone(),
+                )
+            })
+            .collect();
+        counts.sort();
+        let count =
+            |level: &str, word: &str, n| (level.to_string(), word.to_string(), data::Value::Int(n));
+        assert_eq!(
+            counts,
+            vec![
+                count("info", "cat", 1),
+                count("info", "end", 1),
+                count("info", "the", 2),
+                count("warn", "dog", 1),
+                count("warn", "the", 1),
+            ]
+        );
+    }
+
     #[test]
     fn count_groups() {
         let ops: Vec<(String, Box<dyn AggregateFunction>)> =
@@ -1531,6 +4843,312 @@ mod tests {
         );
     }
 
+    #[test]
+    fn concat_agg() {
+        let mut concat = Concat::empty("k1", ", ".to_string(), false, 3, 100);
+        let mut distinct = Concat::empty("k1", "|".to_string(), true, 3, 100);
+        let mut short = Concat::empty("k1", ", ".to_string(), false, 10, 5);
+        for value in &["a", "b", "a", "c", "d"] {
+            let rec = Record::new("").put("k1", Value::Str(value.to_string()));
+            concat.process(&rec.data).unwrap();
+            distinct.process(&rec.data).unwrap();
+            short.process(&rec.data).unwrap();
+        }
+        assert_eq!(concat.emit(), Value::Str("a, b, a, …".to_string()));
+        assert_eq!(distinct.emit(), Value::Str("a|b|c|…".to_string()));
+        assert_eq!(short.emit(), Value::Str("a, b,…".to_string()));
+
+        // The ellipsis of the output, like `...` for `--ascii`, is kept by each group's copy
+        concat.set_ellipsis("...");
+        let mut group = concat.empty_box();
+        for value in &["a", "b", "c", "d"] {
+            let rec = Record::new("").put("k1", Value::Str(value.to_string()));
+            group.process(&rec.data).unwrap();
+        }
+        assert_eq!(group.emit(), Value::Str("a, b, c, ...".to_string()));
+    }
+
+    #[test]
+    fn sparkline_agg() {
+        let mut sparkline = Sparkline::empty("x");
+        for x in &[1, 3, 2] {
+            let rec = Record::new("").put("x", Value::Int(*x));
+            sparkline.process(&rec.data).unwrap();
+        }
+        assert_eq!(
+            sparkline.emit(),
+            Value::Array(vec![Value::Int(1), Value::Int(3), Value::Int(2)])
+        );
+
+        // Past the limit, neighbouring points are averaged and a partly filled point is kept
+        let mut sparkline = Sparkline::empty("x");
+        for x in 0..(MAX_SPARKLINE_POINTS + 3) {
+            let rec = Record::new("").put("x", Value::Int(x as i64));
+            sparkline.process(&rec.data).unwrap();
+        }
+        let points = match sparkline.emit() {
+            Value::Array(points) => points,
+            other => panic!("Expected an array, found {:?}", other),
+        };
+        assert_eq!(points.len(), MAX_SPARKLINE_POINTS / 2 + 2);
+        assert_eq!(points[0], Value::from_float(0.5));
+        assert_eq!(points[MAX_SPARKLINE_POINTS / 2], Value::from_float(32.5));
+        assert_eq!(
+            points[MAX_SPARKLINE_POINTS / 2 + 1],
+            Value::from_float(34.0)
+        );
+    }
+
+    #[test]
+    fn mode_agg() {
+        let mut mode = Mode::empty("x");
+        assert_eq!(mode.emit(), Value::None);
+        for x in &["b", "a", "c", "a", "b", "a"] {
+            let rec = Record::new("").put("x", Value::Str(x.to_string()));
+            mode.process(&rec.data).unwrap();
+        }
+        assert_eq!(mode.emit(), Value::Str("a".to_string()));
+
+        // Ties go to the value seen first
+        let mut mode = Mode::empty("x");
+        for x in &[3, 1, 1, 3, 2] {
+            let rec = Record::new("").put("x", Value::Int(*x));
+            mode.process(&rec.data).unwrap();
+        }
+        assert_eq!(mode.emit(), Value::Int(3));
+    }
+
+    #[test]
+    fn first_last_agg() {
+        let mut first = First::empty("x");
+        let mut last = Last::empty("x");
+        assert_eq!(first.emit(), Value::None);
+        assert_eq!(last.emit(), Value::None);
+        for x in &["b", "a", "c"] {
+            let rec = Record::new("").put("x", Value::Str(x.to_string()));
+            first.process(&rec.data).unwrap();
+            last.process(&rec.data).unwrap();
+        }
+        assert_eq!(first.emit(), Value::Str("b".to_string()));
+        assert_eq!(last.emit(), Value::Str("c".to_string()));
+
+        // Records without the column don't change either value
+        let rec = Record::new("").put("y", Value::Int(1));
+        assert!(first.process(&rec.data).is_ok());
+        assert!(last.process(&rec.data).is_err());
+        assert_eq!(first.emit(), Value::Str("b".to_string()));
+        assert_eq!(last.emit(), Value::Str("c".to_string()));
+    }
+
+    #[test]
+    fn median_agg() {
+        let mut median = Median::empty("x");
+        assert_eq!(median.emit(), Value::None);
+        for x in &[5, 1, 3] {
+            let rec = Record::new("").put("x", Value::Int(*x));
+            median.process(&rec.data).unwrap();
+        }
+        assert_eq!(median.emit(), Value::from_float(3.0));
+
+        let rec = Record::new("").put("x", Value::from_float(8.0));
+        median.process(&rec.data).unwrap();
+        assert_eq!(median.emit(), Value::from_float(4.0));
+
+        let rec = Record::new("").put("x", Value::Str("slow".to_string()));
+        assert!(median.process(&rec.data).is_err());
+        assert!(median.warns_on_non_numbers());
+        assert_eq!(median.emit(), Value::from_float(4.0));
+    }
+
+    #[test]
+    fn percentiles_agg() {
+        let mut percentiles = Percentiles::empty("x", vec![0.0, 50.0, 90.0, 100.0], false);
+        assert_eq!(
+            percentiles.column_suffixes(),
+            vec!["_p0", "_p50", "_p90", "_p100"]
+        );
+        assert_eq!(percentiles.emit_columns(), vec![Value::None; 4]);
+        for x in 1..=11 {
+            let rec = Record::new("").put("x", Value::Int(12 - x));
+            percentiles.process(&rec.data).unwrap();
+        }
+        assert_eq!(
+            percentiles.emit_columns(),
+            vec![
+                Value::from_float(1.0),
+                Value::from_float(6.0),
+                Value::from_float(10.0),
+                Value::from_float(11.0),
+            ]
+        );
+
+        let mut percentiles = Percentiles::empty("x", vec![25.0], false);
+        for x in &[1, 2] {
+            let rec = Record::new("").put("x", Value::Int(*x));
+            percentiles.process(&rec.data).unwrap();
+        }
+        assert_eq!(percentiles.emit(), Value::from_float(1.25));
+    }
+
+    #[test]
+    fn approx_percentiles_are_close_to_exact() {
+        let thresholds = vec![1.0, 50.0, 90.0, 99.0, 99.9];
+        let mut exact = Percentiles::empty("x", thresholds.clone(), false);
+        let mut approx = Percentiles::empty("x", thresholds, true);
+        assert_eq!(approx.emit_columns(), vec![Value::None; 5]);
+        // 0 to 19,999 in a scrambled order
+        let count = 20_000;
+        for i in 0..count {
+            let rec = Record::new("").put("x", Value::Int(i * 7_919 % count));
+            exact.process(&rec.data).unwrap();
+            approx.process(&rec.data).unwrap();
+        }
+        // Each value is its own rank, so the error bound on the rank bounds the value too
+        let tolerance = APPROX_PERCENTILE_ERROR * count as f64 + 1.0;
+        let number = |value: Value| match value {
+            Value::Int(i) => i as f64,
+            Value::Float(f) => f.into_inner(),
+            other => panic!("unexpected percentile {:?}", other),
+        };
+        for (exact, approx) in exact.emit_columns().into_iter().zip(approx.emit_columns()) {
+            let (exact, approx) = (number(exact), number(approx));
+            assert!(
+                (exact - approx).abs() <= tolerance,
+                "{} is too far from {}",
+                approx,
+                exact
+            );
+        }
+    }
+
+    #[test]
+    fn ratio_agg() {
+        let errors = Expr::Comparison(BinaryExpr::<BoolExpr> {
+            left: Box::new(Expr::column("status")),
+            right: Box::new(Expr::Value(&Value::Int(500))),
+            operator: BoolExpr::Gte,
+        });
+        let mut ratio = Ratio::empty(errors.clone(), Expr::Value(&Value::Bool(true)));
+        let mut never = Ratio::empty(errors, Expr::Value(&Value::Bool(false)));
+        for status in &[200, 500, 503, 404] {
+            let rec = Record::new("").put("status", Value::Int(*status));
+            ratio.process(&rec.data).unwrap();
+            never.process(&rec.data).unwrap();
+        }
+        assert_eq!(ratio.emit(), Value::Float(OrderedFloat(0.5)));
+        assert_eq!(never.emit(), Value::None);
+    }
+
+    #[test]
+    fn arg_min_max_agg() {
+        let mut argmax = ArgMinMax::empty(Expr::column("latency"), Expr::column("ts"), true);
+        let mut argmin = ArgMinMax::empty(Expr::column("latency"), Expr::column("ts"), false);
+        assert_eq!(argmax.emit(), Value::None);
+        for (latency, ts) in &[(5, "t1"), (9, "t2"), (2, "t3"), (9, "t4"), (2, "t5")] {
+            let rec = Record::new("")
+                .put("latency", Value::Int(*latency))
+                .put("ts", Value::Str(ts.to_string()));
+            argmax.process(&rec.data).unwrap();
+            argmin.process(&rec.data).unwrap();
+        }
+        assert_eq!(argmax.emit(), Value::Str("t2".to_string()));
+        assert_eq!(argmin.emit(), Value::Str("t3".to_string()));
+
+        // Values are ordered the same way as for `max`
+        let last_of = |values: &[&str]| {
+            let mut argmax = ArgMinMax::empty(Expr::column("v"), Expr::column("ts"), true);
+            for (i, v) in values.iter().enumerate() {
+                let rec = Record::new("")
+                    .put("v", Value::from_string(*v))
+                    .put("ts", Value::Int(i as i64));
+                argmax.process(&rec.data).unwrap();
+            }
+            argmax.emit()
+        };
+        assert_eq!(last_of(&["beta", "gamma", "alpha"]), Value::Int(1));
+        assert_eq!(last_of(&["slow", "7", "fast"]), Value::Int(1));
+    }
+
+    #[test]
+    fn topk_approx() {
+        let mut topk = TopKApprox::new(Expr::column("k1"), "k1".to_string(), 1);
+        // 10 slots are tracked, so the 30 unique values keep evicting each other
+        for i in 0..30 {
+            for value in &["heavy".to_string(), format!("unique{}", i)] {
+                let rec = Record::new("").put("k1", Value::Str(value.to_string()));
+                topk.process(Row::Record(rec));
+            }
+        }
+        let agg = topk.emit();
+        assert_eq!(agg.columns, vec!["k1", "_count", "_max_overcount"]);
+        assert_eq!(agg.data.len(), 1);
+        let row = &agg.data[0];
+        assert_eq!(row["k1"], Value::Str("heavy".to_string()));
+        let count = match row["_count"] {
+            Value::Int(count) => count,
+            ref other => panic!("unexpected count {:?}", other),
+        };
+        let max_overcount = match row["_max_overcount"] {
+            Value::Int(max_overcount) => max_overcount,
+            ref other => panic!("unexpected overcount {:?}", other),
+        };
+        assert!(count >= 30);
+        assert!(count - max_overcount <= 30);
+        assert_eq!(topk.counters.len(), 10);
+    }
+
+    #[test]
+    fn rate_skips_unwritable_timestamps() {
+        let mut rate = Rate::new(Expr::column("ts"), 60.0, false);
+        for ts in &[1_551_434_700, 1_551_434_700_000, 253_402_300_800] {
+            let rec = Record::new("").put("ts", Value::Int(*ts));
+            rate.process(Row::Record(rec));
+        }
+        let agg = rate.emit();
+        assert_eq!(agg.data.len(), 1);
+        assert_eq!(
+            agg.data[0]["_timeslice"],
+            Value::Str("2019-03-01T10:05:00Z".to_string())
+        );
+    }
+
+    #[test]
+    fn rate_fills_empty_intervals() {
+        let mut rate = Rate::new(Expr::column("ts"), 60.0, true);
+        for ts in &[0, 30, 59, 180] {
+            let rec = Record::new("").put("ts", Value::Int(*ts));
+            rate.process(Row::Record(rec));
+        }
+        let agg = rate.emit();
+        assert_eq!(agg.columns, vec!["_timeslice", "_rate"]);
+        let rates: Vec<(&Value, &Value)> = agg
+            .data
+            .iter()
+            .map(|row| (&row["_timeslice"], &row["_rate"]))
+            .collect();
+        assert_eq!(
+            rates,
+            vec![
+                (
+                    &Value::Str("1970-01-01T00:00:00Z".to_string()),
+                    &Value::from_float(0.05)
+                ),
+                (
+                    &Value::Str("1970-01-01T00:01:00Z".to_string()),
+                    &Value::Float(OrderedFloat(0.0))
+                ),
+                (
+                    &Value::Str("1970-01-01T00:02:00Z".to_string()),
+                    &Value::Float(OrderedFloat(0.0))
+                ),
+                (
+                    &Value::Str("1970-01-01T00:03:00Z".to_string()),
+                    &Value::from_float(1.0 / 60.0)
+                ),
+            ]
+        );
+    }
+
     #[test]
     fn sort_raw() {}
 
@@ -1556,11 +5174,11 @@ mod tests {
                 ),
             ],
         );
-        let mut sorter = Sorter::new(vec!["count".to_string()], SortDirection::Ascending);
+        let mut sorter = Sorter::new(vec![("count".to_string(), SortDirection::Ascending)]);
         sorter.process(data::Row::Aggregate(agg.clone()));
         assert_eq!(sorter.emit(), agg.clone());
 
-        let mut sorter = Sorter::new(vec!["count".to_string()], SortDirection::Descending);
+        let mut sorter = Sorter::new(vec![("count".to_string(), SortDirection::Descending)]);
         sorter.process(data::Row::Aggregate(agg.clone()));
 
         let mut revagg = agg.clone();
@@ -1568,6 +5186,103 @@ mod tests {
         assert_eq!(sorter.emit(), revagg);
     }
 
+    #[test]
+    fn sort_top_k() {
+        let rows: Vec<_> = [3, 9, 1, 7, 5]
+            .iter()
+            .map(|n| (hashmap! {"k".to_string() => n.to_string()}, Value::Int(*n)))
+            .collect();
+        let agg = Aggregate::new(&["k".to_string()], "count".to_string(), &rows);
+
+        let mut sorter = Sorter::top_k(vec![("count".to_string(), SortDirection::Descending)], 2);
+        sorter.process(data::Row::Aggregate(agg.clone()));
+        let counts: Vec<_> = sorter
+            .emit()
+            .data
+            .iter()
+            .map(|r| r["count"].clone())
+            .collect();
+        assert_eq!(counts, vec![Value::Int(9), Value::Int(7)]);
+
+        let mut sorter = Sorter::top_k(vec![("count".to_string(), SortDirection::Ascending)], 10);
+        sorter.process(data::Row::Aggregate(agg));
+        let counts: Vec<_> = sorter
+            .emit()
+            .data
+            .iter()
+            .map(|r| r["count"].clone())
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                Value::Int(1),
+                Value::Int(3),
+                Value::Int(5),
+                Value::Int(7),
+                Value::Int(9)
+            ]
+        );
+    }
+
+    #[test]
+    fn grand_total_sums_numeric_columns() {
+        let mut rows = vec![
+            hashmap! {
+                "host".to_string() => Value::Str("a".to_string()),
+                "count".to_string() => Value::Int(2),
+                "avg".to_string() => Value::from_float(1.5),
+            },
+            hashmap! {
+                "host".to_string() => Value::Str("b".to_string()),
+                "count".to_string() => Value::Int(3),
+                "avg".to_string() => Value::None,
+            },
+        ];
+        let agg = data::Aggregate {
+            columns: vec!["host".to_string(), "count".to_string(), "avg".to_string()],
+            data: rows.clone(),
+            formats: HashMap::new(),
+        };
+        let mut total = GrandTotal::new(vec!["host".to_string()]);
+        total.process(data::Row::Aggregate(agg));
+        rows.push(hashmap! {
+            "host".to_string() => Value::Str("total".to_string()),
+            "count".to_string() => Value::Int(5),
+            "avg".to_string() => Value::from_float(1.5),
+        });
+        assert_eq!(total.emit().data, rows);
+    }
+
+    #[test]
+    fn sort_columns_in_their_own_directions() {
+        let rows: Vec<_> = [("b", 1), ("a", 1), ("b", 2), ("a", 2)]
+            .iter()
+            .map(|(k, n)| (hashmap! {"k".to_string() => k.to_string()}, Value::Int(*n)))
+            .collect();
+        let agg = Aggregate::new(&["k".to_string()], "count".to_string(), &rows);
+
+        let mut sorter = Sorter::new(vec![
+            ("k".to_string(), SortDirection::Descending),
+            ("count".to_string(), SortDirection::Ascending),
+        ]);
+        sorter.process(data::Row::Aggregate(agg));
+        let rows: Vec<_> = sorter
+            .emit()
+            .data
+            .iter()
+            .map(|r| (r["k"].to_string(), r["count"].clone()))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("b".to_string(), Value::Int(1)),
+                ("b".to_string(), Value::Int(2)),
+                ("a".to_string(), Value::Int(1)),
+                ("a".to_string(), Value::Int(2)),
+            ]
+        );
+    }
+
     #[test]
     fn test_agg_adapter() {
         let where_op = Where::new(true);
@@ -1597,6 +5312,79 @@ mod tests {
         assert_eq!(adapted.emit(), agg.clone());
     }
 
+    #[test]
+    fn moving_average() {
+        let values = [
+            Value::Int(1),
+            Value::Int(3),
+            Value::Str("slow".to_string()),
+            Value::Int(8),
+            Value::Int(10),
+        ];
+        let averages = |gaps: bool| -> Vec<Value> {
+            let mut op = MovingAverage::new("x", 3, gaps, "avg".to_string());
+            values
+                .iter()
+                .map(|value| {
+                    let rec = Record::new("").put("x", value.clone());
+                    let rec = op.process_mut(rec).unwrap().unwrap();
+                    rec.data["avg"].clone()
+                })
+                .collect()
+        };
+        assert_eq!(
+            averages(false),
+            vec![
+                Value::Int(1),
+                Value::Int(2),
+                Value::Int(2),
+                Value::Int(4),
+                Value::Int(7),
+            ]
+        );
+        assert_eq!(
+            averages(true),
+            vec![
+                Value::Int(1),
+                Value::Int(2),
+                Value::Int(2),
+                Value::from_float(5.5),
+                Value::Int(9),
+            ]
+        );
+    }
+
+    #[test]
+    fn quantile_buckets() {
+        let values = [
+            Value::Int(40),
+            Value::Int(10),
+            Value::Str("slow".to_string()),
+            Value::Int(30),
+            Value::Int(20),
+            Value::Int(20),
+            Value::Int(50),
+        ];
+        let mut op = Box::new(QuantileBucket::new("x", 2, "half".to_string()));
+        for value in values.iter() {
+            let rec = Record::new("").put("x", value.clone());
+            assert_eq!(op.process_mut(rec).unwrap(), None);
+        }
+        let buckets: Vec<Value> = op.drain().map(|rec| rec.data["half"].clone()).collect();
+        assert_eq!(
+            buckets,
+            vec![
+                Value::Int(2),
+                Value::Int(1),
+                Value::None,
+                Value::Int(2),
+                Value::Int(1),
+                Value::Int(1),
+                Value::Int(2),
+            ]
+        );
+    }
+
     #[test]
     fn test_total() {
         let mut total_op = PreAggAdapter::new(Box::new(TotalDef::new(
@@ -0,0 +1,113 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 address block in CIDR notation, e.g. `10.0.0.0/8`.  An address without a
+/// prefix length is treated as a block containing only that address.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidNetwork;
+
+fn max_prefix_len(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Compare the leading `prefix_len` bits of two addresses that are `bits` wide.
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len;
+    (net >> shift) == (ip >> shift)
+}
+
+impl FromStr for IpNetwork {
+    type Err = InvalidNetwork;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .unwrap_or("")
+            .parse()
+            .map_err(|_| InvalidNetwork)?;
+        let max_len = max_prefix_len(&addr);
+        let prefix_len = match parts.next() {
+            None => max_len,
+            Some(len) => len.parse::<u8>().map_err(|_| InvalidNetwork)?,
+        };
+        if prefix_len > max_len {
+            return Err(InvalidNetwork);
+        }
+        Ok(IpNetwork { addr, prefix_len })
+    }
+}
+
+impl IpNetwork {
+    /// Test if the given address falls within this block.  Addresses from a different family
+    /// than the block never match.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u128::from(u32::from(net)),
+                u128::from(u32::from(*ip)),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(*ip), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_networks() {
+        assert!("10.0.0.0/8".parse::<IpNetwork>().is_ok());
+        assert!("10.0.0.1".parse::<IpNetwork>().is_ok());
+        assert!("fe80::/10".parse::<IpNetwork>().is_ok());
+        assert_eq!("10.0.0.0/33".parse::<IpNetwork>(), Err(InvalidNetwork));
+        assert_eq!("10.0.0/8".parse::<IpNetwork>(), Err(InvalidNetwork));
+        assert_eq!("10.0.0.0/x".parse::<IpNetwork>(), Err(InvalidNetwork));
+    }
+
+    #[test]
+    fn ipv4_membership() {
+        let net: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(&ip("10.0.0.1")));
+        assert!(net.contains(&ip("10.255.255.255")));
+        assert!(!net.contains(&ip("11.0.0.1")));
+        assert!(!net.contains(&ip("::1")));
+
+        let single: IpNetwork = "192.168.1.7".parse().unwrap();
+        assert!(single.contains(&ip("192.168.1.7")));
+        assert!(!single.contains(&ip("192.168.1.8")));
+
+        let everything: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(&ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn ipv6_membership() {
+        let net: IpNetwork = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(&ip("2001:db8::1")));
+        assert!(!net.contains(&ip("2001:db9::1")));
+        assert!(!net.contains(&ip("10.0.0.1")));
+    }
+}
@@ -0,0 +1,57 @@
+/// The smallest Levenshtein distance between `pattern` and any substring of `text`.  This lets
+/// a short pattern match a near-identical phrase somewhere inside a longer message.  It's the
+/// usual edit distance table, except that starting the match anywhere in `text` is free, as is
+/// stopping it anywhere.
+pub fn substring_distance(pattern: &[char], text: &str) -> usize {
+    if pattern.is_empty() {
+        return 0;
+    }
+    // column[i] is the cost of matching the first i characters of the pattern so that the
+    // match ends at the current position in the text.
+    let mut column: Vec<usize> = (0..=pattern.len()).collect();
+    let mut best = pattern.len();
+    for c in text.chars() {
+        let mut diagonal = column[0];
+        column[0] = 0;
+        for (i, &p) in pattern.iter().enumerate() {
+            let substitution = diagonal + if p == c { 0 } else { 1 };
+            diagonal = column[i + 1];
+            column[i + 1] = substitution.min(column[i] + 1).min(diagonal + 1);
+        }
+        best = best.min(column[pattern.len()]);
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distance(pattern: &str, text: &str) -> usize {
+        substring_distance(&pattern.chars().collect::<Vec<_>>(), text)
+    }
+
+    #[test]
+    fn exact_and_substring_matches() {
+        assert_eq!(distance("refused", "refused"), 0);
+        assert_eq!(distance("refused", "error: connection refused by host"), 0);
+        assert_eq!(distance("", "anything"), 0);
+    }
+
+    #[test]
+    fn near_matches() {
+        assert_eq!(
+            distance("connection refused", "error: conection refused"),
+            1
+        );
+        // The match can stop early, so dropping the trailing "e" is cheaper than a transposition
+        assert_eq!(
+            distance("connection refused", "error: connection refusde"),
+            1
+        );
+        assert_eq!(distance("connection refused", "error: conection refsed"), 2);
+        assert_eq!(distance("timeout", "time out"), 1);
+        assert_eq!(distance("abc", ""), 3);
+        assert_eq!(distance("abc", "xyz"), 3);
+    }
+}
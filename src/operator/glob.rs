@@ -0,0 +1,92 @@
+/// Translate a shell-style glob into an anchored regular expression.  `*` matches any run of
+/// characters (including `/`), `?` matches a single character and `[...]` matches one character
+/// from a class, with `[!...]` or `[^...]` for a negated class.  A backslash makes the next
+/// character literal.
+pub fn glob_to_regex(glob: &str) -> Result<String, String> {
+    let mut regex = String::with_capacity(glob.len() + 2);
+    regex.push('^');
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '\\' => match chars.next() {
+                Some(escaped) => regex.push_str(&regex::escape(&escaped.to_string())),
+                None => return Err("The pattern ends with an unused backslash".to_string()),
+            },
+            '[' => {
+                regex.push('[');
+                if let Some(&negation) = chars.peek() {
+                    if negation == '!' || negation == '^' {
+                        regex.push('^');
+                        chars.next();
+                    }
+                }
+                let mut first = true;
+                loop {
+                    match chars.next() {
+                        // `]` right after the opening bracket is part of the class
+                        Some(']') if !first => break,
+                        Some('-') => regex.push('-'),
+                        Some(member) => regex.push_str(&regex::escape(&member.to_string())),
+                        None => {
+                            return Err("A character class is missing its closing `]`".to_string())
+                        }
+                    }
+                    first = false;
+                }
+                regex.push(']');
+            }
+            other => regex.push_str(&regex::escape(&other.to_string())),
+        }
+    }
+    regex.push('$');
+    Ok(regex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(glob: &str, text: &str) -> bool {
+        regex::Regex::new(&glob_to_regex(glob).unwrap())
+            .unwrap()
+            .is_match(text)
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(matches("/api/*/users", "/api/v1/users"));
+        assert!(matches("/api/*/users", "/api/v1/admin/users"));
+        assert!(!matches("/api/*/users", "/api/v1/users/7"));
+        assert!(matches("*.log", ".log"));
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("file?.txt", "file.txt"));
+        assert!(!matches("file?.txt", "file12.txt"));
+        assert!(matches("a.b(c)+", "a.b(c)+"));
+        assert!(!matches("a.b", "axb"));
+        assert!(matches(r"literal\*", "literal*"));
+        assert!(!matches(r"literal\*", "literally"));
+    }
+
+    #[test]
+    fn glob_classes() {
+        assert!(matches("log[0-9]", "log7"));
+        assert!(!matches("log[0-9]", "logx"));
+        assert!(matches("[!a-c]x", "dx"));
+        assert!(!matches("[!a-c]x", "bx"));
+        assert!(matches("[^a-c]x", "dx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a^]", "^"));
+        assert!(matches("[.]", "."));
+        assert!(!matches("[.]", "x"));
+        assert_eq!(
+            glob_to_regex("[abc"),
+            Err("A character class is missing its closing `]`".to_string())
+        );
+        assert_eq!(
+            glob_to_regex(r"abc\"),
+            Err("The pattern ends with an unused backslash".to_string())
+        );
+    }
+}
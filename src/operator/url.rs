@@ -0,0 +1,141 @@
+/// The parts of a URL that can be extracted with the `url_*` functions
+#[derive(Debug, PartialEq)]
+pub struct UrlParts<'a> {
+    /// Lowercased, like `https`.  Scheme-relative and path-only URLs have none.
+    pub scheme: Option<String>,
+    /// Lowercased, without any user info and without the brackets around an IPv6 address
+    pub host: Option<String>,
+    /// Only a port given explicitly in the URL.  Default ports aren't filled in.
+    pub port: Option<u16>,
+    /// Everything up to the query or fragment.  A URL with a host but no path has the path `/`.
+    pub path: &'a str,
+}
+
+fn is_scheme(scheme: &str) -> bool {
+    let mut chars = scheme.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+}
+
+/// Split a URL into its parts.  Besides absolute URLs like `https://example.com:8443/a`, this
+/// accepts scheme-relative URLs like `//cdn.example.com/lib.js` and path-only URLs like
+/// `/search?q=x`.  Anything else, like a bare `example.com` or a port that isn't a number,
+/// isn't a URL.
+pub fn parse_url(url: &str) -> Option<UrlParts<'_>> {
+    let url = url.trim();
+    let (scheme, rest) = match url.find("://") {
+        Some(end) if is_scheme(&url[..end]) => {
+            (Some(url[..end].to_ascii_lowercase()), &url[end + 1..])
+        }
+        _ => (None, url),
+    };
+    let end_of_path = rest.find(&['?', '#'][..]).unwrap_or(rest.len());
+    let rest = &rest[..end_of_path];
+    if !rest.starts_with("//") {
+        if scheme.is_some() || !rest.starts_with('/') {
+            return None;
+        }
+        return Some(UrlParts {
+            scheme: None,
+            host: None,
+            port: None,
+            path: rest,
+        });
+    }
+    let rest = &rest[2..];
+    let (authority, path) = match rest.find('/') {
+        Some(start) => (&rest[..start], &rest[start..]),
+        None => (rest, "/"),
+    };
+    // The user info can contain `:`, so it's removed before looking for the port
+    let host_port = match authority.rfind('@') {
+        Some(at) => &authority[at + 1..],
+        None => authority,
+    };
+    let (host, port) = if host_port.starts_with('[') {
+        let close = host_port.find(']')?;
+        (&host_port[1..close], &host_port[close + 1..])
+    } else {
+        match host_port.find(':') {
+            Some(colon) => (&host_port[..colon], &host_port[colon..]),
+            None => (host_port, ""),
+        }
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let port = match port {
+        "" => None,
+        port if port.starts_with(':') => Some(port[1..].parse::<u16>().ok()?),
+        _ => return None,
+    };
+    Some(UrlParts {
+        scheme,
+        host: Some(host.to_ascii_lowercase()),
+        port,
+        path,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn absolute_urls() {
+        assert_eq!(
+            parse_url("HTTPS://user:pw@Example.COM:8443/a/b?q=1#top"),
+            Some(UrlParts {
+                scheme: Some("https".to_string()),
+                host: Some("example.com".to_string()),
+                port: Some(8443),
+                path: "/a/b",
+            })
+        );
+        assert_eq!(
+            parse_url("http://example.com"),
+            Some(UrlParts {
+                scheme: Some("http".to_string()),
+                host: Some("example.com".to_string()),
+                port: None,
+                path: "/",
+            })
+        );
+        assert_eq!(
+            parse_url("http://[::1]:80/health").and_then(|url| url.host),
+            Some("::1".to_string())
+        );
+    }
+
+    #[test]
+    fn relative_urls() {
+        assert_eq!(
+            parse_url("//cdn.example.com/lib.js"),
+            Some(UrlParts {
+                scheme: None,
+                host: Some("cdn.example.com".to_string()),
+                port: None,
+                path: "/lib.js",
+            })
+        );
+        assert_eq!(
+            parse_url("/search?q=x"),
+            Some(UrlParts {
+                scheme: None,
+                host: None,
+                port: None,
+                path: "/search",
+            })
+        );
+    }
+
+    #[test]
+    fn not_urls() {
+        assert_eq!(parse_url("example.com/a"), None);
+        assert_eq!(parse_url("http://example.com:http/"), None);
+        assert_eq!(parse_url("http://example.com:99999/"), None);
+        assert_eq!(parse_url("http:///a"), None);
+        assert_eq!(parse_url("mailto:someone@example.com"), None);
+        assert_eq!(parse_url(""), None);
+    }
+}
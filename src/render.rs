@@ -1,8 +1,15 @@
 use crate::data;
+use crate::pipeline::AgrindError;
 use failure::Error;
 use std;
-use std::collections::HashMap;
-use std::io::{stdout, Write};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{stdout, BufWriter, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 extern crate strfmt;
 use strfmt::strfmt;
@@ -12,20 +19,414 @@ extern crate terminal_size;
 use self::terminal_size::{terminal_size, Height, Width};
 use std::time::{Duration, Instant};
 
+/// How rows are written to the output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    /// Bracketed `[key=value]` records and padded aggregate tables
+    Legacy,
+    /// Comma separated aggregate tables with a header row
+    Csv,
+    /// An xlsx spreadsheet of the final aggregate with typed cells
+    Xlsx,
+    /// Records with one field per line and nested objects and lists indented beneath their
+    /// field.  Aggregates are rendered as legacy tables.
+    Vertical,
+    /// An SQL `INSERT` statement per record, or per row of the final aggregate
+    Sql,
+    /// A JSON object per record, or an array of row objects for the final aggregate
+    Json,
+    /// Aggregates as Markdown tables, to paste into an issue or pull request.  Records are
+    /// rendered as legacy records.
+    Markdown,
+}
+
+impl OutputMode {
+    /// Whether the output is meant to be read by another program.  Floats are written with
+    /// every digit needed to read them back exactly, rather than rounded for display.
+    pub fn is_machine_readable(self) -> bool {
+        match self {
+            OutputMode::Csv | OutputMode::Xlsx | OutputMode::Sql | OutputMode::Json => true,
+            OutputMode::Legacy | OutputMode::Vertical | OutputMode::Markdown => false,
+        }
+    }
+
+    /// Infer the output mode from the extension of an output file, if it implies one
+    pub fn from_path(path: &Path) -> Option<OutputMode> {
+        let ext = path
+            .extension()
+            .and_then(OsStr::to_str)?
+            .to_ascii_lowercase();
+        match ext.as_str() {
+            "csv" => Some(OutputMode::Csv),
+            "xlsx" => Some(OutputMode::Xlsx),
+            "sql" => Some(OutputMode::Sql),
+            "json" => Some(OutputMode::Json),
+            "md" | "markdown" => Some(OutputMode::Markdown),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Fail)]
+#[fail(
+    display = "Unknown output mode `{}`. Expected one of: legacy, csv, xlsx, vertical, sql, json, markdown",
+    mode
+)]
+pub struct UnknownOutputMode {
+    mode: String,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "xlsx output requires agrind to be built with the `xlsx` feature")]
+pub struct XlsxUnsupported;
+
+/// Which colors to use for records and aggregate tables in the terminal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Theme {
+    /// Pick light or dark from the `COLORFGBG` environment variable, defaulting to dark
+    Auto,
+    Dark,
+    Light,
+    /// Never use colors
+    NoColor,
+}
+
+#[derive(Debug, Fail)]
+#[fail(
+    display = "Unknown theme `{}`. Expected one of: auto, dark, light, none",
+    theme
+)]
+pub struct UnknownTheme {
+    theme: String,
+}
+
+impl FromStr for Theme {
+    type Err = UnknownTheme;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Theme::Auto),
+            "dark" => Ok(Theme::Dark),
+            "light" => Ok(Theme::Light),
+            "none" => Ok(Theme::NoColor),
+            other => Err(UnknownTheme {
+                theme: other.to_string(),
+            }),
+        }
+    }
+}
+
+/// When to color the output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    /// Color when writing to a terminal, unless `NO_COLOR` is set
+    Auto,
+    /// Color even when writing to a file or pipe, and even if `NO_COLOR` is set
+    Always,
+    Never,
+}
+
+#[derive(Debug, Fail)]
+#[fail(
+    display = "Unknown color choice `{}`. Expected one of: always, never, auto",
+    choice
+)]
+pub struct UnknownColorChoice {
+    choice: String,
+}
+
+impl FromStr for ColorChoice {
+    type Err = UnknownColorChoice;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            other => Err(UnknownColorChoice {
+                choice: other.to_string(),
+            }),
+        }
+    }
+}
+
+impl ColorChoice {
+    /// Whether to color output going to a terminal when `is_tty` is set
+    pub fn enabled(self, is_tty: bool) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => is_tty && env::var_os("NO_COLOR").is_none(),
+        }
+    }
+}
+
+/// What to do with a value too long for its column
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// Cut it off with an ellipsis
+    Truncate,
+    /// Continue it on the lines below, leaving the other columns blank
+    Wrap,
+}
+
+#[derive(Debug, Fail)]
+#[fail(
+    display = "Unknown overflow `{}`. Expected one of: truncate, wrap",
+    overflow
+)]
+pub struct UnknownOverflow {
+    overflow: String,
+}
+
+impl FromStr for Overflow {
+    type Err = UnknownOverflow;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(Overflow::Truncate),
+            "wrap" => Ok(Overflow::Wrap),
+            other => Err(UnknownOverflow {
+                overflow: other.to_string(),
+            }),
+        }
+    }
+}
+
+/// ANSI foreground colors.  Only foreground colors are used so the user's background always
+/// shows through.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Palette {
+    /// The header of an aggregate table and the field names of a record
+    header: &'static str,
+    number: &'static str,
+    /// Values that are `None`
+    none: &'static str,
+}
+
+const RESET: &str = "\x1b[0m";
+
+const DARK_PALETTE: Palette = Palette {
+    header: "\x1b[1;36m",
+    number: "\x1b[33m",
+    none: "\x1b[2m",
+};
+
+const LIGHT_PALETTE: Palette = Palette {
+    header: "\x1b[1;34m",
+    number: "\x1b[35m",
+    none: "\x1b[2m",
+};
+
+impl Theme {
+    /// `colorfgbg` is the value of the `COLORFGBG` variable set by some terminals, like `15;0`
+    /// for white text on a black background.  The last field is the background color.
+    fn palette(self, colorfgbg: Option<&str>) -> Option<Palette> {
+        match self {
+            Theme::Dark => Some(DARK_PALETTE),
+            Theme::Light => Some(LIGHT_PALETTE),
+            Theme::NoColor => None,
+            Theme::Auto => {
+                let background = colorfgbg
+                    .and_then(|colors| colors.rsplit(';').next())
+                    .and_then(|bg| bg.trim().parse::<u8>().ok());
+                match background {
+                    // White and the bright colors other than black are light backgrounds
+                    Some(7) | Some(9..=15) => Some(LIGHT_PALETTE),
+                    _ => Some(DARK_PALETTE),
+                }
+            }
+        }
+    }
+}
+
+impl Palette {
+    fn value(self, value: &data::Value) -> Option<&'static str> {
+        match value {
+            data::Value::Int(_) | data::Value::Float(_) => Some(self.number),
+            data::Value::None => Some(self.none),
+            _ => None,
+        }
+    }
+}
+
+fn paint(color: Option<&'static str>, text: String) -> String {
+    match color {
+        Some(color) => format!("{}{}{}", color, text, RESET),
+        None => text,
+    }
+}
+
+/// Paint the value in a padded cell but not the spaces around it, so the padding isn't styled
+/// and trailing whitespace can still be trimmed
+fn paint_padded(color: Option<&'static str>, cell: String) -> String {
+    let value = cell.trim_matches(' ');
+    if color.is_none() || value.is_empty() {
+        return cell;
+    }
+    let start = cell.len() - cell.trim_start_matches(' ').len();
+    let end = start + value.len();
+    format!(
+        "{}{}{}",
+        &cell[..start],
+        paint(color, value.to_string()),
+        &cell[end..]
+    )
+}
+
+impl FromStr for OutputMode {
+    type Err = UnknownOutputMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "legacy" => Ok(OutputMode::Legacy),
+            "csv" => Ok(OutputMode::Csv),
+            "xlsx" => Ok(OutputMode::Xlsx),
+            "vertical" => Ok(OutputMode::Vertical),
+            "sql" => Ok(OutputMode::Sql),
+            "json" => Ok(OutputMode::Json),
+            "markdown" => Ok(OutputMode::Markdown),
+            other => Err(UnknownOutputMode {
+                mode: other.to_string(),
+            }),
+        }
+    }
+}
+
 pub struct RenderConfig {
     pub floating_points: usize,
     pub min_buffer: usize,
     pub max_buffer: usize,
     pub format: Option<String>,
+    pub output_mode: OutputMode,
+    /// Write output to this file instead of stdout
+    pub output_file: Option<PathBuf>,
+    /// Add to the end of `output_file` instead of replacing it.  The file is locked for the whole
+    /// run so concurrent runs don't interleave, and a CSV header or SQL `CREATE TABLE` is only
+    /// written if the file was empty.
+    pub append: bool,
+    /// Suppress records that render identically to the record before them
+    pub dedup_output: bool,
+    /// When deduplicating, prefix each line with the number of times it was repeated
+    pub dedup_count: bool,
+    /// Colors for records and aggregate tables
+    pub theme: Theme,
+    /// Whether to use the colors of `theme` at all
+    pub color: ColorChoice,
+    /// Always render the fields of a record in alphabetical order, rather than appending fields
+    /// to the end as they first appear
+    pub sort_record_fields: bool,
+    /// Render these fields of a record first, in this order, ahead of the rest
+    pub pinned_fields: Vec<String>,
+    /// How often to redraw a live aggregate, at least `MIN_UPDATE_INTERVAL`
+    pub update_interval: Duration,
+    /// When the output isn't a terminal, also write the aggregate as it stands this often, each
+    /// table after a marker line, instead of only once the input ends
+    pub partial_interval: Option<Duration>,
+    /// Also redraw a live aggregate after this many records, even if the update interval hasn't
+    /// passed
+    pub records_per_update: Option<u64>,
+    /// Write each record to a file in a directory named after the value of one of its fields,
+    /// instead of to the output
+    pub split: Option<SplitConfig>,
+    /// Size columns to fit this percentile of their value lengths instead of the longest value,
+    /// so a few outliers are cut off with an ellipsis rather than widening the whole column
+    pub width_percentile: Option<f64>,
+    /// Periodically write the current state of an aggregate to a file, as well as rendering it
+    pub snapshot: Option<SnapshotConfig>,
+    /// Leave fields that are `None` or an empty string out of records, instead of rendering them
+    pub compact: bool,
+    /// After all the output, write a line with the number of records or aggregate rows, the
+    /// number of bytes and a CRC-32 of them, so a reader can tell the output is complete
+    pub trailer: Option<TrailerTarget>,
+    /// Only write ASCII, for terminals that can't show Unicode.  Sparklines are drawn with
+    /// punctuation instead of block characters.
+    pub ascii: bool,
+    /// Marks where a value too long for its column was cut off
+    pub ellipsis: String,
+    /// Whether values too long for their column are cut off or wrapped onto more lines
+    pub overflow: Overflow,
+    /// How a value that's `None`, like a JSON `null`, is written.  A field that's missing from
+    /// a row of an aggregate is left blank instead, so the two can be told apart.
+    pub none_placeholder: String,
+    /// Repeat the header of an aggregate table after every this many rows, so the columns can
+    /// still be told apart deep into a long table.  Zero only writes it at the top.
+    pub header_interval: usize,
+    /// Right-align the columns of an aggregate table that only hold numbers, and their headers
+    pub right_align_numbers: bool,
+    /// Written after each record instead of a newline, like `\0` for `xargs -0`.  Aggregate
+    /// tables and the files written when splitting records always use newlines.
+    pub record_separator: String,
+    /// Separate the thousands of numbers, like `1,000,000`.  The fractional part of a float is
+    /// left alone.
+    pub group_digits: bool,
+    /// Written between the groups of digits when `group_digits` is set, for locales that write
+    /// `1.000.000` or `1 000 000`
+    pub digit_separator: char,
+    /// The table named in SQL output
+    pub sql_table: String,
+    /// Start SQL output with a `CREATE TABLE` statement, with column types inferred from the
+    /// rows
+    pub sql_create: bool,
+}
+
+/// Where the trailer written after the output goes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrailerTarget {
+    /// The last line of the output itself
+    Output,
+    Stderr,
+}
+
+/// Where records are written when they're split into a file per value of a field
+#[derive(Debug, Clone, PartialEq)]
+pub struct SplitConfig {
+    pub field: String,
+    pub dir: PathBuf,
+}
+
+/// Where and how often snapshots of a running aggregate are written
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotConfig {
+    pub path: PathBuf,
+    pub interval: Duration,
 }
 
-impl RenderConfig {
-    pub fn default() -> Self {
+impl Default for RenderConfig {
+    fn default() -> Self {
         RenderConfig {
             floating_points: 2,
             min_buffer: 1,
             max_buffer: 4,
             format: None,
+            output_mode: OutputMode::Legacy,
+            output_file: None,
+            append: false,
+            dedup_output: false,
+            dedup_count: false,
+            theme: Theme::Auto,
+            color: ColorChoice::Auto,
+            sort_record_fields: false,
+            pinned_fields: Vec::new(),
+            update_interval: DEFAULT_UPDATE_INTERVAL,
+            partial_interval: None,
+            records_per_update: None,
+            split: None,
+            width_percentile: None,
+            snapshot: None,
+            compact: false,
+            record_separator: "\n".to_string(),
+            trailer: None,
+            ascii: false,
+            ellipsis: ELLIPSIS.to_string(),
+            overflow: Overflow::Truncate,
+            header_interval: 0,
+            none_placeholder: "None".to_string(),
+            right_align_numbers: false,
+            group_digits: false,
+            digit_separator: ',',
+            sql_table: "results".to_string(),
+            sql_create: false,
         }
     }
 }
@@ -35,31 +436,157 @@ struct TerminalSize {
     width: u16,
 }
 
+/// How many recent value lengths are kept per column of streaming records when sizing columns by
+/// percentile
+const WIDTH_SAMPLE_SIZE: usize = 1000;
+
 struct PrettyPrinter {
     render_config: RenderConfig,
     column_widths: HashMap<String, usize>,
     column_order: Vec<String>,
+    /// The lengths of the most recent values of each column of streaming records
+    value_lengths: HashMap<String, VecDeque<usize>>,
+    /// The longest value length a column is sized for, when sizing columns by percentile.  Longer
+    /// values are cut off.
+    length_limits: HashMap<String, usize>,
     term_size: Option<TerminalSize>,
+    palette: Option<Palette>,
+    /// Whether the `CREATE TABLE` statement has been written before the first SQL record
+    sql_table_created: bool,
 }
 
-// MAYBE TODO: do any terminals not support unicode anymore? If so it would be nice to detect that
-// and display "..." instead
-const ELLIPSIS: &str = "…";
+/// The default for `RenderConfig::ellipsis`
+pub const ELLIPSIS: &str = "…";
 
-fn format_with_ellipsis<S: Into<String>>(inp: S, limit: usize) -> String {
+/// How often a live aggregate is redrawn by default
+pub const DEFAULT_UPDATE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Redrawing more often than this only makes a live aggregate flicker, so shorter update
+/// intervals are raised to it
+pub const MIN_UPDATE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Pad `inp` to `limit` characters, or cut it off with `ellipsis` and a space so that it takes
+/// exactly `limit` characters, however long the ellipsis is.  Below that, even the ellipsis is
+/// cut off.
+fn format_with_ellipsis<S: Into<String>>(inp: S, limit: usize, ellipsis: &str) -> String {
     let inp = inp.into();
     if inp.chars().count() > limit {
         format!(
             "{str:.prelimit$}{ellipsis} ",
             str = inp,
-            prelimit = limit - ELLIPSIS.chars().count() - 1,
-            ellipsis = ELLIPSIS
+            prelimit = limit.saturating_sub(ellipsis.chars().count() + 1),
+            ellipsis = ellipsis
         )
+        .chars()
+        .take(limit)
+        .collect()
     } else {
         format!("{:limit$}", inp, limit = limit)
     }
 }
 
+const SPARK_BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const SPARK_ASCII: [char; 8] = ['_', '.', ',', '-', '~', '+', '*', '#'];
+
+/// One character per point, from the lowest level for the smallest point to the highest for
+/// the largest.  When all the points are the same they're drawn at the lowest level.
+pub fn sparkline(points: &[f64], ascii: bool) -> String {
+    let levels = if ascii { &SPARK_ASCII } else { &SPARK_BLOCKS };
+    let min = points.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = points.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    points
+        .iter()
+        .map(|point| {
+            let level = if max > min {
+                ((point - min) / (max - min) * (levels.len() - 1) as f64).round() as usize
+            } else {
+                0
+            };
+            levels[level]
+        })
+        .collect()
+}
+
+/// Whether a field is left out of compact records
+fn is_empty_value(value: &data::Value) -> bool {
+    match value {
+        data::Value::None => true,
+        data::Value::Str(s) => s.is_empty(),
+        _ => false,
+    }
+}
+
+/// Cut `inp` off with `ellipsis` if it's longer than `limit` characters
+fn truncate_with_ellipsis(inp: String, limit: usize, ellipsis: &str) -> String {
+    if inp.chars().count() > limit {
+        let kept: String = inp
+            .chars()
+            .take(limit.saturating_sub(ellipsis.chars().count()))
+            .collect();
+        kept + ellipsis
+    } else {
+        inp
+    }
+}
+
+/// Split `text` into lines of at most `width` characters, breaking at the last space that fits
+/// when there is one.  Newlines in the text always start a new line.
+fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let chars: Vec<char> = paragraph.chars().collect();
+        let mut start = 0;
+        while chars.len() - start > width {
+            // One more than fits, so a space right after a full line is a place to break
+            let window = &chars[start..=start + width];
+            match window.iter().rposition(|c| *c == ' ') {
+                Some(space) if space > 0 => {
+                    lines.push(window[..space].iter().collect());
+                    start += space + 1;
+                }
+                _ => {
+                    lines.push(window[..width].iter().collect());
+                    start += width;
+                }
+            }
+        }
+        lines.push(chars[start..].iter().collect());
+    }
+    lines
+}
+
+/// Lay out cells that may span several lines side by side.  Each cell is the width of its
+/// column and a list of lines; a cell with fewer lines than the others is blank below them.
+fn join_cell_lines(cells: &[(usize, Vec<String>)]) -> String {
+    let height = cells
+        .iter()
+        .map(|(_, lines)| lines.len())
+        .max()
+        .unwrap_or(0);
+    (0..height)
+        .map(|i| {
+            let line: String = cells
+                .iter()
+                .map(|(width, lines)| lines.get(i).cloned().unwrap_or_else(|| " ".repeat(*width)))
+                .collect();
+            line.trim_end().to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The length that `percentile` percent of `lengths` fit within, by the nearest rank method
+fn percentile_length<I: Iterator<Item = usize>>(lengths: I, percentile: f64) -> usize {
+    let mut lengths: Vec<usize> = lengths.collect();
+    if lengths.is_empty() {
+        return 0;
+    }
+    lengths.sort();
+    let rank = (percentile / 100.0 * lengths.len() as f64).ceil() as usize;
+    lengths[rank.max(1).min(lengths.len()) - 1]
+}
+
 impl PrettyPrinter {
     fn new(render_config: RenderConfig, term_size: Option<TerminalSize>) -> Self {
         PrettyPrinter {
@@ -67,18 +594,72 @@ impl PrettyPrinter {
             term_size,
             column_widths: HashMap::new(),
             column_order: Vec::new(),
+            value_lengths: HashMap::new(),
+            length_limits: HashMap::new(),
+            palette: None,
+            sql_table_created: false,
+        }
+    }
+
+    /// Add the value lengths of a record to the rolling samples of its columns and update the
+    /// length limits of those columns
+    fn sample_record_lengths(&mut self, data: &HashMap<String, data::Value>, percentile: f64) {
+        for (column_name, value) in data {
+            let lengths = self.value_lengths.entry(column_name.clone()).or_default();
+            if lengths.len() == WIDTH_SAMPLE_SIZE {
+                lengths.pop_front();
+            }
+            lengths.push_back(value.render(&self.render_config).len());
+            let limit = percentile_length(lengths.iter().cloned(), percentile);
+            self.length_limits.insert(column_name.clone(), limit);
+        }
+    }
+
+    /// Set the length limit of each column of an aggregate from all of its current rows
+    fn sample_aggregate_lengths(&mut self, aggregate: &data::Aggregate, percentile: f64) {
+        for column_name in &aggregate.columns {
+            let format = aggregate.formats.get(column_name).cloned();
+            let lengths = aggregate.data.iter().map(|row| {
+                row.get(column_name)
+                    .unwrap_or(&data::Value::None)
+                    .render_as(&self.render_config, format)
+                    .len()
+            });
+            let limit = percentile_length(lengths, percentile);
+            self.length_limits.insert(column_name.clone(), limit);
         }
     }
 
-    fn compute_column_widths(&self, data: &HashMap<String, data::Value>) -> HashMap<String, usize> {
+    fn compute_column_widths(
+        &self,
+        data: &HashMap<String, data::Value>,
+        formats: &HashMap<String, data::ValueFormat>,
+    ) -> HashMap<String, usize> {
         data.iter()
             .map(|(column_name, value)| {
                 let current_width = *self.column_widths.get(column_name).unwrap_or(&0);
+                let format = formats.get(column_name).cloned();
+                let rendered = value.render_as(&self.render_config, format);
+                // Sparkline blocks and some digit separators take several bytes each but only one
+                // column on screen
+                let value_length = if format == Some(data::ValueFormat::Sparkline)
+                    || self.render_config.group_digits
+                {
+                    rendered.chars().count()
+                } else {
+                    rendered.len()
+                };
+                // When sizing by percentile, columns also shrink once an outlier that widened
+                // them is no longer in the sample
+                let (value_length, current_width) = match self.length_limits.get(column_name) {
+                    Some(&limit) => {
+                        let widest = limit.max(column_name.len()) + self.render_config.max_buffer;
+                        (value_length.min(limit), current_width.min(widest))
+                    }
+                    None => (value_length, current_width),
+                };
+                let value_length = value_length.max(column_name.len());
                 // 1. If the width would increase, set it to max_buffer
-                let value_length = value
-                    .render(&self.render_config)
-                    .len()
-                    .max(column_name.len());
                 let min_column_width = value_length + self.render_config.min_buffer;
                 let new_column_width = if min_column_width > current_width {
                     // if we're resizing, go to the max
@@ -101,6 +682,23 @@ impl PrettyPrinter {
         new_keys
     }
 
+    /// Sort the columns of records alphabetically if configured, and then move the pinned fields
+    /// to the front.  The sort is stable, so the other columns keep their order.
+    fn order_columns(&mut self) {
+        if self.render_config.sort_record_fields {
+            self.column_order.sort();
+        }
+        let pinned = &self.render_config.pinned_fields;
+        if !pinned.is_empty() {
+            self.column_order.sort_by_key(|column_name| {
+                pinned
+                    .iter()
+                    .position(|field| field == column_name)
+                    .unwrap_or(pinned.len())
+            });
+        }
+    }
+
     fn projected_width(column_widths: &HashMap<String, usize>) -> usize {
         column_widths
             .iter()
@@ -120,56 +718,205 @@ impl PrettyPrinter {
     }
 
     fn format_record_as_columns(&mut self, record: &data::Record) -> String {
-        let new_column_widths = self.compute_column_widths(&(record.data));
+        let compacted: HashMap<String, data::Value>;
+        let fields = if self.render_config.compact {
+            compacted = record
+                .data
+                .iter()
+                .filter(|(_, value)| !is_empty_value(value))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            &compacted
+        } else {
+            &record.data
+        };
+        if let Some(percentile) = self.render_config.width_percentile {
+            self.sample_record_lengths(fields, percentile);
+        }
+        let new_column_widths = self.compute_column_widths(fields, &HashMap::new());
         self.column_widths.extend(new_column_widths);
-        let new_columns = self.new_columns(&(record.data));
+        let new_columns = self.new_columns(fields);
+        let added_columns = !new_columns.is_empty();
         self.column_order.extend(new_columns);
+        if added_columns {
+            self.order_columns();
+        }
         if self.column_order.is_empty() {
             return record.raw.trim_end().to_string();
         }
 
         let no_padding = if self.overflows_term() {
             self.column_widths = HashMap::new();
-            self.column_widths = self.compute_column_widths(&(record.data));
+            self.column_widths = self.compute_column_widths(fields, &HashMap::new());
             self.column_order = Vec::new();
-            self.column_order = self.new_columns(&(record.data));
+            self.column_order = self.new_columns(fields);
+            self.order_columns();
             self.overflows_term()
         } else {
             false
         };
-        let strs: Vec<String> = self
+        let cells: Vec<(usize, Vec<String>)> = self
             .column_order
             .iter()
+            // Compact records leave out the fields they don't have instead of padding them
+            .filter(|column_name| !self.render_config.compact || fields.contains_key(*column_name))
             .map(|column_name| {
-                let value = record.data.get(column_name);
-
-                let unpadded = match value {
+                let width = column_name.len() + 3 + self.column_widths[column_name];
+                let lines: Vec<(String, String)> = match fields.get(column_name) {
                     Some(value) => {
-                        format!("[{}={}]", column_name, value.render(&self.render_config))
+                        let rendered = value.render(&self.render_config);
+                        let parts = match self.length_limits.get(column_name) {
+                            Some(_) if !no_padding => {
+                                let limit = self.column_widths[column_name]
+                                    .saturating_sub(self.render_config.min_buffer);
+                                match self.render_config.overflow {
+                                    Overflow::Wrap => wrap_text(&rendered, limit),
+                                    Overflow::Truncate => vec![truncate_with_ellipsis(
+                                        rendered,
+                                        limit,
+                                        &self.render_config.ellipsis,
+                                    )],
+                                }
+                            }
+                            _ => vec![rendered],
+                        };
+                        // The lines of a wrapped value line up under the first one
+                        let last = parts.len() - 1;
+                        parts
+                            .into_iter()
+                            .enumerate()
+                            .map(|(i, part)| {
+                                let close = if i == last { "]" } else { "" };
+                                let open = if i == 0 {
+                                    format!("[{}=", column_name)
+                                } else {
+                                    " ".repeat(column_name.len() + 2)
+                                };
+                                let unpadded = format!("{}{}{}", open, part, close);
+                                let painted = match self.palette {
+                                    Some(palette) if i == 0 => format!(
+                                        "[{}={}{}",
+                                        paint(Some(palette.header), column_name.to_string()),
+                                        paint(palette.value(value), part),
+                                        close
+                                    ),
+                                    Some(palette) => format!(
+                                        "{}{}{}",
+                                        open,
+                                        paint(palette.value(value), part),
+                                        close
+                                    ),
+                                    None => unpadded.clone(),
+                                };
+                                (unpadded, painted)
+                            })
+                            .collect()
                     }
-                    None => "".to_string(),
+                    None => vec![("".to_string(), "".to_string())],
                 };
-                if no_padding {
-                    unpadded
-                } else {
-                    format!(
-                        "{:width$}",
-                        unpadded,
-                        width = column_name.len() + 3 + self.column_widths[column_name]
-                    )
-                }
+                let lines = lines
+                    .into_iter()
+                    .map(|(unpadded, painted)| {
+                        if no_padding {
+                            painted
+                        } else {
+                            // Pad by the width of the text alone, since color codes take up no
+                            // space
+                            let padding = width.saturating_sub(unpadded.chars().count());
+                            format!("{}{}", painted, " ".repeat(padding))
+                        }
+                    })
+                    .collect();
+                (width, lines)
             })
             .collect();
-        strs.join("").trim().to_string()
+        if cells.iter().all(|(_, lines)| lines.len() == 1) {
+            let strs: Vec<&str> = cells.iter().map(|(_, lines)| lines[0].as_str()).collect();
+            strs.join("").trim().to_string()
+        } else {
+            join_cell_lines(&cells)
+        }
     }
 
     fn format_record_as_format(&self, format: &String, record: &data::Record) -> String {
         strfmt(format, &record.data).unwrap()
     }
 
+    /// Render a record with one `key: value` line per field, in alphabetical order.  The record
+    /// ends with an empty line to separate it from the next one.
+    fn format_record_vertically(&self, record: &data::Record) -> String {
+        if record.data.is_empty() {
+            return record.raw.trim_end().to_string();
+        }
+        let mut keys: Vec<&String> = record.data.keys().collect();
+        keys.sort();
+        let mut lines = Vec::new();
+        for key in keys {
+            push_vertical_lines(
+                &mut lines,
+                0,
+                &format!("{}: ", key),
+                &record.data[key],
+                &self.render_config,
+            );
+        }
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    /// Render a record as an `INSERT` with its fields in alphabetical order.  A record without
+    /// fields is inserted into a `line` column.  With `sql_create`, the first record is preceded
+    /// by a `CREATE TABLE` typed from its values, since the rest of the stream isn't known yet.
+    fn format_record_as_sql(&mut self, record: &data::Record) -> String {
+        let raw = data::Value::Str(record.raw.trim_end().to_string());
+        let mut fields: Vec<(&str, &data::Value)> = record
+            .data
+            .iter()
+            .map(|(key, value)| (key.as_str(), value))
+            .collect();
+        if fields.is_empty() {
+            fields.push(("line", &raw));
+        }
+        fields.sort_by_key(|(key, _)| *key);
+        let columns: Vec<&str> = fields.iter().map(|(key, _)| *key).collect();
+        let mut output = String::new();
+        if self.render_config.sql_create && !self.sql_table_created {
+            let types: Vec<&str> = fields
+                .iter()
+                .map(|(_, value)| sql_type(std::iter::once(*value), None))
+                .collect();
+            output.push_str(&sql_create_table(
+                &self.render_config.sql_table,
+                &columns,
+                &types,
+            ));
+            output.push('\n');
+            self.sql_table_created = true;
+        }
+        let values: Vec<String> = fields
+            .iter()
+            .map(|(_, value)| sql_literal(value, &self.render_config, None))
+            .collect();
+        output.push_str(&sql_insert(
+            &self.render_config.sql_table,
+            &columns,
+            &values,
+        ));
+        output
+    }
+
     fn format_record(&mut self, record: &data::Record) -> String {
         match self.render_config.format {
             Some(ref format) => self.format_record_as_format(format, record),
+            None if self.render_config.output_mode == OutputMode::Vertical => {
+                self.format_record_vertically(record)
+            }
+            None if self.render_config.output_mode == OutputMode::Sql => {
+                self.format_record_as_sql(record)
+            }
+            None if self.render_config.output_mode == OutputMode::Json => {
+                format_record_as_json(record)
+            }
             None => self.format_record_as_columns(record),
         }
     }
@@ -217,56 +964,179 @@ impl PrettyPrinter {
         }
     }
 
+    /// Whether a column of an aggregate is right-aligned: it's turned on and every value in the
+    /// column is a number or missing
+    fn is_right_aligned(&self, aggregate: &data::Aggregate, column_name: &str) -> bool {
+        self.render_config.right_align_numbers
+            && aggregate
+                .data
+                .iter()
+                .any(|row| row.contains_key(column_name))
+            && aggregate.data.iter().all(|row| match row.get(column_name) {
+                Some(data::Value::Int(_)) | Some(data::Value::Float(_)) => true,
+                Some(data::Value::None) | None => true,
+                Some(_) => false,
+            })
+    }
+
+    /// Fit `text` into a column, right-aligned before the column's spacing when `right_aligned`
+    /// is set and it fits, and otherwise left-aligned and cut off if needed
+    fn format_cell(&self, text: String, width: usize, right_aligned: bool) -> String {
+        let content_width = width.saturating_sub(self.render_config.min_buffer);
+        if right_aligned && text.chars().count() <= content_width {
+            format!(
+                "{:>content_width$}{:buffer$}",
+                text,
+                "",
+                content_width = content_width,
+                buffer = width - content_width
+            )
+        } else {
+            format_with_ellipsis(text, width, &self.render_config.ellipsis)
+        }
+    }
+
+    /// Like `format_cell`, but with `Overflow::Wrap` a value too long for the column is wrapped
+    /// onto more lines instead of being cut off
+    fn format_cell_lines(&self, text: String, width: usize, right_aligned: bool) -> Vec<String> {
+        let content_width = width.saturating_sub(self.render_config.min_buffer).max(1);
+        let overflows = text.chars().count() > content_width || text.contains('\n');
+        if self.render_config.overflow == Overflow::Wrap && overflows {
+            wrap_text(&text, content_width)
+                .into_iter()
+                .map(|line| format!("{:width$}", line, width = width))
+                .collect()
+        } else {
+            vec![self.format_cell(text, width, right_aligned)]
+        }
+    }
+
     fn format_aggregate_row(
         &self,
-        columns: &[String],
+        aggregate: &data::Aggregate,
         row: &HashMap<String, data::Value>,
+        right_aligned: &HashSet<&str>,
     ) -> String {
-        let row: Vec<String> = columns
+        let cells: Vec<(usize, Vec<String>)> = aggregate
+            .columns
             .iter()
             .map(|column_name| {
-                format_with_ellipsis(
-                    row.get(column_name)
-                        .unwrap_or(&data::Value::None)
-                        .render(&self.render_config),
-                    self.column_widths[column_name],
-                )
+                let width = self.column_widths[column_name];
+                let value = match row.get(column_name) {
+                    Some(value) => value,
+                    None => return (width, vec![" ".repeat(width)]),
+                };
+                let color = self.palette.and_then(|palette| palette.value(value));
+                let lines = self
+                    .format_cell_lines(
+                        value.render_as(
+                            &self.render_config,
+                            aggregate.formats.get(column_name).cloned(),
+                        ),
+                        width,
+                        right_aligned.contains(column_name.as_str()),
+                    )
+                    .into_iter()
+                    .map(|line| paint_padded(color, line))
+                    .collect();
+                (width, lines)
             })
             .collect();
-        row.join("").trim().to_string()
+        join_cell_lines(&cells)
+    }
+
+    /// Render an aggregate as a Markdown table.  Cells aren't padded or cut off, since whatever
+    /// displays the table lines the columns up.  `|` in a value is escaped and newlines become
+    /// `<br>`, so each row stays on one line.  Missing values are empty cells.
+    fn format_aggregate_markdown(&self, aggregate: &data::Aggregate) -> String {
+        let escape = |text: &str| text.replace('|', "\\|").replace('\n', "<br>");
+        let line = |cells: Vec<String>| format!("| {} |\n", cells.join(" | "));
+        let mut output = line(aggregate.columns.iter().map(|c| escape(c)).collect());
+        output.push_str(&line(
+            aggregate
+                .columns
+                .iter()
+                .map(|column_name| {
+                    if self.is_right_aligned(aggregate, column_name) {
+                        "---:".to_string()
+                    } else {
+                        "---".to_string()
+                    }
+                })
+                .collect(),
+        ));
+        for row in &aggregate.data {
+            output.push_str(&line(
+                aggregate
+                    .columns
+                    .iter()
+                    .map(|column_name| match row.get(column_name) {
+                        None | Some(data::Value::None) => String::new(),
+                        Some(value) => escape(&value.render_as(
+                            &self.render_config,
+                            aggregate.formats.get(column_name).cloned(),
+                        )),
+                    })
+                    .collect(),
+            ));
+        }
+        output
     }
 
     fn format_aggregate(&mut self, aggregate: &data::Aggregate) -> String {
+        if self.render_config.output_mode == OutputMode::Markdown {
+            return self.format_aggregate_markdown(aggregate);
+        }
         if aggregate.data.is_empty() {
             return "No data\n".to_string();
         }
 
+        if let Some(percentile) = self.render_config.width_percentile {
+            self.sample_aggregate_lengths(aggregate, percentile);
+        }
         aggregate.data.iter().for_each(|row| {
-            let new_widths = self.compute_column_widths(row);
+            let new_widths = self.compute_column_widths(row, &aggregate.formats);
             self.column_widths.extend(new_widths);
         });
 
         self.column_widths = self.resize_widths_to_fit(&self.column_widths, &aggregate.columns);
         assert!(self.fits_within_term_agg(), "{:?}", self.column_widths);
+        let right_aligned: HashSet<&str> = aggregate
+            .columns
+            .iter()
+            .filter(|column_name| self.is_right_aligned(aggregate, column_name))
+            .map(String::as_str)
+            .collect();
         let header: Vec<String> = aggregate
             .columns
             .iter()
             .map(|column_name| {
-                format!(
-                    "{:width$}",
-                    column_name,
-                    width = self.column_widths[column_name]
-                )
+                let width = self.column_widths[column_name];
+                if right_aligned.contains(column_name.as_str()) {
+                    self.format_cell(column_name.clone(), width, true)
+                } else {
+                    format!("{:width$}", column_name, width = width)
+                }
             })
             .collect();
         let header = header.join("");
         let header_len = header.len();
-        let header = format!("{}\n{}", header.trim(), "-".repeat(header_len));
-        let body: Vec<String> = aggregate
-            .data
-            .iter()
-            .map(|row| self.format_aggregate_row(&aggregate.columns, row))
-            .collect();
+        let header = format!(
+            "{}\n{}",
+            paint(
+                self.palette.map(|palette| palette.header),
+                header.trim_end().to_string()
+            ),
+            "-".repeat(header_len)
+        );
+        let mut body: Vec<String> = Vec::with_capacity(aggregate.data.len());
+        for (i, row) in aggregate.data.iter().enumerate() {
+            let interval = self.render_config.header_interval;
+            if interval > 0 && i > 0 && i % interval == 0 {
+                body.push(header.clone());
+            }
+            body.push(self.format_aggregate_row(aggregate, row, &right_aligned));
+        }
         let overlength_str = format!("{}\n{}\n", header, body.join("\n"));
         match self.term_size {
             Some(TerminalSize { height, .. }) => {
@@ -278,88 +1148,931 @@ impl PrettyPrinter {
     }
 }
 
-pub struct Renderer {
-    pretty_printer: PrettyPrinter,
-    update_interval: Duration,
-    stdout: std::io::Stdout,
-
-    reset_sequence: String,
-    is_tty: bool,
-    last_print: Option<Instant>,
+/// Add the lines for one value of a vertical record.  `prefix` is `key: ` for the fields of an
+/// object and `- ` for the items of a list.  Non-empty objects and lists put their contents on
+/// the following lines, indented beneath the prefix; everything else fits on one line.
+fn push_vertical_lines(
+    lines: &mut Vec<String>,
+    indent: usize,
+    prefix: &str,
+    value: &data::Value,
+    render_config: &RenderConfig,
+) {
+    match value {
+        data::Value::Obj(ref obj) if !obj.is_empty() => {
+            lines.push(format!(
+                "{:indent$}{}",
+                "",
+                prefix.trim_end(),
+                indent = indent
+            ));
+            let mut items: Vec<&(String, data::Value)> = obj.iter().collect();
+            items.sort();
+            for (key, value) in items {
+                let prefix = format!("{}: ", key);
+                push_vertical_lines(lines, indent + 2, &prefix, value, render_config);
+            }
+        }
+        data::Value::Array(ref items) if !items.is_empty() => {
+            lines.push(format!(
+                "{:indent$}{}",
+                "",
+                prefix.trim_end(),
+                indent = indent
+            ));
+            for value in items {
+                push_vertical_lines(lines, indent + 2, "- ", value, render_config);
+            }
+        }
+        _ => lines.push(format!(
+            "{:indent$}{}{}",
+            "",
+            prefix,
+            value.render(render_config),
+            indent = indent
+        )),
+    }
 }
 
-impl Renderer {
-    pub fn new(config: RenderConfig, update_interval: Duration) -> Self {
-        let tsize_opt =
-            terminal_size().map(|(Width(width), Height(height))| TerminalSize { width, height });
-        Renderer {
-            is_tty: tsize_opt.is_some(),
-            pretty_printer: PrettyPrinter::new(config, tsize_opt),
-            stdout: stdout(),
-            reset_sequence: "".to_string(),
-            last_print: None,
-            update_interval,
-        }
+/// Quote a CSV cell if it contains a separator, quote or line break
+fn csv_escape(cell: &str) -> Cow<'_, str> {
+    if cell.contains(&[',', '"', '\n', '\r'][..]) {
+        Cow::Owned(format!("\"{}\"", cell.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(cell)
     }
+}
 
-    pub fn render(&mut self, row: &data::Row, last_row: bool) -> Result<(), Error> {
-        match *row {
-            data::Row::Aggregate(ref aggregate) => {
-                if !self.is_tty {
-                    if last_row {
-                        let output = self.pretty_printer.format_aggregate(aggregate);
-                        write!(self.stdout, "{}", output)?;
-                    }
-                } else if self.should_print() || last_row {
-                    let output = self.pretty_printer.format_aggregate(aggregate);
-                    let num_lines = output.matches('\n').count();
-                    write!(self.stdout, "{}{}", self.reset_sequence, output)?;
-                    self.reset_sequence = "\x1b[2K\x1b[1A".repeat(num_lines);
-                    self.last_print = Some(Instant::now());
-                }
+/// Write an aggregate as CSV one row at a time so the full table is never buffered as a string.
+/// Missing values are written as empty cells.
+fn write_csv_header<W: Write>(out: &mut W, columns: &[String]) -> std::io::Result<()> {
+    let header: Vec<Cow<'_, str>> = columns
+        .iter()
+        .map(|column_name| csv_escape(column_name))
+        .collect();
+    writeln!(out, "{}", header.join(","))
+}
 
-                Ok(())
+fn write_aggregate_csv<W: Write>(
+    out: &mut W,
+    aggregate: &data::Aggregate,
+    render_config: &RenderConfig,
+    header: bool,
+) -> std::io::Result<()> {
+    if header {
+        write_csv_header(out, &aggregate.columns)?;
+    }
+    for row in &aggregate.data {
+        for (i, column_name) in aggregate.columns.iter().enumerate() {
+            if i > 0 {
+                out.write_all(b",")?;
             }
-            data::Row::Record(ref record) => {
-                let output = self.pretty_printer.format_record(record);
-                writeln!(self.stdout, "{}", output)?;
-
-                Ok(())
+            match row.get(column_name) {
+                None | Some(data::Value::None) => {}
+                Some(value) => {
+                    let format = aggregate.formats.get(column_name).cloned();
+                    out.write_all(csv_escape(&value.render_as(render_config, format)).as_bytes())?
+                }
             }
         }
+        out.write_all(b"\n")?;
     }
+    out.flush()
+}
 
-    pub fn should_print(&self) -> bool {
-        if !self.is_tty {
-            return false;
+/// Numbers stay numbers, except for NaN and infinity which JSON can't represent, and are written
+/// as `null` like missing values
+fn value_to_json(value: &data::Value) -> serde_json::Value {
+    match value {
+        data::Value::None => serde_json::Value::Null,
+        data::Value::Str(s) => serde_json::Value::String(s.clone()),
+        data::Value::Int(i) => serde_json::Value::from(*i),
+        data::Value::Float(f) => serde_json::Number::from_f64(f.into_inner())
+            .map_or(serde_json::Value::Null, serde_json::Value::Number),
+        data::Value::Bool(b) => serde_json::Value::Bool(*b),
+        data::Value::Obj(o) => serde_json::Value::Object(
+            o.iter()
+                .map(|(k, v)| (k.clone(), value_to_json(v)))
+                .collect(),
+        ),
+        data::Value::Array(vec) => {
+            serde_json::Value::Array(vec.iter().map(value_to_json).collect())
         }
-        self.last_print
-            .map(|instant| instant.elapsed() > self.update_interval)
-            .unwrap_or(true)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::data::*;
-    use crate::operator::*;
-    use maplit::hashmap;
-
-    #[test]
-    fn print_raw() {
-        let rec = Record::new("Hello, World!\n");
-        let mut pp = PrettyPrinter::new(
-            RenderConfig {
-                floating_points: 2,
-                min_buffer: 1,
-                max_buffer: 4,
-                format: None,
-            },
-            None,
+/// Render a record as a single line JSON object with its keys in alphabetical order.  A record
+/// without fields is written as `{"line": ...}` so every line of the output is still an object.
+fn format_record_as_json(record: &data::Record) -> String {
+    let object: serde_json::Map<String, serde_json::Value> = if record.data.is_empty() {
+        let mut object = serde_json::Map::new();
+        object.insert(
+            "line".to_string(),
+            serde_json::Value::String(record.raw.trim_end().to_string()),
         );
-        assert_eq!(pp.format_record(&rec), "Hello, World!");
-    }
+        object
+    } else {
+        record
+            .data
+            .iter()
+            .map(|(k, v)| (k.clone(), value_to_json(v)))
+            .collect()
+    };
+    serde_json::Value::Object(object).to_string()
+}
+
+/// Write an aggregate as a JSON array with an object per row on a line of its own.  The keys are
+/// in the order of the aggregate's columns, and missing values are `null` so every object has
+/// all of the columns.
+fn write_aggregate_json<W: Write>(out: &mut W, aggregate: &data::Aggregate) -> std::io::Result<()> {
+    out.write_all(b"[")?;
+    for (i, row) in aggregate.data.iter().enumerate() {
+        out.write_all(if i > 0 { b",\n  {" } else { b"\n  {" })?;
+        for (j, column_name) in aggregate.columns.iter().enumerate() {
+            if j > 0 {
+                out.write_all(b",")?;
+            }
+            let value = row
+                .get(column_name)
+                .map_or(serde_json::Value::Null, value_to_json);
+            write!(
+                out,
+                "{}:{}",
+                serde_json::Value::String(column_name.clone()),
+                value
+            )?;
+        }
+        out.write_all(b"}")?;
+    }
+    out.write_all(b"\n]\n")?;
+    out.flush()
+}
+
+/// Quote an SQL identifier so column names like `count` or `_p50` are always valid
+fn sql_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Missing values and floats SQL can't represent, like NaN, are written as `NULL`.  Objects and
+/// lists are written as their rendered text.
+fn sql_literal(
+    value: &data::Value,
+    render_config: &RenderConfig,
+    format: Option<data::ValueFormat>,
+) -> String {
+    match value {
+        data::Value::None => "NULL".to_string(),
+        data::Value::Float(f) if !f.is_finite() => "NULL".to_string(),
+        data::Value::Int(_) | data::Value::Float(_) => value.render_as(render_config, format),
+        data::Value::Bool(true) => "TRUE".to_string(),
+        data::Value::Bool(false) => "FALSE".to_string(),
+        _ => format!(
+            "'{}'",
+            value.render_as(render_config, format).replace('\'', "''")
+        ),
+    }
+}
+
+/// The column type for a set of values, ignoring missing ones.  Integers mixed with floats, or
+/// rendered as floats, make a `REAL` column and any other mix falls back to `TEXT`.
+fn sql_type<'a, I: Iterator<Item = &'a data::Value>>(
+    values: I,
+    format: Option<data::ValueFormat>,
+) -> &'static str {
+    let mut column_type = None;
+    for value in values {
+        let value_type = match value {
+            data::Value::None => continue,
+            data::Value::Int(_) if format == Some(data::ValueFormat::Float) => "REAL",
+            data::Value::Float(_) if format == Some(data::ValueFormat::Integer) => "INTEGER",
+            data::Value::Int(_) => "INTEGER",
+            data::Value::Float(_) => "REAL",
+            data::Value::Bool(_) => "BOOLEAN",
+            _ => "TEXT",
+        };
+        column_type = match (column_type, value_type) {
+            (None, value_type) => Some(value_type),
+            (Some("INTEGER"), "REAL") | (Some("REAL"), "INTEGER") => Some("REAL"),
+            (Some(column_type), value_type) if column_type == value_type => Some(column_type),
+            _ => Some("TEXT"),
+        };
+    }
+    column_type.unwrap_or("TEXT")
+}
+
+fn sql_create_table(table: &str, columns: &[&str], types: &[&str]) -> String {
+    let definitions: Vec<String> = columns
+        .iter()
+        .zip(types)
+        .map(|(column, column_type)| format!("{} {}", sql_identifier(column), column_type))
+        .collect();
+    format!(
+        "CREATE TABLE {} ({});",
+        sql_identifier(table),
+        definitions.join(", ")
+    )
+}
+
+fn sql_insert(table: &str, columns: &[&str], values: &[String]) -> String {
+    let columns: Vec<String> = columns
+        .iter()
+        .map(|column| sql_identifier(column))
+        .collect();
+    format!(
+        "INSERT INTO {} ({}) VALUES ({});",
+        sql_identifier(table),
+        columns.join(", "),
+        values.join(", ")
+    )
+}
+
+/// Write an aggregate as an `INSERT` per row, in the order of the aggregate's columns
+fn write_aggregate_sql<W: Write>(
+    out: &mut W,
+    aggregate: &data::Aggregate,
+    render_config: &RenderConfig,
+) -> std::io::Result<()> {
+    let columns: Vec<&str> = aggregate.columns.iter().map(String::as_str).collect();
+    if render_config.sql_create {
+        let types: Vec<&str> = aggregate
+            .columns
+            .iter()
+            .map(|column_name| {
+                sql_type(
+                    aggregate.data.iter().filter_map(|row| row.get(column_name)),
+                    aggregate.formats.get(column_name).cloned(),
+                )
+            })
+            .collect();
+        writeln!(
+            out,
+            "{}",
+            sql_create_table(&render_config.sql_table, &columns, &types)
+        )?;
+    }
+    for row in &aggregate.data {
+        let values: Vec<String> = aggregate
+            .columns
+            .iter()
+            .map(|column_name| match row.get(column_name) {
+                None => "NULL".to_string(),
+                Some(value) => sql_literal(
+                    value,
+                    render_config,
+                    aggregate.formats.get(column_name).cloned(),
+                ),
+            })
+            .collect();
+        writeln!(
+            out,
+            "{}",
+            sql_insert(&render_config.sql_table, &columns, &values)
+        )?;
+    }
+    out.flush()
+}
+
+/// Write an aggregate as a single sheet spreadsheet.  The header row holds the column names and
+/// numbers and booleans are written as typed cells rather than text.
+#[cfg(feature = "xlsx")]
+fn write_aggregate_xlsx<W: Write>(
+    out: &mut W,
+    aggregate: &data::Aggregate,
+    render_config: &RenderConfig,
+) -> Result<(), Error> {
+    use simple_excel_writer::{Row, Workbook};
+
+    let mut workbook = Workbook::create_in_memory();
+    let mut sheet = workbook.create_sheet("agrind");
+    workbook.write_sheet(&mut sheet, |sheet_writer| {
+        let mut header = Row::new();
+        for column_name in &aggregate.columns {
+            header.add_cell(column_name.as_str());
+        }
+        sheet_writer.append_row(header)?;
+        for data_row in &aggregate.data {
+            let mut row = Row::new();
+            for column_name in &aggregate.columns {
+                match data_row.get(column_name) {
+                    None | Some(data::Value::None) => row.add_empty_cells(1),
+                    Some(data::Value::Int(i)) => row.add_cell(*i as f64),
+                    Some(data::Value::Float(f)) => row.add_cell(f.into_inner()),
+                    Some(data::Value::Bool(b)) => row.add_cell(*b),
+                    Some(value) => row.add_cell(value.render(render_config)),
+                }
+            }
+            sheet_writer.append_row(row)?;
+        }
+        Ok(())
+    })?;
+    if let Some(bytes) = workbook.close()? {
+        out.write_all(&bytes)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "xlsx"))]
+fn write_aggregate_xlsx<W: Write>(
+    _out: &mut W,
+    _aggregate: &data::Aggregate,
+    _render_config: &RenderConfig,
+) -> Result<(), Error> {
+    Err(XlsxUnsupported.into())
+}
+
+/// Writes the state of an aggregate to a file every interval.  The format comes from the file's
+/// extension like with `--output-file`, and each snapshot replaces the last one atomically, by
+/// writing to a temporary file beside it and renaming that over it.
+struct SnapshotWriter {
+    config: SnapshotConfig,
+    mode: OutputMode,
+    pretty_printer: PrettyPrinter,
+    last_write: Instant,
+}
+
+impl SnapshotWriter {
+    fn new(config: SnapshotConfig, render_config: &RenderConfig) -> Self {
+        let mode = OutputMode::from_path(&config.path).unwrap_or(OutputMode::Legacy);
+        let pretty_printer = PrettyPrinter::new(
+            RenderConfig {
+                output_mode: mode,
+                floating_points: render_config.floating_points,
+                min_buffer: render_config.min_buffer,
+                max_buffer: render_config.max_buffer,
+                width_percentile: render_config.width_percentile,
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        SnapshotWriter {
+            config,
+            mode,
+            pretty_printer,
+            last_write: Instant::now(),
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        self.last_write.elapsed() >= self.config.interval
+    }
+
+    fn write(&mut self, aggregate: &data::Aggregate) -> Result<(), Error> {
+        let mut temp_path = self.config.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        {
+            let mut out = BufWriter::new(File::create(&temp_path)?);
+            let render_config = &self.pretty_printer.render_config;
+            match self.mode {
+                OutputMode::Csv => write_aggregate_csv(&mut out, aggregate, render_config, true)?,
+                OutputMode::Xlsx => write_aggregate_xlsx(&mut out, aggregate, render_config)?,
+                OutputMode::Sql => write_aggregate_sql(&mut out, aggregate, render_config)?,
+                OutputMode::Json => write_aggregate_json(&mut out, aggregate)?,
+                OutputMode::Markdown => {
+                    let output = self.pretty_printer.format_aggregate_markdown(aggregate);
+                    write!(out, "{}", output)?
+                }
+                OutputMode::Legacy | OutputMode::Vertical => {
+                    let output = self.pretty_printer.format_aggregate(aggregate);
+                    write!(out, "{}", output)?
+                }
+            }
+            out.flush()?;
+        }
+        std::fs::rename(&temp_path, &self.config.path)?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+}
+
+/// The most files kept open at once when splitting records into files.  Beyond this, the least
+/// recently written file is closed and reopened for appending if it's needed again.
+const MAX_OPEN_SPLIT_FILES: usize = 64;
+
+/// Turn a field value into a file name by replacing anything other than letters, digits, `-`,
+/// `_` and `.` with `_`.  Names that would refer to a directory, like `..`, are replaced too.
+fn sanitize_file_name(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '-' | '_' | '.' => c,
+            c if c.is_alphanumeric() => c,
+            _ => '_',
+        })
+        .collect();
+    if sanitized.chars().all(|c| c == '.') {
+        "_".repeat(sanitized.len().max(1))
+    } else {
+        sanitized
+    }
+}
+
+/// Routes each rendered record to `<dir>/<value>.log` based on the value of a field.  Files
+/// are opened as records for them arrive, and only the most recently used ones are kept open.
+struct SplitWriter {
+    config: SplitConfig,
+    /// Open files, with the most recently written last
+    open: Vec<(String, LineWriter<File>)>,
+    /// Files created by this run.  They're truncated when first opened and appended to after.
+    created: HashSet<String>,
+}
+
+impl SplitWriter {
+    fn new(config: SplitConfig) -> Self {
+        SplitWriter {
+            config,
+            open: Vec::new(),
+            created: HashSet::new(),
+        }
+    }
+
+    fn write(&mut self, record: &data::Record, line: &str) -> std::io::Result<()> {
+        let key = record
+            .data
+            .get(&self.config.field)
+            .unwrap_or(&data::Value::None)
+            .render(&RenderConfig::default());
+        let name = sanitize_file_name(&key);
+        let index = match self.open.iter().position(|(open, _)| *open == name) {
+            Some(index) => index,
+            None => {
+                if self.open.len() >= MAX_OPEN_SPLIT_FILES {
+                    let (_, mut oldest) = self.open.remove(0);
+                    oldest.flush()?;
+                }
+                let path = self.config.dir.join(format!("{}.log", name));
+                let file = if self.created.insert(name.clone()) {
+                    File::create(path)?
+                } else {
+                    OpenOptions::new().append(true).open(path)?
+                };
+                self.open.push((name, LineWriter::new(file)));
+                self.open.len() - 1
+            }
+        };
+        let entry = self.open.remove(index);
+        self.open.push(entry);
+        let (_, file) = self.open.last_mut().unwrap();
+        writeln!(file, "{}", line)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        for (_, file) in &mut self.open {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Collapses runs of identical rendered lines, like `uniq`.  Without counts, the first line of
+/// a run is written immediately.  With counts, a line is only written once its run ends since
+/// the count isn't known until then.
+struct LineDeduper {
+    count: bool,
+    last: Option<String>,
+    repeats: usize,
+}
+
+impl LineDeduper {
+    fn new(count: bool) -> Self {
+        LineDeduper {
+            count,
+            last: None,
+            repeats: 0,
+        }
+    }
+
+    /// Returns the line that should be written, if any
+    fn push(&mut self, line: String) -> Option<String> {
+        if self.last.as_ref() == Some(&line) {
+            self.repeats += 1;
+            return None;
+        }
+        let output = if self.count {
+            self.take_pending()
+        } else {
+            Some(line.clone())
+        };
+        self.last = Some(line);
+        self.repeats = 1;
+        output
+    }
+
+    /// Returns the line held back for the final run, if any
+    fn finish(&mut self) -> Option<String> {
+        if self.count {
+            self.take_pending()
+        } else {
+            None
+        }
+    }
+
+    fn take_pending(&mut self) -> Option<String> {
+        let repeats = self.repeats;
+        self.last
+            .take()
+            .map(|line| format!("{:>7} {}", repeats, line))
+    }
+}
+
+/// The number of bytes written and their CRC-32, using the IEEE polynomial like zlib and
+/// `gzip`
+#[derive(Debug, Default, PartialEq)]
+struct Checksum {
+    bytes: u64,
+    crc: u32,
+}
+
+impl Checksum {
+    fn update(&mut self, buf: &[u8]) {
+        let mut crc = !self.crc;
+        for &byte in buf {
+            crc ^= u32::from(byte);
+            for _ in 0..8 {
+                let mask = (crc & 1).wrapping_neg();
+                crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+        self.crc = !crc;
+        self.bytes += buf.len() as u64;
+    }
+}
+
+/// The renderer's output, which also checksums what's written through it when a trailer is
+/// wanted
+struct Output {
+    inner: Box<dyn Write + Send>,
+    checksum: Option<Checksum>,
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(ref mut checksum) = self.checksum {
+            checksum.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// When to write an aggregate as it stands to output that can't be redrawn in place
+struct PartialSchedule {
+    interval: Duration,
+    last_write: Instant,
+    /// How many partial tables have been written
+    written: usize,
+}
+
+impl PartialSchedule {
+    fn new(interval: Duration) -> Self {
+        PartialSchedule {
+            interval,
+            last_write: Instant::now(),
+            written: 0,
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        self.last_write.elapsed() >= self.interval
+    }
+}
+
+pub struct Renderer {
+    pretty_printer: PrettyPrinter,
+    update_interval: Duration,
+    out: Output,
+
+    reset_sequence: String,
+    is_tty: bool,
+    last_print: Option<Instant>,
+    records_since_print: u64,
+    dedup: Option<LineDeduper>,
+    split: Option<SplitWriter>,
+    snapshot: Option<SnapshotWriter>,
+    partials: Option<PartialSchedule>,
+    /// Records written so far, for the trailer
+    records_written: u64,
+    /// The number of rows of the final aggregate, once it has been rendered
+    aggregate_rows: Option<usize>,
+    /// Whether a header row still has to be written before CSV aggregates.  It doesn't when
+    /// appending to a file that already has one.
+    csv_header: bool,
+}
+
+/// Block until this process is the only one holding a lock on the file.  The lock is released
+/// when the file is closed.
+#[cfg(unix)]
+fn lock_exclusive(file: &File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_exclusive(_file: &File) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Open a file to append to, and whether it was empty
+fn open_for_append(path: &Path) -> std::io::Result<(File, bool)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    lock_exclusive(&file)?;
+    let empty = file.metadata()?.len() == 0;
+    Ok((file, empty))
+}
+
+impl Renderer {
+    pub fn new(mut config: RenderConfig, update_interval: Duration) -> Result<Self, AgrindError> {
+        if cfg!(not(feature = "xlsx")) && config.output_mode == OutputMode::Xlsx {
+            return Err(AgrindError::Render(XlsxUnsupported.to_string()));
+        }
+        let mut csv_header = true;
+        let (out, tsize_opt): (Box<dyn Write + Send>, _) = match config.output_file {
+            Some(ref path) if config.append => {
+                let (file, empty) = open_for_append(path)?;
+                if !empty {
+                    csv_header = false;
+                    config.sql_create = false;
+                }
+                (Box::new(LineWriter::new(file)), None)
+            }
+            Some(ref path) => (Box::new(LineWriter::new(File::create(path)?)), None),
+            None => (
+                Box::new(stdout()),
+                terminal_size()
+                    .map(|(Width(width), Height(height))| TerminalSize { width, height }),
+            ),
+        };
+        let dedup = if config.dedup_output || config.dedup_count {
+            Some(LineDeduper::new(config.dedup_count))
+        } else {
+            None
+        };
+        let split = match config.split {
+            Some(ref split) => {
+                std::fs::create_dir_all(&split.dir)?;
+                Some(SplitWriter::new(split.clone()))
+            }
+            None => None,
+        };
+        let snapshot = config
+            .snapshot
+            .clone()
+            .map(|snapshot| SnapshotWriter::new(snapshot, &config));
+        if cfg!(not(feature = "xlsx"))
+            && snapshot.as_ref().map(|snapshot| snapshot.mode) == Some(OutputMode::Xlsx)
+        {
+            return Err(AgrindError::Render(XlsxUnsupported.to_string()));
+        }
+        let is_tty = tsize_opt.is_some();
+        let partials = config.partial_interval.map(PartialSchedule::new);
+        // Split files get the same text as the terminal would, so they'd get the colors too
+        let palette = if config.color.enabled(is_tty && split.is_none()) {
+            config.theme.palette(env::var("COLORFGBG").ok().as_deref())
+        } else {
+            None
+        };
+        let out = Output {
+            inner: out,
+            checksum: config.trailer.map(|_| Checksum::default()),
+        };
+        let mut pretty_printer = PrettyPrinter::new(config, tsize_opt);
+        pretty_printer.palette = palette;
+        Ok(Renderer {
+            dedup,
+            split,
+            snapshot,
+            partials,
+            is_tty,
+            pretty_printer,
+            out,
+            reset_sequence: "".to_string(),
+            last_print: None,
+            records_since_print: 0,
+            update_interval: update_interval.max(MIN_UPDATE_INTERVAL),
+            records_written: 0,
+            aggregate_rows: None,
+            csv_header,
+        })
+    }
+
+    pub fn render(&mut self, row: &data::Row, last_row: bool) -> Result<(), Error> {
+        if let (data::Row::Aggregate(ref aggregate), Some(ref mut snapshot)) =
+            (row, self.snapshot.as_mut())
+        {
+            if last_row || snapshot.is_due() {
+                snapshot.write(aggregate)?;
+            }
+        }
+        if let (data::Row::Aggregate(ref aggregate), true) = (row, last_row) {
+            self.aggregate_rows = Some(aggregate.data.len());
+        }
+        match *row {
+            data::Row::Aggregate(ref aggregate)
+                if self.pretty_printer.render_config.output_mode == OutputMode::Csv =>
+            {
+                // The header goes out with the first batch, but CSV can't be redrawn in place, so
+                // the rows are only written once the aggregate is final
+                let mut out = std::io::BufWriter::new(&mut self.out);
+                if self.csv_header {
+                    write_csv_header(&mut out, &aggregate.columns)?;
+                    self.csv_header = false;
+                }
+                if last_row {
+                    write_aggregate_csv(
+                        &mut out,
+                        aggregate,
+                        &self.pretty_printer.render_config,
+                        false,
+                    )?;
+                }
+                out.flush()?;
+                Ok(())
+            }
+            data::Row::Aggregate(ref aggregate)
+                if self.pretty_printer.render_config.output_mode == OutputMode::Sql =>
+            {
+                if last_row {
+                    let mut out = std::io::BufWriter::new(&mut self.out);
+                    write_aggregate_sql(&mut out, aggregate, &self.pretty_printer.render_config)?;
+                }
+                Ok(())
+            }
+            data::Row::Aggregate(ref aggregate)
+                if self.pretty_printer.render_config.output_mode == OutputMode::Json =>
+            {
+                if last_row {
+                    let mut out = std::io::BufWriter::new(&mut self.out);
+                    write_aggregate_json(&mut out, aggregate)?;
+                }
+                Ok(())
+            }
+            data::Row::Aggregate(ref aggregate)
+                if self.pretty_printer.render_config.output_mode == OutputMode::Xlsx =>
+            {
+                if last_row {
+                    write_aggregate_xlsx(
+                        &mut self.out,
+                        aggregate,
+                        &self.pretty_printer.render_config,
+                    )?;
+                }
+                Ok(())
+            }
+            data::Row::Aggregate(ref aggregate) => {
+                if !self.is_tty {
+                    if last_row {
+                        let output = self.pretty_printer.format_aggregate(aggregate);
+                        if self
+                            .partials
+                            .as_ref()
+                            .map(|p| p.written > 0)
+                            .unwrap_or(false)
+                        {
+                            writeln!(self.out, "--- final result ---")?;
+                        }
+                        write!(self.out, "{}", output)?;
+                    } else if let Some(partials) = self.partials.as_mut().filter(|p| p.is_due()) {
+                        // Each table is complete, so the latest one can be read on its own
+                        let output = self.pretty_printer.format_aggregate(aggregate);
+                        partials.written += 1;
+                        partials.last_write = Instant::now();
+                        writeln!(self.out, "--- partial result {} ---", partials.written)?;
+                        write!(self.out, "{}", output)?;
+                        self.out.flush()?;
+                    }
+                } else if self.should_print() || last_row {
+                    let output = self.pretty_printer.format_aggregate(aggregate);
+                    let num_lines = output.matches('\n').count();
+                    write!(self.out, "{}{}", self.reset_sequence, output)?;
+                    self.reset_sequence = "\x1b[2K\x1b[1A".repeat(num_lines);
+                    self.last_print = Some(Instant::now());
+                    self.records_since_print = 0;
+                }
+
+                Ok(())
+            }
+            data::Row::Record(ref record) => {
+                let output = self.pretty_printer.format_record(record);
+                if let Some(ref mut split) = self.split {
+                    split.write(record, &output)?;
+                    return Ok(());
+                }
+                let output = match self.dedup {
+                    Some(ref mut dedup) => dedup.push(output),
+                    None => Some(output),
+                };
+                if let Some(output) = output {
+                    let separator = &self.pretty_printer.render_config.record_separator;
+                    write!(self.out, "{}{}", output, separator)?;
+                    self.records_written += 1;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Write anything still held back once the stream has ended
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if let Some(output) = self.dedup.as_mut().and_then(LineDeduper::finish) {
+            let separator = &self.pretty_printer.render_config.record_separator;
+            write!(self.out, "{}{}", output, separator)?;
+            self.records_written += 1;
+        }
+        if let Some(ref mut split) = self.split {
+            split.flush()?;
+        }
+        if let (Some(target), Some(checksum)) = (
+            self.pretty_printer.render_config.trailer,
+            self.out.checksum.as_ref(),
+        ) {
+            let trailer = self.trailer(checksum);
+            match target {
+                TrailerTarget::Output => writeln!(self.out.inner, "{}", trailer)?,
+                TrailerTarget::Stderr => eprintln!("{}", trailer),
+            }
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /// Like `agrind-trailer records=3 bytes=42 crc32=414fa339`.  For an aggregate the count is
+    /// of the rows of the final table.
+    fn trailer(&self, checksum: &Checksum) -> String {
+        let count = match self.aggregate_rows {
+            Some(rows) => format!("rows={}", rows),
+            None => format!("records={}", self.records_written),
+        };
+        format!(
+            "agrind-trailer {} bytes={} crc32={:08x}",
+            count, checksum.bytes, checksum.crc
+        )
+    }
+
+    /// Note that another record went into the aggregate being rendered
+    pub fn count_record(&mut self) {
+        self.records_since_print += 1;
+    }
+
+    /// Whether it's time to write another snapshot of the aggregate being rendered
+    pub fn should_snapshot(&self) -> bool {
+        self.snapshot
+            .as_ref()
+            .map(SnapshotWriter::is_due)
+            .unwrap_or(false)
+    }
+
+    pub fn should_print(&self) -> bool {
+        if !self.is_tty {
+            return self
+                .partials
+                .as_ref()
+                .map(PartialSchedule::is_due)
+                .unwrap_or(false);
+        }
+        let enough_records = match self.pretty_printer.render_config.records_per_update {
+            Some(records) => self.records_since_print >= records,
+            None => false,
+        };
+        enough_records
+            || self
+                .last_print
+                .map(|instant| instant.elapsed() > self.update_interval)
+                .unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::*;
+    use crate::operator::*;
+    use maplit::hashmap;
+
+    #[test]
+    fn print_raw() {
+        let rec = Record::new("Hello, World!\n");
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                floating_points: 2,
+                min_buffer: 1,
+                max_buffer: 4,
+                format: None,
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        assert_eq!(pp.format_record(&rec), "Hello, World!");
+    }
 
     #[test]
     fn pretty_print_record() {
@@ -368,190 +2081,1224 @@ mod tests {
         let rec = parser.process(rec).unwrap().unwrap();
         let mut pp = PrettyPrinter::new(
             RenderConfig {
-                floating_points: 2,
-                min_buffer: 1,
-                max_buffer: 4,
-                format: None,
+                floating_points: 2,
+                min_buffer: 1,
+                max_buffer: 4,
+                format: None,
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        assert_eq!(pp.format_record(&rec), "[k1=5]     [k2=5.50]    [k3=str]");
+        let rec = Record::new(r#"{"k1": 955, "k2": 5.5000001, "k3": "str3"}"#);
+        let parser = ParseJson::new(None);
+        let rec = parser.process(rec).unwrap().unwrap();
+        assert_eq!(pp.format_record(&rec), "[k1=955]   [k2=5.50]    [k3=str3]");
+        let rec = Record::new(
+            r#"{"k1": "here is a amuch longer stsring", "k2": 5.5000001, "k3": "str3"}"#,
+        );
+        let parser = ParseJson::new(None);
+        let rec = parser.process(rec).unwrap().unwrap();
+        assert_eq!(
+            pp.format_record(&rec),
+            "[k1=here is a amuch longer stsring]    [k2=5.50]    [k3=str3]"
+        );
+        let rec = Record::new(r#"{"k1": 955, "k2": 5.5000001, "k3": "str3"}"#);
+        let parser = ParseJson::new(None);
+        let rec = parser.process(rec).unwrap().unwrap();
+        assert_eq!(
+            pp.format_record(&rec),
+            "[k1=955]                               [k2=5.50]    [k3=str3]"
+        );
+    }
+
+    #[test]
+    fn pretty_print_record_sorted_fields() {
+        let parser = ParseJson::new(None);
+        let mut unsorted = PrettyPrinter::new(
+            RenderConfig {
+                min_buffer: 1,
+                max_buffer: 4,
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        let mut sorted = PrettyPrinter::new(
+            RenderConfig {
+                min_buffer: 1,
+                max_buffer: 4,
+                sort_record_fields: true,
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        let rec = parser
+            .process(Record::new(r#"{"b": 1, "d": 2}"#))
+            .unwrap()
+            .unwrap();
+        assert_eq!(unsorted.format_record(&rec), "[b=1]    [d=2]");
+        assert_eq!(sorted.format_record(&rec), "[b=1]    [d=2]");
+        let rec = parser
+            .process(Record::new(r#"{"a": 3, "b": 4, "d": 5}"#))
+            .unwrap()
+            .unwrap();
+        assert_eq!(unsorted.format_record(&rec), "[b=4]    [d=5]    [a=3]");
+        assert_eq!(sorted.format_record(&rec), "[a=3]    [b=4]    [d=5]");
+    }
+
+    #[test]
+    fn pretty_print_record_pinned_fields() {
+        let parser = ParseJson::new(None);
+        let pinned = |sort_record_fields| {
+            PrettyPrinter::new(
+                RenderConfig {
+                    min_buffer: 1,
+                    max_buffer: 4,
+                    sort_record_fields,
+                    pinned_fields: vec!["level".to_string(), "host".to_string()],
+                    ..RenderConfig::default()
+                },
+                None,
+            )
+        };
+        let mut arrival = pinned(false);
+        let mut sorted = pinned(true);
+        let lines = [
+            r#"{"msg": "a", "host": "h1"}"#,
+            r#"{"zone": "z", "host": "h2", "level": "info", "msg": "b"}"#,
+            r#"{"level": "warn", "code": 7, "msg": "c", "host": "h3", "zone": "y"}"#,
+        ];
+        let rendered = |pp: &mut PrettyPrinter| -> Vec<String> {
+            lines
+                .iter()
+                .map(|line| {
+                    let rec = parser.process(Record::new(line)).unwrap().unwrap();
+                    pp.format_record(&rec)
+                })
+                .collect()
+        };
+        assert_eq!(
+            rendered(&mut arrival),
+            vec![
+                "[host=h1]      [msg=a]",
+                "[level=info]     [host=h2]      [msg=b]      [zone=z]",
+                "[level=warn]     [host=h3]      [msg=c]      [zone=y]       [code=7]",
+            ]
+        );
+        assert_eq!(
+            rendered(&mut sorted),
+            vec![
+                "[host=h1]      [msg=a]",
+                "[level=info]     [host=h2]      [msg=b]      [zone=z]",
+                "[level=warn]     [host=h3]      [code=7]       [msg=c]      [zone=y]",
+            ]
+        );
+    }
+
+    #[test]
+    fn width_percentile() {
+        assert_eq!(
+            percentile_length(vec![1, 2, 3, 4, 100].into_iter(), 80.0),
+            4
+        );
+        assert_eq!(
+            percentile_length(vec![1, 2, 3, 4, 100].into_iter(), 100.0),
+            100
+        );
+        assert_eq!(percentile_length(vec![5].into_iter(), 1.0), 5);
+        assert_eq!(percentile_length(vec![].into_iter(), 95.0), 0);
+
+        let parser = ParseJson::new(None);
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                min_buffer: 1,
+                max_buffer: 2,
+                width_percentile: Some(75.0),
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        let mut format = |line: &str| {
+            let rec = parser.process(Record::new(line)).unwrap().unwrap();
+            pp.format_record(&rec)
+        };
+        assert_eq!(format(r#"{"k": "abc", "n": 1}"#), "[k=abc]  [n=1]");
+        assert_eq!(format(r#"{"k": "abd", "n": 2}"#), "[k=abd]  [n=2]");
+        assert_eq!(format(r#"{"k": "abe", "n": 3}"#), "[k=abe]  [n=3]");
+        assert_eq!(
+            format(r#"{"k": "a much longer value", "n": 4}"#),
+            "[k=a m…] [n=4]"
+        );
+    }
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(
+            wrap_text("hello world foo", 5),
+            vec!["hello", "world", "foo"]
+        );
+        assert_eq!(wrap_text("abcdefgh", 3), vec!["abc", "def", "gh"]);
+        assert_eq!(wrap_text("a\nb", 10), vec!["a", "b"]);
+        assert_eq!(wrap_text("", 10), vec![""]);
+
+        let parser = ParseJson::new(None);
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                min_buffer: 1,
+                max_buffer: 2,
+                width_percentile: Some(75.0),
+                overflow: Overflow::Wrap,
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        let mut format = |line: &str| {
+            let rec = parser.process(Record::new(line)).unwrap().unwrap();
+            pp.format_record(&rec)
+        };
+        assert_eq!(format(r#"{"k": "abc", "n": 1}"#), "[k=abc]  [n=1]");
+        assert_eq!(format(r#"{"k": "abd", "n": 2}"#), "[k=abd]  [n=2]");
+        assert_eq!(format(r#"{"k": "abe", "n": 3}"#), "[k=abe]  [n=3]");
+        assert_eq!(
+            format(r#"{"k": "a much longer value", "n": 4}"#),
+            "[k=a     [n=4]\n   much\n   long\n   er\n   valu\n   e]"
+        );
+    }
+
+    #[test]
+    fn pretty_print_aggregate_none_and_missing() {
+        let agg = data::Aggregate {
+            columns: vec!["k".to_string(), "v".to_string(), "w".to_string()],
+            data: vec![
+                hashmap! {
+                    "k".to_string() => Value::Str("a".to_string()),
+                    "v".to_string() => Value::None,
+                    "w".to_string() => Value::Int(1),
+                },
+                hashmap! {
+                    "k".to_string() => Value::Str("b".to_string()),
+                    "w".to_string() => Value::Int(2),
+                },
+            ],
+            formats: HashMap::new(),
+        };
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                min_buffer: 2,
+                max_buffer: 4,
+                none_placeholder: "-".to_string(),
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        assert_eq!(
+            pp.format_aggregate(&agg),
+            "k    v    w\n---------------\na    -    1\nb         2\n"
+        );
+    }
+
+    #[test]
+    fn pretty_print_aggregate_header_interval() {
+        let rows: Vec<_> = (1..=5)
+            .map(|n| (hashmap! {"k".to_string() => n.to_string()}, Value::Int(n)))
+            .collect();
+        let agg = Aggregate::new(&["k".to_string()], "count".to_string(), &rows);
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                min_buffer: 2,
+                max_buffer: 4,
+                header_interval: 2,
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        let header = "k    count\n--------------";
+        assert_eq!(
+            pp.format_aggregate(&agg),
+            format!(
+                "{h}\n1    1\n2    2\n{h}\n3    3\n4    4\n{h}\n5    5\n",
+                h = header
+            )
+        );
+    }
+
+    #[test]
+    fn pretty_print_aggregate_wrapped() {
+        let agg = Aggregate::new(
+            &["kc1".to_string()],
+            "count".to_string(),
+            &[(
+                hashmap! {"kc1".to_string() => "wrap this\nvalue".to_string()},
+                Value::Int(100),
+            )],
+        );
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                min_buffer: 2,
+                max_buffer: 4,
+                overflow: Overflow::Wrap,
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        assert_eq!(
+            pp.format_aggregate(&agg),
+            "kc1                count\n----------------------------\nwrap this          100\nvalue\n"
+        );
+    }
+
+    #[test]
+    fn pretty_print_record_vertical() {
+        let parser = ParseJson::new(None);
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                output_mode: OutputMode::Vertical,
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        let rec = parser
+            .process(Record::new(
+                r#"{"user": {"name": "ella", "roles": ["admin", {"scope": "billing"}]}, "empty": {}, "status": 200}"#,
+            ))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            pp.format_record(&rec),
+            "empty: {}
+status: 200
+user:
+  name: ella
+  roles:
+    - admin
+    -
+      scope: billing
+"
+        );
+        assert_eq!(pp.format_record(&Record::new("raw line\n")), "raw line");
+    }
+
+    #[test]
+    fn pretty_print_record_compact() {
+        let parser = ParseJson::new(None);
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                compact: true,
+                min_buffer: 4,
+                max_buffer: 8,
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        let mut format = |line: &str| {
+            let rec = parser.process(Record::new(line)).unwrap().unwrap();
+            pp.format_record(&rec)
+        };
+        assert_eq!(
+            format(r#"{"a": 1, "b": null, "c": "x"}"#),
+            "[a=1]        [c=x]"
+        );
+        assert_eq!(
+            format(r#"{"a": 2, "b": "", "c": "y"}"#),
+            "[a=2]        [c=y]"
+        );
+        assert_eq!(format(r#"{"b": "z", "c": "w"}"#), "[c=w]        [b=z]");
+    }
+
+    #[test]
+    fn pretty_print_record_formatted() {
+        let rec = Record::new(r#"{"k1": 5, "k2": 5.5000001, "k3": "str"}"#);
+        let parser = ParseJson::new(None);
+        let rec = parser.process(rec).unwrap().unwrap();
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                floating_points: 2,
+                min_buffer: 1,
+                max_buffer: 4,
+                format: Some("{k1:>3} k2={k2:<10.3} k3[{k3}]".to_string()),
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        assert_eq!(pp.format_record(&rec), "  5 k2=5.5        k3[str]");
+        let rec = Record::new(r#"{"k1": 955, "k2": 5.5000001, "k3": "str3"}"#);
+        let parser = ParseJson::new(None);
+        let rec = parser.process(rec).unwrap().unwrap();
+        assert_eq!(pp.format_record(&rec), "955 k2=5.5        k3[str3]");
+        let rec = Record::new(
+            r#"{"k1": "here is a amuch longer stsring", "k2": 5.5000001, "k3": "str3"}"#,
+        );
+        let parser = ParseJson::new(None);
+        let rec = parser.process(rec).unwrap().unwrap();
+        assert_eq!(
+            pp.format_record(&rec),
+            "here is a amuch longer stsring k2=5.5        k3[str3]"
+        );
+    }
+
+    #[test]
+    fn pretty_print_record_too_long() {
+        let rec = Record::new(r#"{"k1": 5, "k2": 5.5000001, "k3": "str"}"#);
+        let parser = ParseJson::new(None);
+        let rec = parser.process(rec).unwrap().unwrap();
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                floating_points: 2,
+                min_buffer: 1,
+                max_buffer: 4,
+                format: None,
+                ..RenderConfig::default()
+            },
+            Some(TerminalSize {
+                width: 10,
+                height: 2,
+            }),
+        );
+        assert_eq!(pp.format_record(&rec), "[k1=5][k2=5.50][k3=str]");
+    }
+
+    #[test]
+    fn pretty_print_aggregate() {
+        let agg = Aggregate::new(
+            &["kc1".to_string(), "kc2".to_string()],
+            "count".to_string(),
+            &[
+                (
+                    hashmap! {
+                        "kc1".to_string() => "k1".to_string(),
+                        "kc2".to_string() => "k2".to_string()
+                    },
+                    Value::Int(100),
+                ),
+                (
+                    hashmap! {
+                        "kc1".to_string() => "k300".to_string(),
+                        "kc2".to_string() => "k40000".to_string()
+                    },
+                    Value::Int(500),
+                ),
+            ],
+        );
+        assert_eq!(agg.data.len(), 2);
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                floating_points: 2,
+                min_buffer: 2,
+                max_buffer: 4,
+                format: None,
+                ..RenderConfig::default()
+            },
+            Some(TerminalSize {
+                width: 100,
+                height: 10,
+            }),
+        );
+        println!("{}", pp.format_aggregate(&agg));
+        assert_eq!(
+            "kc1    kc2       count\n--------------------------\nk1     k2        100\nk300   k40000    500\n",
+            pp.format_aggregate(&agg)
+        );
+    }
+
+    #[test]
+    fn pretty_print_aggregate_right_aligned() {
+        let agg = Aggregate::new(
+            &["kc1".to_string(), "kc2".to_string()],
+            "count".to_string(),
+            &[
+                (
+                    hashmap! {
+                        "kc1".to_string() => "k1".to_string(),
+                        "kc2".to_string() => "k2".to_string()
+                    },
+                    Value::Int(5),
+                ),
+                (
+                    hashmap! {
+                        "kc1".to_string() => "k300".to_string(),
+                        "kc2".to_string() => "2".to_string()
+                    },
+                    Value::from_float(1500.5),
+                ),
+            ],
+        );
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                min_buffer: 2,
+                max_buffer: 4,
+                right_align_numbers: true,
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        // kc2 only holds strings, even if one of them looks like a number, so it stays on the left
+        assert_eq!(
+            pp.format_aggregate(&agg),
+            "kc1    kc2      count\n-----------------------\nk1     k2           5\nk300   2      1500.50\n"
+        );
+    }
+
+    #[test]
+    fn pretty_print_aggregate_grouped_digits() {
+        let agg = Aggregate::new(
+            &["kc1".to_string()],
+            "count".to_string(),
+            &[
+                (
+                    hashmap! {"kc1".to_string() => "k1".to_string()},
+                    Value::Int(1_234_567),
+                ),
+                (
+                    hashmap! {"kc1".to_string() => "k2".to_string()},
+                    Value::from_float(1500.5),
+                ),
+            ],
+        );
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                min_buffer: 2,
+                max_buffer: 4,
+                right_align_numbers: true,
+                group_digits: true,
+                digit_separator: '\u{202f}',
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        // The narrow space is three bytes, but only one column wide
+        assert_eq!(
+            pp.format_aggregate(&agg),
+            "kc1          count\n--------------------\nk1       1\u{202f}234\u{202f}567\nk2        1\u{202f}500.50\n"
+        );
+    }
+
+    #[test]
+    fn pretty_print_aggregate_too_long() {
+        let agg = Aggregate::new(
+            &["kc1".to_string(), "kc2".to_string()],
+            "count".to_string(),
+            &[
+                (
+                    hashmap! {
+                        "kc1".to_string() => "k1".to_string(),
+                        "kc2".to_string() => "k40000 k40000k50000k60000k70000k80000".to_string()
+                    },
+                    Value::from_string("0bcdefghijklmnopqrztuvwxyz 1bcdefghijklmnopqrztuvwxyz 2bcdefghijklmnopqrztuvwxyz"),
+                ),
+                (
+                    hashmap! {
+                        "kc1".to_string() => "k1".to_string(),
+                        "kc2".to_string() => "k2".to_string()
+                    },
+                    Value::from_string("0bcdefghijklmnopqrztuvwxyz 1bcdefghijklmnopqrztuvwxyz 2bcdefghijklmnopqrztuvwxyz"),
+                ),
+                (
+                    hashmap! {
+                        "kc1".to_string() => "k300".to_string(),
+                        "kc2".to_string() => "k40000 k40000k50000k60000k70000k80000".to_string()
+                    },
+                    Value::Int(500),
+                ),
+            ],
+        );
+        let max_width = 60;
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                floating_points: 2,
+                min_buffer: 2,
+                max_buffer: 4,
+                format: None,
+                ..RenderConfig::default()
+            },
+            Some(TerminalSize {
+                width: max_width as u16,
+                height: 10,
+            }),
+        );
+        println!("{}", pp.format_aggregate(&agg));
+        let result = pp.format_aggregate(&agg);
+        for line in result.lines() {
+            assert!(
+                line.chars().count() <= max_width as usize,
+                "Expected `{}` to be shorter than {} -- it was {}",
+                line,
+                max_width,
+                line.len()
+            );
+        }
+        assert_eq!(
+            pp.format_aggregate(&agg),
+            "kc1    kc2                       count\n------------------------------------------------------------\nk1     k40000 k40000k50000k6000… 0bcdefghijklmnopqrztuvwxy…\nk1     k2                        0bcdefghijklmnopqrztuvwxy…\nk300   k40000 k40000k50000k6000… 500\n"
+        );
+    }
+
+    #[test]
+    fn csv_aggregate() {
+        let agg = Aggregate::new(
+            &["kc1".to_string()],
+            "count".to_string(),
+            &[
+                (
+                    hashmap! {"kc1".to_string() => "a, \"quoted\" value".to_string()},
+                    Value::from_float(1.23456),
+                ),
+                (
+                    hashmap! {"kc1".to_string() => "plain".to_string()},
+                    Value::None,
+                ),
+                (
+                    hashmap! {"kc1".to_string() => "two\nlines".to_string()},
+                    Value::from_float(2.5),
+                ),
+            ],
+        );
+        let mut out = Vec::new();
+        write_aggregate_csv(&mut out, &agg, &RenderConfig::default(), true).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "kc1,count\n\"a, \"\"quoted\"\" value\",1.23\nplain,\n\"two\nlines\",2.50\n"
+        );
+    }
+
+    #[test]
+    fn sql_aggregate() {
+        let agg = Aggregate::new(
+            &["kc1".to_string()],
+            "count".to_string(),
+            &[
+                (
+                    hashmap! {"kc1".to_string() => "it's".to_string()},
+                    Value::Int(3),
+                ),
+                (
+                    hashmap! {"kc1".to_string() => "plain".to_string()},
+                    Value::from_float(1.5),
+                ),
+                (
+                    hashmap! {"kc1".to_string() => "none".to_string()},
+                    Value::None,
+                ),
+            ],
+        );
+        let mut out = Vec::new();
+        let render_config = RenderConfig {
+            sql_table: "counts".to_string(),
+            sql_create: true,
+            ..RenderConfig::default()
+        };
+        write_aggregate_sql(&mut out, &agg, &render_config).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "CREATE TABLE \"counts\" (\"kc1\" TEXT, \"count\" REAL);
+INSERT INTO \"counts\" (\"kc1\", \"count\") VALUES ('it''s', 3);
+INSERT INTO \"counts\" (\"kc1\", \"count\") VALUES ('plain', 1.50);
+INSERT INTO \"counts\" (\"kc1\", \"count\") VALUES ('none', NULL);
+"
+        );
+    }
+
+    #[test]
+    fn json_aggregate() {
+        let agg = Aggregate::new(
+            &["kc1".to_string()],
+            "count".to_string(),
+            &[
+                (
+                    hashmap! {"kc1".to_string() => "a \"quoted\" value".to_string()},
+                    Value::Int(3),
+                ),
+                (
+                    hashmap! {"kc1".to_string() => "plain".to_string()},
+                    Value::from_float(f64::NAN),
+                ),
+            ],
+        );
+        let mut out = Vec::new();
+        write_aggregate_json(&mut out, &agg).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[
+  {\"kc1\":\"a \\\"quoted\\\" value\",\"count\":3},
+  {\"kc1\":\"plain\",\"count\":null}
+]
+"
+        );
+    }
+
+    #[test]
+    fn markdown_aggregate() {
+        let agg = Aggregate::new(
+            &["kc1".to_string()],
+            "count".to_string(),
+            &[
+                (
+                    hashmap! {"kc1".to_string() => "a|b".to_string()},
+                    Value::Int(3),
+                ),
+                (
+                    hashmap! {"kc1".to_string() => "two\nlines".to_string()},
+                    Value::None,
+                ),
+            ],
+        );
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                output_mode: OutputMode::Markdown,
+                right_align_numbers: true,
+                ..RenderConfig::default()
             },
             None,
         );
-        assert_eq!(pp.format_record(&rec), "[k1=5]     [k2=5.50]    [k3=str]");
-        let rec = Record::new(r#"{"k1": 955, "k2": 5.5000001, "k3": "str3"}"#);
-        let parser = ParseJson::new(None);
-        let rec = parser.process(rec).unwrap().unwrap();
-        assert_eq!(pp.format_record(&rec), "[k1=955]   [k2=5.50]    [k3=str3]");
-        let rec = Record::new(
-            r#"{"k1": "here is a amuch longer stsring", "k2": 5.5000001, "k3": "str3"}"#,
-        );
-        let parser = ParseJson::new(None);
-        let rec = parser.process(rec).unwrap().unwrap();
-        assert_eq!(
-            pp.format_record(&rec),
-            "[k1=here is a amuch longer stsring]    [k2=5.50]    [k3=str3]"
-        );
-        let rec = Record::new(r#"{"k1": 955, "k2": 5.5000001, "k3": "str3"}"#);
-        let parser = ParseJson::new(None);
-        let rec = parser.process(rec).unwrap().unwrap();
         assert_eq!(
-            pp.format_record(&rec),
-            "[k1=955]                               [k2=5.50]    [k3=str3]"
+            pp.format_aggregate(&agg),
+            "| kc1 | count |\n| --- | ---: |\n| a\\|b | 3 |\n| two<br>lines |  |\n"
         );
     }
 
     #[test]
-    fn pretty_print_record_formatted() {
-        let rec = Record::new(r#"{"k1": 5, "k2": 5.5000001, "k3": "str"}"#);
+    fn pretty_print_record_json() {
         let parser = ParseJson::new(None);
-        let rec = parser.process(rec).unwrap().unwrap();
         let mut pp = PrettyPrinter::new(
             RenderConfig {
-                floating_points: 2,
-                min_buffer: 1,
-                max_buffer: 4,
-                format: Some("{k1:>3} k2={k2:<10.3} k3[{k3}]".to_string()),
+                output_mode: OutputMode::Json,
+                ..RenderConfig::default()
             },
             None,
         );
-        assert_eq!(pp.format_record(&rec), "  5 k2=5.5        k3[str]");
-        let rec = Record::new(r#"{"k1": 955, "k2": 5.5000001, "k3": "str3"}"#);
-        let parser = ParseJson::new(None);
-        let rec = parser.process(rec).unwrap().unwrap();
-        assert_eq!(pp.format_record(&rec), "955 k2=5.5        k3[str3]");
-        let rec = Record::new(
-            r#"{"k1": "here is a amuch longer stsring", "k2": 5.5000001, "k3": "str3"}"#,
-        );
-        let parser = ParseJson::new(None);
-        let rec = parser.process(rec).unwrap().unwrap();
+        let rec = parser
+            .process(Record::new(
+                r#"{"status": 200, "latency": 1.5, "user": {"name": "ella"}, "tags": ["a", null]}"#,
+            ))
+            .unwrap()
+            .unwrap();
         assert_eq!(
             pp.format_record(&rec),
-            "here is a amuch longer stsring k2=5.5        k3[str3]"
+            r#"{"latency":1.5,"status":200,"tags":["a",null],"user":{"name":"ella"}}"#
         );
+        assert_eq!(
+            pp.format_record(&Record::new("raw \"line\"\n")),
+            r#"{"line":"raw \"line\""}"#
+        );
+
+        // Floats keep every digit, so they read back as exactly the same number
+        let rec = parser
+            .process(Record::new(r#"{"x": 0.30000000000000004, "y": 1e-7}"#))
+            .unwrap()
+            .unwrap();
+        let rendered = pp.format_record(&rec);
+        assert_eq!(rendered, r#"{"x":0.30000000000000004,"y":1e-7}"#);
+        let reparsed = parser.process(Record::new(&rendered)).unwrap().unwrap();
+        assert_eq!(reparsed.data, rec.data);
     }
 
     #[test]
-    fn pretty_print_record_too_long() {
-        let rec = Record::new(r#"{"k1": 5, "k2": 5.5000001, "k3": "str"}"#);
+    fn pretty_print_record_sql() {
         let parser = ParseJson::new(None);
-        let rec = parser.process(rec).unwrap().unwrap();
         let mut pp = PrettyPrinter::new(
             RenderConfig {
-                floating_points: 2,
-                min_buffer: 1,
-                max_buffer: 4,
-                format: None,
+                output_mode: OutputMode::Sql,
+                sql_create: true,
+                ..RenderConfig::default()
             },
-            Some(TerminalSize {
-                width: 10,
-                height: 2,
-            }),
+            None,
+        );
+        let rec = parser
+            .process(Record::new(
+                r#"{"status": 200, "ok": true, "path": "/", "tags": ["a"]}"#,
+            ))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            pp.format_record(&rec),
+            "CREATE TABLE \"results\" (\"ok\" BOOLEAN, \"path\" TEXT, \"status\" INTEGER, \"tags\" TEXT);
+INSERT INTO \"results\" (\"ok\", \"path\", \"status\", \"tags\") VALUES (TRUE, '/', 200, '[a]');"
+        );
+        assert_eq!(
+            pp.format_record(&Record::new("raw line\n")),
+            "INSERT INTO \"results\" (\"line\") VALUES ('raw line');"
         );
-        assert_eq!(pp.format_record(&rec), "[k1=5][k2=5.50][k3=str]");
     }
 
     #[test]
-    fn pretty_print_aggregate() {
-        let agg = Aggregate::new(
-            &["kc1".to_string(), "kc2".to_string()],
-            "count".to_string(),
+    fn aggregate_value_formats() {
+        let mut agg = Aggregate::new(
+            &["kc1".to_string()],
+            "_average".to_string(),
             &[
                 (
-                    hashmap! {
-                        "kc1".to_string() => "k1".to_string(),
-                        "kc2".to_string() => "k2".to_string()
-                    },
-                    Value::Int(100),
+                    hashmap! {"kc1".to_string() => "k1".to_string()},
+                    Value::Int(1105),
                 ),
                 (
-                    hashmap! {
-                        "kc1".to_string() => "k300".to_string(),
-                        "kc2".to_string() => "k40000".to_string()
-                    },
-                    Value::Int(500),
+                    hashmap! {"kc1".to_string() => "k2".to_string()},
+                    Value::from_float(736.5),
                 ),
             ],
         );
-        assert_eq!(agg.data.len(), 2);
+        agg.formats
+            .insert("_average".to_string(), data::ValueFormat::Float);
         let mut pp = PrettyPrinter::new(
             RenderConfig {
-                floating_points: 2,
                 min_buffer: 2,
                 max_buffer: 4,
-                format: None,
+                ..RenderConfig::default()
             },
             Some(TerminalSize {
                 width: 100,
                 height: 10,
             }),
         );
-        println!("{}", pp.format_aggregate(&agg));
         assert_eq!(
-            "kc1    kc2       count\n--------------------------\nk1     k2        100\nk300   k40000    500\n",
-            pp.format_aggregate(&agg)
+            pp.format_aggregate(&agg),
+            "kc1    _average\n-------------------\nk1     1105.00\nk2     736.50\n"
+        );
+        let mut out = Vec::new();
+        write_aggregate_csv(&mut out, &agg, &RenderConfig::default(), true).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "kc1,_average\nk1,1105.00\nk2,736.50\n"
         );
+        let mut out = Vec::new();
+        write_aggregate_csv(&mut out, &agg, &RenderConfig::default(), false).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "k1,1105.00\nk2,736.50\n");
     }
 
     #[test]
-    fn pretty_print_aggregate_too_long() {
+    fn color_choices() {
+        assert!(ColorChoice::Always.enabled(false));
+        assert!(!ColorChoice::Never.enabled(true));
+        assert!(!ColorChoice::Auto.enabled(false));
+    }
+
+    #[test]
+    fn theme_palettes() {
+        assert_eq!(Theme::Dark.palette(Some("0;15")), Some(DARK_PALETTE));
+        assert_eq!(Theme::Light.palette(None), Some(LIGHT_PALETTE));
+        assert_eq!(Theme::NoColor.palette(Some("0;15")), None);
+        assert_eq!(Theme::Auto.palette(None), Some(DARK_PALETTE));
+        assert_eq!(Theme::Auto.palette(Some("15;0")), Some(DARK_PALETTE));
+        assert_eq!(Theme::Auto.palette(Some("0;15")), Some(LIGHT_PALETTE));
+        assert_eq!(
+            Theme::Auto.palette(Some("0;default;7")),
+            Some(LIGHT_PALETTE)
+        );
+        assert_eq!(Theme::Auto.palette(Some("garbage")), Some(DARK_PALETTE));
+    }
+
+    #[test]
+    fn pretty_print_aggregate_colored() {
         let agg = Aggregate::new(
-            &["kc1".to_string(), "kc2".to_string()],
+            &["kc1".to_string()],
             "count".to_string(),
-            &[
-                (
-                    hashmap! {
-                        "kc1".to_string() => "k1".to_string(),
-                        "kc2".to_string() => "k40000 k40000k50000k60000k70000k80000".to_string()
-                    },
-                    Value::from_string("0bcdefghijklmnopqrztuvwxyz 1bcdefghijklmnopqrztuvwxyz 2bcdefghijklmnopqrztuvwxyz"),
-                ),
-                (
-                    hashmap! {
-                        "kc1".to_string() => "k1".to_string(),
-                        "kc2".to_string() => "k2".to_string()
-                    },
-                    Value::from_string("0bcdefghijklmnopqrztuvwxyz 1bcdefghijklmnopqrztuvwxyz 2bcdefghijklmnopqrztuvwxyz"),
-                ),
-                (
-                    hashmap! {
-                        "kc1".to_string() => "k300".to_string(),
-                        "kc2".to_string() => "k40000 k40000k50000k60000k70000k80000".to_string()
-                    },
-                    Value::Int(500),
-                ),
-            ],
+            &[(
+                hashmap! {"kc1".to_string() => "k1".to_string()},
+                Value::Int(100),
+            )],
         );
-        let max_width = 60;
         let mut pp = PrettyPrinter::new(
             RenderConfig {
-                floating_points: 2,
                 min_buffer: 2,
                 max_buffer: 4,
-                format: None,
+                ..RenderConfig::default()
             },
-            Some(TerminalSize {
-                width: max_width as u16,
-                height: 10,
-            }),
+            None,
         );
-        println!("{}", pp.format_aggregate(&agg));
-        let result = pp.format_aggregate(&agg);
-        for line in result.lines() {
-            assert!(
-                line.chars().count() <= max_width as usize,
-                "Expected `{}` to be shorter than {} -- it was {}",
-                line,
-                max_width,
-                line.len()
-            );
-        }
+        pp.palette = Some(LIGHT_PALETTE);
+        // Only the value is colored, so its padding is trimmed like in uncolored output
         assert_eq!(
             pp.format_aggregate(&agg),
-            "kc1    kc2                       count\n------------------------------------------------------------\nk1     k40000 k40000k50000k6000… 0bcdefghijklmnopqrztuvwxy…\nk1     k2                        0bcdefghijklmnopqrztuvwxy…\nk300   k40000 k40000k50000k6000… 500\n"
+            "\x1b[1;34mkc1    count\x1b[0m\n----------------\nk1     \x1b[35m100\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn pretty_print_record_colored() {
+        let rec = Record::new("")
+            .put("k1", Value::Int(5))
+            .put("k2", Value::None)
+            .put("k3", Value::Str("str".to_string()));
+        let mut pp = PrettyPrinter::new(
+            RenderConfig {
+                min_buffer: 1,
+                max_buffer: 4,
+                ..RenderConfig::default()
+            },
+            None,
+        );
+        pp.palette = Some(DARK_PALETTE);
+        // The padding lines up with the uncolored output
+        assert_eq!(
+            pp.format_record(&rec),
+            "[\x1b[1;36mk1\x1b[0m=\x1b[33m5\x1b[0m]     \
+             [\x1b[1;36mk2\x1b[0m=\x1b[2mNone\x1b[0m]    \
+             [\x1b[1;36mk3\x1b[0m=str]"
+        );
+        pp.palette = None;
+        assert_eq!(pp.format_record(&rec), "[k1=5]     [k2=None]    [k3=str]");
+    }
+
+    #[test]
+    fn split_file_names() {
+        assert_eq!(sanitize_file_name("web-1.example.com"), "web-1.example.com");
+        assert_eq!(sanitize_file_name("a/b c"), "a_b_c");
+        assert_eq!(sanitize_file_name(".."), "__");
+        assert_eq!(sanitize_file_name(""), "_");
+    }
+
+    #[test]
+    fn split_records() {
+        let dir = env::temp_dir().join(format!("agrind-split-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut writer = SplitWriter::new(SplitConfig {
+            field: "host".to_string(),
+            dir: dir.clone(),
+        });
+        for (host, line) in &[("a", "1"), ("b/c", "2"), ("a", "3")] {
+            let rec = Record::new(line).put("host", Value::Str(host.to_string()));
+            writer.write(&rec, line).unwrap();
+        }
+        writer.write(&Record::new("4"), "4").unwrap();
+        // Reopening a file that was closed appends to it
+        writer.open.clear();
+        writer
+            .write(
+                &Record::new("5").put("host", Value::Str("a".to_string())),
+                "5",
+            )
+            .unwrap();
+        writer.flush().unwrap();
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("a.log"), "1\n3\n5\n");
+        assert_eq!(read("b_c.log"), "2\n");
+        assert_eq!(read("None.log"), "4\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn print_after_records() {
+        let config = RenderConfig {
+            records_per_update: Some(3),
+            ..RenderConfig::default()
+        };
+        let mut renderer = Renderer {
+            pretty_printer: PrettyPrinter::new(config, None),
+            update_interval: Duration::from_secs(3600),
+            out: Output {
+                inner: Box::new(Vec::new()),
+                checksum: None,
+            },
+            reset_sequence: "".to_string(),
+            is_tty: true,
+            last_print: None,
+            records_since_print: 0,
+            dedup: None,
+            split: None,
+            snapshot: None,
+            partials: None,
+            records_written: 0,
+            aggregate_rows: None,
+            csv_header: true,
+        };
+        let agg = Row::Aggregate(Aggregate::new(
+            &["k".to_string()],
+            "count".to_string(),
+            &[(hashmap! {"k".to_string() => "a".to_string()}, Value::Int(1))],
+        ));
+        assert!(renderer.should_print());
+        renderer.render(&agg, false).unwrap();
+        renderer.count_record();
+        renderer.count_record();
+        assert!(!renderer.should_print());
+        renderer.count_record();
+        assert!(renderer.should_print());
+        renderer.render(&agg, false).unwrap();
+        assert!(!renderer.should_print());
+    }
+
+    /// Output that a test can still read after handing it to a `Renderer`
+    #[derive(Clone, Default)]
+    struct SharedOutput(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_aggregates_when_not_a_tty() {
+        let shared = SharedOutput::default();
+        let config = RenderConfig {
+            min_buffer: 4,
+            max_buffer: 8,
+            ..RenderConfig::default()
+        };
+        let mut renderer = Renderer {
+            pretty_printer: PrettyPrinter::new(config, None),
+            update_interval: DEFAULT_UPDATE_INTERVAL,
+            out: Output {
+                inner: Box::new(shared.clone()),
+                checksum: None,
+            },
+            reset_sequence: "".to_string(),
+            is_tty: false,
+            last_print: None,
+            records_since_print: 0,
+            dedup: None,
+            split: None,
+            snapshot: None,
+            partials: Some(PartialSchedule::new(Duration::from_secs(0))),
+            records_written: 0,
+            aggregate_rows: None,
+            csv_header: true,
+        };
+        let agg = |count| {
+            Row::Aggregate(Aggregate::new(
+                &["k".to_string()],
+                "count".to_string(),
+                &[(
+                    hashmap! {"k".to_string() => "a".to_string()},
+                    Value::Int(count),
+                )],
+            ))
+        };
+        assert!(renderer.should_print());
+        renderer.render(&agg(1), false).unwrap();
+        renderer.render(&agg(2), false).unwrap();
+        renderer.render(&agg(3), true).unwrap();
+        let written = String::from_utf8(shared.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            written,
+            "--- partial result 1 ---
+k        count
+----------------------
+a        1
+--- partial result 2 ---
+k        count
+----------------------
+a        2
+--- final result ---
+k        count
+----------------------
+a        3
+"
+        );
+
+        renderer.partials = Some(PartialSchedule::new(Duration::from_secs(3600)));
+        assert!(!renderer.should_print());
+    }
+
+    #[test]
+    fn csv_header_before_final_aggregate() {
+        let shared = SharedOutput::default();
+        let config = RenderConfig {
+            output_mode: OutputMode::Csv,
+            ..RenderConfig::default()
+        };
+        let mut renderer = Renderer {
+            pretty_printer: PrettyPrinter::new(config, None),
+            update_interval: DEFAULT_UPDATE_INTERVAL,
+            out: Output {
+                inner: Box::new(shared.clone()),
+                checksum: None,
+            },
+            reset_sequence: "".to_string(),
+            is_tty: false,
+            last_print: None,
+            records_since_print: 0,
+            dedup: None,
+            split: None,
+            snapshot: None,
+            partials: None,
+            records_written: 0,
+            aggregate_rows: None,
+            csv_header: true,
+        };
+        let agg = |count| {
+            Row::Aggregate(Aggregate::new(
+                &["k".to_string()],
+                "count".to_string(),
+                &[(
+                    hashmap! {"k".to_string() => "a".to_string()},
+                    Value::Int(count),
+                )],
+            ))
+        };
+        renderer.render(&agg(1), false).unwrap();
+        assert_eq!(
+            String::from_utf8(shared.0.lock().unwrap().clone()).unwrap(),
+            "k,count\n"
+        );
+        renderer.render(&agg(2), false).unwrap();
+        renderer.render(&agg(3), true).unwrap();
+        assert_eq!(
+            String::from_utf8(shared.0.lock().unwrap().clone()).unwrap(),
+            "k,count\na,3\n"
+        );
+    }
+
+    #[test]
+    fn update_interval_minimum() {
+        let renderer = Renderer::new(RenderConfig::default(), Duration::from_millis(1)).unwrap();
+        assert_eq!(renderer.update_interval, MIN_UPDATE_INTERVAL);
+        let renderer = Renderer::new(RenderConfig::default(), Duration::from_secs(1)).unwrap();
+        assert_eq!(renderer.update_interval, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn snapshot_aggregate() {
+        let path = env::temp_dir().join(format!("agrind-snapshot-{}.csv", std::process::id()));
+        let config = RenderConfig {
+            snapshot: Some(SnapshotConfig {
+                path: path.clone(),
+                interval: Duration::from_secs(3600),
+            }),
+            ..RenderConfig::default()
+        };
+        let mut renderer = Renderer::new(config, Duration::from_secs(3600)).unwrap();
+        let agg = |count| {
+            Row::Aggregate(Aggregate::new(
+                &["k".to_string()],
+                "count".to_string(),
+                &[(
+                    hashmap! {"k".to_string() => "a".to_string()},
+                    Value::Int(count),
+                )],
+            ))
+        };
+        assert!(!renderer.should_snapshot());
+        renderer.render(&agg(1), false).unwrap();
+        assert!(!path.exists());
+        renderer.snapshot.as_mut().unwrap().config.interval = Duration::from_secs(0);
+        assert!(renderer.should_snapshot());
+        renderer.render(&agg(2), false).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "k,count\na,2\n");
+        renderer.snapshot.as_mut().unwrap().config.interval = Duration::from_secs(3600);
+        renderer.render(&agg(3), true).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "k,count\na,3\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sparkline_levels() {
+        assert_eq!(sparkline(&[1.0, 2.0, 4.0, 8.0], false), "▁▂▄█");
+        assert_eq!(sparkline(&[1.0, 2.0, 4.0, 8.0], true), "_.-#");
+        assert_eq!(sparkline(&[3.0, 3.0], false), "▁▁");
+        assert_eq!(sparkline(&[], false), "");
+    }
+
+    #[test]
+    fn checksum_crc32() {
+        let mut checksum = Checksum::default();
+        checksum.update(b"");
+        assert_eq!(checksum, Checksum { bytes: 0, crc: 0 });
+        checksum.update(b"1234");
+        checksum.update(b"56789");
+        assert_eq!(
+            checksum,
+            Checksum {
+                bytes: 9,
+                crc: 0xCBF4_3926
+            }
+        );
+    }
+
+    #[test]
+    fn dedup_lines() {
+        let lines = ["a", "a", "b", "a", "a", "a"];
+        let mut dedup = LineDeduper::new(false);
+        let mut written: Vec<String> = lines
+            .iter()
+            .filter_map(|line| dedup.push(line.to_string()))
+            .collect();
+        written.extend(dedup.finish());
+        assert_eq!(written, vec!["a", "b", "a"]);
+
+        let mut dedup = LineDeduper::new(true);
+        let mut written: Vec<String> = lines
+            .iter()
+            .filter_map(|line| dedup.push(line.to_string()))
+            .collect();
+        written.extend(dedup.finish());
+        assert_eq!(written, vec!["      2 a", "      1 b", "      3 a"]);
+    }
+
+    #[test]
+    fn output_mode_from_path() {
+        assert_eq!(
+            OutputMode::from_path(Path::new("report.XLSX")),
+            Some(OutputMode::Xlsx)
+        );
+        assert_eq!(
+            OutputMode::from_path(Path::new("out/results.csv")),
+            Some(OutputMode::Csv)
+        );
+        assert_eq!(
+            OutputMode::from_path(Path::new("rows.sql")),
+            Some(OutputMode::Sql)
+        );
+        assert_eq!(
+            OutputMode::from_path(Path::new("rows.json")),
+            Some(OutputMode::Json)
+        );
+        assert_eq!(
+            OutputMode::from_path(Path::new("README.md")),
+            Some(OutputMode::Markdown)
+        );
+        assert_eq!(OutputMode::from_path(Path::new("report.txt")), None);
+        assert_eq!(OutputMode::from_path(Path::new("report")), None);
+    }
+
+    #[cfg(feature = "xlsx")]
+    #[test]
+    fn xlsx_aggregate() {
+        let agg = Aggregate::new(
+            &["kc1".to_string()],
+            "count".to_string(),
+            &[(
+                hashmap! {"kc1".to_string() => "k1".to_string()},
+                Value::Int(5),
+            )],
         );
+        let mut out = Vec::new();
+        write_aggregate_xlsx(&mut out, &agg, &RenderConfig::default()).unwrap();
+        // xlsx files are zip archives
+        assert!(out.starts_with(b"PK"));
     }
 
     #[test]
     fn test_format_with_ellipsis() {
-        assert_eq!(format_with_ellipsis("abcde", 4), "ab… ");
-        assert_eq!(format_with_ellipsis("abcde", 10), "abcde     ");
+        assert_eq!(format_with_ellipsis("abcde", 4, ELLIPSIS), "ab… ");
+        assert_eq!(format_with_ellipsis("abcde", 5, ELLIPSIS), "abcde");
+        assert_eq!(format_with_ellipsis("abcdef", 5, ELLIPSIS), "abc… ");
+        assert_eq!(format_with_ellipsis("abcde", 10, ELLIPSIS), "abcde     ");
+        assert_eq!(format_with_ellipsis("abcdefg", 6, "..."), "ab... ");
+        assert_eq!(format_with_ellipsis("abcdef", 6, "..."), "abcdef");
+        assert_eq!(format_with_ellipsis("abcdefg", 4, "..."), "... ");
+        // Too narrow for the ellipsis and its space, so that's cut off too
+        assert_eq!(format_with_ellipsis("abcdefg", 3, "..."), "...");
+        assert_eq!(format_with_ellipsis("abcdefg", 2, "..."), "..");
+        assert_eq!(format_with_ellipsis("abcdefg", 1, ELLIPSIS), "…");
+        assert_eq!(format_with_ellipsis("abcdefg", 0, "..."), "");
+    }
+
+    #[test]
+    fn truncate_with_configured_ellipsis() {
+        assert_eq!(
+            truncate_with_ellipsis("abcdef".to_string(), 4, ELLIPSIS),
+            "abc…"
+        );
+        assert_eq!(
+            truncate_with_ellipsis("abcdef".to_string(), 5, "..."),
+            "ab..."
+        );
+        assert_eq!(
+            truncate_with_ellipsis("abcde".to_string(), 5, "..."),
+            "abcde"
+        );
     }
 }
@@ -2,6 +2,7 @@ use crate::data::Value;
 use crate::errors::ErrorBuilder;
 use crate::lang;
 use crate::operator;
+use std::fs;
 
 #[derive(Debug, Fail)]
 pub enum TypeError {
@@ -12,13 +13,69 @@ pub enum TypeError {
     ExpectedExpr,
 
     #[fail(
-        display = "Wrong number of patterns for parse. Pattern has {} but {} were extracted",
+        display = "Wrong number of fields for parse. The pattern has {} `*` but {} fields are named after `as`",
         pattern, extracted
     )]
     ParseNumPatterns { pattern: usize, extracted: usize },
 
+    #[fail(
+        display = "`{}` has a default value, but it isn't extracted by parse",
+        field
+    )]
+    UnknownParseDefault { field: String },
+
     #[fail(display = "Limit must be a non-zero integer, found {}", limit)]
     InvalidLimit { limit: f64 },
+
+    #[fail(display = "Invalid CIDR block {}", cidr)]
+    InvalidCidr { cidr: String },
+
+    #[fail(display = "Invalid glob {}: {}", pattern, message)]
+    InvalidGlob { pattern: String, message: String },
+
+    #[fail(display = "Invalid regex {}: {}", pattern, message)]
+    InvalidRegex { pattern: String, message: String },
+
+    #[fail(display = "Invalid fuzzy match threshold {}", threshold)]
+    InvalidFuzzyThreshold { threshold: i64 },
+
+    #[fail(display = "Invalid pattern file {}: {}", path, message)]
+    InvalidPatternFile { path: String, message: String },
+
+    #[fail(display = "Invalid argument: {}", message)]
+    InvalidArgument { message: String },
+
+    #[fail(display = "Unknown function {}", name)]
+    UnknownFunction { name: String },
+
+    #[fail(display = "The aggregate has more than one column named `{}`", name)]
+    DuplicateAggregateName { name: String },
+
+    #[fail(
+        display = "Wrong number of arguments for {}. Expected {} but found {}",
+        name, expected, found
+    )]
+    FunctionArity {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+}
+
+/// Compile the regexes in a pattern file, one per line.  Blank lines and lines starting with `#`
+/// are skipped.
+fn load_pattern_file(path: &str) -> Result<regex::RegexSet, String> {
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut patterns = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        regex::Regex::new(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        patterns.push(line);
+    }
+    regex::RegexSet::new(patterns).map_err(|e| e.to_string())
 }
 
 pub trait TypeCheck<O> {
@@ -38,6 +95,34 @@ impl From<lang::ComparisonOp> for operator::BoolExpr {
     }
 }
 
+impl From<lang::LogicalOp> for operator::LogicalExpr {
+    fn from(op: lang::LogicalOp) -> Self {
+        match op {
+            lang::LogicalOp::And => operator::LogicalExpr::And,
+            lang::LogicalOp::Or => operator::LogicalExpr::Or,
+        }
+    }
+}
+
+impl From<lang::ArithmeticOp> for operator::ArithmeticExpr {
+    fn from(op: lang::ArithmeticOp) -> Self {
+        match op {
+            lang::ArithmeticOp::Add => operator::ArithmeticExpr::Add,
+            lang::ArithmeticOp::Subtract => operator::ArithmeticExpr::Subtract,
+            lang::ArithmeticOp::Multiply => operator::ArithmeticExpr::Multiply,
+            lang::ArithmeticOp::Divide => operator::ArithmeticExpr::Divide,
+        }
+    }
+}
+
+/// Syntax errors come with a copy of the pattern pointing at the problem, but the report already
+/// shows the pattern so only the last line is kept
+fn regex_error_message(e: regex::Error) -> String {
+    let message = e.to_string();
+    let last = message.lines().last().unwrap_or_default();
+    last.trim_start_matches("error: ").to_string()
+}
+
 impl TypeCheck<operator::Expr> for lang::Expr {
     fn type_check<E: ErrorBuilder>(self, error_builder: &E) -> Result<operator::Expr, TypeError> {
         match self {
@@ -72,7 +157,190 @@ impl TypeCheck<operator::Expr> for lang::Expr {
                         operator: com_op.into(),
                     }))
                 }
+                lang::BinaryOp::Arithmetic(arith_op) => {
+                    Ok(operator::Expr::Arithmetic(operator::BinaryExpr::<
+                        operator::ArithmeticExpr,
+                    > {
+                        left: Box::new((*left).type_check(error_builder)?),
+                        right: Box::new((*right).type_check(error_builder)?),
+                        operator: arith_op.into(),
+                    }))
+                }
+                lang::BinaryOp::Logical(logical_op) => {
+                    Ok(operator::Expr::Logical(operator::BinaryExpr::<
+                        operator::LogicalExpr,
+                    > {
+                        left: Box::new((*left).type_check(error_builder)?),
+                        right: Box::new((*right).type_check(error_builder)?),
+                        operator: logical_op.into(),
+                    }))
+                }
             },
+            lang::Expr::InCidr { operand, cidrs } => {
+                let networks = cidrs
+                    .into_iter()
+                    .map(|cidr| {
+                        cidr.value.parse::<operator::IpNetwork>().map_err(|_| {
+                            let e = TypeError::InvalidCidr {
+                                cidr: cidr.value.clone(),
+                            };
+
+                            error_builder
+                                .report_error_for(&e)
+                                .with_code_pointer(&cidr, "Not an IP address or network")
+                                .with_resolution(
+                                    "Use an IPv4 or IPv6 address followed by a prefix length",
+                                )
+                                .with_resolution(r#"example: where src_ip in_cidr "10.0.0.0/8""#)
+                                .send_report();
+
+                            e
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(operator::Expr::InCidr(operator::InCidrExpr {
+                    operand: Box::new((*operand).type_check(error_builder)?),
+                    networks,
+                }))
+            }
+            lang::Expr::Glob { operand, pattern } => {
+                let regex = operator::glob_to_regex(&pattern.value)
+                    .and_then(|regex| regex::Regex::new(&regex).map_err(|e| e.to_string()));
+                let regex = match regex {
+                    Ok(regex) => regex,
+                    Err(message) => {
+                        let e = TypeError::InvalidGlob {
+                            pattern: pattern.value.clone(),
+                            message: message.clone(),
+                        };
+
+                        error_builder
+                            .report_error_for(&e)
+                            .with_code_pointer(&pattern, message)
+                            .with_resolution(r#"example: where path glob "/api/*/users""#)
+                            .send_report();
+
+                        return Err(e);
+                    }
+                };
+
+                Ok(operator::Expr::Glob(operator::GlobExpr {
+                    operand: Box::new((*operand).type_check(error_builder)?),
+                    pattern: regex,
+                }))
+            }
+            lang::Expr::RegexMatch { operand, pattern } => {
+                let regex = match regex::Regex::new(&pattern.value) {
+                    Ok(regex) => regex,
+                    Err(e) => {
+                        let message = regex_error_message(e);
+                        let e = TypeError::InvalidRegex {
+                            pattern: pattern.value.clone(),
+                            message: message.clone(),
+                        };
+
+                        error_builder
+                            .report_error_for(&e)
+                            .with_code_pointer(&pattern, message)
+                            .with_resolution(r#"example: where path ~= "^/api/""#)
+                            .send_report();
+
+                        return Err(e);
+                    }
+                };
+
+                Ok(operator::Expr::RegexMatch(operator::RegexMatchExpr {
+                    operand: Box::new((*operand).type_check(error_builder)?),
+                    regex,
+                }))
+            }
+            lang::Expr::Fuzzy {
+                operand,
+                target,
+                op,
+                threshold,
+            } => {
+                // `< n` allows up to n - 1 edits, so `< 0` could never match
+                let max_distance = match op {
+                    lang::ComparisonOp::Lt => threshold.value - 1,
+                    _ => threshold.value,
+                };
+                if max_distance < 0 {
+                    let e = TypeError::InvalidFuzzyThreshold {
+                        threshold: threshold.value,
+                    };
+
+                    error_builder
+                        .report_error_for(&e)
+                        .with_code_pointer(&threshold, "No distance is below this")
+                        .with_resolution("The edit distance threshold must allow at least 0 edits")
+                        .with_resolution(r#"example: where message fuzzy "connection refused" < 3"#)
+                        .send_report();
+
+                    return Err(e);
+                }
+
+                Ok(operator::Expr::Fuzzy(operator::FuzzyExpr {
+                    operand: Box::new((*operand).type_check(error_builder)?),
+                    target: target.chars().collect(),
+                    max_distance: max_distance as usize,
+                }))
+            }
+            lang::Expr::FunctionCall { name, args } => {
+                let (function, arity) = match name.value.as_str() {
+                    "len" => (operator::Function::Len, 1),
+                    "is_nan" => (operator::Function::IsNan, 1),
+                    "is_inf" => (operator::Function::IsInf, 1),
+                    "age" => (operator::Function::Age, 1),
+                    "rand" => (operator::Function::Rand, 0),
+                    "json_get" => (operator::Function::JsonGet, 2),
+                    "split" => (operator::Function::Split, 2),
+                    "typeof" => (operator::Function::TypeOf, 1),
+                    "url_host" => (operator::Function::UrlHost, 1),
+                    "url_path" => (operator::Function::UrlPath, 1),
+                    "url_scheme" => (operator::Function::UrlScheme, 1),
+                    "url_port" => (operator::Function::UrlPort, 1),
+                    _ => {
+                        let e = TypeError::UnknownFunction {
+                            name: name.value.clone(),
+                        };
+
+                        error_builder
+                            .report_error_for(&e)
+                            .with_code_pointer(&name, "No function with this name")
+                            .with_resolution(
+                                "Valid functions are: len, is_nan, is_inf, age, rand, json_get, split, typeof, url_host, url_path, url_scheme, url_port",
+                            )
+                            .send_report();
+
+                        return Err(e);
+                    }
+                };
+                if args.len() != arity {
+                    let e = TypeError::FunctionArity {
+                        name: name.value.clone(),
+                        expected: arity,
+                        found: args.len(),
+                    };
+
+                    error_builder
+                        .report_error_for(&e)
+                        .with_code_pointer(&name, "Called with the wrong number of arguments")
+                        .send_report();
+
+                    return Err(e);
+                }
+                let args = args
+                    .into_iter()
+                    .map(|arg| arg.type_check(error_builder))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(operator::Expr::FunctionCall(operator::FunctionCall {
+                    function,
+                    args,
+                }))
+            }
             lang::Expr::Value(value) => {
                 let boxed = Box::new(value);
                 let static_value: &'static mut Value = Box::leak(boxed);
@@ -82,7 +350,83 @@ impl TypeCheck<operator::Expr> for lang::Expr {
     }
 }
 
-const DEFAULT_LIMIT: i64 = 10;
+pub const DEFAULT_LIMIT: i64 = 10;
+
+/// Check that no two columns in the output of an aggregate share a name, whether they were
+/// renamed with `as` or not
+pub fn check_aggregate_names<E: ErrorBuilder>(
+    op: &lang::MultiAggregateOperator,
+    error_builder: &E,
+) -> Result<(), TypeError> {
+    match op.duplicate_name() {
+        Some((name, function)) => {
+            let e = TypeError::DuplicateAggregateName { name: name.clone() };
+
+            error_builder
+                .report_error_for(&e)
+                .with_code_pointer(function, format!("Another column is also named `{}`", name))
+                .with_resolution("give each column a different name with `as`")
+                .send_report();
+
+            Err(e)
+        }
+        None => Ok(()),
+    }
+}
+const DEFAULT_CONCAT_SEPARATOR: &str = ", ";
+const DEFAULT_CONCAT_ITEMS: usize = 10;
+const DEFAULT_CONCAT_LENGTH: usize = 100;
+
+impl TypeCheck<operator::TopKApprox> for lang::TopKApproxOperator {
+    fn type_check<E: ErrorBuilder>(
+        self,
+        error_builder: &E,
+    ) -> Result<operator::TopKApprox, TypeError> {
+        let k = self.k.value;
+        if k < 1.0 || k.fract() != 0.0 {
+            let e = TypeError::InvalidArgument {
+                message: format!(
+                    "topk_approx needs a positive whole number of values, found {}",
+                    k
+                ),
+            };
+
+            error_builder
+                .report_error_for(&e)
+                .with_code_pointer(&self.k, "Not a positive integer")
+                .with_resolution("example: topk_approx(url, 20)")
+                .send_report();
+
+            return Err(e);
+        }
+        Ok(operator::TopKApprox::new(
+            self.key_col.type_check(error_builder)?,
+            self.key_col_header,
+            k as usize,
+        ))
+    }
+}
+
+impl TypeCheck<operator::Rate> for lang::RateOperator {
+    fn type_check<E: ErrorBuilder>(self, error_builder: &E) -> Result<operator::Rate, TypeError> {
+        if self.interval.value <= 0.0 {
+            let e = TypeError::InvalidArgument {
+                message: "rate intervals must be longer than zero".to_string(),
+            };
+            error_builder
+                .report_error_for(&e)
+                .with_code_pointer(&self.interval, "Zero-length interval")
+                .with_resolution("example: rate(timestamp, 1m)")
+                .send_report();
+            return Err(e);
+        }
+        Ok(operator::Rate::new(
+            self.column.type_check(error_builder)?,
+            self.interval.value,
+            self.fill,
+        ))
+    }
+}
 
 impl TypeCheck<Box<dyn operator::OperatorBuilder + Send + Sync>>
     for lang::Positioned<lang::InlineOperator>
@@ -106,32 +450,153 @@ impl TypeCheck<Box<dyn operator::OperatorBuilder + Send + Sync>>
                         .transpose()?,
                 )))
             }
+            lang::InlineOperator::KeyValue {
+                pair_separator,
+                kv_separator,
+                input_column,
+            } => {
+                for separator in pair_separator.iter().chain(kv_separator.iter()) {
+                    if separator.value.is_empty() {
+                        let e = TypeError::InvalidArgument {
+                            message: "kv separators can't be empty".to_string(),
+                        };
+                        error_builder
+                            .report_error_for(&e)
+                            .with_code_pointer(separator, "Empty separator")
+                            .with_resolution(r#"example: kv pair_sep=";" kv_sep=":""#)
+                            .send_report();
+                        return Err(e);
+                    }
+                }
+                let pair_separator = pair_separator
+                    .map_or_else(|| lang::DEFAULT_PAIR_SEPARATOR.to_string(), |sep| sep.value);
+                let kv_separator = kv_separator
+                    .map_or_else(|| lang::DEFAULT_KV_SEPARATOR.to_string(), |sep| sep.value);
+                Ok(Box::new(operator::ParseKeyValue::new(
+                    pair_separator,
+                    kv_separator,
+                    input_column
+                        .map(|e| e.type_check(error_builder))
+                        .transpose()?,
+                )))
+            }
             lang::InlineOperator::Parse {
                 pattern,
                 fields,
-                input_column,
+                field_types,
+                input_columns,
+                defaults,
                 no_drop,
             } => {
                 let regex = pattern.to_regex();
 
+                if let Some((field, _)) = defaults.iter().find(|(f, _)| !fields.contains(&f.value))
+                {
+                    let e = TypeError::UnknownParseDefault {
+                        field: field.value.clone(),
+                    };
+
+                    error_builder
+                        .report_error_for(&e)
+                        .with_code_pointer(field, "Not one of the fields after `as`")
+                        .with_resolution(
+                            r#"example: parse "* *" as status, user defaults user="anon""#,
+                        )
+                        .send_report();
+
+                    return Err(e);
+                }
+
                 if (regex.captures_len() - 1) != fields.len() {
-                    Err(TypeError::ParseNumPatterns {
+                    let e = TypeError::ParseNumPatterns {
                         pattern: regex.captures_len() - 1,
                         extracted: fields.len(),
-                    })
+                    };
+
+                    error_builder
+                        .report_error_for(&e)
+                        .with_code_range(
+                            self.start_pos,
+                            self.end_pos,
+                            "Each `*` needs a field name after `as`",
+                        )
+                        .with_resolution(r#"example: parse "user=* path=*" as user, path"#)
+                        .send_report();
+
+                    Err(e)
                 } else {
-                    Ok(Box::new(operator::Parse::new(
-                        regex,
-                        fields,
-                        input_column
-                            .map(|e| e.type_check(error_builder))
-                            .transpose()?,
-                        operator::ParseOptions {
-                            drop_nonmatching: !no_drop,
-                        },
-                    )))
+                    Ok(Box::new(
+                        operator::Parse::new(
+                            regex,
+                            fields,
+                            input_columns
+                                .into_iter()
+                                .map(|e| e.type_check(error_builder))
+                                .collect::<Result<Vec<_>, _>>()?,
+                            operator::ParseOptions {
+                                drop_nonmatching: !no_drop,
+                            },
+                        )
+                        .with_defaults(
+                            defaults
+                                .into_iter()
+                                .map(|(field, value)| (field.value, value))
+                                .collect(),
+                        )
+                        .with_types(
+                            field_types
+                                .into_iter()
+                                .map(|(field, capture)| {
+                                    let capture = match capture {
+                                        lang::CaptureType::Number => operator::CaptureType::Number,
+                                        lang::CaptureType::String => operator::CaptureType::String,
+                                    };
+                                    (field, capture)
+                                })
+                                .collect(),
+                        ),
+                    ))
                 }
             }
+            lang::InlineOperator::Extract {
+                pattern,
+                input_column,
+                drop_nomatch,
+            } => {
+                let regex = regex::Regex::new(&pattern.value)
+                    .map_err(regex_error_message)
+                    .and_then(|regex| {
+                        if regex.capture_names().any(|name| name.is_some()) {
+                            Ok(regex)
+                        } else {
+                            Err("the pattern has no named groups".to_string())
+                        }
+                    });
+                let regex = match regex {
+                    Ok(regex) => regex,
+                    Err(message) => {
+                        let e = TypeError::InvalidRegex {
+                            pattern: pattern.value.clone(),
+                            message: message.clone(),
+                        };
+
+                        error_builder
+                            .report_error_for(&e)
+                            .with_code_pointer(&pattern, message)
+                            .with_resolution(r#"example: extract '(?P<user>\w+) logged in'"#)
+                            .send_report();
+
+                        return Err(e);
+                    }
+                };
+                Ok(Box::new(operator::Extract::new(
+                    regex,
+                    input_column
+                        .map(|e| e.type_check(error_builder))
+                        .transpose()?,
+                    drop_nomatch,
+                )))
+            }
             lang::InlineOperator::Fields { fields, mode } => {
                 let omode = match mode {
                     lang::FieldMode::Except => operator::FieldMode::Except,
@@ -139,6 +604,16 @@ impl TypeCheck<Box<dyn operator::OperatorBuilder + Send + Sync>>
                 };
                 Ok(Box::new(operator::Fields::new(&fields, omode)))
             }
+            lang::InlineOperator::Eval {
+                output_column,
+                expr,
+            } => Ok(Box::new(operator::Eval::new(
+                output_column,
+                expr.type_check(error_builder)?,
+            ))),
+            lang::InlineOperator::Rename { renames } => {
+                Ok(Box::new(operator::Rename::new(renames)))
+            }
             lang::InlineOperator::Where { expr: Some(expr) } => match expr
                 .value
                 .type_check(error_builder)?
@@ -178,6 +653,33 @@ impl TypeCheck<Box<dyn operator::OperatorBuilder + Send + Sync>>
 
                 Err(e)
             }
+            lang::InlineOperator::WhereMatchesFile {
+                input_column,
+                negate,
+                path,
+            } => {
+                let patterns = load_pattern_file(&path.value).map_err(|message| {
+                    let e = TypeError::InvalidPatternFile {
+                        path: path.value.clone(),
+                        message,
+                    };
+
+                    error_builder
+                        .report_error_for(&e)
+                        .with_code_pointer(&path, "Unable to load patterns from this file")
+                        .with_resolution("The file should contain one regex per line")
+                        .send_report();
+
+                    e
+                })?;
+                Ok(Box::new(operator::MatchesAny::new(
+                    patterns,
+                    input_column
+                        .map(|e| e.type_check(error_builder))
+                        .transpose()?,
+                    negate,
+                )))
+            }
             lang::InlineOperator::Limit { count: Some(count) } => match count.value {
                 limit if limit.trunc() == 0.0 || limit.fract() != 0.0 => {
                     let e = TypeError::InvalidLimit { limit };
@@ -207,6 +709,7 @@ impl TypeCheck<Box<dyn operator::OperatorBuilder + Send + Sync>>
                 separator,
                 input_column,
                 output_column,
+                explode,
             } => Ok(Box::new(operator::Split::new(
                 separator,
                 input_column
@@ -215,6 +718,59 @@ impl TypeCheck<Box<dyn operator::OperatorBuilder + Send + Sync>>
                 output_column
                     .map(|e| e.type_check(error_builder))
                     .transpose()?,
+                explode,
+            ))),
+            lang::InlineOperator::Squeeze {
+                input_column,
+                output_column,
+            } => Ok(Box::new(operator::Squeeze::new(
+                input_column.type_check(error_builder)?,
+                output_column.type_check(error_builder)?,
+            ))),
+            lang::InlineOperator::ChangeCase {
+                case,
+                input_column,
+                output_column,
+            } => Ok(Box::new(operator::ChangeCase::new(
+                match case {
+                    lang::Case::Lower => operator::Case::Lower,
+                    lang::Case::Upper => operator::Case::Upper,
+                },
+                input_column.type_check(error_builder)?,
+                output_column.type_check(error_builder)?,
+            ))),
+            lang::InlineOperator::Timeslice {
+                interval,
+                input_column,
+                output_column,
+            } => {
+                if interval.value <= 0.0 {
+                    let e = TypeError::InvalidArgument {
+                        message: "timeslice intervals must be longer than zero".to_string(),
+                    };
+                    error_builder
+                        .report_error_for(&e)
+                        .with_code_pointer(&interval, "Zero-length interval")
+                        .with_resolution("example: timeslice 5m timestamp")
+                        .send_report();
+                    return Err(e);
+                }
+                Ok(Box::new(operator::Timeslice::new(
+                    interval.value,
+                    input_column.type_check(error_builder)?,
+                    output_column.type_check(error_builder)?,
+                )))
+            }
+            lang::InlineOperator::Substring {
+                input_column,
+                start,
+                end,
+                output_column,
+            } => Ok(Box::new(operator::Substring::new(
+                input_column.type_check(error_builder)?,
+                start,
+                end,
+                output_column.type_check(error_builder)?,
             ))),
             lang::InlineOperator::Total {
                 input_column,
@@ -223,6 +779,62 @@ impl TypeCheck<Box<dyn operator::OperatorBuilder + Send + Sync>>
                 input_column.type_check(error_builder)?,
                 output_column,
             ))),
+            lang::InlineOperator::MovingAverage {
+                input_column,
+                window,
+                gaps,
+                output_column,
+            } => {
+                if window.value < 1.0 || window.value.fract() != 0.0 {
+                    let e = TypeError::InvalidArgument {
+                        message: format!(
+                            "moving_avg needs a positive whole number of values, found {}",
+                            window.value
+                        ),
+                    };
+
+                    error_builder
+                        .report_error_for(&e)
+                        .with_code_pointer(&window, "Not a positive integer")
+                        .with_resolution("example: moving_avg(latency, 10)")
+                        .send_report();
+
+                    return Err(e);
+                }
+                Ok(Box::new(operator::MovingAverageDef::new(
+                    input_column.type_check(error_builder)?,
+                    window.value as usize,
+                    gaps,
+                    output_column,
+                )))
+            }
+            lang::InlineOperator::QuantileBucket {
+                input_column,
+                buckets,
+                output_column,
+            } => {
+                if buckets.value < 1.0 || buckets.value.fract() != 0.0 {
+                    let e = TypeError::InvalidArgument {
+                        message: format!(
+                            "qbucket needs a positive whole number of buckets, found {}",
+                            buckets.value
+                        ),
+                    };
+
+                    error_builder
+                        .report_error_for(&e)
+                        .with_code_pointer(&buckets, "Not a positive integer")
+                        .with_resolution("example: qbucket(latency, 4)")
+                        .send_report();
+
+                    return Err(e);
+                }
+                Ok(Box::new(operator::QuantileBucketDef::new(
+                    input_column.type_check(error_builder)?,
+                    buckets.value as usize,
+                    output_column,
+                )))
+            }
         }
     }
 }
@@ -246,12 +858,52 @@ impl TypeCheck<Box<dyn operator::AggregateFunction>> for lang::Positioned<lang::
             lang::AggregateFunction::Sum { column } => Ok(Box::new(operator::Sum::empty(
                 column.type_check(error_builder)?,
             ))),
+            lang::AggregateFunction::Sparkline { column } => Ok(Box::new(
+                operator::Sparkline::empty(column.type_check(error_builder)?),
+            )),
+            lang::AggregateFunction::Median { column } => Ok(Box::new(operator::Median::empty(
+                column.type_check(error_builder)?,
+            ))),
+            lang::AggregateFunction::Mode { column } => Ok(Box::new(operator::Mode::empty(
+                column.type_check(error_builder)?,
+            ))),
+            lang::AggregateFunction::First { column } => Ok(Box::new(operator::First::empty(
+                column.type_check(error_builder)?,
+            ))),
+            lang::AggregateFunction::Last { column } => Ok(Box::new(operator::Last::empty(
+                column.type_check(error_builder)?,
+            ))),
             lang::AggregateFunction::Percentile {
                 column, percentile, ..
             } => Ok(Box::new(operator::Percentile::empty(
                 column.type_check(error_builder)?,
                 percentile,
             ))),
+            lang::AggregateFunction::Percentiles {
+                column,
+                percentiles,
+                approx,
+            } => {
+                if let Some(pct) = percentiles
+                    .iter()
+                    .find(|pct| pct.value < 0.0 || pct.value > 100.0)
+                {
+                    let e = TypeError::InvalidArgument {
+                        message: format!("percentiles must be from 0 to 100, found {}", pct.value),
+                    };
+                    error_builder
+                        .report_error_for(&e)
+                        .with_code_pointer(pct, "Not a percentile")
+                        .with_resolution("example: percentile(latency, 50, 90, 99)")
+                        .send_report();
+                    return Err(e);
+                }
+                Ok(Box::new(operator::Percentiles::empty(
+                    column.type_check(error_builder)?,
+                    percentiles.into_iter().map(|pct| pct.value).collect(),
+                    approx,
+                )))
+            }
             lang::AggregateFunction::CountDistinct { column: Some(pos) } => {
                 match pos.value.as_slice() {
                     [column] => Ok(Box::new(operator::CountDistinct::empty(
@@ -274,6 +926,82 @@ impl TypeCheck<Box<dyn operator::AggregateFunction>> for lang::Positioned<lang::
                     }
                 }
             }
+            lang::AggregateFunction::Concat { distinct, args } => {
+                let invalid = |message: &str| {
+                    error_builder
+                        .report_error_for("Invalid arguments for concat")
+                        .with_code_pointer(&args, message)
+                        .with_resolution(r#"example: concat(message, ", ", 10, 100)"#)
+                        .send_report();
+
+                    TypeError::InvalidArgument {
+                        message: message.to_string(),
+                    }
+                };
+                let limit = |arg: Option<&lang::Expr>, default: usize| match arg {
+                    None => Ok(default),
+                    Some(lang::Expr::Value(Value::Int(limit))) if *limit > 0 => Ok(*limit as usize),
+                    Some(_) => Err(invalid(
+                        "The item and length limits for concat must be positive integers",
+                    )),
+                };
+                let column = match args.value.first() {
+                    Some(column) => column.clone().type_check(error_builder)?,
+                    None => return Err(invalid("Expecting an expression to concatenate")),
+                };
+                let separator = match args.value.get(1) {
+                    None => DEFAULT_CONCAT_SEPARATOR.to_string(),
+                    Some(lang::Expr::Value(Value::Str(separator))) => separator.clone(),
+                    Some(_) => return Err(invalid("The separator for concat must be a string")),
+                };
+                let max_items = limit(args.value.get(2), DEFAULT_CONCAT_ITEMS)?;
+                let max_length = limit(args.value.get(3), DEFAULT_CONCAT_LENGTH)?;
+                if args.value.len() > 4 {
+                    return Err(invalid("Too many arguments given to concat"));
+                }
+                Ok(Box::new(operator::Concat::empty(
+                    column, separator, distinct, max_items, max_length,
+                )))
+            }
+            lang::AggregateFunction::Ratio { args } => match args.value.as_slice() {
+                [numerator, denominator] => Ok(Box::new(operator::Ratio::empty(
+                    numerator.clone().type_check(error_builder)?,
+                    denominator.clone().type_check(error_builder)?,
+                ))),
+                _ => {
+                    let message = "ratio needs exactly two conditions";
+                    error_builder
+                        .report_error_for("Invalid arguments for ratio")
+                        .with_code_pointer(&args, "Expecting a numerator and a denominator")
+                        .with_resolution("example: ratio(status >= 500, status > 0)")
+                        .send_report();
+
+                    Err(TypeError::InvalidArgument {
+                        message: message.to_string(),
+                    })
+                }
+            },
+            lang::AggregateFunction::ArgMinMax { max, args } => match args.value.as_slice() {
+                [value, ret] => Ok(Box::new(operator::ArgMinMax::empty(
+                    value.clone().type_check(error_builder)?,
+                    ret.clone().type_check(error_builder)?,
+                    max,
+                ))),
+                _ => {
+                    let name = if max { "argmax" } else { "argmin" };
+                    let message = format!("{} needs exactly two arguments", name);
+                    error_builder
+                        .report_error_for(format!("Invalid arguments for {}", name))
+                        .with_code_pointer(
+                            &args,
+                            "Expecting a value to compare and a field to return",
+                        )
+                        .with_resolution(format!("example: {}(latency, timestamp)", name))
+                        .send_report();
+
+                    Err(TypeError::InvalidArgument { message })
+                }
+            },
             lang::AggregateFunction::CountDistinct { column: None } => {
                 error_builder
                     .report_error_for("Expecting an expression to count")
@@ -20,9 +20,18 @@ struct TestDefinition {
 #[cfg(test)]
 mod integration {
     use super::*;
-    use ag::pipeline::{ErrorReporter, Pipeline, QueryContainer};
+    use ag::pipeline::{
+        AgrindError, CompileError, ErrorReporter, InputFormat, Pipeline, QueryContainer,
+        RenderConfig, TypeError,
+    };
     use assert_cli;
     use std::borrow::Borrow;
+    use std::env;
+    use std::error::Error;
+    use std::fs;
+    use std::io;
+    use std::sync::mpsc;
+    use std::thread;
     use toml;
 
     pub struct EmptyErrorReporter;
@@ -51,12 +60,41 @@ mod integration {
         asserter.unwrap();
     }
 
+    #[test]
+    fn topk_approx_operator() {
+        structured_test(include_str!("structured_tests/topk_approx.toml"));
+        structured_test(include_str!("structured_tests/topk_approx_error.toml"));
+    }
+
     #[test]
     fn count_distinct_operator() {
         structured_test(include_str!("structured_tests/count_distinct.toml"));
         structured_test(include_str!("structured_tests/count_distinct_error.toml"));
     }
 
+    #[test]
+    fn count_by_split() {
+        structured_test(include_str!("structured_tests/count_by_split.toml"));
+    }
+
+    #[test]
+    fn ratio_operator() {
+        structured_test(include_str!("structured_tests/ratio.toml"));
+        structured_test(include_str!("structured_tests/ratio_error.toml"));
+    }
+
+    #[test]
+    fn argmax_operator() {
+        structured_test(include_str!("structured_tests/argmax.toml"));
+        structured_test(include_str!("structured_tests/argmax_error.toml"));
+    }
+
+    #[test]
+    fn concat_operator() {
+        structured_test(include_str!("structured_tests/concat.toml"));
+        structured_test(include_str!("structured_tests/concat_error.toml"));
+    }
+
     #[test]
     fn long_aggregate_values() {
         structured_test(include_str!("structured_tests/longlines.toml"));
@@ -70,8 +108,17 @@ mod integration {
         structured_test(include_str!(
             "structured_tests/parse_error_unterminated_sq.toml"
         ));
+        structured_test(include_str!(
+            "structured_tests/parse_error_invalid_escape.toml"
+        ));
+        structured_test(include_str!("structured_tests/parse_escapes.toml"));
         structured_test(include_str!("structured_tests/parse_drop.toml"));
         structured_test(include_str!("structured_tests/parse_nodrop.toml"));
+        structured_test(include_str!("structured_tests/parse_multiple_sources.toml"));
+        structured_test(include_str!("structured_tests/parse_defaults.toml"));
+        structured_test(include_str!("structured_tests/parse_defaults_error.toml"));
+        structured_test(include_str!("structured_tests/parse_num_fields_error.toml"));
+        structured_test(include_str!("structured_tests/parse_rest.toml"));
     }
 
     #[test]
@@ -82,11 +129,24 @@ mod integration {
     #[test]
     fn sum_operator() {
         structured_test(include_str!("structured_tests/sum.toml"));
+        structured_test(include_str!(
+            "structured_tests/aggregate_several_fields.toml"
+        ));
+        structured_test(include_str!(
+            "structured_tests/aggregate_default_name_error.toml"
+        ));
+    }
+
+    #[test]
+    fn aggregate_aliases() {
+        structured_test(include_str!("structured_tests/aggregate_alias.toml"));
+        structured_test(include_str!("structured_tests/aggregate_alias_error.toml"));
     }
 
     #[test]
     fn min_max_operators() {
         structured_test(include_str!("structured_tests/min_max.toml"));
+        structured_test(include_str!("structured_tests/min_max_strings.toml"));
         structured_test(include_str!("structured_tests/min_max_none.toml"));
     }
 
@@ -100,6 +160,32 @@ mod integration {
         structured_test(include_str!("structured_tests/where-6.toml"));
         structured_test(include_str!("structured_tests/where-7.toml"));
         structured_test(include_str!("structured_tests/where-8.toml"));
+        structured_test(include_str!("structured_tests/where_logical.toml"));
+        structured_test(include_str!("structured_tests/where_in_cidr.toml"));
+        structured_test(include_str!("structured_tests/where_in_cidr_error.toml"));
+        structured_test(include_str!("structured_tests/where_len.toml"));
+        structured_test(include_str!("structured_tests/where_len_error.toml"));
+        structured_test(include_str!("structured_tests/where_is_nan.toml"));
+        structured_test(include_str!("structured_tests/where_is_inf.toml"));
+        structured_test(include_str!("structured_tests/where_age.toml"));
+        structured_test(include_str!("structured_tests/where_json_get.toml"));
+        structured_test(include_str!("structured_tests/where_typeof.toml"));
+        structured_test(include_str!("structured_tests/url_functions.toml"));
+        structured_test(include_str!("structured_tests/where_glob.toml"));
+        structured_test(include_str!("structured_tests/where_glob_error.toml"));
+        structured_test(include_str!("structured_tests/where_regex_match.toml"));
+        structured_test(include_str!(
+            "structured_tests/where_regex_match_error.toml"
+        ));
+        structured_test(include_str!(
+            "structured_tests/where_error_invalid_escape.toml"
+        ));
+        structured_test(include_str!("structured_tests/where_fuzzy.toml"));
+        structured_test(include_str!("structured_tests/where_fuzzy_error.toml"));
+        structured_test(include_str!("structured_tests/where_matches_file.toml"));
+        structured_test(include_str!(
+            "structured_tests/where_matches_file_error.toml"
+        ));
     }
 
     #[test]
@@ -110,12 +196,113 @@ mod integration {
     #[test]
     fn sort_order() {
         structured_test(include_str!("structured_tests/sort_order.toml"));
+        structured_test(include_str!("structured_tests/sort_limit.toml"));
+        structured_test(include_str!("structured_tests/sort_directions.toml"));
     }
 
     #[test]
     fn total() {
         structured_test(include_str!("structured_tests/total.toml"));
         structured_test(include_str!("structured_tests/total_agg.toml"));
+        structured_test(include_str!("structured_tests/grand_total.toml"));
+        structured_test(include_str!("structured_tests/grand_total_error.toml"));
+    }
+
+    #[test]
+    fn moving_avg() {
+        structured_test(include_str!("structured_tests/moving_avg.toml"));
+        structured_test(include_str!("structured_tests/moving_avg_gaps.toml"));
+        structured_test(include_str!("structured_tests/moving_avg_error.toml"));
+    }
+
+    #[test]
+    fn eval() {
+        structured_test(include_str!("structured_tests/eval.toml"));
+    }
+
+    #[test]
+    fn rename() {
+        structured_test(include_str!("structured_tests/rename.toml"));
+    }
+
+    #[test]
+    fn extract() {
+        structured_test(include_str!("structured_tests/extract.toml"));
+        structured_test(include_str!("structured_tests/extract_error.toml"));
+    }
+
+    #[test]
+    fn parse_typed() {
+        structured_test(include_str!("structured_tests/parse_typed.toml"));
+    }
+
+    #[test]
+    fn kv() {
+        structured_test(include_str!("structured_tests/kv.toml"));
+        structured_test(include_str!("structured_tests/kv_error.toml"));
+    }
+
+    #[test]
+    fn squeeze() {
+        structured_test(include_str!("structured_tests/squeeze.toml"));
+    }
+
+    #[test]
+    fn timeslice() {
+        structured_test(include_str!("structured_tests/timeslice.toml"));
+        structured_test(include_str!("structured_tests/timeslice_milliseconds.toml"));
+    }
+
+    #[test]
+    fn rate() {
+        structured_test(include_str!("structured_tests/rate.toml"));
+        structured_test(include_str!("structured_tests/rate_milliseconds.toml"));
+    }
+
+    #[test]
+    fn substring() {
+        structured_test(include_str!("structured_tests/substring.toml"));
+    }
+
+    #[test]
+    fn change_case() {
+        structured_test(include_str!("structured_tests/change_case.toml"));
+    }
+
+    #[test]
+    fn sparkline() {
+        structured_test(include_str!("structured_tests/sparkline.toml"));
+    }
+
+    #[test]
+    fn median() {
+        structured_test(include_str!("structured_tests/median.toml"));
+    }
+
+    #[test]
+    fn percentiles() {
+        structured_test(include_str!("structured_tests/percentiles.toml"));
+    }
+
+    #[test]
+    fn percentiles_error() {
+        structured_test(include_str!("structured_tests/percentiles_error.toml"));
+    }
+
+    #[test]
+    fn mode() {
+        structured_test(include_str!("structured_tests/mode.toml"));
+    }
+
+    #[test]
+    fn first_last() {
+        structured_test(include_str!("structured_tests/first_last.toml"));
+    }
+
+    #[test]
+    fn qbucket() {
+        structured_test(include_str!("structured_tests/qbucket.toml"));
+        structured_test(include_str!("structured_tests/qbucket_error.toml"));
     }
 
     #[test]
@@ -164,6 +351,7 @@ mod integration {
         structured_test(include_str!("structured_tests/split_8.toml"));
         structured_test(include_str!("structured_tests/split_9.toml"));
         structured_test(include_str!("structured_tests/split_10.toml"));
+        structured_test(include_str!("structured_tests/split_11.toml"));
     }
 
     #[test]
@@ -235,6 +423,144 @@ mod integration {
             .unwrap();
     }
 
+    #[test]
+    fn booleans() {
+        assert_cli::Assert::main_binary()
+            .stdin("{\"ok\": true, \"n\": 1}\n{\"ok\": false, \"n\": 2}\n{\"ok\": true, \"n\": 3}\n")
+            .with_args(&["* | json | where ok == true | count, sum(ok)"])
+            .stdout()
+            .is("_count        _sum
+--------------------------
+2             0")
+            .stderr()
+            .is("error: _sum: Expected number, found the boolean true. Booleans aren't counted as 1 or 0, so filter on them with `where` and `count` the records instead")
+            .unwrap();
+    }
+
+    #[test]
+    fn positional_files() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count by level",
+                "test_files/test_json.log",
+                "test_files/test_json.log",
+            ])
+            .stdout()
+            .is("level        _count
+---------------------------
+info         6
+error        4
+None         2")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "--input-tail",
+                "1",
+                "*",
+                "test_files/test_json.log",
+                "test_files/test_json.log",
+            ])
+            .fails()
+            .stderr()
+            .contains("--input-tail can only seek within one file, but 2 files were given")
+            .unwrap();
+    }
+
+    #[test]
+    fn gzip_input() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count",
+                "test_files/test_json.log.gz",
+                "test_files/test_json.log",
+            ])
+            .stdout()
+            .is("_count
+--------------
+12")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count by level",
+                "--file",
+                "test_files/test_json.log.gz",
+            ])
+            .stdout()
+            .is("level        _count
+---------------------------
+info         3
+error        2
+None         1")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "*",
+                "--file",
+                "test_files/test_json.log.gz",
+                "--input-tail",
+                "2",
+            ])
+            .fails()
+            .stderr()
+            .contains("--input-tail can't seek within a gzipped file")
+            .unwrap();
+    }
+
+    #[test]
+    fn broken_pipe() {
+        use std::io::{BufRead, Write};
+        use std::process::{Command, Stdio};
+        let mut child = Command::new(env!("CARGO_BIN_EXE_agrind"))
+            .arg("* | logfmt")
+            .env("RUST_BACKTRACE", "0")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        // Keep writing until agrind goes away, so it has output to write after the pipe closes
+        let writer = thread::spawn(move || {
+            for i in 0..1_000_000 {
+                if writeln!(stdin, "n={}", i).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut stdout = io::BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        assert!(line.contains("[n=0]"), "unexpected first line: {:?}", line);
+        drop(stdout);
+        let output = child.wait_with_output().unwrap();
+        writer.join().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "agrind failed: {}", stderr);
+        assert!(!stderr.contains("panic"), "agrind panicked: {}", stderr);
+        assert!(
+            !stderr.contains("error"),
+            "agrind reported an error: {}",
+            stderr
+        );
+    }
+
+    #[test]
+    fn truncated_gzip_input() {
+        // What was read before the end of the file is still counted
+        assert_cli::Assert::main_binary()
+            .with_args(&["* | json | count", "test_files/truncated.log.gz"])
+            .fails()
+            .stdout()
+            .is("_count
+--------------
+3")
+            .stderr()
+            .contains("error: failed to read the input: ")
+            .stderr()
+            .doesnt_contain("panic")
+            .unwrap();
+    }
+
     #[test]
     fn file_input() {
         assert_cli::Assert::main_binary()
@@ -253,121 +579,995 @@ None         1")
     }
 
     #[test]
-    fn aggregate_of_aggregate() {
+    fn input_tail() {
         assert_cli::Assert::main_binary()
             .with_args(&[
-                "* | json | count by level | count",
+                "* | json | count by level",
                 "--file",
                 "test_files/test_json.log",
+                "--input-tail",
+                "3",
             ])
             .stdout()
-            .is("_count\n--------------\n3")
+            .is("level        _count
+---------------------------
+info         2
+None         1")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&["* | count", "--input-tail", "3"])
+            .fails()
+            .and()
+            .stderr()
+            .contains("--input-tail needs a file to seek within")
             .unwrap();
     }
 
     #[test]
-    fn json_from() {
+    fn seek_and_report_offset() {
         assert_cli::Assert::main_binary()
             .with_args(&[
-                r#"* | parse "* *" as lev, js | json from js | count by level"#,
+                "* | json | count by level",
                 "--file",
-                "test_files/test_partial_json.log",
+                "test_files/test_json.log",
+                "--seek",
+                "1",
+                "--report-offset",
             ])
             .stdout()
             .is("level        _count
 ---------------------------
-info         3
 error        2
+info         2
 None         1")
+            .stderr()
+            .is("offset=332")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&["* | count", "--report-offset"])
+            .fails()
+            .and()
+            .stderr()
+            .contains("--report-offset needs a file to seek within")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&["* | count", "--follow"])
+            .fails()
+            .and()
+            .stderr()
+            .contains("--follow needs a file to seek within")
             .unwrap();
     }
 
     #[test]
-    fn fields() {
+    fn seeded_rand() {
+        for _ in 0..2 {
+            assert_cli::Assert::main_binary()
+                .with_args(&[
+                    "* | json | where rand() < 0.5 | fields level, message",
+                    "--file",
+                    "test_files/test_json.log",
+                    "--seed",
+                    "1",
+                ])
+                .stdout()
+                .is("[level=error]        [message=Oh now an error!]
+[level=error]        [message=So many more errors!]
+[level=info]         [message=A different event]")
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn csv_output() {
         assert_cli::Assert::main_binary()
             .with_args(&[
-                r#"* "error" | parse "* *" as lev, js
-                     | json from js
-                     | fields except js, lev"#,
+                "* | json | count by level",
                 "--file",
-                "test_files/test_partial_json.log",
+                "test_files/test_json.log",
+                "--output",
+                "csv",
             ])
             .stdout()
-            .is("[level=error]        [message=Oh now an error!]
-[level=error]        [message=So many more errors!]")
+            .is("level,_count
+info,3
+error,2
+,1")
             .unwrap();
     }
 
     #[test]
-    fn parse_plain_text() {
+    fn group_digits_only_in_tables() {
+        assert_cli::Assert::main_binary()
+            .with_args(&["--group-digits", "--output", "csv", "* | logfmt | sum(n)"])
+            .stdin("n=1234567.5\n")
+            .stdout()
+            .is("_sum\n1234567.5")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&["--group-digits", "--output", "sql", "* | logfmt | sum(n)"])
+            .stdin("n=1234567.5\n")
+            .succeeds()
+            .stdout()
+            .contains("1234567.5")
+            .unwrap();
         assert_cli::Assert::main_binary()
             .with_args(&[
-                r#"db-1 response | parse "in *ms" as duration"#,
-                "--file",
-                "test_files/test_parse.log",
+                "--group-digits",
+                "--digit-separator",
+                ".",
+                "* | logfmt | sum(n)",
             ])
+            .stdin("n=1234567.5\n")
             .stdout()
-            .is("[duration=500]
-[duration=100]
-[duration=102]
-[duration=102]
-[duration=100]
-[duration=100]
-[duration=100]
-[duration=122]")
+            .contains("1.234.567,50")
             .unwrap();
     }
 
     #[test]
-    fn filter_wildcard() {
+    fn precision_overrides_column_format() {
         assert_cli::Assert::main_binary()
-            .with_args(&[r#""*STAR*""#, "--file", "test_files/filter_test.log"])
+            .with_args(&["--precision", "0", "* | logfmt | avg(n), sum(n)"])
+            .stdin("n=1104\nn=1106.6\n")
             .stdout()
-            .is("[INFO] Match a *STAR*!")
+            .is("_average        _sum\n----------------------------\n1105            2211")
             .unwrap();
         assert_cli::Assert::main_binary()
-            .with_args(&[r#"*STAR*"#, "--file", "test_files/filter_test.log"])
+            .with_args(&["--precision", "3", "* | logfmt | avg(n)"])
+            .stdin("n=1105\n")
             .stdout()
-            .is("[INFO] Match a *STAR*!
-[INFO] Not a STAR!")
+            .is("_average\n----------------\n1105.000")
             .unwrap();
     }
 
     #[test]
-    fn test_limit() {
+    fn concat_ascii_ellipsis() {
         assert_cli::Assert::main_binary()
-            .with_args(&[r#"* | limit 2"#, "--file", "test_files/filter_test.log"])
+            .with_args(&[
+                "* | json | concat_distinct(message, \"; \", 1) as first by level",
+                "--file",
+                "test_files/test_json.log",
+                "--ascii",
+                "--output",
+                "csv",
+            ])
             .stdout()
-            .is("[INFO] I am a log!
-[WARN] Uh oh, danger ahead! ")
+            .is("level,first
+error,Oh now an error!; ...
+info,A thing happened; ...
+,")
             .unwrap();
     }
 
     #[test]
-    fn custom_format() {
+    fn split_by_field() {
+        let dir = env::temp_dir().join(format!("agrind-split-by-{}", std::process::id()));
         assert_cli::Assert::main_binary()
             .with_args(&[
-                "* | logfmt",
-                "--format",
-                "{level} | {msg:<30} module={module}",
+                "* | json | fields level, message",
                 "--file",
-                "test_files/test_logfmt.log",
+                "test_files/test_json.log",
+                "--split-by",
+                "level",
+                "--split-dir",
+                dir.to_str().unwrap(),
             ])
             .stdout()
-            .is(
-                "info | Stopping all fetchers          module=kafka.consumer.ConsumerFetcherManager
-info | Starting all fetchers          module=kafka.consumer.ConsumerFetcherManager
-warn | Fetcher failed to start        module=kafka.consumer.ConsumerFetcherManager",
-            )
+            .is("")
             .unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("error.log")).unwrap(),
+            "[level=error]        [message=Oh now an error!]
+[level=error]        [message=So many more errors!]
+"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("info.log"))
+                .unwrap()
+                .lines()
+                .count(),
+            3
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("None.log")).unwrap(),
+            "[level=None]\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    fn ensure_parses(query: &str) {
-        let query_container = QueryContainer::new(query.to_string(), Box::new(EmptyErrorReporter));
-        Pipeline::new(&query_container, None).expect(&format!(
-            "Query: `{}` from the README should have parsed",
-            query
-        ));
+    #[test]
+    fn show_plan() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count by level",
+                "--file",
+                "test_files/test_json.log",
+                "--show-plan",
+            ])
+            .stdout()
+            .contains("info         3")
+            .stderr()
+            .is("search: *
+1. [stream] json from the raw line
+2. [aggregate] count as _count by level
+3. [aggregate] sort by _count descending (added automatically)
+output: an aggregate table")
+            .unwrap();
+    }
+
+    #[test]
+    fn stats() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | where level == \"error\" | count",
+                "--file",
+                "test_files/test_json.log",
+                "--stats",
+            ])
+            .stdout()
+            .contains("2")
+            .stderr()
+            .is("search: 6 → 6
+json from the raw line: 6 → 6
+where level == \"error\": 6 → 2 (dropped 4)")
+            .unwrap();
+    }
+
+    #[test]
+    fn limit_stops_reading() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | limit 2",
+                "--file",
+                "test_files/test_json.log",
+                "--stats",
+            ])
+            .stderr()
+            .is("search: 2 → 2
+json from the raw line: 2 → 2
+limit 2: 2 → 2")
+            .unwrap();
+    }
+
+    #[test]
+    fn color_choice() {
+        let env = assert_cli::Environment::inherit().insert("NO_COLOR", "1");
+        assert_cli::Assert::main_binary()
+            .with_env(env)
+            .with_args(&[
+                "* | json | fields level",
+                "--file",
+                "test_files/test_json.log",
+                "--color",
+                "always",
+            ])
+            .stdout()
+            .contains("[\x1b[1;36mlevel\x1b[0m=info]")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | fields level",
+                "--file",
+                "test_files/test_json.log",
+                "--color",
+                "never",
+            ])
+            .stdout()
+            .doesnt_contain("\x1b[")
+            .unwrap();
+    }
+
+    #[test]
+    fn update_interval_minimum() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count",
+                "--file",
+                "test_files/test_json.log",
+                "--interval",
+                "5",
+            ])
+            .stderr()
+            .is("warning: --interval can't be under 20ms, so redrawing every 20ms instead")
+            .unwrap();
+    }
+
+    #[test]
+    fn distinct_warning() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count_distinct(message)",
+                "--file",
+                "test_files/test_json.log",
+                "--distinct-warning",
+                "2",
+            ])
+            .stdout()
+            .is("_countDistinct
+----------------------
+4")
+            .stderr()
+            .is("warning: _countDistinct: more than 2 distinct values in one group, which are all kept in memory")
+            .unwrap();
+    }
+
+    #[test]
+    fn per_key_limit() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | fields level, message",
+                "--file",
+                "test_files/test_json.log",
+                "--per-key-limit",
+                "level=1",
+            ])
+            .stdout()
+            .is("[level=info]         [message=A thing happened]
+[level=error]        [message=Oh now an error!]
+[level=None]")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count",
+                "--file",
+                "test_files/test_json.log",
+                "--per-key-limit",
+                "level=1",
+            ])
+            .fails()
+            .stderr()
+            .contains("--per-key-limit only limits records")
+            .unwrap();
+    }
+
+    #[test]
+    fn join_side_query() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count by level, severity",
+                "--file",
+                "test_files/test_json.log",
+                "--join",
+                "test_files/levels.log | logfmt",
+                "--join-on",
+                "level",
+            ])
+            .stdout()
+            .is("level        severity        _count
+-------------------------------------------
+info         6               3
+error        3               2
+None         None            1")
+            .unwrap();
+    }
+
+    #[test]
+    fn trailer() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | fields level",
+                "--file",
+                "test_files/test_json.log",
+                "--trailer",
+            ])
+            .stdout()
+            .is("[level=info]
+[level=error]
+[level=error]
+[level=info]
+[level=info]
+[level=None]
+agrind-trailer records=6 bytes=80 crc32=69437525")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count by level",
+                "--file",
+                "test_files/test_json.log",
+                "--output",
+                "csv",
+                "--trailer",
+                "--trailer-stderr",
+            ])
+            .stdout()
+            .is("level,_count\ninfo,3\nerror,2\n,1")
+            .stderr()
+            .is("agrind-trailer rows=3 bytes=31 crc32=e994d3e5")
+            .unwrap();
+    }
+
+    #[test]
+    fn output_record_separator() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | fields level",
+                "--file",
+                "test_files/test_json.log",
+                "--output-record-separator",
+                r"\0",
+            ])
+            .stdout()
+            .is("[level=info]\0[level=error]\0[level=error]\0[level=info]\0[level=info]\0[level=None]\0")
+            .unwrap();
+    }
+
+    #[test]
+    fn json_output() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | fields level, num_things",
+                "--file",
+                "test_files/test_json.log",
+                "--output",
+                "json",
+            ])
+            .stdout()
+            .is(r#"{"level":"info","num_things":1102}
+{"level":"error"}
+{"level":"error"}
+{"level":"info","num_things":12}
+{"level":"info"}
+{"level":null}"#)
+            .unwrap();
+    }
+
+    #[test]
+    fn markdown_output() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count by level",
+                "--file",
+                "test_files/test_json.log",
+                "--output",
+                "markdown",
+            ])
+            .stdout()
+            .is("| level | _count |
+| --- | --- |
+| info | 3 |
+| error | 2 |
+|  | 1 |")
+            .unwrap();
+    }
+
+    #[test]
+    fn sql_output() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count by level",
+                "--file",
+                "test_files/test_json.log",
+                "--output",
+                "sql",
+                "--table",
+                "levels",
+                "--sql-create",
+            ])
+            .stdout()
+            .is(
+                "CREATE TABLE \"levels\" (\"level\" TEXT, \"_count\" INTEGER);
+INSERT INTO \"levels\" (\"level\", \"_count\") VALUES ('info', 3);
+INSERT INTO \"levels\" (\"level\", \"_count\") VALUES ('error', 2);
+INSERT INTO \"levels\" (\"level\", \"_count\") VALUES (NULL, 1);",
+            )
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&["*", "--sql-create"])
+            .fails()
+            .and()
+            .stderr()
+            .contains("--sql-create is only used with --output sql")
+            .unwrap();
+    }
+
+    #[test]
+    fn append_output() {
+        let report = env::temp_dir().join(format!("agrind-append-{}.csv", std::process::id()));
+        let _ = fs::remove_file(&report);
+        for _ in 0..2 {
+            assert_cli::Assert::main_binary()
+                .with_args(&[
+                    "* | json | count by level",
+                    "--file",
+                    "test_files/test_json.log",
+                    "--append",
+                    report.to_str().unwrap(),
+                ])
+                .unwrap();
+        }
+        assert_eq!(
+            fs::read_to_string(&report).unwrap(),
+            "level,_count\ninfo,3\nerror,2\n,1\ninfo,3\nerror,2\n,1\n"
+        );
+        fs::remove_file(&report).unwrap();
+    }
+
+    #[test]
+    fn snapshot_file() {
+        let snapshot = env::temp_dir().join(format!("agrind-snapshot-{}.csv", std::process::id()));
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count by level",
+                "--file",
+                "test_files/test_json.log",
+                "--snapshot-file",
+                snapshot.to_str().unwrap(),
+            ])
+            .stdout()
+            .contains("info         3")
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&snapshot).unwrap(),
+            "level,_count\ninfo,3\nerror,2\n,1\n"
+        );
+        fs::remove_file(&snapshot).unwrap();
+
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json",
+                "--file",
+                "test_files/test_json.log",
+                "--snapshot-file",
+                snapshot.to_str().unwrap(),
+            ])
+            .fails()
+            .and()
+            .stderr()
+            .contains("Snapshots are only written for aggregates")
+            .unwrap();
+    }
+
+    #[test]
+    fn partial_interval() {
+        // The input ends long before the interval, so only the final table is written
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count",
+                "--file",
+                "test_files/test_json.log",
+                "--partial-interval",
+                "1h",
+            ])
+            .stdout()
+            .is("_count
+--------------
+6")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&["* | count", "--partial-interval", "10s", "--output", "csv"])
+            .fails()
+            .and()
+            .stderr()
+            .contains("--partial-interval writes tables, so it can't be used with csv")
+            .unwrap();
+    }
+
+    #[test]
+    fn output_mode_from_extension() {
+        let dir = env::temp_dir();
+        let csv_file = dir.join(format!("agrind-{}.csv", std::process::id()));
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count by level",
+                "--file",
+                "test_files/test_json.log",
+                "--output-file",
+                csv_file.to_str().unwrap(),
+            ])
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&csv_file).unwrap(),
+            "level,_count\ninfo,3\nerror,2\n,1\n"
+        );
+
+        // --output always wins over the extension
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count by level",
+                "--file",
+                "test_files/test_json.log",
+                "--output-file",
+                csv_file.to_str().unwrap(),
+                "--output",
+                "legacy",
+            ])
+            .unwrap();
+        assert!(fs::read_to_string(&csv_file)
+            .unwrap()
+            .starts_with("level        _count\n"));
+        fs::remove_file(&csv_file).unwrap();
+    }
+
+    #[test]
+    fn xlsx_requires_aggregate() {
+        assert_cli::Assert::main_binary()
+            .with_args(&["* | json", "--output-file", "report.xlsx"])
+            .fails()
+            .and()
+            .stderr()
+            .contains("xlsx output is only supported for aggregates")
+            .unwrap();
+    }
+
+    #[test]
+    fn dedup_output() {
+        assert_cli::Assert::main_binary()
+            .stdin("a\na\nb\na\n")
+            .with_args(&["*", "--dedup-output"])
+            .stdout()
+            .is("a\nb\na")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .stdin("a\na\nb\na\n")
+            .with_args(&["*", "--dedup-count"])
+            .stdout()
+            .is("2 a\n      1 b\n      1 a")
+            .unwrap();
+    }
+
+    #[test]
+    fn env_vars() {
+        let env = assert_cli::Environment::inherit()
+            .insert("RUST_BACKTRACE", "0")
+            .insert("AGRIND_LEVEL", "info")
+            .insert("AGRIND_MIN_THINGS", "100");
+        assert_cli::Assert::main_binary()
+            .with_env(&env)
+            .with_args(&[
+                "* | json | where level == $AGRIND_LEVEL | where num_things > $AGRIND_MIN_THINGS | count",
+                "--file",
+                "test_files/test_json.log",
+            ])
+            .stdout()
+            .is("_count\n--------------\n1")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_env(&env)
+            .with_args(&["* | where level == $AGRIND_UNSET"])
+            .fails()
+            .and()
+            .stderr()
+            .contains("Environment variable `$AGRIND_UNSET` isn't set")
+            .unwrap();
+    }
+
+    #[test]
+    fn default_flags() {
+        let env = assert_cli::Environment::inherit()
+            .insert("RUST_BACKTRACE", "0")
+            .insert("XDG_CONFIG_HOME", "test_files/defaults")
+            .insert("AGRIND_OPTS", "");
+        let args = &[
+            "* | json | count by level",
+            "--file",
+            "test_files/test_json.log",
+        ];
+        assert_cli::Assert::main_binary()
+            .with_env(&env)
+            .with_args(args)
+            .stdout()
+            .is("level,_count\ninfo,3\nerror,2\n,1\n")
+            .stderr()
+            .contains("warning: ignoring unknown option `bogus` in the config file")
+            .unwrap();
+        let legacy_output = "level        _count
+---------------------------
+info         3
+error        2
+None         1";
+        assert_cli::Assert::main_binary()
+            .with_env(&env)
+            .with_args(args)
+            .with_args(&["--output", "legacy"])
+            .stdout()
+            .is(legacy_output)
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_env(env.clone().insert("AGRIND_OPTS", "--output legacy"))
+            .with_args(args)
+            .stdout()
+            .is(legacy_output)
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_env(&env)
+            .with_args(args)
+            .with_args(&["--profile", "table"])
+            .stdout()
+            .is(legacy_output)
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_env(&env)
+            .with_args(args)
+            .with_args(&["--profile", "missing"])
+            .fails()
+            .and()
+            .stderr()
+            .contains("No profile named `missing`")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_env(env.clone().insert("AGRIND_OPTS", "--profile table"))
+            .with_args(args)
+            .stdout()
+            .is(legacy_output)
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_env(env.clone().insert(
+                "AGRIND_OPTS",
+                "--output legacy --none-placeholder 'no level'",
+            ))
+            .with_args(args)
+            .stdout()
+            .contains("no level     1")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_env(env.clone().insert("AGRIND_OPTS", "--output \"legacy"))
+            .with_args(args)
+            .fails()
+            .and()
+            .stderr()
+            .contains("AGRIND_OPTS ends inside a quote or after a backslash")
+            .unwrap();
+    }
+
+    #[test]
+    fn query_macros() {
+        let env = assert_cli::Environment::inherit()
+            .insert("RUST_BACKTRACE", "0")
+            .insert("HOME", "test_files/macros");
+        assert_cli::Assert::main_binary()
+            .with_env(&env)
+            .with_args(&["* | @levels", "--file", "test_files/test_json.log"])
+            .stdout()
+            .is("level        _count
+---------------------------
+info         3
+error        2
+None         1")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_env(&env)
+            .with_args(&[
+                "@errors | fields level",
+                "--file",
+                "test_files/test_json.log",
+            ])
+            .stdout()
+            .is("[level=error]\n[level=error]")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_env(&env)
+            .with_args(&["* | @loop"])
+            .fails()
+            .and()
+            .stderr()
+            .contains("Macro `@loop` is recursive: @loop -> @loop")
+            .unwrap();
+    }
+
+    #[test]
+    fn auto_input_format() {
+        assert_cli::Assert::main_binary()
+            .stdin("{\"level\": \"info\"}\nlevel=error msg=oops\nplain text\n")
+            .with_args(&["* | count by level", "--input-format", "auto"])
+            .stdout()
+            .is("level        _count
+---------------------------
+None         1
+error        1
+info         1")
+            .unwrap();
+    }
+
+    #[test]
+    fn aggregate_of_aggregate() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | json | count by level | count",
+                "--file",
+                "test_files/test_json.log",
+            ])
+            .stdout()
+            .is("_count\n--------------\n3")
+            .unwrap();
+    }
+
+    #[test]
+    fn json_from() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                r#"* | parse "* *" as lev, js | json from js | count by level"#,
+                "--file",
+                "test_files/test_partial_json.log",
+            ])
+            .stdout()
+            .is("level        _count
+---------------------------
+info         3
+error        2
+None         1")
+            .unwrap();
+    }
+
+    #[test]
+    fn fields() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                r#"* "error" | parse "* *" as lev, js
+                     | json from js
+                     | fields except js, lev"#,
+                "--file",
+                "test_files/test_partial_json.log",
+            ])
+            .stdout()
+            .is("[level=error]        [message=Oh now an error!]
+[level=error]        [message=So many more errors!]")
+            .unwrap();
+    }
+
+    #[test]
+    fn fields_order() {
+        structured_test(include_str!("structured_tests/fields_order.toml"));
+    }
+
+    #[test]
+    fn parse_plain_text() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                r#"db-1 response | parse "in *ms" as duration"#,
+                "--file",
+                "test_files/test_parse.log",
+            ])
+            .stdout()
+            .is("[duration=500]
+[duration=100]
+[duration=102]
+[duration=102]
+[duration=100]
+[duration=100]
+[duration=100]
+[duration=122]")
+            .unwrap();
+    }
+
+    #[test]
+    fn filter_wildcard() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[r#""*STAR*""#, "--file", "test_files/filter_test.log"])
+            .stdout()
+            .is("[INFO] Match a *STAR*!")
+            .unwrap();
+        assert_cli::Assert::main_binary()
+            .with_args(&[r#"*STAR*"#, "--file", "test_files/filter_test.log"])
+            .stdout()
+            .is("[INFO] Match a *STAR*!
+[INFO] Not a STAR!")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_limit() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[r#"* | limit 2"#, "--file", "test_files/filter_test.log"])
+            .stdout()
+            .is("[INFO] I am a log!
+[WARN] Uh oh, danger ahead! ")
+            .unwrap();
+    }
+
+    #[test]
+    fn custom_format() {
+        assert_cli::Assert::main_binary()
+            .with_args(&[
+                "* | logfmt",
+                "--format",
+                "{level} | {msg:<30} module={module}",
+                "--file",
+                "test_files/test_logfmt.log",
+            ])
+            .stdout()
+            .is(
+                "info | Stopping all fetchers          module=kafka.consumer.ConsumerFetcherManager
+info | Starting all fetchers          module=kafka.consumer.ConsumerFetcherManager
+warn | Fetcher failed to start        module=kafka.consumer.ConsumerFetcherManager",
+            )
+            .unwrap();
+    }
+
+    fn pipeline_error(query: &str, render_config: RenderConfig) -> AgrindError {
+        let query_container = QueryContainer::new(query.to_string(), Box::new(EmptyErrorReporter));
+        match Pipeline::new(&query_container, InputFormat::Raw, render_config) {
+            Ok(_) => panic!("Query: `{}` should have failed", query),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn pipeline_error_kinds() {
+        match pipeline_error("* | foo", RenderConfig::default()) {
+            AgrindError::Parse => (),
+            other => panic!("Expected a parse error, found {:?}", other),
+        }
+        match pipeline_error("* | limit 0", RenderConfig::default()) {
+            AgrindError::Query(CompileError::Type(TypeError::InvalidLimit { .. })) => (),
+            other => panic!("Expected an invalid limit, found {:?}", other),
+        }
+        let missing_dir = RenderConfig {
+            output_file: Some(env::temp_dir().join("agrind-missing-dir").join("out.log")),
+            ..RenderConfig::default()
+        };
+        match pipeline_error("*", missing_dir) {
+            ref e @ AgrindError::Io(_) => assert!(e.source().is_some()),
+            other => panic!("Expected an io error, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rendered_alias_error_message() {
+        let error = AgrindError::Query(CompileError::RenderedAlias {
+            alias: "json | count by".to_string(),
+        });
+        assert_eq!(
+            error.to_string(),
+            "Failed to parse rendered alias: `json | count by` as operator_list"
+        );
+    }
+
+    /// Input that never ends: after its lines, reads block forever, like a quiet `tail -f`
+    struct StallingReader {
+        lines: io::Cursor<Vec<u8>>,
+        stalled: mpsc::Sender<()>,
+    }
+
+    impl io::Read for StallingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.lines.read(buf)?;
+            if read == 0 {
+                self.stalled.send(()).unwrap();
+                loop {
+                    thread::park();
+                }
+            }
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn interrupt_renders_partial_aggregate() {
+        let output = env::temp_dir().join(format!("agrind-interrupt-{}.log", std::process::id()));
+        let query_container = QueryContainer::new(
+            "* | parse \"level=*\" as level | count by level".to_string(),
+            Box::new(EmptyErrorReporter),
+        );
+        let render_config = RenderConfig {
+            output_file: Some(output.clone()),
+            min_buffer: 4,
+            max_buffer: 8,
+            ..RenderConfig::default()
+        };
+        let pipeline = Pipeline::new(&query_container, InputFormat::Raw, render_config).unwrap();
+        let interrupt = pipeline.interrupt_handle();
+        let (stalled, is_stalled) = mpsc::channel();
+        let reader = StallingReader {
+            lines: io::Cursor::new(b"level=info\nlevel=error\nlevel=info\n".to_vec()),
+            stalled,
+        };
+        thread::spawn(move || pipeline.process(io::BufReader::new(reader)));
+        is_stalled.recv().unwrap();
+        assert!(interrupt.interrupt());
+        assert!(!interrupt.interrupt());
+        interrupt.wait_until_rendered();
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "level        _count\n---------------------------\ninfo         2\nerror        1\n"
+        );
+        fs::remove_file(&output).unwrap();
+    }
+
+    fn ensure_parses(query: &str) {
+        let query_container = QueryContainer::new(query.to_string(), Box::new(EmptyErrorReporter));
+        Pipeline::new(&query_container, InputFormat::Raw, RenderConfig::default()).expect(
+            &format!("Query: `{}` from the README should have parsed", query),
+        );
         println!("validated {}", query);
     }
 